@@ -0,0 +1,40 @@
+/* C interface to the mdpu virtual machine. */
+#ifndef MDPU_H
+#define MDPU_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Status codes returned by every function that can fail. */
+#define MDPU_OK 0
+#define MDPU_ERR_NULL -1
+#define MDPU_ERR_PARSE -2
+#define MDPU_ERR_RUNTIME -3
+#define MDPU_ERR_BOUNDS -4
+
+typedef struct MdpuMachine MdpuMachine;
+
+/* Returns NULL on invalid dimensions. */
+MdpuMachine *mdpu_new(size_t num_registers, size_t memory_size);
+/* Also writes the program's .data image; MDPU_ERR_BOUNDS if it does not fit in memory.
+ * Registers, flags, the stack and the instruction count start over from zero. */
+int mdpu_load_program(MdpuMachine *handle, const char *text);
+/* Each call may execute up to max_instructions more instructions. */
+int mdpu_run(MdpuMachine *handle, size_t max_instructions);
+int mdpu_get_register(const MdpuMachine *handle, size_t idx, int32_t *out);
+int mdpu_set_register(MdpuMachine *handle, size_t idx, int32_t value);
+int mdpu_read_memory(const MdpuMachine *handle, size_t addr, int32_t *out);
+void mdpu_free(MdpuMachine *handle);
+
+/* Message for the last failure on the calling thread. */
+const char *mdpu_last_error_message(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* MDPU_H */
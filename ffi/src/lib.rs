@@ -0,0 +1,183 @@
+// C interface for embedding the VM, see include/mdpu.h
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, CStr, CString};
+
+use mdpu::{assemble, run, ProcessingUnit, ProcessingUnitBuilder, Program};
+
+pub const MDPU_OK: c_int = 0;
+pub const MDPU_ERR_NULL: c_int = -1;
+pub const MDPU_ERR_PARSE: c_int = -2;
+pub const MDPU_ERR_RUNTIME: c_int = -3;
+pub const MDPU_ERR_BOUNDS: c_int = -4;
+
+// Machine handed to C callers as an opaque pointer
+pub struct MdpuMachine {
+    pu: ProcessingUnit,
+    program: Program,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+fn set_last_error(message: impl ToString) {
+    // Interior NULs cannot cross the C boundary, so drop them
+    let message = message.to_string().replace('\0', "");
+    LAST_ERROR.with(|last| *last.borrow_mut() = CString::new(message).unwrap_or_default());
+}
+
+fn fail(status: c_int, message: impl ToString) -> c_int {
+    set_last_error(message);
+    status
+}
+
+/// Create a machine with the given register count and memory size.
+/// Returns NULL on invalid dimensions; see `mdpu_last_error_message`.
+#[no_mangle]
+pub extern "C" fn mdpu_new(num_registers: usize, memory_size: usize) -> *mut MdpuMachine {
+    match ProcessingUnitBuilder::new()
+        .registers(num_registers)
+        .memory(&[memory_size])
+        .build()
+    {
+        Ok(pu) => Box::into_raw(Box::new(MdpuMachine {
+            pu,
+            program: Program::default(),
+        })),
+        Err(err) => {
+            set_last_error(err);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Assemble NUL-terminated program text, make it the machine's program and write its
+/// data image into memory. Registers, flags, the stack and the instruction count start
+/// over, so nothing carries across from an earlier program.
+///
+/// # Safety
+/// `handle` must come from `mdpu_new` and `text` must be NULL or a valid C string.
+#[no_mangle]
+pub unsafe extern "C" fn mdpu_load_program(handle: *mut MdpuMachine, text: *const c_char) -> c_int {
+    let Some(machine) = handle.as_mut() else {
+        return fail(MDPU_ERR_NULL, "Machine handle is NULL");
+    };
+    if text.is_null() {
+        return fail(MDPU_ERR_NULL, "Program text is NULL");
+    }
+    let Ok(source) = CStr::from_ptr(text).to_str() else {
+        return fail(MDPU_ERR_PARSE, "Program text is not valid UTF-8");
+    };
+    match assemble(source) {
+        Ok(program) => {
+            machine.pu.reset_registers_only();
+            if let Err(err) = machine.pu.load_data(&program) {
+                return fail(MDPU_ERR_BOUNDS, err);
+            }
+            machine.program = program;
+            MDPU_OK
+        }
+        Err(err) => fail(MDPU_ERR_PARSE, err),
+    }
+}
+
+/// Run the loaded program for at most `max_instructions` more instructions.
+///
+/// # Safety
+/// `handle` must come from `mdpu_new`.
+#[no_mangle]
+pub unsafe extern "C" fn mdpu_run(handle: *mut MdpuMachine, max_instructions: usize) -> c_int {
+    let Some(machine) = handle.as_mut() else {
+        return fail(MDPU_ERR_NULL, "Machine handle is NULL");
+    };
+    // `run` takes a limit on the total count, which goes on from earlier calls
+    let limit = machine
+        .pu
+        .instruction_count()
+        .saturating_add(max_instructions);
+    match run(&mut machine.pu, &machine.program, limit) {
+        Ok(_) => MDPU_OK,
+        Err(err) => fail(MDPU_ERR_RUNTIME, err),
+    }
+}
+
+/// Read register `idx` into `*out`.
+///
+/// # Safety
+/// `handle` must come from `mdpu_new` and `out` must be NULL or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn mdpu_get_register(
+    handle: *const MdpuMachine,
+    idx: usize,
+    out: *mut i32,
+) -> c_int {
+    let (Some(machine), Some(out)) = (handle.as_ref(), out.as_mut()) else {
+        return fail(MDPU_ERR_NULL, "Machine handle or output pointer is NULL");
+    };
+    match machine.pu.get_register(idx) {
+        Ok(value) => {
+            *out = value;
+            MDPU_OK
+        }
+        Err(err) => fail(MDPU_ERR_BOUNDS, err),
+    }
+}
+
+/// Set register `idx` to `value`.
+///
+/// # Safety
+/// `handle` must come from `mdpu_new`.
+#[no_mangle]
+pub unsafe extern "C" fn mdpu_set_register(
+    handle: *mut MdpuMachine,
+    idx: usize,
+    value: i32,
+) -> c_int {
+    let Some(machine) = handle.as_mut() else {
+        return fail(MDPU_ERR_NULL, "Machine handle is NULL");
+    };
+    match machine.pu.set_register(idx, value) {
+        Ok(()) => MDPU_OK,
+        Err(err) => fail(MDPU_ERR_BOUNDS, err),
+    }
+}
+
+/// Read memory cell `addr` into `*out`.
+///
+/// # Safety
+/// `handle` must come from `mdpu_new` and `out` must be NULL or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn mdpu_read_memory(
+    handle: *const MdpuMachine,
+    addr: usize,
+    out: *mut i32,
+) -> c_int {
+    let (Some(machine), Some(out)) = (handle.as_ref(), out.as_mut()) else {
+        return fail(MDPU_ERR_NULL, "Machine handle or output pointer is NULL");
+    };
+    match machine.pu.read_memory(addr) {
+        Ok(value) => {
+            *out = value;
+            MDPU_OK
+        }
+        Err(err) => fail(MDPU_ERR_BOUNDS, err),
+    }
+}
+
+/// Destroy a machine created by `mdpu_new`. NULL is ignored.
+///
+/// # Safety
+/// `handle` must come from `mdpu_new` and must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn mdpu_free(handle: *mut MdpuMachine) {
+    if !handle.is_null() {
+        drop(Box::from_raw(handle));
+    }
+}
+
+/// Message describing the last failure on this thread. The pointer stays
+/// valid until the next failing call on the same thread.
+#[no_mangle]
+pub extern "C" fn mdpu_last_error_message() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ptr())
+}
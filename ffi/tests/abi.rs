@@ -0,0 +1,69 @@
+// The C interface called the way a C program would, through raw pointers and C strings
+use std::ffi::{CStr, CString};
+
+use mdpu_ffi::*;
+
+fn load(handle: *mut MdpuMachine, text: &str) -> i32 {
+    let text = CString::new(text).unwrap();
+    unsafe { mdpu_load_program(handle, text.as_ptr()) }
+}
+
+fn register(handle: *const MdpuMachine, idx: usize) -> i32 {
+    let mut value = 0;
+    assert_eq!(
+        unsafe { mdpu_get_register(handle, idx, &mut value) },
+        MDPU_OK
+    );
+    value
+}
+
+#[test]
+fn loaded_program_runs_and_leaves_its_state() {
+    let handle = mdpu_new(2, 8);
+    assert!(!handle.is_null());
+    assert_eq!(
+        load(handle, ".data 5\n.word 9\nLI R0 2\nLOAD R1 5"),
+        MDPU_OK
+    );
+    assert_eq!(unsafe { mdpu_set_register(handle, 0, 40) }, MDPU_OK);
+    assert_eq!(unsafe { mdpu_run(handle, 10) }, MDPU_OK);
+    assert_eq!(register(handle, 0), 2);
+    assert_eq!(register(handle, 1), 9);
+    let mut cell = 0;
+    assert_eq!(unsafe { mdpu_read_memory(handle, 5, &mut cell) }, MDPU_OK);
+    assert_eq!(cell, 9);
+    unsafe { mdpu_free(handle) };
+}
+
+#[test]
+fn each_load_and_run_starts_a_fresh_budget() {
+    let handle = mdpu_new(2, 8);
+    assert_eq!(load(handle, "LI R0 1\nPUSH R0\nCMP R0 R0"), MDPU_OK);
+    assert_eq!(unsafe { mdpu_run(handle, 4) }, MDPU_OK);
+    // The count, stack and flags left by the first program do not carry over
+    assert_eq!(load(handle, "LI R0 1\nLI R1 2\nADD R0 R1 R1\nNOP"), MDPU_OK);
+    assert_eq!(unsafe { mdpu_run(handle, 4) }, MDPU_OK);
+    assert_eq!(register(handle, 1), 3);
+
+    // A second run gets its own budget rather than what the first one left
+    assert_eq!(load(handle, "loop: JMP loop"), MDPU_OK);
+    assert_eq!(unsafe { mdpu_run(handle, 3) }, MDPU_ERR_RUNTIME);
+    assert_eq!(unsafe { mdpu_run(handle, 3) }, MDPU_ERR_RUNTIME);
+    let message = unsafe { CStr::from_ptr(mdpu_last_error_message()) };
+    assert!(message.to_str().unwrap().contains('6'), "{message:?}");
+    unsafe { mdpu_free(handle) };
+}
+
+#[test]
+fn failures_set_the_last_error() {
+    assert!(mdpu_new(1, 0).is_null());
+    assert_eq!(load(std::ptr::null_mut(), "NOP"), MDPU_ERR_NULL);
+
+    let handle = mdpu_new(1, 4);
+    assert_eq!(load(handle, "FROB R0"), MDPU_ERR_PARSE);
+    let message = unsafe { CStr::from_ptr(mdpu_last_error_message()) };
+    assert!(!message.is_empty());
+    assert_eq!(load(handle, ".data 10\n.word 1"), MDPU_ERR_BOUNDS);
+    assert_eq!(unsafe { mdpu_set_register(handle, 1, 0) }, MDPU_ERR_BOUNDS);
+    unsafe { mdpu_free(handle) };
+}
@@ -0,0 +1,131 @@
+// Checks over a whole assembled program, reported by the assembler as warnings
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::isa::{Control, Instruction, Opcode};
+
+// Something suspicious about the instruction at an address
+pub(crate) enum Finding {
+    // First instruction of a run that no path from the entry reaches, after the
+    // instruction that does not fall through into it. A run at the very start of a
+    // program that has an entry further down comes after nothing.
+    Unreachable { addr: usize, after: Option<Opcode> },
+    // First write to a register whose value is overwritten before anything reads it
+    UnreadRegister { addr: usize, reg: usize },
+    // Conditional jump whose target is the instruction after it
+    BranchToNext { addr: usize },
+}
+
+// Analyze the control flow and register use of a program that starts at `entry`. NOPs are
+// never reported, since blank lines, comments and padding assemble to them. `taken` holds
+// the addresses of code labels used as values, which a JMPR may jump to.
+pub(crate) fn analyze(program: &[Instruction], entry: usize, taken: &[usize]) -> Vec<Finding> {
+    let mut findings = Vec::new();
+
+    // Every address some path from the entry can reach
+    let mut reachable = vec![false; program.len()];
+    let mut pending = vec![entry];
+    if program
+        .iter()
+        .any(|instr| instr.opcode.control() == Control::Indirect)
+    {
+        pending.extend_from_slice(taken);
+        // An address from RDPC plus some offset can lead a JMPR anywhere
+        if program.iter().any(|instr| instr.opcode == Opcode::Rdpc) {
+            pending.extend(0..program.len());
+        }
+    }
+    while let Some(addr) = pending.pop() {
+        match reachable.get_mut(addr) {
+            Some(seen) if !*seen => *seen = true,
+            _ => continue,
+        }
+        pending.extend(program[addr].successors(addr));
+    }
+    let mut addr = 0;
+    while addr < program.len() {
+        if reachable[addr] {
+            addr += 1;
+            continue;
+        }
+        // A run can only start after an instruction that does not fall through
+        let after = addr.checked_sub(1).map(|before| program[before].opcode);
+        let start = addr;
+        while addr < program.len() && !reachable[addr] {
+            addr += 1;
+        }
+        if let Some(first) = (start..addr).find(|&addr| program[addr].opcode != Opcode::Nop) {
+            findings.push(Finding::Unreachable { addr: first, after });
+        }
+    }
+
+    let live = live_registers(program);
+    // PUSHA reads every register, and the handler of a SYS may read any of them
+    let saves_all = program
+        .iter()
+        .any(|instr| matches!(instr.opcode, Opcode::PushAll | Opcode::Sys));
+    let mut reported = Vec::new();
+    for (addr, instr) in program.iter().enumerate() {
+        if let Some(reg) = instr.writes().filter(|_| !saves_all) {
+            if !live_after(program, &live, addr)[reg] && !reported.contains(&reg) {
+                reported.push(reg);
+                findings.push(Finding::UnreadRegister { addr, reg });
+            }
+        }
+        if instr.opcode.control() == Control::Branch && instr.jump_target(addr) == Some(addr + 1) {
+            findings.push(Finding::BranchToNext { addr });
+        }
+    }
+    findings
+}
+
+// Registers live before each instruction, which some path from it reads before writing
+// them again. The registers are the machine's result, so every one of them is live where
+// the program stops: at a HALT, past its last instruction, or at a RET or JMPR, which may
+// go anywhere.
+fn live_registers(program: &[Instruction]) -> Vec<Vec<bool>> {
+    let registers = program
+        .iter()
+        .flat_map(|instr| instr.reads().chain(instr.writes()))
+        .max()
+        .map_or(0, |reg| reg + 1);
+    let mut live = vec![vec![false; registers]; program.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for addr in (0..program.len()).rev() {
+            let mut before = live_after(program, &live, addr);
+            if let Some(reg) = program[addr].writes() {
+                before[reg] = false;
+            }
+            for reg in program[addr].reads() {
+                before[reg] = true;
+            }
+            if before != live[addr] {
+                live[addr] = before;
+                changed = true;
+            }
+        }
+    }
+    live
+}
+
+// Registers live once the instruction at `addr` has run, given those live before each
+fn live_after(program: &[Instruction], live: &[Vec<bool>], addr: usize) -> Vec<bool> {
+    let registers = live.first().map_or(0, Vec::len);
+    let instr = &program[addr];
+    if matches!(
+        instr.opcode.control(),
+        Control::Halt | Control::Return | Control::Indirect
+    ) || instr.successors(addr).any(|next| next >= program.len())
+    {
+        return vec![true; registers];
+    }
+    let mut after = vec![false; registers];
+    for next in instr.successors(addr) {
+        for (reg, &used) in live[next].iter().enumerate() {
+            after[reg] |= used;
+        }
+    }
+    after
+}
@@ -0,0 +1,3054 @@
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::rc::Rc;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::analysis::{analyze, Finding};
+use crate::debug::DebugInfo;
+use crate::error::ParseError;
+use crate::isa::{AddrOperand, Instruction, Opcode, Operand};
+#[cfg(feature = "std")]
+use crate::optimize::peephole;
+use crate::program::Program;
+use crate::symbols::{SymbolEntry, SymbolKind, SymbolTable};
+
+// Operand order of the legacy five-field form `OP reg1 reg2 reg3 addr imm`
+const LEGACY_OPERANDS: [Operand; 5] = [
+    Operand::Reg1,
+    Operand::Reg2,
+    Operand::Reg3,
+    Operand::Addr,
+    Operand::Imm,
+];
+
+// `.align` warns when it inserts more NOPs than this, which usually means a typo
+const ALIGN_WARNING_NOPS: usize = 256;
+
+// Macros nested deeper than this are reported instead of expanded
+const MAX_MACRO_DEPTH: usize = 64;
+
+// Binary operators in constant expressions, from the loosest to the tightest binding
+const PRECEDENCE: [&[&str]; 6] = [
+    &["|"],
+    &["^"],
+    &["&"],
+    &["<<", ">>"],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+// Program text handed to the assembler, either the program itself or an included file
+pub(crate) struct SourceFile {
+    pub(crate) name: Option<String>, // Shown in diagnostics, None for text without a file
+    pub(crate) key: String,          // Identifies the file when looking for include cycles
+    pub(crate) text: String,
+}
+
+// Finds the file named by `.include "path"` inside the file with the given name
+pub(crate) type Resolver<'r> = dyn FnMut(&str, Option<&str>) -> Result<SourceFile, String> + 'r;
+
+// Where a diagnostic sorts: the index of its expanded line, then its column
+type Position = (usize, usize);
+
+// How a diagnostic affects loading
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Severity {
+    Error,
+    UnknownOpcode, // An error, unless the program is loaded permissively
+    Warning,
+}
+
+// One source line after includes and macros are expanded, with the file and line it
+// came from
+#[derive(Clone)]
+struct Line {
+    index: usize, // Position among all expanded lines, used to keep diagnostics in order
+    unit: usize,  // Program file the line belongs to, when several files are linked
+    file: Option<Rc<str>>,
+    number: usize,
+    text: String,
+    note: Option<Rc<ParseError>>, // The macro invocation that produced the line
+}
+
+impl Line {
+    // Where the line is, for messages that refer back to it
+    fn place(&self) -> String {
+        match &self.file {
+            Some(file) => format!("{}:{}", file, self.number),
+            None => format!("line {}", self.number),
+        }
+    }
+}
+
+// A whitespace-separated token and the byte offset where it starts in its line
+#[derive(Debug, Clone, Copy)]
+struct Token<'a> {
+    text: &'a str,
+    start: usize,
+}
+
+// Value of a label or constant and where it was defined
+#[derive(Clone, Copy)]
+struct Symbol<'a> {
+    value: i128,
+    kind: SymbolKind,
+    line: &'a Line,
+    token: Token<'a>,
+    used: bool, // Whether any operand or expression referred to it
+}
+
+// Piece of a constant expression
+#[derive(Debug, Clone, Copy)]
+enum Term<'e> {
+    Value(i128),
+    Operator(&'e str),
+    Open,
+    Close,
+}
+
+// Non-local label that starts a scope for the local labels after it, or the start of a
+// linked file when `label` is empty
+struct Scope<'a> {
+    label: &'a str,
+    line: &'a Line,
+}
+
+impl Scope<'_> {
+    fn describe(&self) -> String {
+        match (self.label, &self.line.file) {
+            ("", Some(file)) => format!("the start of {}", file),
+            ("", None) => String::from("the start of the file"),
+            (label, _) => format!("{} at {}", label, self.line.place()),
+        }
+    }
+}
+
+// A value placed in memory by a data directive
+#[derive(Debug, Clone, Copy)]
+enum Word<'a> {
+    Token(Token<'a>), // Resolved in the second pass, so it may name a label
+    Value(i32),
+}
+
+// Something placed by the first pass whose operands are resolved in the second
+enum Item<'a> {
+    Instruction {
+        line: &'a Line,
+        mnemonic: Option<Token<'a>>, // None for the NOPs of blank lines and padding
+        scope: usize,
+        opcode: Opcode,
+        operands: Vec<Token<'a>>,
+    },
+    // Values written to memory by a data directive, starting at `addr`
+    Data {
+        line: &'a Line,
+        scope: usize,
+        directive: Token<'a>,
+        addr: usize,
+        values: Vec<Word<'a>>,
+    },
+}
+
+// Assembled program along with every problem found in it, in source order. Lines with
+// an unknown opcode are assembled as NOP, and so is any line with an error, so the
+// instructions after them keep their addresses.
+pub(crate) struct Assembly {
+    pub(crate) program: Program,
+    pub(crate) diagnostics: Vec<(Severity, ParseError)>,
+    lines: Vec<Line>, // Every line after preprocessing, for the listing
+    #[cfg_attr(not(feature = "std"), allow(dead_code))] // Only listed by the loader
+    emitted: Vec<(usize, Emitted)>, // What each line placed, by line index in source order
+    #[cfg_attr(not(feature = "std"), allow(dead_code))] // Only used by the loader
+    placements: Vec<Placement>, // Where each instruction was written
+    #[cfg_attr(not(feature = "std"), allow(dead_code))] // Only used by the optimizer
+    padding: Vec<usize>, // Addresses of `.org` and `.align` padding, in order
+    #[cfg_attr(not(feature = "std"), allow(dead_code))]
+    value_label: Option<String>, // A code label used other than as a jump target
+}
+
+// Where an instruction was written, for problems found after assembly
+#[cfg_attr(not(feature = "std"), allow(dead_code))]
+struct Placement {
+    line: usize, // Index into `Assembly::lines`
+    mnemonic: Option<Span>,
+    operands: Vec<(Operand, Span)>,
+}
+
+type Span = (usize, usize); // Byte offset and length of a token in its line
+
+// Something a line placed in the program or in memory
+#[cfg_attr(not(feature = "std"), allow(dead_code))]
+enum Emitted {
+    Instruction(usize), // Address of the instruction
+    Data(usize, Vec<i32>),
+}
+
+// Values shown for a data directive before the rest are left out of the listing
+#[cfg(feature = "std")]
+const LISTING_VALUES: usize = 8;
+
+impl Assembly {
+    // Problems that stop the program from loading: unknown opcodes unless permissive, and
+    // every warning when warnings are denied
+    pub(crate) fn errors(
+        &self,
+        permissive: bool,
+        deny_warnings: bool,
+    ) -> impl Iterator<Item = &ParseError> {
+        self.diagnostics
+            .iter()
+            .filter(move |(severity, _)| match severity {
+                Severity::Error => true,
+                Severity::UnknownOpcode => !permissive || deny_warnings,
+                Severity::Warning => deny_warnings,
+            })
+            .map(|(_, err)| err)
+    }
+
+    // Diagnostic pointing at `operand` of the instruction at `ip`, or at its mnemonic if
+    // the operand was not written out
+    #[cfg(feature = "std")]
+    pub(crate) fn diagnostic_at(&self, ip: usize, operand: Operand, message: String) -> ParseError {
+        let placement = &self.placements[ip];
+        let line = &self.lines[placement.line];
+        let (start, len) = placement
+            .operands
+            .iter()
+            .find(|(slot, _)| *slot == operand)
+            .map(|(_, span)| *span)
+            .or(placement.mnemonic)
+            .unwrap_or((0, line.text.len()));
+        let token = Token {
+            text: &line.text[start..start + len],
+            start,
+        };
+        diagnostic(line, token, message)
+    }
+
+    // Run the peephole optimizer over the program, returning how many instructions it
+    // removed. A program that uses a code label as a value or reads an address with RDPC
+    // is left alone, as the optimizer cannot tell which values are addresses that would
+    // need to move too.
+    #[cfg(feature = "std")]
+    pub(crate) fn optimize(&mut self) -> Result<usize, String> {
+        if let Some(name) = &self.value_label {
+            return Err(format!(
+                "the address of label {} is used as a value, not only as a jump target",
+                name
+            ));
+        }
+        if self
+            .program
+            .iter()
+            .any(|instr| instr.opcode == Opcode::Rdpc)
+        {
+            return Err(String::from("RDPC reads instruction addresses as values"));
+        }
+        let symbols = self.program.symbols().entries();
+        let targets: Vec<usize> = symbols
+            .iter()
+            .filter(|symbol| symbol.kind == SymbolKind::Label)
+            .filter_map(|symbol| usize::try_from(symbol.value).ok())
+            // Starting at the first instruction is like falling into it
+            .chain(Some(self.program.entry()).filter(|&entry| entry != 0))
+            .collect();
+        let fixed = self.padding.last().map_or(0, |&addr| addr + 1);
+        let result = peephole(&self.program, &targets, fixed);
+
+        let symbols = symbols
+            .iter()
+            .cloned()
+            .map(|mut symbol| {
+                if let (SymbolKind::Label, Ok(addr)) = (symbol.kind, usize::try_from(symbol.value))
+                {
+                    symbol.value = result.relocate(addr) as i128;
+                }
+                symbol
+            })
+            .collect();
+        let debug = self.program.debug_info();
+        let lines = debug
+            .lines()
+            .iter()
+            .zip(&result.moved)
+            .filter(|(_, new)| new.is_some())
+            .map(|(line, _)| *line)
+            .collect();
+        let mut program = Program::from_instructions(result.instructions.clone())
+            .with_entry(result.relocate(self.program.entry()))
+            .with_symbols(SymbolTable::new(symbols))
+            .with_debug_info(DebugInfo::from_parts(debug.files().to_vec(), lines));
+        for (addr, values) in self.program.data() {
+            program = program.with_data(*addr, values.clone());
+        }
+        self.program = program;
+
+        // Instructions that are gone drop out of the listing and of later diagnostics
+        let mut placements = core::mem::take(&mut self.placements).into_iter();
+        self.placements = result
+            .moved
+            .iter()
+            .zip(&mut placements)
+            .filter_map(|(new, placement)| new.map(|_| placement))
+            .collect();
+        self.emitted.retain_mut(|(_, emission)| match emission {
+            Emitted::Instruction(addr) => match result.moved[*addr] {
+                Some(new) => {
+                    *addr = new;
+                    true
+                }
+                None => false,
+            },
+            Emitted::Data(..) => true,
+        });
+        Ok(result.removed())
+    }
+
+    // Annotated listing with one row per source line, comments and directives included.
+    // Rows show the instruction or memory address in decimal and hex, the decoded
+    // instruction or data values, then the source text. A line that emits several
+    // instructions, such as `.org` padding, continues on rows without source text.
+    #[cfg(feature = "std")]
+    pub(crate) fn listing(&self) -> String {
+        let mut text = String::new();
+        let mut emitted = self.emitted.iter().peekable();
+        let mut file = None;
+        for line in &self.lines {
+            if line.file != file || text.is_empty() {
+                let name = line.file.as_deref().unwrap_or("<source>");
+                text.push_str(&format!("; {}\n", name));
+                file = line.file.clone();
+            }
+            // Lines from a macro expansion are marked with `+`
+            let number = format!(
+                "{:>5}{}",
+                line.number,
+                if line.note.is_some() { "+" } else { " " }
+            );
+            let mut source = line.text.as_str();
+            let mut row = |address: &str, code: &str, source: &str| {
+                let row = format!("{} {:<12}  {:<24} {}", number, address, code, source);
+                text.push_str(row.trim_end());
+                text.push('\n');
+            };
+            let mut empty = true;
+            while let Some((_, emission)) = emitted.next_if(|(index, _)| *index == line.index) {
+                let (addr, code) = match emission {
+                    Emitted::Instruction(addr) => (*addr, self.program[*addr].to_string()),
+                    Emitted::Data(addr, values) => {
+                        let mut shown: Vec<String> = values
+                            .iter()
+                            .take(LISTING_VALUES)
+                            .map(i32::to_string)
+                            .collect();
+                        if values.len() > LISTING_VALUES {
+                            shown.push(format!("... ({} values)", values.len()));
+                        }
+                        (*addr, format!("data {}", shown.join(", ")))
+                    }
+                };
+                row(&format!("{:>5} {:#06x}", addr, addr), &code, source);
+                source = "";
+                empty = false;
+            }
+            if empty {
+                row("", "", source);
+            }
+        }
+        text
+    }
+}
+
+// Assemble a multi-line program, failing with the first problem in the source.
+// `.include` needs a file system and is only available through `load_program`.
+pub fn assemble(source: &str) -> Result<Program, ParseError> {
+    let root = SourceFile {
+        name: None,
+        key: String::new(),
+        text: String::from(source),
+    };
+    let assembly = assemble_lenient(root, &[], &mut |_, _| {
+        Err(String::from(
+            ".include is only available when loading a program from a file",
+        ))
+    });
+    if let Some(err) = assembly.errors(false, false).next() {
+        return Err(err.clone());
+    }
+    Ok(assembly.program)
+}
+
+// Two-pass assembly: the first pass records where every label points, the second parses
+// operands so jumps can refer to labels defined further down. Problems are collected
+// instead of stopping at the first one.
+pub(crate) fn assemble_lenient(
+    root: SourceFile,
+    defines: &[(String, String)],
+    resolve: &mut Resolver,
+) -> Assembly {
+    link(&[root], defines, resolve)
+}
+
+// Assemble several program files into one program, their code placed one after the
+// other. Each file has its own macros, constants, labels and aliases, except for the
+// symbols it exports with `.global`, which every file can refer to.
+pub(crate) fn link(
+    units: &[SourceFile],
+    defines: &[(String, String)],
+    resolve: &mut Resolver,
+) -> Assembly {
+    let mut preprocessor = Preprocessor {
+        resolve,
+        unit: 0,
+        includes: Vec::new(),
+        macros: BTreeMap::new(),
+        definition: None,
+        expansions: Vec::new(),
+        expansion_count: 0,
+        constants: BTreeMap::new(),
+        conditionals: Vec::new(),
+        lines: Vec::new(),
+        diagnostics: Vec::new(),
+    };
+    // Symbols defined outside the source act like `.define` lines in front of it
+    let prelude = SourceFile {
+        name: Some(String::from("<command line>")),
+        key: String::new(),
+        text: defines
+            .iter()
+            .map(|(name, value)| format!(".define {} {}\n", name, value))
+            .collect(),
+    };
+    for (unit, file) in units.iter().enumerate() {
+        preprocessor.unit = unit;
+        preprocessor.includes = vec![file.key.clone()];
+        preprocessor.macros.clear();
+        preprocessor.constants.clear();
+        preprocessor.file(&prelude, None);
+        preprocessor.file(file, None);
+    }
+    let Preprocessor {
+        lines, diagnostics, ..
+    } = preprocessor;
+
+    let mut assembler = Assembler {
+        diagnostics,
+        ..Assembler::default()
+    };
+    for line in &lines {
+        assembler.line(line);
+    }
+    let mut assembly = assembler.finish();
+    assembly.lines = lines;
+    assembly
+}
+
+// Expands includes and macros into the lines the assembler sees
+struct Preprocessor<'r> {
+    resolve: &'r mut Resolver<'r>,
+    unit: usize,           // Program file being expanded
+    includes: Vec<String>, // Keys of the files currently being included
+    macros: BTreeMap<String, Rc<Macro>>,
+    definition: Option<Definition>, // Macro whose body is being read
+    expansions: Vec<String>,        // Names of the macros currently being expanded
+    expansion_count: usize,         // Substituted for `\@`, so it must differ per expansion
+    constants: BTreeMap<String, i128>, // Constants defined so far, for `.if` and `.ifdef`
+    conditionals: Vec<Conditional>, // `.if` blocks that are still open, innermost last
+    lines: Vec<Line>,
+    diagnostics: Vec<(Position, Severity, ParseError)>,
+}
+
+// A macro defined with `.macro NAME param...` and `.endmacro`
+struct Macro {
+    line: Line, // The `.macro` line
+    params: Vec<String>,
+    body: Vec<Line>,
+}
+
+// A macro whose `.endmacro` has not been reached yet
+struct Definition {
+    name: Option<String>, // None when the definition is invalid and will be dropped
+    mac: Macro,
+}
+
+// An `.if`, `.ifdef` or `.ifndef` block whose `.endif` has not been reached yet
+struct Conditional {
+    line: Line,      // The line that opened the block
+    enclosing: bool, // Whether the lines around the block are assembled
+    condition: bool,
+    in_else: bool,
+}
+
+impl Conditional {
+    fn active(&self) -> bool {
+        self.enclosing && self.condition != self.in_else
+    }
+}
+
+impl Preprocessor<'_> {
+    fn error(&mut self, line: &Line, token: Token, message: String) {
+        // Sorts ahead of anything the assembler finds in the next line, in the order found
+        self.diagnostics.push((
+            (self.lines.len(), 0),
+            Severity::Error,
+            diagnostic(line, token, message),
+        ));
+    }
+
+    fn push(&mut self, line: Line) {
+        self.lines.push(Line {
+            index: self.lines.len(),
+            ..line
+        });
+    }
+
+    // Labels in front of a directive or macro invocation still need a line to point at
+    fn push_labels(&mut self, line: &Line, directive: Token) {
+        if directive.start > 0 {
+            self.push(Line {
+                text: String::from(&line.text[..directive.start]),
+                ..line.clone()
+            });
+        }
+    }
+
+    // Expand every line of `file`. `note` says where the file was included from when
+    // that happened inside a macro expansion.
+    fn file(&mut self, file: &SourceFile, note: Option<Rc<ParseError>>) {
+        let depth = self.conditionals.len();
+        let name: Option<Rc<str>> = file.name.as_deref().map(Rc::from);
+        for (index, text) in file.text.lines().enumerate() {
+            self.line(Line {
+                index: 0,
+                unit: self.unit,
+                file: name.clone(),
+                number: index + 1,
+                text: String::from(text),
+                note: note.clone(),
+            });
+        }
+
+        if let Some(definition) = self.definition.take() {
+            let line = &definition.mac.line;
+            let tokens = tokenize(&line.text);
+            if let Some(directive) = tokens.iter().find(|token| token.text == ".macro") {
+                let message = match &definition.name {
+                    Some(name) => format!("Missing .endmacro for macro {}", name),
+                    None => String::from("Missing .endmacro"),
+                };
+                self.error(line, *directive, message);
+            }
+        }
+        self.close_conditionals(depth);
+    }
+
+    fn line(&mut self, line: Line) {
+        let tokens = tokenize(&line.text);
+        let directive = tokens
+            .iter()
+            .position(|token| !token.text.ends_with(':'))
+            .map(|at| (at, tokens[at]));
+
+        if let Some(definition) = &mut self.definition {
+            match directive {
+                Some((_, directive)) if directive.text == ".endmacro" => {
+                    let definition = self.definition.take().expect("definition is open");
+                    if let Some(name) = definition.name {
+                        self.macros.insert(name, Rc::new(definition.mac));
+                    }
+                }
+                Some((_, directive)) if directive.text == ".macro" => {
+                    let message = String::from("Macro definitions cannot be nested");
+                    self.error(&line, directive, message);
+                }
+                // Blank and comment-only lines are left out so they do not become NOPs
+                _ if tokens.is_empty() => {}
+                _ => definition.mac.body.push(line.clone()),
+            }
+            return;
+        }
+
+        let Some((at, directive)) = directive else {
+            if self.active() {
+                self.push(line.clone());
+            }
+            return;
+        };
+        match directive.text {
+            ".if" | ".ifdef" | ".ifndef" => {
+                let args = group_operands(&line.text, &tokens[at + 1..]);
+                return self.open_conditional(&line, directive, &args);
+            }
+            ".else" | ".endif" => return self.continue_conditional(&line, directive),
+            _ if !self.active() => return,
+            _ => {}
+        }
+
+        self.remember_constant(&line.text, &tokens[at..]);
+        let is_constant = tokens.get(at + 1).is_some_and(|token| token.text == "EQU");
+        match directive.text {
+            ".include" => self.include(&line, directive, &tokens[at + 1..]),
+            ".macro" => self.define(&line, directive, &split_list(&tokens[at + 1..])),
+            ".endmacro" => {
+                self.push_labels(&line, directive);
+                let message = String::from(".endmacro without a matching .macro");
+                self.error(&line, directive, message);
+            }
+            name if !is_constant && self.macros.contains_key(name) => {
+                match operand_list(&line.text, &tokens[at + 1..]) {
+                    Ok(args) => self.expand(&line, directive, &args),
+                    Err((token, message)) => self.error(&line, token, message),
+                }
+            }
+            _ => self.push(line.clone()),
+        }
+    }
+
+    // Splice in the file named by `.include "path"`
+    fn include(&mut self, line: &Line, directive: Token, args: &[Token]) {
+        self.push_labels(line, directive);
+        let included = match args {
+            [path] => string_literal(path.text)
+                .and_then(|name| (self.resolve)(&name, line.file.as_deref()))
+                .map_err(|message| (*path, message)),
+            _ => Err((
+                directive,
+                String::from(".include takes exactly one quoted file name"),
+            )),
+        };
+        match included {
+            Ok(included) if self.includes.contains(&included.key) => {
+                let mut trace: Vec<&str> = self.includes.iter().map(String::as_str).collect();
+                trace.push(&included.key);
+                let message = format!("Include cycle: {}", trace.join(" -> "));
+                self.error(line, args[0], message);
+            }
+            Ok(included) => {
+                self.includes.push(included.key.clone());
+                self.file(&included, line.note.clone());
+                self.includes.pop();
+            }
+            Err((token, message)) => self.error(line, token, message),
+        }
+    }
+
+    // Start reading the body of a macro from `.macro NAME param...`
+    fn define(&mut self, line: &Line, directive: Token, args: &[Token]) {
+        self.push_labels(line, directive);
+        let mut name = None;
+        match args.first() {
+            None => self.error(line, directive, String::from(".macro needs a name")),
+            Some(token) if !is_label_name(token.text) => {
+                let message = format!("Invalid macro name: {}", token.text);
+                self.error(line, *token, message);
+            }
+            Some(token) if Opcode::from_mnemonic(token.text).is_some() => {
+                let message = format!("Macro name is already an opcode: {}", token.text);
+                self.error(line, *token, message);
+            }
+            Some(token) => match self.macros.get(token.text) {
+                Some(first) => {
+                    let message = format!(
+                        "Duplicate macro: {} (first defined at {})",
+                        token.text,
+                        first.line.place()
+                    );
+                    self.error(line, *token, message);
+                }
+                None => name = Some(String::from(token.text)),
+            },
+        }
+
+        let mut params: Vec<String> = Vec::new();
+        for param in args.iter().skip(1) {
+            if !is_label_name(param.text) {
+                let message = format!("Invalid macro parameter: {}", param.text);
+                self.error(line, *param, message);
+                name = None;
+            } else if params.iter().any(|other| other == param.text) {
+                let message = format!("Duplicate macro parameter: {}", param.text);
+                self.error(line, *param, message);
+                name = None;
+            } else {
+                params.push(String::from(param.text));
+            }
+        }
+
+        // The body is read even when the definition is invalid, so its lines are not
+        // assembled on their own
+        self.definition = Some(Definition {
+            name,
+            mac: Macro {
+                line: line.clone(),
+                params,
+                body: Vec::new(),
+            },
+        });
+    }
+
+    // Replace a macro invocation with the macro's body
+    fn expand(&mut self, line: &Line, name: Token, args: &[Token]) {
+        self.push_labels(line, name);
+        let mac = Rc::clone(&self.macros[name.text]);
+        if let Some(first) = self.expansions.iter().position(|other| other == name.text) {
+            let mut trace: Vec<&str> = self.expansions[first..]
+                .iter()
+                .map(String::as_str)
+                .collect();
+            trace.push(name.text);
+            let message = format!("Recursive macro expansion: {}", trace.join(" -> "));
+            return self.error(line, name, message);
+        }
+        if self.expansions.len() >= MAX_MACRO_DEPTH {
+            let message = format!(
+                "Macro expansion nested deeper than {} levels",
+                MAX_MACRO_DEPTH
+            );
+            return self.error(line, name, message);
+        }
+        if args.len() != mac.params.len() {
+            let message = format!(
+                "Macro {} takes {} argument(s), got {}",
+                name.text,
+                mac.params.len(),
+                args.len()
+            );
+            return self.error(line, name, message);
+        }
+
+        self.expansion_count += 1;
+        let note = Rc::new(diagnostic(
+            line,
+            name,
+            format!("in expansion of macro {}", name.text),
+        ));
+        self.expansions.push(String::from(name.text));
+        let depth = self.conditionals.len();
+        for body in &mac.body {
+            let text = substitute(&body.text, &mac.params, args, self.expansion_count);
+            self.line(Line {
+                text,
+                note: Some(Rc::clone(&note)),
+                ..body.clone()
+            });
+        }
+        self.close_conditionals(depth);
+        self.expansions.pop();
+    }
+
+    // Whether lines are being assembled, rather than skipped by a false condition
+    fn active(&self) -> bool {
+        self.conditionals.last().is_none_or(Conditional::active)
+    }
+
+    // Start an `.if VALUE`, `.ifdef NAME` or `.ifndef NAME` block. Conditions only see
+    // constants defined above them, since label addresses are not known yet.
+    fn open_conditional(&mut self, line: &Line, directive: Token, args: &[Token]) {
+        let enclosing = self.active();
+        if enclosing {
+            self.push_labels(line, directive);
+        }
+        // Conditions inside a skipped block are not evaluated, so they cannot fail
+        let condition = match args {
+            _ if !enclosing => false,
+            [name] if directive.text == ".ifdef" => self.constants.contains_key(name.text),
+            [name] if directive.text == ".ifndef" => !self.constants.contains_key(name.text),
+            [token] => match evaluate(token.text, "Condition", |name| {
+                self.constants
+                    .get(name)
+                    .copied()
+                    .ok_or_else(|| undefined(name))
+            }) {
+                Ok(value) => value != 0,
+                Err(message) => {
+                    self.error(line, *token, message);
+                    false
+                }
+            },
+            _ => {
+                let message = match directive.text {
+                    ".if" => String::from(".if takes exactly one value"),
+                    _ => format!("{} takes exactly one name", directive.text),
+                };
+                self.error(line, directive, message);
+                false
+            }
+        };
+        self.conditionals.push(Conditional {
+            line: line.clone(),
+            enclosing,
+            condition,
+            in_else: false,
+        });
+    }
+
+    // Handle `.else` or `.endif`
+    fn continue_conditional(&mut self, line: &Line, directive: Token) {
+        if self.active() {
+            self.push_labels(line, directive);
+        }
+        let Some(conditional) = self.conditionals.last_mut() else {
+            let message = format!("{} without a matching .if", directive.text);
+            return self.error(line, directive, message);
+        };
+        if directive.text == ".endif" {
+            self.conditionals.pop();
+        } else if conditional.in_else {
+            let message = format!("Second .else for the .if at {}", conditional.line.place());
+            self.error(line, directive, message);
+        } else {
+            conditional.in_else = true;
+        }
+    }
+
+    // Report every `.if` opened since `depth` that was never closed
+    fn close_conditionals(&mut self, depth: usize) {
+        while self.conditionals.len() > depth {
+            let conditional = self.conditionals.pop().expect("more than depth");
+            let line = &conditional.line;
+            let tokens = tokenize(&line.text);
+            if let Some(directive) = tokens.iter().find(|token| token.text.starts_with(".if")) {
+                let message = format!("Missing .endif for this {}", directive.text);
+                self.error(line, *directive, message);
+            }
+        }
+    }
+
+    // Remember a constant defined by `.const`, `.define` or `EQU` so conditions can test
+    // it. The assembler defines it again and reports any problems with it.
+    fn remember_constant(&mut self, text: &str, tokens: &[Token]) {
+        let tokens = group_operands(text, tokens);
+        let (name, token) = match tokens[..] {
+            [directive, name, value] if matches!(directive.text, ".const" | ".define") => {
+                (name, Some(value))
+            }
+            [directive, name] if directive.text == ".define" => (name, None),
+            [name, equ, value] if equ.text == "EQU" => (name, Some(value)),
+            _ => return,
+        };
+        let value = match token {
+            Some(token) => evaluate(token.text, "Constant", |name| {
+                self.constants
+                    .get(name)
+                    .copied()
+                    .ok_or_else(|| undefined(name))
+            }),
+            None => Ok(1),
+        };
+        if let Ok(value) = value {
+            self.constants
+                .entry(String::from(name.text))
+                .or_insert(value);
+        }
+    }
+}
+
+// State of the first pass
+#[derive(Default)]
+struct Assembler<'a> {
+    symbols: BTreeMap<&'a str, BTreeMap<usize, Symbol<'a>>>, // By name, then `Line::unit`
+    exports: Vec<(&'a Line, Token<'a>)>,                     // Names given to `.global`
+    globals: BTreeMap<&'a str, (usize, &'a Line, Token<'a>)>, // Exported names, by file
+    unit: usize,                                             // File of the line being assembled
+    locals: BTreeMap<&'a str, BTreeMap<usize, Symbol<'a>>>,  // Local labels by name, then scope
+    scopes: Vec<Scope<'a>>, // Non-local labels in order; scope N starts at the Nth of them
+    scope: usize,           // Scope of the line being assembled
+    missing: Option<String>, // Name the last failed lookup could not find
+    undefined: Vec<(String, &'a Line, Token<'a>)>, // Operands naming an undefined symbol
+    aliases: BTreeMap<&'a str, BTreeMap<usize, Symbol<'a>>>, // Register names by name and file
+    entry: Option<(&'a Line, usize, Token<'a>)>, // `.entry` line, its scope and its address
+    items: Vec<Item<'a>>,
+    address: usize,      // Address of the next instruction
+    data_address: usize, // Address of the next value placed by a data directive
+    diagnostics: Vec<(Position, Severity, ParseError)>,
+    padding: Vec<usize>, // Addresses of the NOPs `.org` and `.align` fill in
+    in_target: bool,     // Whether a jump target or `.entry` is being resolved
+    in_lea: bool,        // Whether the operands of a LEA are being resolved
+    value_label: Option<String>, // First code label whose address is used as a value
+    taken: Vec<usize>,   // Address of every code label used as a value
+}
+
+impl<'a> Assembler<'a> {
+    fn report(&mut self, severity: Severity, line: &Line, token: Token, message: String) {
+        let err = diagnostic(line, token, message);
+        self.diagnostics
+            .push(((line.index, err.column), severity, err));
+    }
+
+    fn error(&mut self, line: &Line, token: Token, message: String) {
+        self.report(Severity::Error, line, token, message);
+    }
+
+    // First pass over one source line
+    fn line(&mut self, line: &'a Line) {
+        // Local labels at the top of a linked file are not in the scope of the file before
+        if line.unit != self.unit {
+            self.unit = line.unit;
+            self.scopes.push(Scope { label: "", line });
+            self.scope = self.scopes.len();
+        }
+        let mut tokens = tokenize(&line.text);
+        let mut labels = Vec::new();
+        while let Some(token) = tokens.first().copied() {
+            let Some(name) = token.text.strip_suffix(':') else {
+                break;
+            };
+            tokens.remove(0);
+            labels.push((token, name));
+        }
+
+        match tokens.first().copied() {
+            // `NAME EQU value` is another way to write `.const NAME value`
+            Some(name) if tokens.get(1).is_some_and(|token| token.text == "EQU") => {
+                self.define_labels(line, &labels, self.address);
+                match group_operands(&line.text, &tokens[2..])[..] {
+                    [value] => self.constant(line, name, value),
+                    _ => self.error(line, tokens[1], String::from("EQU takes exactly one value")),
+                }
+            }
+            Some(first) if first.text.starts_with('.') => {
+                let directive = tokens.remove(0);
+                self.directive(line, directive, &tokens, &labels);
+            }
+            Some(first) => {
+                // `LEA Rd label` assembles to `LI Rd =label`
+                let mnemonic = match first.text {
+                    "LEA" => "LI",
+                    name => name,
+                };
+                let opcode = match Opcode::from_mnemonic(mnemonic) {
+                    Some(opcode) => opcode,
+                    None => {
+                        let mut message = format!("Unknown opcode: {}", first.text);
+                        if self.find_alias(first.text, self.unit).is_some() {
+                            message.push_str(" (it is a register alias)");
+                        }
+                        self.report(Severity::UnknownOpcode, line, first, message);
+                        tokens.truncate(1);
+                        Opcode::Nop
+                    }
+                };
+                self.define_labels(line, &labels, self.address);
+                let (opcode, operands) = match operand_list(&line.text, &tokens[1..]) {
+                    Ok(operands) => (opcode, operands),
+                    Err((token, message)) => {
+                        self.error(line, token, message);
+                        (Opcode::Nop, Vec::new())
+                    }
+                };
+                let (opcode, operands) = desugar_indexed(opcode, operands);
+                let opcode = legacy_compare(opcode, operands.len());
+                let opcode = self.halt_form(opcode, &operands);
+                self.instruction(line, Some(first), opcode, operands);
+            }
+            // Blank, comment-only and label-only lines still occupy an instruction address
+            None => {
+                self.define_labels(line, &labels, self.address);
+                self.instruction(line, None, Opcode::Nop, Vec::new());
+            }
+        }
+    }
+
+    // `HALT 3` and `HALT R2` are HALTI and HALTR, which give the program an exit code.
+    // A register alias counts as a register when it is defined by then.
+    fn halt_form(&self, opcode: Opcode, operands: &[Token]) -> Opcode {
+        match operands {
+            [code] if opcode == Opcode::Halt => {
+                // A bare number is a register elsewhere, but here it is the code
+                let register = code.text.starts_with(['R', 'r']);
+                if register && parse_register(code.text).is_ok()
+                    || self.find_alias(code.text, self.unit).is_some()
+                {
+                    Opcode::HaltRegister
+                } else {
+                    Opcode::HaltImmediate
+                }
+            }
+            _ => opcode,
+        }
+    }
+
+    // Handle an assembler directive. Directives do not occupy an instruction address.
+    fn directive(
+        &mut self,
+        line: &'a Line,
+        directive: Token<'a>,
+        args: &[Token<'a>],
+        labels: &[(Token<'a>, &'a str)],
+    ) {
+        // Each argument is a value, except that `.word` takes a comma-separated list
+        let list = args;
+        let args = &group_operands(&line.text, args)[..];
+        match directive.text {
+            ".data" => {
+                match args {
+                    [addr] => match self.address_operand(addr.text) {
+                        Ok(addr) => self.data_address = addr,
+                        Err(message) => self.error(line, *addr, message),
+                    },
+                    _ => self.error(
+                        line,
+                        directive,
+                        String::from(".data takes exactly one address"),
+                    ),
+                }
+                self.define_data_labels(line, labels);
+            }
+            ".word" => {
+                let values = group_operands(&line.text, &split_list(list));
+                if values.is_empty() {
+                    self.error(
+                        line,
+                        directive,
+                        String::from(".word needs at least one value"),
+                    );
+                }
+                self.define_data_labels(line, labels);
+                self.data(
+                    line,
+                    directive,
+                    values.into_iter().map(Word::Token).collect(),
+                );
+            }
+            ".string" | ".asciiz" => {
+                let mut values = match args {
+                    [string] => parse_string(string.text).unwrap_or_else(|message| {
+                        self.error(line, *string, message);
+                        Vec::new()
+                    }),
+                    _ => {
+                        let message = format!("{} takes exactly one quoted string", directive.text);
+                        self.error(line, directive, message);
+                        Vec::new()
+                    }
+                };
+                if directive.text == ".asciiz" {
+                    values.push(0);
+                }
+                self.define_data_labels(line, labels);
+                self.data(
+                    line,
+                    directive,
+                    values.into_iter().map(Word::Value).collect(),
+                );
+            }
+            ".org" => {
+                match args {
+                    [addr] => match self.address_operand(addr.text) {
+                        Ok(target) if target < self.address => {
+                            let message = format!(
+                                ".org {} is behind the current instruction address {}",
+                                target, self.address
+                            );
+                            self.error(line, *addr, message);
+                        }
+                        Ok(target) => self.pad_to(line, target),
+                        Err(message) => self.error(line, *addr, message),
+                    },
+                    _ => self.error(
+                        line,
+                        directive,
+                        String::from(".org takes exactly one address"),
+                    ),
+                }
+                self.define_labels(line, labels, self.address);
+            }
+            ".align" => {
+                match args {
+                    [token] => match self.address_operand(token.text) {
+                        Ok(size) if size.is_power_of_two() => {
+                            let target = self.address.next_multiple_of(size);
+                            if target - self.address > ALIGN_WARNING_NOPS {
+                                let message = format!(
+                                    ".align {} inserts {} NOPs",
+                                    size,
+                                    target - self.address
+                                );
+                                self.report(Severity::Warning, line, *token, message);
+                            }
+                            self.pad_to(line, target);
+                        }
+                        Ok(_) => self.error(
+                            line,
+                            *token,
+                            format!(".align needs a power of two: {}", token.text),
+                        ),
+                        Err(message) => self.error(line, *token, message),
+                    },
+                    _ => self.error(
+                        line,
+                        directive,
+                        String::from(".align takes exactly one size"),
+                    ),
+                }
+                self.define_labels(line, labels, self.address);
+            }
+            // The address is resolved in the second pass, so it may name any label
+            ".entry" => {
+                self.define_labels(line, labels, self.address);
+                match (args, self.entry) {
+                    ([_], Some((first, _, _))) => {
+                        let message = format!(".entry is already set at {}", first.place());
+                        self.error(line, directive, message);
+                    }
+                    ([addr], None) => self.entry = Some((line, self.scope, *addr)),
+                    _ => self.error(
+                        line,
+                        directive,
+                        String::from(".entry takes exactly one address"),
+                    ),
+                }
+            }
+            ".global" => {
+                self.define_labels(line, labels, self.address);
+                let names = split_list(list);
+                if names.is_empty() {
+                    let message = String::from(".global needs at least one symbol name");
+                    self.error(line, directive, message);
+                }
+                self.exports
+                    .extend(names.into_iter().map(|name| (line, name)));
+            }
+            ".alias" => {
+                self.define_labels(line, labels, self.address);
+                match *args {
+                    [name, register] => self.alias(line, name, register),
+                    _ => self.error(
+                        line,
+                        directive,
+                        String::from(".alias takes a name and a register"),
+                    ),
+                }
+            }
+            ".const" | ".define" => {
+                self.define_labels(line, labels, self.address);
+                match *args {
+                    // `.define NAME` alone defines a flag for `.ifdef`
+                    [name] if directive.text == ".define" => {
+                        self.define(line, name, name.text, 1, SymbolKind::Constant)
+                    }
+                    [name, value] => self.constant(line, name, value),
+                    _ => {
+                        let message = format!("{} takes a name and a value", directive.text);
+                        self.error(line, directive, message);
+                    }
+                }
+            }
+            _ => {
+                let message = format!("Unknown directive: {}", directive.text);
+                self.error(line, directive, message);
+                self.define_labels(line, labels, self.address);
+            }
+        }
+    }
+
+    // Point every label in `labels` at the instruction address `value`
+    fn define_labels(&mut self, line: &'a Line, labels: &[(Token<'a>, &'a str)], value: usize) {
+        self.bind_labels(line, labels, value, SymbolKind::Label);
+    }
+
+    // Point every label in `labels` at the next address a data directive writes to
+    fn define_data_labels(&mut self, line: &'a Line, labels: &[(Token<'a>, &'a str)]) {
+        self.bind_labels(line, labels, self.data_address, SymbolKind::Data);
+    }
+
+    // Each non-local label starts a new scope for the local labels after it
+    fn bind_labels(
+        &mut self,
+        line: &'a Line,
+        labels: &[(Token<'a>, &'a str)],
+        value: usize,
+        kind: SymbolKind,
+    ) {
+        for &(token, name) in labels {
+            if is_label_name(name) && !is_local(name) {
+                self.scopes.push(Scope { label: name, line });
+                self.scope = self.scopes.len();
+            }
+            self.define(line, token, name, value as i128, kind);
+        }
+    }
+
+    // Add a label or constant to the symbol table, which may hold each name only once,
+    // or only once per scope for local labels
+    fn define(
+        &mut self,
+        line: &'a Line,
+        token: Token<'a>,
+        name: &'a str,
+        value: i128,
+        kind: SymbolKind,
+    ) {
+        if !is_label_name(name) {
+            return self.error(line, token, format!("Invalid symbol name: {}", name));
+        }
+        let first = if is_local(name) {
+            self.locals
+                .get(name)
+                .and_then(|scopes| scopes.get(&self.scope))
+        } else {
+            self.symbols
+                .get(name)
+                .and_then(|units| units.get(&self.unit))
+        };
+        if let Some(first) = first {
+            let mut err = diagnostic(line, token, format!("Duplicate symbol: {}", name));
+            err.add_note(diagnostic(
+                first.line,
+                first.token,
+                String::from("first defined here"),
+            ));
+            self.diagnostics
+                .push(((line.index, err.column), Severity::Error, err));
+            return;
+        }
+
+        let symbol = Symbol {
+            value,
+            kind,
+            line,
+            token,
+            used: false,
+        };
+        if is_local(name) {
+            self.locals
+                .entry(name)
+                .or_default()
+                .insert(self.scope, symbol);
+        } else {
+            self.symbols
+                .entry(name)
+                .or_default()
+                .insert(self.unit, symbol);
+        }
+    }
+
+    // Define `name` as the value of `token`, which may only refer to symbols defined above
+    fn constant(&mut self, line: &'a Line, name: Token<'a>, token: Token<'a>) {
+        match self.value(token.text, "Constant") {
+            Ok(value) => self.define(line, name, name.text, value, SymbolKind::Constant),
+            Err(message) => self.error(line, token, message),
+        }
+    }
+
+    // Let `name` stand for a register in the instructions below this line
+    fn alias(&mut self, line: &'a Line, name: Token<'a>, register: Token<'a>) {
+        let register = match self.register_operand(line, register.text) {
+            Ok(register) => register,
+            Err(message) => return self.error(line, register, message),
+        };
+        let message = if !is_label_name(name.text) || is_local(name.text) {
+            format!("Invalid alias name: {}", name.text)
+        } else if parse_register(name.text).is_ok() {
+            format!("Alias name is already a register: {}", name.text)
+        } else if let Some(special) = special_register(name.text) {
+            format!("{} is reserved for the {}", name.text, special)
+        } else if Opcode::from_mnemonic(name.text).is_some() {
+            format!("Alias name is already an opcode: {}", name.text)
+        } else if let Some(first) = self.find_alias(name.text, self.unit) {
+            let mut err = diagnostic(line, name, format!("Duplicate alias: {}", name.text));
+            err.add_note(diagnostic(
+                first.line,
+                first.token,
+                String::from("first defined here"),
+            ));
+            self.diagnostics
+                .push(((line.index, err.column), Severity::Error, err));
+            return;
+        } else {
+            let symbol = Symbol {
+                value: register as i128,
+                kind: SymbolKind::Register,
+                line,
+                token: name,
+                used: false,
+            };
+            self.aliases
+                .entry(name.text)
+                .or_default()
+                .insert(self.unit, symbol);
+            return;
+        };
+        self.error(line, name, message);
+    }
+
+    fn instruction(
+        &mut self,
+        line: &'a Line,
+        mnemonic: Option<Token<'a>>,
+        opcode: Opcode,
+        operands: Vec<Token<'a>>,
+    ) {
+        self.items.push(Item::Instruction {
+            line,
+            mnemonic,
+            scope: self.scope,
+            opcode,
+            operands,
+        });
+        self.address += 1;
+    }
+
+    // Fill the program with NOPs until the next instruction lands at `target`
+    fn pad_to(&mut self, line: &'a Line, target: usize) {
+        while self.address < target {
+            self.padding.push(self.address);
+            self.instruction(line, None, Opcode::Nop, Vec::new());
+        }
+    }
+
+    fn data(&mut self, line: &'a Line, directive: Token<'a>, values: Vec<Word<'a>>) {
+        let addr = self.data_address;
+        self.data_address = addr.saturating_add(values.len());
+        self.items.push(Item::Data {
+            line,
+            scope: self.scope,
+            directive,
+            addr,
+            values,
+        });
+    }
+
+    // Report data directives that write to the same memory cells
+    fn check_data_overlap(&mut self) {
+        // Data items in source order, then sorted by where they start in memory
+        let mut regions: Vec<(usize, usize, usize, &'a Line, Token<'a>)> = self
+            .items
+            .iter()
+            .filter_map(|item| match item {
+                Item::Data {
+                    line,
+                    directive,
+                    addr,
+                    values,
+                    ..
+                } if !values.is_empty() => Some((*addr, values.len(), *line, *directive)),
+                _ => None,
+            })
+            .enumerate()
+            .map(|(order, (addr, len, line, directive))| (addr, len, order, line, directive))
+            .collect();
+        regions.sort_by_key(|&(addr, _, order, _, _)| (addr, order));
+
+        for pair in regions.windows(2) {
+            let (first, second) = (pair[0], pair[1]);
+            if second.0 >= first.0 + first.1 {
+                continue;
+            }
+            // Blame whichever of the two comes later in the source
+            let (later, earlier) = if second.2 > first.2 {
+                (second, first)
+            } else {
+                (first, second)
+            };
+            let message = format!(
+                "Data at address {} overlaps data defined at {}",
+                second.0,
+                earlier.3.place()
+            );
+            self.error(later.3, later.4, message);
+        }
+    }
+
+    // Report a second pass operand error, holding back references to undefined symbols
+    // so each name is reported once
+    fn operand_error(&mut self, line: &'a Line, token: Token<'a>, message: String) {
+        match self.missing.take() {
+            Some(name) => self.undefined.push((name, line, token)),
+            None => self.error(line, token, message),
+        }
+    }
+
+    // Make the symbols named by `.global` visible to every file. Only one file may export
+    // a name, and exported labels count as used since any file may refer to them.
+    fn export_globals(&mut self) {
+        for (line, token) in core::mem::take(&mut self.exports) {
+            let name = token.text;
+            let symbol = self
+                .symbols
+                .get_mut(name)
+                .and_then(|units| units.get_mut(&line.unit));
+            let Some(symbol) = symbol.filter(|_| !is_local(name)) else {
+                let message = if is_local(name) {
+                    format!("Local label {} cannot be exported", name)
+                } else {
+                    format!(".global names an undefined symbol: {}", name)
+                };
+                self.error(line, token, message);
+                continue;
+            };
+            symbol.used = true;
+            match self.globals.get(name) {
+                None => {
+                    self.globals.insert(name, (line.unit, line, token));
+                }
+                Some(&(unit, _, _)) if unit == line.unit => {}
+                Some(&(_, first_line, first_token)) => {
+                    let message = format!("Duplicate global symbol: {}", name);
+                    let mut err = diagnostic(line, token, message);
+                    err.add_note(diagnostic(
+                        first_line,
+                        first_token,
+                        String::from("first exported here"),
+                    ));
+                    self.diagnostics
+                        .push(((line.index, err.column), Severity::Error, err));
+                }
+            }
+        }
+    }
+
+    // Report each undefined name at its first reference, with a note for every other
+    // reference and a suggestion when a defined name is close to it
+    fn report_undefined(&mut self) {
+        let references = core::mem::take(&mut self.undefined);
+        let mut names: Vec<&str> = Vec::new();
+        for (name, _, _) in &references {
+            if !names.contains(&name.as_str()) {
+                names.push(name);
+            }
+        }
+
+        for name in names {
+            let mut uses = references.iter().filter(|(other, _, _)| other == name);
+            let count = uses.clone().count();
+            let (_, line, token) = uses.next().expect("every name has a reference");
+            let mut message = undefined(name);
+            if count > 1 {
+                message.push_str(&format!(", referenced {} times", count));
+            }
+            // Only other files can define a name this file cannot see
+            let private = self
+                .symbols
+                .get(name)
+                .and_then(|units| units.values().next());
+            if let Some(symbol) = private {
+                message.push_str(&format!(
+                    " ({} defines it without exporting it with .global)",
+                    symbol.line.place()
+                ));
+            } else if let Some(suggestion) = self.suggest(name) {
+                message.push_str(&format!(" (did you mean `{}`?)", suggestion));
+            }
+            let mut err = diagnostic(line, *token, message);
+            for (_, line, token) in uses {
+                err.add_note(diagnostic(
+                    line,
+                    *token,
+                    String::from("also referenced here"),
+                ));
+            }
+            self.diagnostics
+                .push(((line.index, err.column), Severity::Error, err));
+        }
+    }
+
+    fn report_unused_labels(&mut self) {
+        let labels: Vec<(&str, Symbol)> = self
+            .symbols
+            .iter()
+            .flat_map(|(&name, units)| units.values().map(move |&symbol| (name, symbol)))
+            .chain(
+                self.locals
+                    .iter()
+                    .flat_map(|(&name, scopes)| scopes.values().map(move |&symbol| (name, symbol))),
+            )
+            .filter(|(_, symbol)| {
+                !symbol.used && matches!(symbol.kind, SymbolKind::Label | SymbolKind::Data)
+            })
+            .collect();
+        for (name, symbol) in labels {
+            let message = format!("Label {} is never referenced", name);
+            self.report(Severity::Warning, symbol.line, symbol.token, message);
+        }
+    }
+
+    // Report what the whole-program analysis finds, at the mnemonic of each instruction
+    fn report_findings(
+        &mut self,
+        instructions: &[Instruction],
+        entry: usize,
+        sources: &[(&'a Line, Option<Token<'a>>)],
+    ) {
+        for finding in analyze(instructions, entry, &self.taken) {
+            let (addr, message) = match finding {
+                Finding::Unreachable {
+                    addr,
+                    after: Some(after),
+                } => (
+                    addr,
+                    format!(
+                        "Unreachable code: nothing jumps here and the {} before it does not fall through",
+                        after.mnemonic()
+                    ),
+                ),
+                Finding::Unreachable { addr, after: None } => (
+                    addr,
+                    String::from(
+                        "Unreachable code: nothing jumps here and the program starts at its .entry",
+                    ),
+                ),
+                Finding::UnreadRegister { addr, reg } => {
+                    let unit = sources[addr].0.unit;
+                    let name = match self
+                        .aliases
+                        .values()
+                        .filter_map(|units| units.get(&unit))
+                        .find(|alias| alias.value == reg as i128)
+                    {
+                        Some(alias) => format!("R{} ({})", reg, alias.token.text),
+                        None => format!("R{}", reg),
+                    };
+                    (addr, format!("{} is written but overwritten before anything reads it", name))
+                }
+                Finding::BranchToNext { addr } => (
+                    addr,
+                    format!(
+                        "{} jumps to the next instruction, which runs either way",
+                        instructions[addr].opcode.mnemonic()
+                    ),
+                ),
+            };
+            if let (line, Some(token)) = sources[addr] {
+                self.report(Severity::Warning, line, token, message);
+            }
+        }
+    }
+
+    // The defined name closest to `name`, if it is close enough to be a likely typo
+    fn suggest(&self, name: &str) -> Option<&'a str> {
+        let visible = self
+            .symbols
+            .iter()
+            .filter(|(name, units)| {
+                units.contains_key(&self.unit) || self.globals.contains_key(*name)
+            })
+            .map(|(name, _)| name);
+        closest(name, visible.chain(self.locals.keys()).copied())
+    }
+
+    // Every symbol, with local labels qualified by the label that starts their scope
+    fn symbol_table(&self) -> SymbolTable {
+        let globals = self.symbols.iter().flat_map(|(&name, units)| {
+            units
+                .values()
+                .map(move |symbol| (String::from(name), symbol))
+        });
+        let locals = self.locals.iter().flat_map(|(&name, scopes)| {
+            scopes.iter().map(move |(&scope, symbol)| {
+                let qualified = match scope.checked_sub(1) {
+                    Some(index) => format!("{}{}", self.scopes[index].label, name),
+                    None => String::from(name),
+                };
+                (qualified, symbol)
+            })
+        });
+        let aliases = self.aliases.iter().flat_map(|(&name, units)| {
+            units
+                .values()
+                .map(move |symbol| (String::from(name), symbol))
+        });
+        let entries = globals
+            .chain(locals)
+            .chain(aliases)
+            .map(|(name, symbol)| SymbolEntry {
+                name,
+                value: symbol.value,
+                kind: symbol.kind,
+                file: symbol.line.file.as_deref().map(String::from),
+                line: symbol.line.number,
+            })
+            .collect();
+        SymbolTable::new(entries)
+    }
+
+    // Second pass: resolve every operand now that all labels are known
+    fn finish(mut self) -> Assembly {
+        self.check_data_overlap();
+        self.export_globals();
+        self.missing = None; // Left over from the first pass, which reports as it goes
+
+        let mut instructions = Vec::new();
+        let mut data = Vec::new();
+        let mut emitted = Vec::new();
+        let mut sources = Vec::new(); // Line and mnemonic of each instruction
+        let mut placements = Vec::new();
+        let items = core::mem::take(&mut self.items);
+        for item in &items {
+            match item {
+                Item::Instruction {
+                    line,
+                    mnemonic,
+                    scope,
+                    opcode,
+                    operands,
+                } => {
+                    self.scope = *scope;
+                    self.unit = line.unit;
+                    self.in_lea = mnemonic.is_some_and(|token| token.text == "LEA");
+                    emitted.push((line.index, Emitted::Instruction(instructions.len())));
+                    sources.push((*line, *mnemonic));
+                    let span = |token: &Token| (token.start, token.text.len());
+                    placements.push(Placement {
+                        line: line.index,
+                        mnemonic: mnemonic.as_ref().map(span),
+                        operands: slots(*opcode, operands.len())
+                            .iter()
+                            .copied()
+                            .zip(operands.iter().map(span))
+                            .collect(),
+                    });
+                    match self.operands(line, instructions.len(), *opcode, operands) {
+                        Ok(instr) => instructions.push(instr),
+                        Err((token, message)) => {
+                            self.operand_error(line, token, message);
+                            instructions.push(Instruction::new(Opcode::Nop));
+                        }
+                    }
+                }
+                Item::Data {
+                    line,
+                    scope,
+                    addr,
+                    values,
+                    ..
+                } => {
+                    self.scope = *scope;
+                    self.unit = line.unit;
+                    let mut words = Vec::with_capacity(values.len());
+                    for word in values {
+                        match word {
+                            Word::Value(value) => words.push(*value),
+                            Word::Token(token) => match self.immediate_operand(token.text) {
+                                Ok(value) => words.push(value),
+                                Err(message) => {
+                                    self.operand_error(line, *token, message);
+                                    words.push(0);
+                                }
+                            },
+                        }
+                    }
+                    if !words.is_empty() {
+                        emitted.push((line.index, Emitted::Data(*addr, words.clone())));
+                        data.push((*addr, words));
+                    }
+                }
+            }
+        }
+
+        let mut entry = 0;
+        if let Some((line, scope, token)) = self.entry {
+            self.scope = scope;
+            self.unit = line.unit;
+            match self.target_operand(token.text) {
+                Ok(addr) if addr < instructions.len() => entry = addr,
+                Ok(addr) => {
+                    let message = format!("Entry address {} is past the last instruction", addr);
+                    self.error(line, token, message);
+                }
+                Err(message) => self.operand_error(line, token, message),
+            }
+        }
+
+        self.report_undefined();
+        // Warnings about a program that does not assemble would mostly be noise
+        if !self
+            .diagnostics
+            .iter()
+            .any(|(_, severity, _)| *severity == Severity::Error)
+        {
+            self.report_unused_labels();
+            self.report_findings(&instructions, entry, &sources);
+        }
+
+        let mut debug = DebugInfo::new();
+        for (line, _) in &sources {
+            debug.push(line.file.as_deref(), line.number);
+        }
+        let mut program = Program::from_instructions(instructions)
+            .with_entry(entry)
+            .with_symbols(self.symbol_table())
+            .with_debug_info(debug);
+        for (addr, values) in data {
+            program = program.with_data(addr, values);
+        }
+        self.diagnostics.sort_by_key(|(position, _, _)| *position);
+        Assembly {
+            program,
+            diagnostics: self
+                .diagnostics
+                .into_iter()
+                .map(|(_, severity, err)| (severity, err))
+                .collect(),
+            lines: Vec::new(),
+            emitted,
+            placements,
+            padding: self.padding,
+            value_label: self.value_label,
+        }
+    }
+
+    // Fill in the operands of an instruction. Operands are written in the order the
+    // opcode lists them, unless there are more of them than it uses, in which case the
+    // line is in the legacy five-field form. `ip` is the instruction's address.
+    fn operands(
+        &mut self,
+        line: &Line,
+        ip: usize,
+        opcode: Opcode,
+        operands: &[Token<'a>],
+    ) -> Result<Instruction, (Token<'a>, String)> {
+        let mut instr = Instruction::new(opcode);
+        for (slot, &token) in slots(opcode, operands.len()).iter().zip(operands) {
+            let text = token.text;
+            let parsed = match slot {
+                Operand::Reg1 => self
+                    .register_operand(line, text)
+                    .map(|reg| instr.reg1 = reg),
+                Operand::Reg2 => self
+                    .register_operand(line, text)
+                    .map(|reg| instr.reg2 = reg),
+                Operand::Reg3 => self
+                    .register_operand(line, text)
+                    .map(|reg| instr.reg3 = reg),
+                Operand::Reg4 => self
+                    .register_operand(line, text)
+                    .map(|reg| instr.addr = AddrOperand::Absolute(reg)),
+                Operand::Addr | Operand::Target if from_here(text).is_some() => self
+                    .offset_operand(text, ip)
+                    .map(|offset| instr.addr = AddrOperand::Relative(offset)),
+                Operand::Target if is_signed(text) => Err(format!(
+                    "Jump target {} has a sign; a relative target is written from the jump, as .+N or .-N",
+                    text
+                )),
+                Operand::Addr => self
+                    .address_operand(text)
+                    .map(|addr| instr.addr = AddrOperand::Absolute(addr)),
+                Operand::Target => self
+                    .target_operand(text)
+                    .map(|addr| instr.addr = AddrOperand::Absolute(addr)),
+                Operand::Offset => self
+                    .offset_operand(text, ip)
+                    .map(|offset| instr.addr = AddrOperand::Relative(offset)),
+                Operand::Imm if self.in_lea => self
+                    .label_address(text, "LEA ")
+                    .and_then(|value| {
+                        i32::try_from(value).map_err(|_| format!("Address out of range: {}", text))
+                    })
+                    .map(|value| instr.immediate = value),
+                Operand::Imm if is_mask(opcode) => {
+                    self.mask_operand(text).map(|value| instr.immediate = value)
+                }
+                Operand::Imm => self
+                    .immediate_operand(text)
+                    .and_then(|value| check_immediate(opcode, value))
+                    .map(|value| instr.immediate = value),
+                Operand::Width => {
+                    let pos = instr.immediate;
+                    self.immediate_operand(text)
+                        .and_then(|width| check_bit_field(pos, width))
+                        .map(|width| instr.addr = AddrOperand::Absolute(width))
+                }
+            };
+            parsed.map_err(|message| (token, message))?;
+        }
+        if let Some((first, second)) = opcode.distinct_registers() {
+            let slots = slots(opcode, operands.len());
+            let token = slots
+                .iter()
+                .position(|&slot| slot == second)
+                .and_then(|at| operands.get(at));
+            if let Some(&token) = token.filter(|_| instr.register(first) == instr.register(second))
+            {
+                let message = format!(
+                    "{} writes both of its results to {}, which needs two registers",
+                    opcode.mnemonic(),
+                    token.text
+                );
+                return Err((token, message));
+            }
+        }
+        Ok(instr)
+    }
+
+    // Alias `name` as defined in file `unit`
+    fn find_alias(&self, name: &str, unit: usize) -> Option<&Symbol<'a>> {
+        self.aliases.get(name).and_then(|units| units.get(&unit))
+    }
+
+    // Resolve a register operand, written as `R3`, `3` or an alias defined above `line`
+    fn register_operand(&self, line: &Line, token: &str) -> Result<usize, String> {
+        if let Some(alias) = self.find_alias(token, line.unit) {
+            if alias.line.index < line.index {
+                return Ok(alias.value as usize);
+            }
+            return Err(format!(
+                "Register alias {} is used before its definition at {}",
+                token,
+                alias.line.place()
+            ));
+        }
+        let err = match parse_register(token) {
+            Ok(reg) => return Ok(reg),
+            Err(err) => err,
+        };
+        if let Some(special) = special_register(token) {
+            return Err(format!(
+                "{} names the {}, which is not a register instructions can use",
+                token, special
+            ));
+        }
+        if !is_label_name(token) {
+            return Err(err);
+        }
+        // `R`, `RX` or `R1a` is a register number gone wrong rather than an alias
+        let number = token.strip_prefix(['R', 'r']).filter(|rest| {
+            rest.chars().count() <= 1 || rest.contains(|c: char| c.is_ascii_digit())
+        });
+        if number.is_some() {
+            return Err(format!(
+                "Invalid register: {}, which needs a number after the R",
+                token
+            ));
+        }
+        let mut message = format!("Undefined register alias: {}", token);
+        let names = self
+            .aliases
+            .iter()
+            .filter(|(_, units)| units.contains_key(&line.unit))
+            .map(|(&name, _)| name);
+        if let Some(suggestion) = closest(token, names) {
+            message.push_str(&format!(" (did you mean `{}`?)", suggestion));
+        }
+        Err(message)
+    }
+
+    // Resolve a numeric operand written as a constant expression over literals, labels
+    // and constants
+    fn value(&mut self, token: &str, kind: &str) -> Result<i128, String> {
+        evaluate(token, kind, |name| self.lookup(name))
+    }
+
+    // Value of a symbol. Local labels are looked up in the scope of the current line.
+    fn lookup(&mut self, name: &str) -> Result<i128, String> {
+        self.symbol(name).map(|symbol| symbol.value)
+    }
+
+    // The symbol `name` refers to on the current line, marked as used
+    fn symbol(&mut self, name: &str) -> Result<Symbol<'a>, String> {
+        let found = if is_local(name) {
+            self.locals
+                .get_mut(name)
+                .map(|scopes| scopes.get_mut(&self.scope))
+        } else {
+            // A file's own symbols hide those other files export
+            let exporter = self.globals.get(name).map(|&(unit, _, _)| unit);
+            self.symbols.get_mut(name).and_then(|units| {
+                let unit = match exporter {
+                    Some(unit) if !units.contains_key(&self.unit) => unit,
+                    _ => self.unit,
+                };
+                units.get_mut(&unit).map(Some)
+            })
+        };
+        match found {
+            Some(Some(symbol)) => {
+                symbol.used = true;
+                let symbol = *symbol;
+                self.note_value_label(name, &symbol);
+                return Ok(symbol);
+            }
+            Some(None) => {} // A local label defined only in other scopes
+            None => {
+                self.missing = Some(String::from(name));
+                return Err(undefined(name));
+            }
+        }
+
+        let scopes = &self.locals[name];
+        if let Some(&symbol) = scopes.get(&self.scope) {
+            self.note_value_label(name, &symbol);
+            return Ok(symbol);
+        }
+        let owners: Vec<String> = scopes
+            .keys()
+            .map(|&scope| self.describe_scope(scope))
+            .collect();
+        Err(format!(
+            "Local label {} is not visible in {}; it is defined in {}",
+            name,
+            self.describe_scope(self.scope),
+            owners.join(" and ")
+        ))
+    }
+
+    // Remember a code label used as a value rather than a jump target, since moving the
+    // instructions after it would change that value behind the program's back, and a
+    // JMPR may land on it
+    fn note_value_label(&mut self, name: &str, symbol: &Symbol) {
+        if symbol.kind != SymbolKind::Label || self.in_target {
+            return;
+        }
+        if self.value_label.is_none() {
+            self.value_label = Some(String::from(name));
+        }
+        if let Ok(addr) = usize::try_from(symbol.value) {
+            self.taken.push(addr);
+        }
+    }
+
+    // Name the labels at either end of a scope, for messages about local labels
+    fn describe_scope(&self, scope: usize) -> String {
+        let start = match scope.checked_sub(1) {
+            Some(index) => self.scopes[index].describe(),
+            None => String::from("the start of the program"),
+        };
+        let end = match self.scopes.get(scope) {
+            Some(next) => next.describe(),
+            None => String::from("the end of the program"),
+        };
+        format!("the scope from {} to {}", start, end)
+    }
+
+    // Resolve a signed immediate operand, which must fit in an i32. `=label` is the
+    // address of a code or data label, and may be followed by an offset as in `=buf+4`.
+    fn immediate_operand(&mut self, token: &str) -> Result<i32, String> {
+        let value = match token.strip_prefix('=') {
+            Some(expr) => self.label_address(expr, "=")?,
+            None => self.value(token, "Immediate")?,
+        };
+        i32::try_from(value).map_err(|_| format!("Immediate out of range: {}", token))
+    }
+
+    // Resolve the immediate of a bitwise opcode, which is a 32-bit pattern: anything from
+    // i32::MIN to u32::MAX, so that `0x8000_0000` means the sign bit
+    fn mask_operand(&mut self, token: &str) -> Result<i32, String> {
+        let value = self.value(token, "Immediate")?;
+        if !(i128::from(i32::MIN)..=i128::from(u32::MAX)).contains(&value) {
+            return Err(format!("Immediate out of range: {}", token));
+        }
+        Ok(value as u32 as i32)
+    }
+
+    // Value of `expr` after the `=` of an immediate or as the operand of a LEA, written
+    // after `prefix`, which must start with a label. A code label is the address of its
+    // instruction and a data label the memory address of its first value.
+    fn label_address(&mut self, expr: &str, prefix: &str) -> Result<i128, String> {
+        let end = expr
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'))
+            .unwrap_or(expr.len());
+        let name = &expr[..end];
+        if !is_label_name(name) {
+            return Err(format!(
+                "Expected a label after {}, found {}",
+                prefix.trim_end(),
+                expr
+            ));
+        }
+        let symbol = self.symbol(name)?;
+        if !matches!(symbol.kind, SymbolKind::Label | SymbolKind::Data) {
+            return Err(format!(
+                "{}{} needs a label, but {} is a constant defined at {}",
+                prefix,
+                name,
+                name,
+                symbol.line.place()
+            ));
+        }
+        self.value(expr, "Immediate")
+    }
+
+    // Resolve the instruction address of a jump or `.entry`
+    fn target_operand(&mut self, token: &str) -> Result<usize, String> {
+        self.in_target = true;
+        let addr = self.address_operand(token);
+        self.in_target = false;
+        addr
+    }
+
+    // Resolve a relative operand at `ip`. One written from the jump, such as `.+2` or `.-3`,
+    // and one with a sign, which only a relative branch takes and counts from the next
+    // instruction, are offsets; anything else is the target, such as a label, and the
+    // offset to it is worked out here.
+    fn offset_operand(&mut self, token: &str, ip: usize) -> Result<i32, String> {
+        let offset = if let Some(distance) = from_here(token) {
+            let distance = if distance.is_empty() {
+                0
+            } else {
+                self.value(distance, "Offset")?
+            };
+            distance - 1
+        } else if is_signed(token) {
+            self.value(token, "Offset")?
+        } else {
+            self.target_operand(token)? as i128 - (ip as i128 + 1)
+        };
+        i32::try_from(offset)
+            .map_err(|_| format!("Branch offset {} does not fit in 32 bits", offset))
+    }
+
+    // Resolve a memory or instruction address operand, which may not be negative
+    fn address_operand(&mut self, token: &str) -> Result<usize, String> {
+        let value = self.value(token, "Address")?;
+        if value < 0 {
+            return Err(format!("Address operand may not be negative: {}", token));
+        }
+        usize::try_from(value).map_err(|_| format!("Address out of range: {}", token))
+    }
+}
+
+// Evaluate a constant expression such as `42`, `loop+2` or `(BUF + 4*3)`, looking up
+// names with `lookup`. `kind` names the operand in error messages.
+fn evaluate(
+    expr: &str,
+    kind: &str,
+    mut lookup: impl FnMut(&str) -> Result<i128, String>,
+) -> Result<i128, String> {
+    let terms = lex_expression(expr, kind, &mut lookup)?;
+    let mut parser = ExpressionParser {
+        terms: &terms,
+        next: 0,
+        expr,
+        kind,
+    };
+    let value = parser.binary(0)?;
+    match terms.get(parser.next) {
+        None => Ok(value),
+        Some(Term::Close) => Err(parser.unbalanced()),
+        Some(_) => Err(parser.invalid()),
+    }
+}
+
+// Split an expression into terms, resolving literals and names as they are found
+fn lex_expression<'e>(
+    expr: &'e str,
+    kind: &str,
+    lookup: &mut dyn FnMut(&str) -> Result<i128, String>,
+) -> Result<Vec<Term<'e>>, String> {
+    let mut terms = Vec::new();
+    let mut rest = expr;
+    while let Some(c) = rest.chars().next() {
+        let len = if c.is_whitespace() {
+            c.len_utf8()
+        } else if c == '(' || c == ')' {
+            terms.push(if c == '(' { Term::Open } else { Term::Close });
+            1
+        } else if rest.starts_with("<<") || rest.starts_with(">>") {
+            terms.push(Term::Operator(&rest[..2]));
+            2
+        } else if "+-*/%&|^".contains(c) {
+            terms.push(Term::Operator(&rest[..1]));
+            1
+        } else if c == '\'' {
+            // Up to the closing quote, stepping over escaped characters
+            let mut escaped = false;
+            let len = rest
+                .char_indices()
+                .skip(1)
+                .find(|&(_, c)| match c {
+                    _ if escaped => {
+                        escaped = false;
+                        false
+                    }
+                    '\\' => {
+                        escaped = true;
+                        false
+                    }
+                    _ => c == '\'',
+                })
+                .map_or(rest.len(), |(index, _)| index + 1);
+            terms.push(Term::Value(parse_char(&rest[..len])?.into()));
+            len
+        } else {
+            let len = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'))
+                .unwrap_or(rest.len());
+            let word = &rest[..len];
+            let value = if c.is_ascii_digit() {
+                parse_integer(word).map_err(|err| match err {
+                    LiteralError::OutOfRange => format!("{} out of range: {}", kind, expr),
+                    LiteralError::Invalid => format!("Invalid {}: {}", kind.to_lowercase(), expr),
+                })?
+            } else if is_label_name(word) {
+                lookup(word)?
+            } else {
+                return Err(format!("Invalid {}: {}", kind.to_lowercase(), expr));
+            };
+            terms.push(Term::Value(value));
+            len
+        };
+        rest = &rest[len..];
+    }
+    Ok(terms)
+}
+
+// Recursive descent over the terms of a constant expression
+struct ExpressionParser<'t, 'e> {
+    terms: &'t [Term<'e>],
+    next: usize,
+    expr: &'e str,
+    kind: &'e str,
+}
+
+impl ExpressionParser<'_, '_> {
+    // Parse operators from the `level` entry of `PRECEDENCE` or tighter
+    fn binary(&mut self, level: usize) -> Result<i128, String> {
+        let Some(operators) = PRECEDENCE.get(level) else {
+            return self.unary();
+        };
+        let mut left = self.binary(level + 1)?;
+        while let Some(&Term::Operator(op)) = self.terms.get(self.next) {
+            if !operators.contains(&op) {
+                break;
+            }
+            self.next += 1;
+            let right = self.binary(level + 1)?;
+            left = self.apply(op, left, right)?;
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<i128, String> {
+        let term = self.terms.get(self.next).copied();
+        self.next += 1;
+        match term {
+            Some(Term::Value(value)) => Ok(value),
+            Some(Term::Operator("-")) => self.unary()?.checked_neg().ok_or_else(|| self.overflow()),
+            Some(Term::Operator("+")) => self.unary(),
+            Some(Term::Open) => {
+                let value = self.binary(0)?;
+                if !matches!(self.terms.get(self.next), Some(Term::Close)) {
+                    return Err(self.unbalanced());
+                }
+                self.next += 1;
+                Ok(value)
+            }
+            _ => Err(self.invalid()),
+        }
+    }
+
+    fn apply(&self, op: &str, left: i128, right: i128) -> Result<i128, String> {
+        let result = match op {
+            "/" | "%" if right == 0 => {
+                return Err(format!("Division by zero in expression: {}", self.expr))
+            }
+            "+" => left.checked_add(right),
+            "-" => left.checked_sub(right),
+            "*" => left.checked_mul(right),
+            "/" => left.checked_div(right),
+            "%" => left.checked_rem(right),
+            "<<" | ">>" => {
+                let Some(shift) = u32::try_from(right).ok().filter(|&shift| shift < 128) else {
+                    return Err(format!(
+                        "Shift amount out of range in expression: {}",
+                        self.expr
+                    ));
+                };
+                if op == ">>" {
+                    Some(left >> shift)
+                } else {
+                    // Bits shifted out of the value are an overflow
+                    Some(left << shift).filter(|value| value >> shift == left)
+                }
+            }
+            "&" => Some(left & right),
+            "|" => Some(left | right),
+            "^" => Some(left ^ right),
+            _ => unreachable!("operator {} is not in PRECEDENCE", op),
+        };
+        result.ok_or_else(|| self.overflow())
+    }
+
+    fn invalid(&self) -> String {
+        format!("Invalid {}: {}", self.kind.to_lowercase(), self.expr)
+    }
+
+    fn unbalanced(&self) -> String {
+        format!("Unbalanced parentheses in expression: {}", self.expr)
+    }
+
+    fn overflow(&self) -> String {
+        format!("Overflow in expression: {}", self.expr)
+    }
+}
+
+// Join the tokens of an expression written with spaces, such as `(BUF + 4)` or `A * 2`,
+// into one token. Tokens are joined inside parentheses and around an operator that
+// stands alone or ends a token. A token starting with `-` stays a separate operand, so
+// `LI 0 -5` keeps meaning what it did.
+fn group_operands<'a>(text: &'a str, tokens: &[Token<'a>]) -> Vec<Token<'a>> {
+    let is_operator = |c: char| "+-*/%<>&|^".contains(c);
+    let mut groups: Vec<Token<'a>> = Vec::new();
+    let mut depth = 0;
+    let mut joining = false;
+    for &token in tokens {
+        match groups.last_mut() {
+            Some(group) if joining || token.text.chars().all(is_operator) => {
+                group.text = &text[group.start..token.start + token.text.len()];
+            }
+            _ => groups.push(token),
+        }
+        if !token.text.starts_with(['\'', '"']) {
+            for c in token.text.chars() {
+                match c {
+                    '(' | '[' => depth += 1,
+                    ')' | ']' => depth -= 1,
+                    _ => {}
+                }
+            }
+        }
+        joining = depth > 0
+            || token
+                .text
+                .ends_with(|c| is_operator(c) || c == '(' || c == '[');
+    }
+    groups
+}
+
+// Operands of an instruction or macro call, separated by spaces, by commas or by both, as
+// in `LOAD R1, [R2+4]`. A comma with no operand on one side of it is an error.
+fn operand_list<'a>(
+    text: &'a str,
+    tokens: &[Token<'a>],
+) -> Result<Vec<Token<'a>>, (Token<'a>, String)> {
+    let mut values = Vec::new();
+    let mut comma = None; // The last comma, until an operand follows it
+    for token in tokens {
+        let mut start = 0;
+        let mut quote = None;
+        let mut escaped = false;
+        for (index, c) in token.text.char_indices() {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' if quote.is_some() => escaped = true,
+                '\'' | '"' if quote.is_none() => quote = Some(c),
+                _ if quote == Some(c) => quote = None,
+                ',' if quote.is_none() => {
+                    if index > start {
+                        values.push(Token {
+                            text: &token.text[start..index],
+                            start: token.start + start,
+                        });
+                        comma = None;
+                    }
+                    let here = Token {
+                        text: ",",
+                        start: token.start + index,
+                    };
+                    if comma.is_some() || values.is_empty() {
+                        return Err((here, String::from("Missing operand before ','")));
+                    }
+                    comma = Some(here);
+                    start = index + 1;
+                }
+                _ => {}
+            }
+        }
+        if start < token.text.len() {
+            values.push(Token {
+                text: &token.text[start..],
+                start: token.start + start,
+            });
+            comma = None;
+        }
+    }
+    match comma {
+        Some(comma) => Err((comma, String::from("Missing operand after ','"))),
+        None => Ok(group_operands(text, &values)),
+    }
+}
+
+// CMP and TEST given a destination, as they were before the flags, still write their
+// result there
+fn legacy_compare(opcode: Opcode, operands: usize) -> Opcode {
+    match opcode {
+        Opcode::Cmp if operands > 2 => Opcode::CmpWrite,
+        Opcode::Test if operands > 2 => Opcode::TestWrite,
+        _ => opcode,
+    }
+}
+
+// Rewrite `LOAD R1 [R2+4]` and `STORE R1 [R2-1]` as LOADO and STOREO, with the base
+// register and offset as separate operands. `[R2]` has an offset of 0.
+fn desugar_indexed<'a>(opcode: Opcode, mut operands: Vec<Token<'a>>) -> (Opcode, Vec<Token<'a>>) {
+    let indexed = match opcode {
+        Opcode::Load => Opcode::LoadOffset,
+        Opcode::Store => Opcode::StoreOffset,
+        _ => return (opcode, operands),
+    };
+    let Some(inner) = operands
+        .get(1)
+        .and_then(|token| token.text.strip_prefix('['))
+        .and_then(|text| text.strip_suffix(']'))
+    else {
+        return (opcode, operands);
+    };
+    let start = operands[1].start + 1;
+    // The base register ends where the offset's sign starts
+    let split = inner.find(['+', '-']).unwrap_or(inner.len());
+    let base = inner[..split].trim_end();
+    let offset = match inner[split..].strip_prefix('+') {
+        Some(rest) => Token {
+            text: rest.trim(),
+            start: start + inner.len() - rest.trim_start().len(),
+        },
+        None if split < inner.len() => Token {
+            text: inner[split..].trim_end(),
+            start: start + split,
+        },
+        None => Token {
+            text: "0",
+            start: operands[1].start,
+        },
+    };
+    let base = Token {
+        text: base.trim_start(),
+        start: start + base.len() - base.trim_start().len(),
+    };
+    operands.splice(1..2, [base, offset]);
+    (indexed, operands)
+}
+
+// Build an error pointing at `token` in the given source line
+fn diagnostic(line: &Line, token: Token, message: String) -> ParseError {
+    ParseError {
+        file: line.file.as_deref().map(ToString::to_string),
+        line: line.number,
+        column: line.text[..token.start].chars().count() + 1,
+        width: token.text.chars().count(),
+        source_line: line.text.clone(),
+        message,
+        note: line.note.as_deref().cloned().map(Box::new),
+    }
+}
+
+// Replace the parameters in a line of a macro body with the invocation's arguments, and
+// `\@` with the number of the expansion so labels inside the macro stay unique
+fn substitute(text: &str, params: &[String], args: &[Token], expansion: usize) -> String {
+    let mut result = String::new();
+    let mut end = 0;
+    for token in tokenize(text) {
+        result.push_str(&text[end..token.start]);
+        let (word, suffix) = match token.text.strip_suffix([':', ',']) {
+            Some(word) => (word, &token.text[word.len()..]),
+            None => (token.text, ""),
+        };
+        match params.iter().position(|param| param == word) {
+            Some(index) => {
+                result.push_str(args[index].text);
+                result.push_str(suffix);
+            }
+            None => result.push_str(&token.text.replace("\\@", &expansion.to_string())),
+        }
+        end = token.start + token.text.len();
+    }
+    result.push_str(&text[end..]);
+    result
+}
+
+// Split a line into whitespace-separated tokens, keeping a quoted character literal
+// such as `' '` or a string such as `"a b"` in a single token. A comment starting with
+// `//`, `;` or `#` runs to the end of the line and is dropped.
+fn tokenize(line: &str) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let mut start = None;
+    let mut chars = line.char_indices();
+    while let Some((index, c)) = chars.next() {
+        let comment = c == ';' || c == '#' || line[index..].starts_with("//");
+        if c.is_whitespace() || comment {
+            if let Some(start) = start.take() {
+                tokens.push(Token {
+                    text: &line[start..index],
+                    start,
+                });
+            }
+            if comment {
+                return tokens;
+            }
+            continue;
+        }
+        start.get_or_insert(index);
+        if c == '\'' || c == '"' {
+            // Skip to the closing quote, stepping over escaped characters
+            let quote = c;
+            while let Some((_, c)) = chars.next() {
+                match c {
+                    '\\' => {
+                        chars.next();
+                    }
+                    _ if c == quote => break,
+                    _ => {}
+                }
+            }
+        }
+    }
+    if let Some(start) = start {
+        tokens.push(Token {
+            text: &line[start..],
+            start,
+        });
+    }
+    tokens
+}
+
+// Split tokens such as `1,` `2,3` into the values of a comma-separated list
+fn split_list<'a>(tokens: &[Token<'a>]) -> Vec<Token<'a>> {
+    let mut values = Vec::new();
+    for token in tokens {
+        let mut start = 0;
+        let mut quoted = false;
+        let mut escaped = false;
+        for (index, c) in token.text.char_indices() {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' if quoted => escaped = true,
+                '\'' => quoted = !quoted,
+                ',' if !quoted => {
+                    if index > start {
+                        values.push(Token {
+                            text: &token.text[start..index],
+                            start: token.start + start,
+                        });
+                    }
+                    start = index + 1;
+                }
+                _ => {}
+            }
+        }
+        if start < token.text.len() {
+            values.push(Token {
+                text: &token.text[start..],
+                start: token.start + start,
+            });
+        }
+    }
+    values
+}
+
+// Parse a register written as `R3`, `r3` or a bare `3`
+fn parse_register(token: &str) -> Result<usize, String> {
+    let index = token.strip_prefix(['R', 'r']).unwrap_or(token);
+    if let Ok(index) = index.parse() {
+        return Ok(index);
+    }
+    if index
+        .strip_prefix('-')
+        .is_some_and(|digits| digits.parse::<usize>().is_ok())
+    {
+        return Err(format!("Register operand may not be negative: {}", token));
+    }
+    Err(format!("Invalid register: {}", token))
+}
+
+// Why a token could not be read as an integer literal
+enum LiteralError {
+    Invalid,
+    OutOfRange,
+}
+
+// Parse an integer written in decimal or with a `0x`, `0b` or `0o` prefix, with an
+// optional sign and `_` allowed between digits
+fn parse_integer(token: &str) -> Result<i128, LiteralError> {
+    let (negative, unsigned) = match token.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, token),
+    };
+    let (radix, digits) = match unsigned.get(..2) {
+        Some("0x" | "0X") => (16, &unsigned[2..]),
+        Some("0b" | "0B") => (2, &unsigned[2..]),
+        Some("0o" | "0O") => (8, &unsigned[2..]),
+        _ => (10, unsigned),
+    };
+    if digits.is_empty()
+        || digits.starts_with('_')
+        || !digits.chars().all(|c| c == '_' || c.is_digit(radix))
+    {
+        return Err(LiteralError::Invalid);
+    }
+
+    let digits: String = digits.chars().filter(|&c| c != '_').collect();
+    let magnitude =
+        u64::from_str_radix(&digits, radix).map_err(|_| LiteralError::OutOfRange)? as i128;
+    Ok(if negative { -magnitude } else { magnitude })
+}
+
+// Parse a character literal such as `'A'` or `'\n'` into its Unicode scalar value
+fn parse_char(token: &str) -> Result<i32, String> {
+    let unterminated = || format!("Unterminated character literal: {}", token);
+    let wrong_length = || {
+        format!(
+            "Character literal must hold exactly one character: {}",
+            token
+        )
+    };
+
+    let mut chars = token[1..].chars();
+    let value = match chars.next() {
+        Some('\\') => match chars.next() {
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('r') => '\r',
+            Some('0') => '\0',
+            Some('\'') => '\'',
+            Some('\\') => '\\',
+            Some(_) => return Err(format!("Unknown escape in character literal: {}", token)),
+            None => return Err(unterminated()),
+        },
+        Some('\'') => return Err(wrong_length()),
+        Some(c) => c,
+        None => return Err(unterminated()),
+    };
+
+    match chars.as_str() {
+        "'" => Ok(value as i32),
+        rest if rest.ends_with('\'') => Err(wrong_length()),
+        _ => Err(unterminated()),
+    }
+}
+
+// Parse a double-quoted string into one character code per memory cell
+fn parse_string(token: &str) -> Result<Vec<i32>, String> {
+    string_literal(token).map(|text| text.chars().map(|c| c as i32).collect())
+}
+
+// Unescape a double-quoted string literal
+fn string_literal(token: &str) -> Result<String, String> {
+    let Some(body) = token.strip_prefix('"') else {
+        return Err(format!("Expected a quoted string: {}", token));
+    };
+
+    let mut text = String::new();
+    let mut chars = body.chars();
+    while let Some(c) = chars.next() {
+        let c = match c {
+            '"' if chars.as_str().is_empty() => return Ok(text),
+            '"' => return Err(format!("Unexpected text after string: {}", token)),
+            '\\' => match chars.next() {
+                Some('n') => '\n',
+                Some('t') => '\t',
+                Some('r') => '\r',
+                Some('0') => '\0',
+                Some('"') => '"',
+                Some('\\') => '\\',
+                Some(escape) => {
+                    return Err(format!("Unknown escape \\{} in string: {}", escape, token))
+                }
+                None => break,
+            },
+            c => c,
+        };
+        text.push(c);
+    }
+    Err(format!("Unterminated string: {}", token))
+}
+
+// Number of single-character insertions, deletions and substitutions between two names
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let substituted = diagonal + usize::from(ca != cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substituted.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+// Local labels start with a dot and are only visible between two non-local labels
+fn is_local(name: &str) -> bool {
+    name.starts_with('.')
+}
+
+// Distance of an operand written relative to its own instruction, such as `.+2` or `.-3`
+// (`.` alone is the instruction itself). Any jump target can be, and so can the addr of
+// the legacy five-field form.
+fn from_here(token: &str) -> Option<&str> {
+    token
+        .strip_prefix('.')
+        .filter(|distance| distance.is_empty() || is_signed(distance))
+}
+
+// Whether an operand starts with a sign, such as the `-3` of `BR -3`
+fn is_signed(token: &str) -> bool {
+    token.starts_with(['+', '-'])
+}
+
+// Operand each written operand fills: the opcode's own layout, or the legacy five-field
+// form when there are more operands than the opcode uses
+fn slots(opcode: Opcode, count: usize) -> &'static [Operand] {
+    let layout = opcode.operands();
+    if count > layout.len() {
+        &LEGACY_OPERANDS
+    } else {
+        layout
+    }
+}
+
+// Reject an immediate the instruction could never run correctly with
+fn check_immediate(opcode: Opcode, value: i32) -> Result<i32, String> {
+    match opcode {
+        Opcode::DivImmediate if value == 0 => {
+            Err(String::from("Division by zero: DIVI by an immediate 0"))
+        }
+        Opcode::ShlImmediate | Opcode::ShrImmediate if !(0..=31).contains(&value) => Err(format!(
+            "Shift count must be between 0 and 31, found {}",
+            value
+        )),
+        Opcode::BsetImmediate | Opcode::BclrImmediate | Opcode::BtglImmediate
+            if !(0..=31).contains(&value) =>
+        {
+            Err(format!(
+                "Bit index must be between 0 and 31, found {}",
+                value
+            ))
+        }
+        Opcode::Peek | Opcode::Drop if value < 0 => Err(format!(
+            "Stack value count may not be negative, found {}",
+            value
+        )),
+        Opcode::Extr | Opcode::Extrs | Opcode::Insr if !(0..=31).contains(&value) => Err(format!(
+            "Bit position must be between 0 and 31, found {}",
+            value
+        )),
+        _ => Ok(value),
+    }
+}
+
+// Width of a bit field starting at bit `pos`, which must end by bit 31
+fn check_bit_field(pos: i32, width: i32) -> Result<usize, String> {
+    if !(1..=32).contains(&width) {
+        return Err(format!(
+            "Bit field width must be between 1 and 32, found {}",
+            width
+        ));
+    }
+    if pos + width > 32 {
+        return Err(format!(
+            "Bit field of {} bits from bit {} runs past bit 31",
+            width, pos
+        ));
+    }
+    Ok(width as usize)
+}
+
+// Whether the opcode's immediate is a bit mask rather than a number
+fn is_mask(opcode: Opcode) -> bool {
+    matches!(
+        opcode,
+        Opcode::AndImmediate | Opcode::OrImmediate | Opcode::XorImmediate
+    )
+}
+
+// The candidate closest to `name`, if it is close enough to be a likely typo
+fn closest<'n>(name: &str, candidates: impl Iterator<Item = &'n str>) -> Option<&'n str> {
+    let limit = (name.chars().count() / 3).max(1);
+    candidates
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|&(distance, _)| distance <= limit)
+        .min()
+        .map(|(_, candidate)| candidate)
+}
+
+// What a reserved special register name stands for. None of them can be used as an
+// operand yet, since the stack pointer is kept outside the register file.
+fn special_register(name: &str) -> Option<&'static str> {
+    match name {
+        "SP" => Some("stack pointer"),
+        "FP" => Some("frame pointer"),
+        "PC" => Some("program counter"),
+        _ => None,
+    }
+}
+
+// Message for a name that is not a known symbol
+fn undefined(name: &str) -> String {
+    format!("Undefined symbol: {}", name)
+}
+
+// Labels start with a letter, `_` or `.` and continue with letters, digits, `_` or `.`
+fn is_label_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == '.')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::String;
+
+    use super::*;
+
+    // Assembly of `source` as main.instr with `defines`, whose `.include`s read from `files`
+    fn assembly(source: &str, defines: &[(&str, &str)], files: &[(&str, &str)]) -> Assembly {
+        let root = SourceFile {
+            name: Some(String::from("main.instr")),
+            key: String::from("main.instr"),
+            text: String::from(source),
+        };
+        let defines: Vec<(String, String)> = defines
+            .iter()
+            .map(|&(name, value)| (String::from(name), String::from(value)))
+            .collect();
+        assemble_lenient(root, &defines, &mut |path, _| {
+            let (name, text) = files
+                .iter()
+                .find(|(name, _)| *name == path)
+                .ok_or_else(|| format!("No file {}", path))?;
+            Ok(SourceFile {
+                name: Some(String::from(*name)),
+                key: String::from(*name),
+                text: String::from(*text),
+            })
+        })
+    }
+
+    fn program(source: &str) -> Vec<Instruction> {
+        assemble(source).unwrap().into_instructions()
+    }
+
+    // Message of the first problem that stops `source` from assembling
+    fn error(source: &str) -> String {
+        assemble(source).unwrap_err().message
+    }
+
+    fn immediates(source: &str) -> Vec<i32> {
+        program(source)
+            .iter()
+            .map(|instr| instr.immediate)
+            .collect()
+    }
+
+    // Line and message of every warning about `source`
+    fn warnings(source: &str) -> Vec<(usize, String)> {
+        assembly(source, &[], &[])
+            .diagnostics
+            .into_iter()
+            .filter(|(severity, _)| *severity == Severity::Warning)
+            .map(|(_, err)| (err.line, err.message))
+            .collect()
+    }
+
+    #[test]
+    fn numeric_literals() {
+        let source = "LI R0 0x7fffffff\nLI R0 -0x80000000\nLI R0 0b1010\nLI R0 0o17\nLI R0 1_000";
+        assert_eq!(immediates(source), [i32::MAX, i32::MIN, 10, 15, 1000]);
+    }
+
+    #[test]
+    fn literals_out_of_range_or_malformed_are_refused() {
+        for (source, message) in [
+            ("LI R0 2147483648", "Immediate out of range: 2147483648"),
+            ("LI R0 -2147483649", "Immediate out of range: -2147483649"),
+            (
+                "LI R0 0x1_0000_0000",
+                "Immediate out of range: 0x1_0000_0000",
+            ),
+            (
+                "LI R0 99999999999999999999999",
+                "Immediate out of range: 99999999999999999999999",
+            ),
+            ("LI R0 0x", "Invalid immediate: 0x"),
+            ("LI R0 0b102", "Invalid immediate: 0b102"),
+            ("LOAD R0 -1", "Address operand may not be negative: -1"),
+            ("DROP -1", "Stack value count may not be negative, found -1"),
+        ] {
+            assert_eq!(error(source), message, "{}", source);
+        }
+    }
+
+    #[test]
+    fn character_literals_and_escapes() {
+        let source = "LI R0 'A'\nLI R0 '\\n'\nLI R0 '\\t'\nLI R0 '\\0'\nLI R0 '\\''\nLI R0 '\\\\'\nLI R0 ';'\nLI R0 '#'\nLI R0 ' '";
+        assert_eq!(
+            immediates(source),
+            [65, 10, 9, 0, 39, 92, 59, 35, 32].map(i32::from)
+        );
+        for (source, message) in [
+            (
+                "LI R0 'AB'",
+                "Character literal must hold exactly one character: 'AB'",
+            ),
+            ("LI R0 '\\q'", "Unknown escape in character literal: '\\q'"),
+            ("LI R0 'A", "Unterminated character literal: 'A"),
+        ] {
+            assert_eq!(error(source), message, "{}", source);
+        }
+    }
+
+    #[test]
+    fn every_comment_style_runs_to_the_end_of_the_line() {
+        let source = "LI R0 1 ; one\nLI R0 2 # two\nLI R0 3 // three\n; four\n# five\n// six";
+        let instructions = program(source);
+        assert_eq!(immediates(source)[..3], [1, 2, 3]);
+        // A line with nothing but a comment still takes an address, as a NOP
+        assert!(instructions[3..]
+            .iter()
+            .all(|instr| instr.opcode == Opcode::Nop));
+    }
+
+    #[test]
+    fn overlapping_data_is_refused() {
+        let data = assemble(".data 4\n.word 1,2,3\n.data 7\n.word 9").unwrap();
+        assert_eq!(
+            data.data(),
+            &[(4, Vec::from([1, 2, 3])), (7, Vec::from([9]))]
+        );
+        assert_eq!(
+            error(".data 4\n.word 1,2,3\n.data 6\n.word 9"),
+            "Data at address 6 overlaps data defined at line 2"
+        );
+    }
+
+    #[test]
+    fn constants_and_equ() {
+        assert_eq!(immediates(".const N 5\nM EQU N*2\nLI R0 M"), [10]);
+        assert_eq!(error(".const N 5\n.const N 6"), "Duplicate symbol: N");
+    }
+
+    #[test]
+    fn org_and_align_pad_with_nops() {
+        let instructions = program("NOP\n.org 4\nHALT");
+        assert_eq!(instructions.len(), 5);
+        assert_eq!(instructions[4].opcode, Opcode::Halt);
+
+        let instructions = program("NOP\n.align 4\nHALT");
+        assert_eq!(instructions.len(), 5);
+        assert_eq!(instructions[4].opcode, Opcode::Halt);
+
+        assert_eq!(error(".align 3\nHALT"), ".align needs a power of two: 3");
+        assert_eq!(
+            error("NOP\n.org 4\nHALT\n.org 2"),
+            ".org 2 is behind the current instruction address 5"
+        );
+    }
+
+    #[test]
+    fn includes_are_expanded_and_cycles_refused() {
+        let files = [
+            ("lib.inc", "INC R0"),
+            ("a.inc", ".include \"b.inc\""),
+            ("b.inc", ".include \"a.inc\""),
+        ];
+        let included = assembly(".include \"lib.inc\"\nHALT R0", &[], &files);
+        assert_eq!(included.errors(false, false).count(), 0);
+        assert_eq!(included.program[0].opcode, Opcode::Inc);
+
+        let cycle = assembly(".include \"a.inc\"", &[], &files);
+        let messages: Vec<&str> = cycle
+            .errors(false, false)
+            .map(|err| err.message.as_str())
+            .collect();
+        assert_eq!(
+            messages,
+            ["Include cycle: main.instr -> a.inc -> b.inc -> a.inc"]
+        );
+    }
+
+    #[test]
+    fn macros_expand_with_unique_labels_and_refuse_recursion() {
+        let source =
+            ".macro SPIN r\nspin\\@: DEC r\nJNZ r spin\\@\n.endmacro\nSPIN R0\nSPIN R1\nHALT";
+        let program = assemble(source).unwrap();
+        let first = program.symbols().get("spin1").unwrap().value;
+        let second = program.symbols().get("spin2").unwrap().value;
+        assert_ne!(first, second);
+        let jumps: Vec<_> = program
+            .iter()
+            .filter(|instr| instr.opcode == Opcode::Jnz)
+            .map(|instr| (instr.reg1, instr.addr))
+            .collect();
+        assert_eq!(
+            jumps,
+            [
+                (0, AddrOperand::Absolute(first as usize)),
+                (1, AddrOperand::Absolute(second as usize)),
+            ]
+        );
+
+        assert_eq!(
+            error(".macro PING\nPONG\n.endmacro\n.macro PONG\nPING\n.endmacro\nPING"),
+            "Recursive macro expansion: PING -> PONG -> PING"
+        );
+    }
+
+    #[test]
+    fn registers_are_numbers_with_or_without_an_r() {
+        let add = program("ADD R1 R2 R3");
+        for source in ["ADD r1 r2 r3", "ADD 1 2 3", "ADD R1 2 r3", "ADD R01 R2 R3"] {
+            assert_eq!(program(source), add, "{}", source);
+        }
+        let regs = program("MOV R15 r0")[0];
+        assert_eq!((regs.reg1, regs.reg2), (15, 0));
+        for (source, message) in [
+            (
+                "LI RX 5",
+                "Invalid register: RX, which needs a number after the R",
+            ),
+            (
+                "LI r1a 5",
+                "Invalid register: r1a, which needs a number after the R",
+            ),
+            (
+                "LI R 5",
+                "Invalid register: R, which needs a number after the R",
+            ),
+            ("LI R-1 5", "Register operand may not be negative: R-1"),
+            (
+                "LI R1.5 5",
+                "Invalid register: R1.5, which needs a number after the R",
+            ),
+            ("LI result 5", "Undefined register alias: result"),
+        ] {
+            assert_eq!(error(source), message, "{}", source);
+        }
+        // Out of range registers are reported as written, once the machine is known
+        let err = Program::from_instructions(program("MOV r9 R1"))
+            .validate(4, 4)
+            .unwrap_err();
+        assert_eq!(
+            err[0].to_string(),
+            "Register R9 at instruction 0 is outside the 4 available registers"
+        );
+    }
+
+    #[test]
+    fn commas_separate_operands() {
+        for (commas, spaces) in [
+            ("STORE R0, [R2-1]", "STORE R0 [R2-1]"),
+            ("LOAD R1, [R2 + 4]", "LOAD R1 [R2+4]"),
+            ("LOADF R3, -2", "LOADF R3 -2"),
+            ("LOADR R1,R2", "LOADR R1 R2"),
+            ("next: LOOP R1, next", "next: LOOP R1 next"),
+            ("LEA R1, next\nnext: HALT", "LEA R1 next\nnext: HALT"),
+            ("ADD R0 ,R1 , R2", "ADD R0 R1 R2"),
+            ("LI R0, ','", "LI R0 44"),
+            (".const A 2\nLI R0, A + 1", ".const A 2\nLI R0 3"),
+            (
+                ".macro TWICE r, n\nADDI r, r, n\nADDI r r n\n.endmacro\nTWICE R0, 3",
+                "ADDI R0 R0 3\nADDI R0 R0 3",
+            ),
+        ] {
+            assert_eq!(program(commas), program(spaces), "{}", commas);
+        }
+        for (source, message) in [
+            ("ADD R0,, R1 R2", "Missing operand before ','"),
+            ("ADD , R0 R1 R2", "Missing operand before ','"),
+            ("ADD R0 R1 R2,", "Missing operand after ','"),
+            (
+                ".macro M a\nINC a\n.endmacro\nM R0,",
+                "Missing operand after ','",
+            ),
+        ] {
+            assert_eq!(error(source), message, "{}", source);
+        }
+    }
+
+    #[test]
+    fn ifdef_follows_the_defines() {
+        let source = ".ifdef DEBUG\nLI R0 1\n.else\nLI R0 2\n.endif";
+        let value = |defines: &[(&str, &str)]| {
+            let assembly = assembly(source, defines, &[]);
+            assert_eq!(assembly.errors(false, false).count(), 0);
+            assembly
+                .program
+                .iter()
+                .find(|instr| instr.opcode == Opcode::LoadImmediate)
+                .unwrap()
+                .immediate
+        };
+        assert_eq!(value(&[]), 2);
+        assert_eq!(value(&[("DEBUG", "1")]), 1);
+        assert_eq!(
+            error(".ifndef X\n.endif\n.endif"),
+            ".endif without a matching .if"
+        );
+    }
+
+    #[test]
+    fn expressions_and_their_faults() {
+        assert_eq!(
+            immediates("LI R0 1+2*3\nLI R0 (1+2)*3\nLI R0 1|6&3\nLI R0 -(2<<3)"),
+            [7, 9, 3, -16]
+        );
+        for (source, message) in [
+            ("LI R0 5/0", "Division by zero in expression: 5/0"),
+            ("LI R0 5%(2-2)", "Division by zero in expression: 5%(2-2)"),
+            ("LI R0 (1<<127)", "Overflow in expression: (1<<127)"),
+            ("LI R0 0x7fffffff+1", "Immediate out of range: 0x7fffffff+1"),
+        ] {
+            assert_eq!(error(source), message, "{}", source);
+        }
+    }
+
+    #[test]
+    fn local_labels_are_scoped_to_their_label() {
+        let instructions = program("a: NOP\n.x: NOP\nJMP .x\nb: NOP\n.x: JMP .x");
+        assert_eq!(instructions[2].addr, AddrOperand::Absolute(1));
+        assert_eq!(instructions[4].addr, AddrOperand::Absolute(4));
+        assert!(error("a:\n.x: NOP\nb:\nJMP .x")
+            .starts_with("Local label .x is not visible in the scope from b at line 3"));
+    }
+
+    #[test]
+    fn misspelled_names_get_a_suggestion() {
+        assert_eq!(
+            error("loop: NOP\nJMP lop"),
+            "Undefined symbol: lop (did you mean `loop`?)"
+        );
+        assert_eq!(
+            error(".alias count R1\nINC cont"),
+            "Undefined register alias: cont (did you mean `count`?)"
+        );
+    }
+
+    #[test]
+    fn map_and_listing() {
+        let source = "; demo\n.const N 2\nstart: LI R0 N\nJMP start\n.data 8\n.word 1, 2";
+        let assembly = assembly(source, &[], &[]);
+        assert_eq!(
+            assembly.program.symbols().to_string(),
+            "start = 1\nN = 2 (const)\n"
+        );
+        #[cfg(feature = "std")]
+        assert_eq!(
+            assembly.listing(),
+            "; main.instr
+    1      0 0x0000  NOP                      ; demo
+    2                                         .const N 2
+    3      1 0x0001  LI R0 2                  start: LI R0 N
+    4      2 0x0002  JMP 1                    JMP start
+    5                                         .data 8
+    6      8 0x0008  data 1, 2                .word 1, 2
+"
+        );
+    }
+
+    #[test]
+    fn registers_left_for_the_end_are_not_unread() {
+        let overwritten = "R1 is written but overwritten before anything reads it";
+        assert_eq!(
+            warnings("LI R1 1\nLI R1 2\nHALT"),
+            [(1, String::from(overwritten))]
+        );
+        // Every register is part of the result once the program stops
+        assert!(warnings("LI R1 1\nHALT").is_empty());
+        assert!(warnings("LI R1 1").is_empty());
+        // One path reaching the end is enough
+        assert!(warnings("LI R1 1\nJZ R0 end\nLI R1 2\nend: HALT").is_empty());
+        // A function's caller may read what it leaves behind
+        assert!(warnings("CALL f\nHALT\nf: LI R1 1\nRET").is_empty());
+        assert_eq!(
+            warnings("LI R1 1\nloop: LI R1 2\nPRINT R1\nJMP loop"),
+            [(1, String::from(overwritten))]
+        );
+    }
+}
@@ -0,0 +1,641 @@
+// Binary program images, so a program can be shipped and loaded without its source.
+//
+// An image starts with the magic bytes, the format version as a little-endian u16 and
+// the CRC-32 of everything after it as a little-endian u32. The header follows as
+// unsigned LEB128 numbers: the instruction count, the number of data words, the number
+// of data blocks, the suggested register count and memory size, the entry address and
+// the number of source lines in the debug section. Then come the instructions, each its
+// opcode number followed by reg1, reg2 and reg3 as unsigned LEB128, its addr, and the
+// immediate as signed LEB128, and the data blocks, each its address and length as
+// unsigned LEB128 followed by its values as signed LEB128. The addr is its form, 0 for
+// absolute or 1 for relative, as unsigned LEB128 followed by the address as unsigned or
+// the offset as signed LEB128.
+//
+// The debug section ends the image when it has any source lines, one per instruction.
+// It holds the number of files and each file name as its length and UTF-8 bytes, then
+// for each instruction the index of its file plus one, or zero for source without a
+// file name, and its line number, all as unsigned LEB128.
+use alloc::string::String;
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use std::io::{Read, Write};
+
+use crate::debug::DebugInfo;
+use crate::error::BinaryError;
+#[cfg(feature = "std")]
+use crate::error::LoadError;
+use crate::isa::{AddrOperand, Instruction, Opcode};
+use crate::program::Program;
+
+// First bytes of every binary program. No text program starts with a NUL byte.
+pub const MAGIC: [u8; 4] = *b"\0MDP";
+
+// Format version written after the magic bytes, bumped when the layout changes
+pub const FORMAT_VERSION: u16 = 1;
+
+// What the header of a binary program says about it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BinaryHeader {
+    pub version: u16,
+    pub checksum: u32, // CRC-32 of the rest of the image
+    pub instructions: usize,
+    pub data_words: usize,
+    pub data_blocks: usize,
+    pub registers: usize, // Suggested dimensions, as from `Program::suggested_dimensions`
+    pub memory: usize,
+    pub entry: usize,
+    pub debug_lines: usize, // Source lines in the debug section, zero if it has none
+}
+
+// Opcode numbers used in binary programs and by `Instruction::encode`. A number keeps its meaning forever: new
+// opcodes take numbers that have never been used, and numbers of removed opcodes stay
+// reserved. Numbers 124 to 255 are still free.
+const OPCODES: [(Opcode, u8); 124] = [
+    (Opcode::Nop, 0),
+    (Opcode::Add, 1),
+    (Opcode::Sub, 2),
+    (Opcode::Mul, 3),
+    (Opcode::Div, 4),
+    (Opcode::Store, 5),
+    (Opcode::Load, 6),
+    (Opcode::LoadImmediate, 7),
+    (Opcode::Push, 8),
+    (Opcode::Pop, 9),
+    (Opcode::Jmp, 10),
+    (Opcode::Jz, 11),
+    (Opcode::Jnz, 12),
+    (Opcode::Mov, 13),
+    (Opcode::Je, 14),
+    (Opcode::Jne, 15),
+    (Opcode::And, 16),
+    (Opcode::Or, 17),
+    (Opcode::Xor, 18),
+    (Opcode::Not, 19),
+    (Opcode::Shl, 20),
+    (Opcode::Shr, 21),
+    (Opcode::CmpWrite, 22), // Programs written before the flags expect CMP to write reg3
+    (Opcode::TestWrite, 23),
+    (Opcode::B, 24),
+    (Opcode::Bz, 25),
+    (Opcode::Bnz, 26),
+    (Opcode::Neg, 27),
+    (Opcode::Abs, 28),
+    (Opcode::Mod, 29),
+    (Opcode::Inc, 30),
+    (Opcode::Dec, 31),
+    (Opcode::Halt, 32),
+    (Opcode::Call, 33),
+    (Opcode::Ret, 34),
+    (Opcode::Enter, 35),
+    (Opcode::Leave, 36),
+    (Opcode::LoadFrame, 37),
+    (Opcode::StoreFrame, 38),
+    (Opcode::AddImmediate, 39),
+    (Opcode::SubImmediate, 40),
+    (Opcode::MulImmediate, 41),
+    (Opcode::DivImmediate, 42),
+    (Opcode::AndImmediate, 43),
+    (Opcode::OrImmediate, 44),
+    (Opcode::XorImmediate, 45),
+    (Opcode::ShlImmediate, 46),
+    (Opcode::ShrImmediate, 47),
+    (Opcode::ShrLogical, 48),
+    (Opcode::Rol, 49),
+    (Opcode::Ror, 50),
+    (Opcode::RolImmediate, 51),
+    (Opcode::RorImmediate, 52),
+    (Opcode::LoadIndirect, 53),
+    (Opcode::StoreIndirect, 54),
+    (Opcode::LoadOffset, 55),
+    (Opcode::StoreOffset, 56),
+    (Opcode::JumpRegister, 57),
+    (Opcode::Br, 58),
+    (Opcode::Brz, 59),
+    (Opcode::Brnz, 60),
+    (Opcode::Loop, 61),
+    (Opcode::Cmp, 62),
+    (Opcode::Test, 63),
+    (Opcode::CmpUnsigned, 64),
+    (Opcode::Ja, 65),
+    (Opcode::Jae, 66),
+    (Opcode::Jb, 67),
+    (Opcode::Jbe, 68),
+    (Opcode::Cmovz, 69),
+    (Opcode::Cmovnz, 70),
+    (Opcode::Swap, 71),
+    (Opcode::Adds, 72),
+    (Opcode::Subs, 73),
+    (Opcode::AddsImmediate, 74),
+    (Opcode::SubsImmediate, 75),
+    (Opcode::Mulh, 76),
+    (Opcode::Mulhu, 77),
+    (Opcode::Mulw, 78),
+    (Opcode::Divmod, 79),
+    (Opcode::Popcnt, 80),
+    (Opcode::Clz, 81),
+    (Opcode::Ctz, 82),
+    (Opcode::Bset, 83),
+    (Opcode::Bclr, 84),
+    (Opcode::Btgl, 85),
+    (Opcode::Btst, 86),
+    (Opcode::BsetImmediate, 87),
+    (Opcode::BclrImmediate, 88),
+    (Opcode::BtglImmediate, 89),
+    (Opcode::Extr, 90),
+    (Opcode::Extrs, 91),
+    (Opcode::Insr, 92),
+    (Opcode::Sextb, 93),
+    (Opcode::Sexth, 94),
+    (Opcode::Zextb, 95),
+    (Opcode::Zexth, 96),
+    (Opcode::PushImmediate, 97),
+    (Opcode::PushAll, 98),
+    (Opcode::PopAll, 99),
+    (Opcode::IncMemory, 100),
+    (Opcode::DecMemory, 101),
+    (Opcode::IncIndirect, 102),
+    (Opcode::DecIndirect, 103),
+    (Opcode::HaltImmediate, 104),
+    (Opcode::HaltRegister, 105),
+    (Opcode::AssertEq, 106),
+    (Opcode::AssertEqRegister, 107),
+    (Opcode::Brk, 108),
+    (Opcode::Sys, 109),
+    (Opcode::Print, 110),
+    (Opcode::PrintChar, 111),
+    (Opcode::PrintString, 112),
+    (Opcode::PrintStringIndirect, 113),
+    (Opcode::ReadInt, 114),
+    (Opcode::ReadChar, 115),
+    (Opcode::Rand, 116),
+    (Opcode::RandRange, 117),
+    (Opcode::Rdcycle, 118),
+    (Opcode::Rdpc, 119),
+    (Opcode::Rdsp, 120),
+    (Opcode::Wrsp, 121),
+    (Opcode::Peek, 122),
+    (Opcode::Drop, 123),
+];
+
+pub(crate) fn opcode_number(opcode: Opcode) -> u8 {
+    OPCODES
+        .iter()
+        .find(|(op, _)| *op == opcode)
+        .map(|(_, number)| *number)
+        .expect("every opcode has a number")
+}
+
+pub(crate) fn opcode_from_number(number: u8) -> Option<Opcode> {
+    OPCODES
+        .iter()
+        .find(|(_, n)| *n == number)
+        .map(|(opcode, _)| *opcode)
+}
+
+// Whether `bytes` look like a binary program rather than assembly text
+pub fn is_binary(bytes: &[u8]) -> bool {
+    bytes.starts_with(&MAGIC)
+}
+
+// Binary image of a program's instructions, data, entry and source lines. Symbols are
+// not included.
+pub fn encode_program(program: &Program) -> Vec<u8> {
+    let (registers, memory) = program.suggested_dimensions();
+    let data_words = program.data().iter().map(|(_, values)| values.len()).sum();
+    let debug = program.debug_info();
+
+    let mut body = Vec::new();
+    for value in [
+        program.len(),
+        data_words,
+        program.data().len(),
+        registers,
+        memory,
+        program.entry(),
+        debug.len(),
+    ] {
+        write_unsigned(&mut body, value);
+    }
+    for instr in program.iter() {
+        body.push(opcode_number(instr.opcode));
+        for value in [instr.reg1, instr.reg2, instr.reg3] {
+            write_unsigned(&mut body, value);
+        }
+        match instr.addr {
+            AddrOperand::Absolute(addr) => {
+                write_unsigned(&mut body, 0);
+                write_unsigned(&mut body, addr);
+            }
+            AddrOperand::Relative(offset) => {
+                write_unsigned(&mut body, 1);
+                write_signed(&mut body, offset);
+            }
+        }
+        write_signed(&mut body, instr.immediate);
+    }
+    for (addr, values) in program.data() {
+        write_unsigned(&mut body, *addr);
+        write_unsigned(&mut body, values.len());
+        for &value in values {
+            write_signed(&mut body, value);
+        }
+    }
+    if !debug.is_empty() {
+        write_unsigned(&mut body, debug.files().len());
+        for file in debug.files() {
+            write_unsigned(&mut body, file.len());
+            body.extend_from_slice(file.as_bytes());
+        }
+        for &(file, line) in debug.lines() {
+            write_unsigned(&mut body, file.map_or(0, |index| index + 1));
+            write_unsigned(&mut body, line);
+        }
+    }
+
+    let mut out = Vec::from(MAGIC);
+    out.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    out.extend_from_slice(&crc32(&body).to_le_bytes());
+    out.extend_from_slice(&body);
+    out
+}
+
+// Write the image of `program` to `out`
+#[cfg(feature = "std")]
+pub fn write_bytecode(program: &Program, out: &mut impl Write) -> std::io::Result<()> {
+    out.write_all(&encode_program(program))
+}
+
+// Read an image written by `write_bytecode` to its end
+#[cfg(feature = "std")]
+pub fn read_bytecode(input: &mut impl Read) -> Result<Program, LoadError> {
+    let mut bytes = Vec::new();
+    input.read_to_end(&mut bytes)?;
+    decode_program(&bytes).map_err(LoadError::Binary)
+}
+
+// Header of an image, without decoding or verifying the rest of it
+pub fn decode_header(bytes: &[u8]) -> Result<BinaryHeader, BinaryError> {
+    read_header(&mut Reader { bytes }, false)
+}
+
+// Read the header, checking the rest of the image against its checksum if `verify` is set
+fn read_header(reader: &mut Reader, verify: bool) -> Result<BinaryHeader, BinaryError> {
+    let magic = reader
+        .take(MAGIC.len())
+        .map_err(|_| BinaryError::BadMagic)?;
+    if magic != MAGIC {
+        return Err(BinaryError::BadMagic);
+    }
+    let version = reader.take(2)?;
+    let version = u16::from_le_bytes([version[0], version[1]]);
+    if version != FORMAT_VERSION {
+        return Err(BinaryError::UnsupportedVersion { version });
+    }
+    let bytes = reader.take(4)?;
+    let checksum = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    let actual = crc32(reader.bytes);
+    if verify && actual != checksum {
+        return Err(BinaryError::ChecksumMismatch {
+            expected: checksum,
+            actual,
+        });
+    }
+    Ok(BinaryHeader {
+        version,
+        checksum,
+        instructions: reader.unsigned("header")?,
+        data_words: reader.unsigned("header")?,
+        data_blocks: reader.unsigned("header")?,
+        registers: reader.unsigned("header")?,
+        memory: reader.unsigned("header")?,
+        entry: reader.unsigned("header")?,
+        debug_lines: reader.unsigned("header")?,
+    })
+}
+
+// Program from an image written by `encode_program`, refusing a corrupted image
+pub fn decode_program(bytes: &[u8]) -> Result<Program, BinaryError> {
+    decode(bytes, true)
+}
+
+// Like `decode_program`, but without checking the image against its checksum
+pub fn decode_program_unverified(bytes: &[u8]) -> Result<Program, BinaryError> {
+    decode(bytes, false)
+}
+
+fn decode(bytes: &[u8], verify: bool) -> Result<Program, BinaryError> {
+    let mut reader = Reader { bytes };
+    let header = read_header(&mut reader, verify)?;
+    let len = header.instructions;
+    if header.entry > 0 && header.entry >= len {
+        return Err(BinaryError::EntryOutOfBounds {
+            entry: header.entry,
+            len,
+        });
+    }
+
+    let mut instructions = Vec::new();
+    for ip in 0..len {
+        let code = reader.byte("instructions")?;
+        let opcode = opcode_from_number(code).ok_or(BinaryError::UnknownOpcode { code, ip })?;
+        let mut instr = Instruction::new(opcode);
+        instr.reg1 = reader.unsigned("instructions")?;
+        instr.reg2 = reader.unsigned("instructions")?;
+        instr.reg3 = reader.unsigned("instructions")?;
+        instr.addr = match reader.unsigned("instructions")? {
+            0 => AddrOperand::Absolute(reader.unsigned("instructions")?),
+            1 => AddrOperand::Relative(reader.signed("instructions")?),
+            form => return Err(BinaryError::UnknownAddrForm { form, ip }),
+        };
+        instr.immediate = reader.signed("instructions")?;
+        instructions.push(instr);
+    }
+
+    let mut program = Program::from_instructions(instructions).with_entry(header.entry);
+    let mut data_words = 0;
+    for _ in 0..header.data_blocks {
+        let addr = reader.unsigned("data")?;
+        let mut values = Vec::new();
+        for _ in 0..reader.unsigned("data")? {
+            values.push(reader.signed("data")?);
+        }
+        data_words += values.len();
+        program = program.with_data(addr, values);
+    }
+    if data_words != header.data_words {
+        return Err(BinaryError::CountMismatch {
+            section: "data",
+            header: header.data_words,
+            found: data_words,
+        });
+    }
+
+    if header.debug_lines > 0 {
+        if header.debug_lines != len {
+            return Err(BinaryError::DebugLinesMismatch {
+                lines: header.debug_lines,
+                instructions: len,
+            });
+        }
+        program = program.with_debug_info(read_debug_info(&mut reader, len)?);
+    }
+
+    if !reader.bytes.is_empty() {
+        return Err(BinaryError::TrailingBytes {
+            len: reader.bytes.len(),
+        });
+    }
+    Ok(program)
+}
+
+// Debug section with a source line for each of `len` instructions
+fn read_debug_info(reader: &mut Reader, len: usize) -> Result<DebugInfo, BinaryError> {
+    let mut files = Vec::new();
+    for _ in 0..reader.unsigned("debug")? {
+        let name_len = reader.unsigned("debug")?;
+        let name = reader
+            .take(name_len)
+            .map_err(|_| BinaryError::Truncated { section: "debug" })?;
+        let name = core::str::from_utf8(name).map_err(|_| BinaryError::InvalidFileName)?;
+        files.push(String::from(name));
+    }
+    let mut lines = Vec::new();
+    for _ in 0..len {
+        let file = match reader.unsigned("debug")? {
+            0 => None,
+            index if index <= files.len() => Some(index - 1),
+            index => {
+                return Err(BinaryError::FileIndexOutOfBounds {
+                    index,
+                    files: files.len(),
+                })
+            }
+        };
+        lines.push((file, reader.unsigned("debug")?));
+    }
+    Ok(DebugInfo::from_parts(files, lines))
+}
+
+// CRC-32 as used by zip and PNG, computed bit by bit as images are small
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+fn write_unsigned(out: &mut Vec<u8>, value: usize) {
+    let mut value = value as u64;
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn write_signed(out: &mut Vec<u8>, value: i32) {
+    let mut value = i64::from(value);
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        // Done once the remaining bits are all copies of the sign bit just written
+        if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+// Reads the numbers of an image front to back, naming the section for errors
+struct Reader<'b> {
+    bytes: &'b [u8],
+}
+
+impl<'b> Reader<'b> {
+    fn take(&mut self, len: usize) -> Result<&'b [u8], BinaryError> {
+        if self.bytes.len() < len {
+            return Err(BinaryError::Truncated { section: "header" });
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn byte(&mut self, section: &'static str) -> Result<u8, BinaryError> {
+        let (&byte, rest) = self
+            .bytes
+            .split_first()
+            .ok_or(BinaryError::Truncated { section })?;
+        self.bytes = rest;
+        Ok(byte)
+    }
+
+    fn unsigned(&mut self, section: &'static str) -> Result<usize, BinaryError> {
+        let mut value: u64 = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.byte(section)?;
+            let bits = u64::from(byte & 0x7f);
+            if shift > 63 || (shift == 63 && bits > 1) {
+                return Err(BinaryError::ValueTooLarge { section });
+            }
+            value |= bits << shift;
+            if byte & 0x80 == 0 {
+                break;
+            }
+            shift += 7;
+        }
+        usize::try_from(value).map_err(|_| BinaryError::ValueTooLarge { section })
+    }
+
+    fn signed(&mut self, section: &'static str) -> Result<i32, BinaryError> {
+        let mut value: i64 = 0;
+        let mut shift = 0;
+        loop {
+            // Five bytes hold any i32
+            if shift >= 35 {
+                return Err(BinaryError::ValueTooLarge { section });
+            }
+            let byte = self.byte(section)?;
+            value |= i64::from(byte & 0x7f) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if byte & 0x40 != 0 {
+                    value |= -1 << shift; // Sign extend
+                }
+                break;
+            }
+        }
+        i32::try_from(value).map_err(|_| BinaryError::ValueTooLarge { section })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+
+    // Bytes before the body: the magic, the version and the checksum
+    const HEADER_LEN: usize = MAGIC.len() + 2 + 4;
+
+    // Image of a program with instructions, data and source lines
+    fn sample() -> Vec<u8> {
+        let source = ".data 2\n.word -1, 300, 70000\nLI R0 -5\nloop: INC R0\nJNZ R0 loop\nHALT";
+        encode_program(&assemble(source).unwrap())
+    }
+
+    #[test]
+    fn sample_decodes() {
+        let program = decode_program(&sample()).unwrap();
+        assert_eq!(program.len(), 4);
+        assert_eq!(program.data(), &[(2, Vec::from([-1, 300, 70000]))]);
+        assert_eq!(program.debug_info().len(), 4);
+    }
+
+    #[test]
+    fn flipped_byte_is_refused() {
+        let image = sample();
+        for index in 0..image.len() {
+            let mut corrupt = image.clone();
+            corrupt[index] ^= 0xff;
+            let err = decode_program(&corrupt).unwrap_err();
+            match index {
+                0..4 => assert_eq!(err, BinaryError::BadMagic),
+                4..6 => assert!(
+                    matches!(err, BinaryError::UnsupportedVersion { .. }),
+                    "{:?}",
+                    err
+                ),
+                _ => assert!(
+                    matches!(err, BinaryError::ChecksumMismatch { .. }),
+                    "byte {}: {:?}",
+                    index,
+                    err
+                ),
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_image_is_refused() {
+        let image = sample();
+        for len in 0..image.len() {
+            let prefix = &image[..len];
+            let err = decode_program(prefix).unwrap_err();
+            let unverified = decode_program_unverified(prefix).unwrap_err();
+            if len < MAGIC.len() {
+                assert_eq!(err, BinaryError::BadMagic);
+            } else if len < HEADER_LEN {
+                assert_eq!(err, BinaryError::Truncated { section: "header" });
+            } else {
+                assert!(
+                    matches!(err, BinaryError::ChecksumMismatch { .. }),
+                    "{} bytes: {:?}",
+                    len,
+                    err
+                );
+                assert!(
+                    matches!(unverified, BinaryError::Truncated { .. }),
+                    "{} bytes: {:?}",
+                    len,
+                    unverified
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn trailing_bytes_are_refused() {
+        let mut image = sample();
+        image.push(0);
+        assert!(matches!(
+            decode_program(&image),
+            Err(BinaryError::ChecksumMismatch { .. })
+        ));
+        assert_eq!(
+            decode_program_unverified(&image),
+            Err(BinaryError::TrailingBytes { len: 1 })
+        );
+    }
+
+    // Image of `version` around `body`
+    fn image(version: u16, body: &[u8]) -> Vec<u8> {
+        let mut image = Vec::from(MAGIC);
+        image.extend_from_slice(&version.to_le_bytes());
+        image.extend_from_slice(&crc32(body).to_le_bytes());
+        image.extend_from_slice(body);
+        image
+    }
+
+    #[test]
+    fn other_versions_are_refused() {
+        let body = &sample()[HEADER_LEN..];
+        assert!(decode_program(&image(FORMAT_VERSION, body)).is_ok());
+        for version in [0, FORMAT_VERSION + 1, u16::MAX] {
+            assert_eq!(
+                decode_program(&image(version, body)),
+                Err(BinaryError::UnsupportedVersion { version })
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_addr_form_is_refused() {
+        // One NOP whose addr is in form 2
+        let body = [1, 0, 0, 1, 1, 0, 0, 0, 0, 0, 0, 2, 0, 0];
+        assert_eq!(
+            decode_program(&image(FORMAT_VERSION, &body)),
+            Err(BinaryError::UnknownAddrForm { form: 2, ip: 0 })
+        );
+    }
+}
@@ -0,0 +1,272 @@
+use alloc::vec::Vec;
+
+use crate::cpu::{BreakMode, ProcessingUnit, UnderflowPolicy};
+use crate::error::BuildError;
+
+// Step-by-step configuration of a processing unit
+#[derive(Debug, Clone)]
+pub struct ProcessingUnitBuilder {
+    registers: usize,
+    memory: usize,
+    stack_size: Option<usize>,
+    max_instructions: usize,
+    trap_overflow: bool,
+    zero_register: bool,
+    break_mode: BreakMode,
+    underflow_policy: UnderflowPolicy,
+    seed: Option<u64>,
+    initial_registers: Vec<(usize, i32)>,
+    initial_memory: Vec<(usize, Vec<i32>)>,
+}
+
+impl Default for ProcessingUnitBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ProcessingUnitBuilder {
+    pub fn new() -> Self {
+        ProcessingUnitBuilder {
+            registers: 0,
+            memory: 0,
+            stack_size: None,
+            max_instructions: usize::MAX,
+            trap_overflow: false,
+            zero_register: false,
+            break_mode: BreakMode::default(),
+            underflow_policy: UnderflowPolicy::default(),
+            seed: None,
+            initial_registers: Vec::new(),
+            initial_memory: Vec::new(),
+        }
+    }
+
+    pub fn registers(mut self, count: usize) -> Self {
+        self.registers = count;
+        self
+    }
+
+    // Memory is sized by the product of its dimensions, e.g. &[10, 10] for 100 cells
+    pub fn memory(mut self, shape: &[usize]) -> Self {
+        self.memory = shape.iter().product();
+        self
+    }
+
+    // Limit the stack to the top `size` cells of memory (defaults to all but the lowest cell)
+    pub fn stack_size(mut self, size: usize) -> Self {
+        self.stack_size = Some(size);
+        self
+    }
+
+    // Stop each run after `count` instructions, on top of the limit passed to `run`
+    // (unlimited by default)
+    pub fn max_instructions(mut self, count: usize) -> Self {
+        self.max_instructions = count;
+        self
+    }
+
+    // Fault on arithmetic that overflows instead of wrapping (off by default)
+    pub fn trap_overflow(mut self, trap: bool) -> Self {
+        self.trap_overflow = trap;
+        self
+    }
+
+    // Hardwire R0 to 0, dropping writes to it, initial values included (off by default)
+    pub fn zero_register(mut self, zero: bool) -> Self {
+        self.zero_register = zero;
+        self
+    }
+
+    // Whether `run` goes on or stops after a BRK (goes on by default)
+    pub fn break_mode(mut self, mode: BreakMode) -> Self {
+        self.break_mode = mode;
+        self
+    }
+
+    // Whether POP, PEEK and DROP fault or give 0 when the stack runs out (fault by
+    // default). RET always faults.
+    pub fn underflow_policy(mut self, policy: UnderflowPolicy) -> Self {
+        self.underflow_policy = policy;
+        self
+    }
+
+    // Seed RAND and RANDR so every run draws the same numbers (a seed of the machine's own
+    // by default)
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    pub fn initial_register(mut self, reg: usize, value: i32) -> Self {
+        self.initial_registers.push((reg, value));
+        self
+    }
+
+    pub fn initial_memory(mut self, addr: usize, values: &[i32]) -> Self {
+        self.initial_memory.push((addr, values.to_vec()));
+        self
+    }
+
+    // Validate the configuration and create the processing unit
+    pub fn build(self) -> Result<ProcessingUnit, BuildError> {
+        if self.memory == 0 {
+            return Err(BuildError::ZeroMemory);
+        }
+
+        let stack_size = self.stack_size.unwrap_or(self.memory - 1);
+        if stack_size >= self.memory {
+            return Err(BuildError::StackTooLarge {
+                stack_size,
+                memory_size: self.memory,
+            });
+        }
+
+        let mut pu = ProcessingUnit::with_stack(self.registers, self.memory, stack_size);
+        pu.set_max_instructions(self.max_instructions);
+        pu.set_trap_overflow(self.trap_overflow);
+        pu.set_zero_register(self.zero_register);
+        pu.set_break_mode(self.break_mode);
+        pu.set_underflow_policy(self.underflow_policy);
+        if let Some(seed) = self.seed {
+            pu.set_seed(seed);
+        }
+
+        for (reg, value) in self.initial_registers {
+            pu.set_register(reg, value)
+                .map_err(|_| BuildError::RegisterOutOfBounds {
+                    reg,
+                    registers: self.registers,
+                })?;
+        }
+
+        for (addr, values) in self.initial_memory {
+            for (offset, &value) in values.iter().enumerate() {
+                pu.write_memory(addr + offset, value).map_err(|_| {
+                    BuildError::MemoryOutOfBounds {
+                        addr,
+                        len: values.len(),
+                        memory_size: self.memory,
+                    }
+                })?;
+            }
+        }
+
+        Ok(pu)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::cpu::run;
+    use crate::error::MdpuError;
+
+    fn builder() -> ProcessingUnitBuilder {
+        ProcessingUnitBuilder::new().registers(2).memory(&[4, 4])
+    }
+
+    #[test]
+    fn defaults_match_a_plain_processing_unit() {
+        let pu = builder().build().unwrap();
+        assert_eq!((pu.registers().len(), pu.memory().len()), (2, 16));
+        assert_eq!(pu.max_instructions(), usize::MAX);
+        assert!(!pu.trap_overflow());
+        assert!(!pu.zero_register());
+        assert_eq!(pu.break_mode(), BreakMode::default());
+        assert_eq!(pu.underflow_policy(), UnderflowPolicy::Trap);
+    }
+
+    #[test]
+    fn every_option_reaches_the_processing_unit() {
+        let pu = builder()
+            .max_instructions(7)
+            .trap_overflow(true)
+            .zero_register(true)
+            .break_mode(BreakMode::Stop)
+            .underflow_policy(UnderflowPolicy::ReturnZero)
+            .seed(42)
+            .initial_register(0, 5)
+            .initial_register(1, 6)
+            .initial_memory(3, &[1, 2, 3])
+            .build()
+            .unwrap();
+        assert_eq!(pu.max_instructions(), 7);
+        assert!(pu.trap_overflow());
+        assert!(pu.zero_register());
+        assert_eq!(pu.break_mode(), BreakMode::Stop);
+        assert_eq!(pu.underflow_policy(), UnderflowPolicy::ReturnZero);
+        assert_eq!(pu.seed(), 42);
+        // The zero register drops its initial value too
+        assert_eq!(pu.registers(), &[0, 6]);
+        assert_eq!(&pu.memory()[2..7], &[0, 1, 2, 3, 0]);
+    }
+
+    #[test]
+    fn stack_size_bounds_pushes() {
+        let mut pu = builder().stack_size(2).build().unwrap();
+        let program = assemble("PUSH R0\nPUSH R0\nPUSH R0").unwrap();
+        assert_eq!(
+            run(&mut pu, &program, 100).unwrap_err(),
+            MdpuError::StackOverflow { reg: 0, ip: 2 }
+        );
+    }
+
+    #[test]
+    fn invalid_configurations_are_rejected() {
+        let build = |builder: ProcessingUnitBuilder| builder.build().err().unwrap();
+        assert_eq!(
+            build(ProcessingUnitBuilder::new().registers(2)),
+            BuildError::ZeroMemory
+        );
+        assert_eq!(
+            build(builder().stack_size(16)),
+            BuildError::StackTooLarge {
+                stack_size: 16,
+                memory_size: 16
+            }
+        );
+        assert_eq!(
+            build(builder().initial_register(2, 1)),
+            BuildError::RegisterOutOfBounds {
+                reg: 2,
+                registers: 2
+            }
+        );
+        assert_eq!(
+            build(builder().initial_memory(14, &[1, 2, 3])),
+            BuildError::MemoryOutOfBounds {
+                addr: 14,
+                len: 3,
+                memory_size: 16
+            }
+        );
+    }
+
+    #[test]
+    fn max_instructions_limits_every_run() {
+        let program = assemble("loop: JMP loop").unwrap();
+        let mut pu = builder().max_instructions(10).build().unwrap();
+        assert_eq!(
+            run(&mut pu, &program, 100).unwrap_err(),
+            MdpuError::InstructionLimitExceeded { limit: 10 }
+        );
+        assert_eq!(pu.instruction_count(), 10);
+        // The next run gets ten more, counted from where the last one stopped
+        assert_eq!(
+            run(&mut pu, &program, 100).unwrap_err(),
+            MdpuError::InstructionLimitExceeded { limit: 20 }
+        );
+        // The limit passed to `run` still applies when it is the lower one
+        pu.reset_execution();
+        assert_eq!(
+            run(&mut pu, &program, 4).unwrap_err(),
+            MdpuError::InstructionLimitExceeded { limit: 4 }
+        );
+
+        let program = assemble("LI R0 1\nLI R1 2\nHALT 3").unwrap();
+        let mut pu = builder().max_instructions(3).build().unwrap();
+        assert_eq!(run(&mut pu, &program, 100).unwrap().exit_code, Some(3));
+    }
+}
@@ -0,0 +1,2725 @@
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::AtomicBool;
+
+use crate::error::MdpuError;
+use crate::flags::Flags;
+use crate::hook::{CancelHook, ExecutionHook, HookControl, NoHook};
+use crate::input::Input;
+use crate::isa::{relative_target, AddrOperand, Instruction, Opcode};
+use crate::output::{character, BufferedSink, OutputSink};
+use crate::random::{default_seed, Rng};
+use crate::syscall::{default_syscalls, SyscallContext, SyscallEffect, SyscallHandler};
+
+// Instruction limit the command line runs programs with. A machine built without one
+// runs until the limit passed to `run`.
+pub const DEFAULT_MAX_INSTRUCTIONS: usize = 1000;
+
+// Number of instructions executed between checks of a cancellation flag
+pub const CANCEL_CHECK_INTERVAL: usize = 256;
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ProcessingUnit {
+    pub(crate) registers: Vec<i32>,
+    pub(crate) memory: Vec<i32>,
+    pub(crate) stack_pointer: usize,
+    pub(crate) stack_limit: usize, // Pushes are allowed while the stack pointer is above this address
+    pub(crate) frame_pointer: usize, // Where ENTER saved the previous one, past the end of memory outside a frame
+    pub(crate) flags: Flags,         // Set by CMP, TEST and arithmetic
+    pub(crate) max_instructions: usize,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) trap_overflow: bool, // Fault instead of wrapping when arithmetic overflows
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) zero_register: bool, // R0 always reads as 0 and writes to it are dropped
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) break_mode: BreakMode, // What `run` does at a BRK
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) underflow_policy: UnderflowPolicy, // What POP, PEEK and DROP do past the stack's base
+    pub(crate) instruction_pointer: usize,
+    pub(crate) instruction_count: usize,
+    #[cfg_attr(feature = "serde", serde(default = "default_seed"))]
+    pub(crate) seed: u64, // What the generator was last seeded with
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) rng: Rng, // Draws the numbers of RAND and RANDR
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub(crate) exit_code: Option<i32>, // From the HALT that stopped the program, if one did
+    #[cfg_attr(feature = "serde", serde(skip))]
+    output: BufferedSink,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    input: Input, // Read by READI, READC and the reading syscalls
+    #[cfg_attr(feature = "serde", serde(skip, default = "default_syscalls"))]
+    syscalls: Box<dyn SyscallHandler>, // Runs every SYS
+}
+
+// Result of executing a single instruction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StepOutcome {
+    Continue,
+    Halted,
+    Breakpoint, // Executed a BRK, stepping again continues after it
+    Fault(MdpuError),
+}
+
+// What `run` does when it steps over a BRK. Either way it first writes the machine's
+// state to the output sink.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum BreakMode {
+    #[default]
+    Continue,
+    Stop, // Return as if halted, so running again continues after the BRK
+}
+
+// What POP, PEEK and DROP do when the stack holds fewer values than they need. RET
+// faults either way, as returning to address 0 would quietly start the program over.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum UnderflowPolicy {
+    #[default]
+    Trap, // Fault with `StackUnderflow`
+    ReturnZero, // POP and PEEK give 0 and DROP empties the stack, leaving it at its base
+}
+
+// Why a cancellable run stopped without an error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum HaltReason {
+    Halted,
+    Cancelled,
+}
+
+// Define the structure to hold the state after execution
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ProcessingUnitState {
+    pub registers: Vec<i32>,
+    pub stack: Vec<i32>,
+    pub stack_pointer: usize,
+    pub flags: Flags,
+    pub zero_register: bool,    // R0 is hardwired to 0
+    pub exit_code: Option<i32>, // Code the program halted with, None if it ran off its end
+}
+
+impl ProcessingUnitState {
+    // View of the state like the one `run` returns
+    pub fn view(&self) -> StateView<'_> {
+        StateView {
+            registers: &self.registers,
+            stack: &self.stack,
+            stack_pointer: self.stack_pointer,
+            flags: self.flags,
+            zero_register: self.zero_register,
+            exit_code: self.exit_code,
+        }
+    }
+}
+
+impl fmt::Display for ProcessingUnitState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.view().fmt(f)
+    }
+}
+
+// The same state borrowed from the processing unit, so `run` copies nothing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct StateView<'a> {
+    pub registers: &'a [i32],
+    pub stack: &'a [i32],
+    pub stack_pointer: usize,
+    pub flags: Flags,
+    pub zero_register: bool,
+    pub exit_code: Option<i32>,
+}
+
+impl StateView<'_> {
+    // Print the registers and stack the way the CLI reports a finished run
+    pub fn write_to(&self, sink: &mut dyn OutputSink) {
+        sink.write_out(&format!("{}", self));
+    }
+}
+
+impl From<StateView<'_>> for ProcessingUnitState {
+    fn from(view: StateView<'_>) -> Self {
+        ProcessingUnitState {
+            registers: view.registers.to_vec(),
+            stack: view.stack.to_vec(),
+            stack_pointer: view.stack_pointer,
+            flags: view.flags,
+            zero_register: view.zero_register,
+            exit_code: view.exit_code,
+        }
+    }
+}
+
+impl fmt::Display for StateView<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Registers: {:?}", self.registers)?;
+        if self.zero_register {
+            f.write_str(" (R0 is hardwired to 0)")?;
+        }
+        writeln!(f)?;
+        writeln!(f, "Stack: {:?}", self.stack)?;
+        writeln!(f, "Stack pointer: {}", self.stack_pointer)?;
+        writeln!(f, "Flags: {}", self.flags)
+    }
+}
+
+impl ProcessingUnit {
+    // Function to initialize the processing unit
+    pub fn initialize(num_registers: usize, memory_size: usize) -> Self {
+        Self::with_stack(num_registers, memory_size, memory_size.saturating_sub(1))
+    }
+
+    // Initialize a processing unit whose stack holds at most `stack_size` values
+    pub(crate) fn with_stack(num_registers: usize, memory_size: usize, stack_size: usize) -> Self {
+        let seed = default_seed();
+        ProcessingUnit {
+            registers: vec![0; num_registers],
+            memory: vec![0; memory_size],
+            stack_pointer: memory_size.saturating_sub(1), // Initialize stack pointer to the top of the memory
+            stack_limit: memory_size.saturating_sub(1).saturating_sub(stack_size),
+            frame_pointer: memory_size,
+            flags: Flags::default(),
+            max_instructions: usize::MAX,
+            trap_overflow: false,
+            zero_register: false,
+            break_mode: BreakMode::default(),
+            underflow_policy: UnderflowPolicy::default(),
+            instruction_pointer: 0,
+            instruction_count: 0,
+            exit_code: None,
+            seed,
+            rng: Rng::new(seed),
+            output: BufferedSink::default(),
+            input: Input::default(),
+            syscalls: default_syscalls(),
+        }
+    }
+
+    pub(crate) fn set_max_instructions(&mut self, max_instructions: usize) {
+        self.max_instructions = max_instructions;
+    }
+
+    // Make arithmetic that overflows fault with `ArithmeticOverflow` instead of wrapping
+    pub fn set_trap_overflow(&mut self, trap: bool) {
+        self.trap_overflow = trap;
+    }
+
+    // Zero registers and memory and empty the stack, reusing the existing allocations
+    pub fn reset(&mut self) {
+        self.memory.fill(0);
+        self.reset_registers_only();
+    }
+
+    // Like `reset`, but memory keeps its contents for the next program
+    pub fn reset_registers_only(&mut self) {
+        self.registers.fill(0);
+        self.stack_pointer = self.memory.len().saturating_sub(1);
+        self.frame_pointer = self.memory.len();
+        self.flags = Flags::default();
+        self.reset_execution();
+    }
+
+    // Start executing from the first instruction again with a zero instruction count
+    pub fn reset_execution(&mut self) {
+        self.instruction_pointer = 0;
+        self.instruction_count = 0;
+        self.exit_code = None;
+    }
+
+    // ++++++++++++++++++++++++++++++ Accessors ++++++++++++++++++++++++++++++ //
+    pub fn registers(&self) -> &[i32] {
+        &self.registers
+    }
+
+    pub fn memory(&self) -> &[i32] {
+        &self.memory
+    }
+
+    // Cheap view of the registers and stack
+    pub fn state(&self) -> StateView<'_> {
+        StateView {
+            registers: &self.registers,
+            stack: self.stack(),
+            stack_pointer: self.stack_pointer,
+            flags: self.flags,
+            zero_register: self.zero_register,
+            exit_code: self.exit_code,
+        }
+    }
+
+    // Values currently on the stack, most recently pushed first
+    pub fn stack(&self) -> &[i32] {
+        self.memory.get(self.stack_pointer + 1..).unwrap_or(&[])
+    }
+
+    // Condition flags left by the last CMP, TEST or arithmetic instruction
+    pub fn flags(&self) -> Flags {
+        self.flags
+    }
+
+    // Address of the cell where the current frame saved the frame pointer before it, with
+    // its locals below it and the return address above it. Past the end of memory when
+    // no ENTER is active.
+    pub fn frame_pointer(&self) -> usize {
+        self.frame_pointer
+    }
+
+    // Instructions one run may execute on this processing unit, `usize::MAX` if unlimited
+    pub fn max_instructions(&self) -> usize {
+        self.max_instructions
+    }
+
+    pub fn trap_overflow(&self) -> bool {
+        self.trap_overflow
+    }
+
+    // Hardwire R0 to 0, clearing it now and dropping every later write to it
+    pub fn set_zero_register(&mut self, zero: bool) {
+        self.zero_register = zero;
+        self.clear_zero_register();
+    }
+
+    pub fn zero_register(&self) -> bool {
+        self.zero_register
+    }
+
+    // Restart the numbers of RAND and RANDR from `seed`, so a run can be repeated exactly.
+    // A machine starts from a seed of its own otherwise.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.rng = Rng::new(seed);
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn set_break_mode(&mut self, mode: BreakMode) {
+        self.break_mode = mode;
+    }
+
+    pub fn break_mode(&self) -> BreakMode {
+        self.break_mode
+    }
+
+    pub fn set_underflow_policy(&mut self, policy: UnderflowPolicy) {
+        self.underflow_policy = policy;
+    }
+
+    pub fn underflow_policy(&self) -> UnderflowPolicy {
+        self.underflow_policy
+    }
+
+    fn clear_zero_register(&mut self) {
+        if self.zero_register {
+            if let Some(r0) = self.registers.first_mut() {
+                *r0 = 0;
+            }
+        }
+    }
+
+    // Sink receiving everything the machine prints
+    pub fn output(&mut self) -> &mut dyn OutputSink {
+        self.output.sink()
+    }
+
+    pub fn set_output(&mut self, sink: impl OutputSink + 'static) {
+        self.output = BufferedSink::new(Box::new(sink));
+    }
+
+    // Read program input from `reader` instead of standard input
+    #[cfg(feature = "std")]
+    pub fn set_input(&mut self, reader: impl std::io::BufRead + Send + 'static) {
+        self.input = Input::from_reader(reader);
+    }
+
+    // Run every SYS through `handler` instead of the standard syscalls
+    pub fn set_syscall_handler(&mut self, handler: impl SyscallHandler + 'static) {
+        self.syscalls = Box::new(handler);
+    }
+
+    // Run syscall `number` for the SYS at the instruction pointer
+    fn syscall(&mut self, number: i32) -> Result<SyscallEffect, MdpuError> {
+        let mut context = SyscallContext {
+            registers: &mut self.registers,
+            output: &mut self.output,
+            input: &mut self.input,
+            ip: self.instruction_pointer,
+            instruction_count: self.instruction_count,
+        };
+        self.syscalls.syscall(number, &mut context)
+    }
+
+    // Index of the next instruction to execute
+    pub fn instruction_pointer(&self) -> usize {
+        self.instruction_pointer
+    }
+
+    // Code the program halted with: 0 for a plain HALT, None while it has not halted or
+    // when it ran off the end of the program instead
+    pub fn exit_code(&self) -> Option<i32> {
+        self.exit_code
+    }
+
+    // Number of instructions executed so far
+    pub fn instruction_count(&self) -> usize {
+        self.instruction_count
+    }
+
+    // Execute the instruction at the instruction pointer
+    pub fn step(&mut self, program: &[Instruction]) -> StepOutcome {
+        let outcome = self.step_buffered(program);
+        self.output.flush();
+        outcome
+    }
+
+    // Like `step`, but leaving what the instruction printed in the output buffer
+    fn step_buffered(&mut self, program: &[Instruction]) -> StepOutcome {
+        let Some(instr) = program.get(self.instruction_pointer) else {
+            return StepOutcome::Halted; // Ran off the end of the program
+        };
+
+        let flow = execute_instruction(self, instr, program.len());
+        // An instruction may write R0 like any register, and the write is undone here
+        self.clear_zero_register();
+        match flow {
+            Ok(Flow::Next) => self.instruction_pointer += 1,
+            Ok(Flow::Jump(target)) => self.instruction_pointer = target,
+            Ok(Flow::Halt(code)) => {
+                self.exit_code = Some(code);
+                return StepOutcome::Halted;
+            }
+            Ok(Flow::Break) => {
+                self.instruction_pointer += 1;
+                self.instruction_count += 1;
+                return StepOutcome::Breakpoint;
+            }
+            Err(err) => return StepOutcome::Fault(err),
+        }
+
+        self.instruction_count += 1;
+        StepOutcome::Continue
+    }
+
+    pub fn get_register(&self, reg: usize) -> Result<i32, MdpuError> {
+        self.check_register_bounds(reg)?;
+        Ok(self.registers[reg])
+    }
+
+    pub fn set_register(&mut self, reg: usize, value: i32) -> Result<(), MdpuError> {
+        self.check_register_bounds(reg)?;
+        self.registers[reg] = value;
+        self.clear_zero_register();
+        Ok(())
+    }
+
+    pub fn read_memory(&self, addr: usize) -> Result<i32, MdpuError> {
+        self.check_memory_bounds(addr)?;
+        Ok(self.memory[addr])
+    }
+
+    pub fn write_memory(&mut self, addr: usize, value: i32) -> Result<(), MdpuError> {
+        self.check_memory_bounds(addr)?;
+        self.memory[addr] = value;
+        Ok(())
+    }
+
+    // Helper function to check register bounds
+    fn check_register_bounds(&self, reg: usize) -> Result<(), MdpuError> {
+        if reg >= self.registers.len() {
+            return Err(MdpuError::RegisterOutOfBounds {
+                reg,
+                ip: self.instruction_pointer,
+            });
+        }
+        Ok(())
+    }
+
+    // Memory address held in register `reg`, for the register-indirect opcodes
+    fn address_in(&self, reg: usize) -> Result<usize, MdpuError> {
+        self.check_register_bounds(reg)?;
+        let value = self.registers[reg];
+        usize::try_from(value)
+            .ok()
+            .filter(|&addr| addr < self.memory.len())
+            .ok_or(MdpuError::AddressRegisterOutOfBounds {
+                reg,
+                value,
+                ip: self.instruction_pointer,
+            })
+    }
+
+    // Memory address `offset` cells from the one held in register `reg`, computed without
+    // wrapping
+    fn offset_address(&self, reg: usize, offset: i32) -> Result<usize, MdpuError> {
+        self.check_register_bounds(reg)?;
+        let addr = i64::from(self.registers[reg]) + i64::from(offset);
+        usize::try_from(addr)
+            .ok()
+            .filter(|&addr| addr < self.memory.len())
+            .ok_or(MdpuError::OffsetAddressOutOfBounds {
+                reg,
+                addr,
+                ip: self.instruction_pointer,
+            })
+    }
+
+    // Instruction address held in register `reg`, which must be inside a program of `len`
+    // instructions
+    fn jump_address(&self, reg: usize, len: usize) -> Result<usize, MdpuError> {
+        self.check_register_bounds(reg)?;
+        let value = self.registers[reg];
+        usize::try_from(value)
+            .ok()
+            .filter(|&addr| addr < len)
+            .ok_or(MdpuError::JumpOutOfBounds {
+                reg,
+                value,
+                len,
+                ip: self.instruction_pointer,
+            })
+    }
+
+    // Instruction address a jump target names. A relative one counts from the next
+    // instruction and may land just past the end of a program of `len` instructions,
+    // halting it like an absolute jump there.
+    fn jump_target(&self, addr: AddrOperand, len: usize) -> Result<usize, MdpuError> {
+        match addr {
+            AddrOperand::Absolute(target) => Ok(target),
+            AddrOperand::Relative(offset) => relative_target(self.instruction_pointer, offset)
+                .filter(|&target| target <= len)
+                .ok_or(MdpuError::BranchOutOfBounds {
+                    offset,
+                    ip: self.instruction_pointer,
+                }),
+        }
+    }
+
+    // Memory address, bit field width or register held in addr, which only a jump may hold
+    // relative
+    fn absolute(&self, addr: AddrOperand) -> Result<usize, MdpuError> {
+        match addr {
+            AddrOperand::Absolute(addr) => Ok(addr),
+            AddrOperand::Relative(offset) => Err(MdpuError::RelativeAddress {
+                offset,
+                ip: self.instruction_pointer,
+            }),
+        }
+    }
+
+    // Helper function to check memory bounds
+    fn check_memory_bounds(&self, addr: usize) -> Result<(), MdpuError> {
+        if addr >= self.memory.len() {
+            return Err(MdpuError::MemoryOutOfBounds {
+                addr,
+                ip: self.instruction_pointer,
+            });
+        }
+        Ok(())
+    }
+
+    // Print the characters from `addr` up to the 0 that ends them, for PRINTS and PRINTSR.
+    // A string that runs to the end of memory faults without printing anything.
+    fn print_string(&mut self, addr: usize) -> Result<(), MdpuError> {
+        self.check_memory_bounds(addr)?;
+        let cells = &self.memory[addr..];
+        let Some(len) = cells.iter().position(|&code| code == 0) else {
+            return Err(MdpuError::UnterminatedString {
+                addr,
+                ip: self.instruction_pointer,
+            });
+        };
+        let text: String = cells[..len].iter().map(|&code| character(code)).collect();
+        self.output.write_out(&text);
+        Ok(())
+    }
+
+    // Next word of the input as a number for READI into `reg`. The end of the input and
+    // a word that is not a number both fault, each with an error of its own.
+    // Input for READI and READC. What the program printed is written out first, so a
+    // prompt shows before the machine waits for the answer.
+    fn input(&mut self) -> &mut Input {
+        self.output.flush();
+        &mut self.input
+    }
+
+    fn read_int(&mut self, reg: usize) -> Result<i32, MdpuError> {
+        let ip = self.instruction_pointer;
+        let word = self
+            .input()
+            .read_word()
+            .ok_or(MdpuError::EndOfInput { reg, ip })?;
+        word.parse()
+            .map_err(|_| MdpuError::InvalidInput { reg, word, ip })
+    }
+
+    // ++++++++++++++++++++++++++++++ Arithmetic operations ++++++++++++++++++++++++++++++ //
+    // reg3 = op(reg1, reg2) for DIV and MOD, which fault on a zero divisor
+    fn divide(
+        &mut self,
+        instr: &Instruction,
+        op: fn(i32, i32) -> (i32, Flags),
+    ) -> Result<(), MdpuError> {
+        self.check_register_bounds(instr.reg1)?;
+        self.check_register_bounds(instr.reg2)?;
+        self.check_register_bounds(instr.reg3)?;
+        if self.registers[instr.reg2] == 0 {
+            return Err(MdpuError::DivisionByZero {
+                reg: instr.reg2,
+                ip: self.instruction_pointer,
+            });
+        }
+        self.arithmetic_registers(instr, op)
+    }
+
+    // Values of the two sources of MULW or DIVMOD, reg3 and the register in addr, once
+    // both results are known to fit the register file
+    fn sources(&self, instr: &Instruction) -> Result<(i32, i32), MdpuError> {
+        let reg4 = self.absolute(instr.addr)?;
+        for reg in [instr.reg1, instr.reg2, instr.reg3, reg4] {
+            self.check_register_bounds(reg)?;
+        }
+        Ok((self.registers[instr.reg3], self.registers[reg4]))
+    }
+
+    // DIVMOD: the quotient of reg3 / reg4 to reg1 and the remainder to reg2, with the
+    // flags and overflow trapping of the quotient. The remainder is written second, so
+    // it is what a built DIVMOD R R leaves, though validation refuses one.
+    fn divmod(&mut self, instr: &Instruction) -> Result<(), MdpuError> {
+        let (a, b) = self.sources(instr)?;
+        if b == 0 {
+            return Err(MdpuError::DivisionByZero {
+                reg: self.absolute(instr.addr)?,
+                ip: self.instruction_pointer,
+            });
+        }
+        self.arithmetic(instr.opcode, instr.reg1, (a, Some(b)), Flags::div(a, b))?;
+        self.registers[instr.reg2] = a.wrapping_rem(b);
+        Ok(())
+    }
+
+    // Write the result of an arithmetic opcode on `operands` to `reg` and keep the flags
+    // it set. With overflow trapping on, a result that wrapped faults instead.
+    fn arithmetic(
+        &mut self,
+        opcode: Opcode,
+        reg: usize,
+        operands: (i32, Option<i32>),
+        (result, flags): (i32, Flags),
+    ) -> Result<(), MdpuError> {
+        self.check_overflow(opcode, operands, flags)?;
+        self.registers[reg] = result;
+        self.flags = flags;
+        Ok(())
+    }
+
+    // Fault if an arithmetic opcode wrapped while overflow trapping is on
+    fn check_overflow(
+        &self,
+        opcode: Opcode,
+        (lhs, rhs): (i32, Option<i32>),
+        flags: Flags,
+    ) -> Result<(), MdpuError> {
+        // The saturating opcodes clamp rather than wrap, and the legacy CMP only compares
+        let wraps = !matches!(
+            opcode,
+            Opcode::Adds
+                | Opcode::Subs
+                | Opcode::AddsImmediate
+                | Opcode::SubsImmediate
+                | Opcode::CmpWrite
+        );
+        if self.trap_overflow && wraps && flags.overflow() {
+            return Err(MdpuError::ArithmeticOverflow {
+                opcode,
+                lhs,
+                rhs,
+                ip: self.instruction_pointer,
+            });
+        }
+        Ok(())
+    }
+
+    // Fault unless reg holds `expected`, which came from the register `other` if any
+    fn assert_equal(
+        &self,
+        reg: usize,
+        other: Option<usize>,
+        expected: i32,
+    ) -> Result<(), MdpuError> {
+        let actual = self.registers[reg];
+        if actual != expected {
+            return Err(MdpuError::AssertionFailed {
+                reg,
+                other,
+                expected,
+                actual,
+                ip: self.instruction_pointer,
+            });
+        }
+        Ok(())
+    }
+
+    // memory[addr] = op(memory[addr]) for INCM, DECM and their indirect forms, wrapping
+    // and setting the flags like INC and DEC
+    fn arithmetic_memory(
+        &mut self,
+        opcode: Opcode,
+        addr: usize,
+        op: fn(i32) -> (i32, Flags),
+    ) -> Result<(), MdpuError> {
+        self.check_memory_bounds(addr)?;
+        let value = self.memory[addr];
+        let (result, flags) = op(value);
+        self.check_overflow(opcode, (value, None), flags)?;
+        self.memory[addr] = result;
+        self.flags = flags;
+        Ok(())
+    }
+
+    // reg3 = op(reg1, reg2), for the arithmetic opcodes that set the flags
+    fn arithmetic_registers(
+        &mut self,
+        instr: &Instruction,
+        op: fn(i32, i32) -> (i32, Flags),
+    ) -> Result<(), MdpuError> {
+        self.check_register_bounds(instr.reg1)?;
+        self.check_register_bounds(instr.reg2)?;
+        self.check_register_bounds(instr.reg3)?;
+        let (a, b) = (self.registers[instr.reg1], self.registers[instr.reg2]);
+        self.arithmetic(instr.opcode, instr.reg3, (a, Some(b)), op(a, b))
+    }
+
+    // Like `apply_immediate`, for the arithmetic opcodes that set the flags
+    fn arithmetic_immediate(
+        &mut self,
+        instr: &Instruction,
+        op: fn(i32, i32) -> (i32, Flags),
+    ) -> Result<(), MdpuError> {
+        self.check_register_bounds(instr.reg1)?;
+        self.check_register_bounds(instr.reg2)?;
+        let (a, b) = (self.registers[instr.reg2], instr.immediate);
+        self.arithmetic(instr.opcode, instr.reg1, (a, Some(b)), op(a, b))
+    }
+
+    // dest = op(src), for NEG, ABS, INC and DEC
+    fn arithmetic_unary(
+        &mut self,
+        instr: &Instruction,
+        (src, dest): (usize, usize),
+        op: fn(i32) -> (i32, Flags),
+    ) -> Result<(), MdpuError> {
+        self.check_register_bounds(src)?;
+        self.check_register_bounds(dest)?;
+        let a = self.registers[src];
+        self.arithmetic(instr.opcode, dest, (a, None), op(a))
+    }
+
+    // reg1 = op(reg2, immediate), for the opcodes with an immediate second source
+    fn apply_immediate(
+        &mut self,
+        instr: &Instruction,
+        op: fn(i32, i32) -> i32,
+    ) -> Result<(), MdpuError> {
+        self.check_register_bounds(instr.reg1)?;
+        self.check_register_bounds(instr.reg2)?;
+        self.registers[instr.reg1] = op(self.registers[instr.reg2], instr.immediate);
+        Ok(())
+    }
+
+    // reg3 = op(reg1, reg2), for the opcodes with two register sources that leave the flags
+    fn apply_registers(
+        &mut self,
+        instr: &Instruction,
+        op: fn(i32, i32) -> i32,
+    ) -> Result<(), MdpuError> {
+        self.check_register_bounds(instr.reg1)?;
+        self.check_register_bounds(instr.reg2)?;
+        self.check_register_bounds(instr.reg3)?;
+        self.registers[instr.reg3] = op(self.registers[instr.reg1], self.registers[instr.reg2]);
+        Ok(())
+    }
+
+    // reg3 = reg1 rotated by reg2, like SHL and SHR
+    fn rotate(&mut self, instr: &Instruction, op: fn(u32, u32) -> u32) -> Result<(), MdpuError> {
+        self.check_register_bounds(instr.reg1)?;
+        self.check_register_bounds(instr.reg2)?;
+        self.check_register_bounds(instr.reg3)?;
+        self.registers[instr.reg3] =
+            rotate(self.registers[instr.reg1], self.registers[instr.reg2], op);
+        Ok(())
+    }
+
+    // ++++++++++++++++++++++++++++++ Memory operations ++++++++++++++++++++++++++++++ //
+    fn store(&mut self, reg: usize, addr: usize) -> Result<(), MdpuError> {
+        self.check_register_bounds(reg)?;
+        self.check_memory_bounds(addr)?;
+        self.memory[addr] = self.registers[reg];
+        Ok(())
+    }
+
+    fn load(&mut self, addr: usize, reg: usize) -> Result<(), MdpuError> {
+        self.check_register_bounds(reg)?;
+        self.check_memory_bounds(addr)?;
+        self.registers[reg] = self.memory[addr];
+        Ok(())
+    }
+
+    // ++++++++++++++++++++++++++++++ Stack operations ++++++++++++++++++++++++++++++ //
+    fn push(&mut self, reg: usize) -> Result<(), MdpuError> {
+        self.check_register_bounds(reg)?;
+        if self.stack_pointer <= self.stack_limit {
+            return Err(MdpuError::StackOverflow {
+                reg,
+                ip: self.instruction_pointer,
+            });
+        }
+        self.memory[self.stack_pointer] = self.registers[reg];
+        self.stack_pointer -= 1;
+        Ok(())
+    }
+
+    // Move the stack pointer to the address in `reg` for WRSP. It has to stay between the
+    // lowest cell the stack may use and the top of memory, where it starts.
+    fn set_stack_pointer(&mut self, reg: usize) -> Result<(), MdpuError> {
+        self.check_register_bounds(reg)?;
+        let value = self.registers[reg];
+        let top = self.memory.len().saturating_sub(1);
+        self.stack_pointer = usize::try_from(value)
+            .ok()
+            .filter(|addr| (self.stack_limit..=top).contains(addr))
+            .ok_or(MdpuError::StackPointerOutOfBounds {
+                reg,
+                value,
+                ip: self.instruction_pointer,
+            })?;
+        Ok(())
+    }
+
+    // Base of the stack, the top of memory, where the stack pointer sits when it is empty
+    fn stack_base(&self) -> usize {
+        self.memory.len().saturating_sub(1)
+    }
+
+    // Values pushed above the base of the stack and not popped yet
+    fn stack_depth(&self) -> usize {
+        self.stack_base().saturating_sub(self.stack_pointer)
+    }
+
+    // Fault of `opcode` needing `needed` values from the stack, which holds fewer
+    fn underflow(&self, opcode: Opcode, needed: usize) -> MdpuError {
+        MdpuError::StackUnderflow {
+            opcode,
+            needed,
+            depth: self.stack_depth(),
+            base: self.stack_base(),
+            ip: self.instruction_pointer,
+        }
+    }
+
+    // Pop into `reg`, or follow the underflow policy when the stack is empty
+    fn pop(&mut self, reg: usize) -> Result<(), MdpuError> {
+        self.check_register_bounds(reg)?;
+        if self.stack_depth() == 0 {
+            if self.underflow_policy == UnderflowPolicy::ReturnZero {
+                self.registers[reg] = 0;
+                return Ok(());
+            }
+            return Err(self.underflow(Opcode::Pop, 1));
+        }
+        self.stack_pointer += 1;
+        self.registers[reg] = self.memory[self.stack_pointer];
+        Ok(())
+    }
+
+    // Copy the value `below` cells under the top of the stack into `reg`, leaving the
+    // stack as it is, or follow the underflow policy when the stack is not that deep
+    fn peek(&mut self, reg: usize, below: i32) -> Result<(), MdpuError> {
+        self.check_register_bounds(reg)?;
+        // A negative count needs more than any stack holds
+        let needed = usize::try_from(below).map_or(usize::MAX, |below| below + 1);
+        if needed > self.stack_depth() {
+            if self.underflow_policy == UnderflowPolicy::ReturnZero {
+                self.registers[reg] = 0;
+                return Ok(());
+            }
+            return Err(self.underflow(Opcode::Peek, needed));
+        }
+        self.registers[reg] = self.memory[self.stack_pointer + needed];
+        Ok(())
+    }
+
+    // Pop `count` values without keeping them. Under `UnderflowPolicy::ReturnZero` a
+    // stack holding fewer is emptied.
+    fn drop_values(&mut self, count: i32) -> Result<(), MdpuError> {
+        let needed = usize::try_from(count).unwrap_or(usize::MAX);
+        if needed > self.stack_depth() {
+            if self.underflow_policy == UnderflowPolicy::ReturnZero {
+                self.stack_pointer = self.stack_base();
+                return Ok(());
+            }
+            return Err(self.underflow(Opcode::Drop, needed));
+        }
+        self.stack_pointer += needed;
+        Ok(())
+    }
+
+    // Push `values` in order, failing before pushing any of them unless all of them fit
+    fn push_block(
+        &mut self,
+        opcode: Opcode,
+        values: impl ExactSizeIterator<Item = i32>,
+    ) -> Result<(), MdpuError> {
+        let free = self.stack_pointer.saturating_sub(self.stack_limit);
+        if values.len() > free {
+            return Err(MdpuError::StackFull {
+                opcode,
+                needed: values.len(),
+                free,
+                ip: self.instruction_pointer,
+            });
+        }
+        for value in values {
+            self.memory[self.stack_pointer] = value;
+            self.stack_pointer -= 1;
+        }
+        Ok(())
+    }
+
+    // Pop every register pushed by PUSHA, failing before popping any of them unless the
+    // stack holds enough values
+    fn pop_all(&mut self) -> Result<(), MdpuError> {
+        let needed = self.registers.len();
+        let held = self
+            .memory
+            .len()
+            .saturating_sub(1)
+            .saturating_sub(self.stack_pointer);
+        if needed > held {
+            return Err(MdpuError::StackShort {
+                opcode: Opcode::PopAll,
+                needed,
+                held,
+                ip: self.instruction_pointer,
+            });
+        }
+        for reg in (0..needed).rev() {
+            self.stack_pointer += 1;
+            self.registers[reg] = self.memory[self.stack_pointer];
+        }
+        Ok(())
+    }
+
+    // Push the address of the instruction after the current one, on the stack PUSH uses
+    fn call(&mut self) -> Result<(), MdpuError> {
+        let ip = self.instruction_pointer;
+        if self.stack_pointer <= self.stack_limit {
+            return Err(MdpuError::CallStackOverflow { ip });
+        }
+        // Stack cells are i32; an address past i32::MAX returns past the end and halts
+        self.memory[self.stack_pointer] = i32::try_from(ip + 1).unwrap_or(i32::MAX);
+        self.stack_pointer -= 1;
+        Ok(())
+    }
+
+    // Pop the address pushed by the matching CALL. A value PUSH put there instead is used
+    // as it is, and a negative one returns past the end of the program. An empty stack
+    // faults under either underflow policy, as returning to a 0 would restart the program.
+    fn ret(&mut self) -> Result<usize, MdpuError> {
+        if self.stack_depth() == 0 {
+            return Err(self.underflow(Opcode::Ret, 1));
+        }
+        self.stack_pointer += 1;
+        Ok(usize::try_from(self.memory[self.stack_pointer]).unwrap_or(usize::MAX))
+    }
+
+    // Push the frame pointer, point it at the saved value and reserve `slots` cells below
+    // it for locals, all or nothing
+    fn enter(&mut self, slots: i32) -> Result<(), MdpuError> {
+        let ip = self.instruction_pointer;
+        // The saved frame pointer and every local must sit above the stack limit
+        let fits = usize::try_from(slots)
+            .ok()
+            .and_then(|slots| self.stack_pointer.checked_sub(slots))
+            .is_some_and(|lowest| lowest > self.stack_limit);
+        if !fits {
+            return Err(MdpuError::FrameOverflow { slots, ip });
+        }
+        let saved = self.stack_pointer;
+        self.memory[saved] = i32::try_from(self.frame_pointer).unwrap_or(i32::MAX);
+        self.frame_pointer = saved;
+        self.stack_pointer = saved - 1 - slots as usize;
+        Ok(())
+    }
+
+    // Drop the current frame's locals and restore the frame pointer saved by its ENTER
+    fn leave(&mut self) -> Result<(), MdpuError> {
+        let Some(&saved) = self.memory.get(self.frame_pointer) else {
+            return Err(MdpuError::NoFrame {
+                ip: self.instruction_pointer,
+            });
+        };
+        self.stack_pointer = self.frame_pointer;
+        self.frame_pointer = usize::try_from(saved).unwrap_or(usize::MAX);
+        Ok(())
+    }
+
+    // Memory address `offset` cells from the frame pointer
+    fn frame_address(&self, offset: i32) -> Result<usize, MdpuError> {
+        self.frame_pointer
+            .checked_add_signed(offset as isize)
+            .filter(|&addr| addr < self.memory.len())
+            .ok_or(MdpuError::FrameOutOfBounds {
+                offset,
+                ip: self.instruction_pointer,
+            })
+    }
+
+    fn mov(&mut self, reg1: usize, reg2: usize) -> Result<(), MdpuError> {
+        self.check_register_bounds(reg1)?;
+        self.check_register_bounds(reg2)?;
+        self.registers[reg1] = self.registers[reg2];
+        Ok(())
+    }
+}
+
+// Function to run the program and return the state, continuing from the current instruction pointer.
+// It stops with `InstructionLimitExceeded` once the instruction count reaches `mic`, or once
+// the run has executed the machine's own `max_instructions`, whichever comes first.
+pub fn run<'a>(
+    pu: &'a mut ProcessingUnit,
+    program: &[Instruction],
+    mic: usize,
+) -> Result<StateView<'a>, MdpuError> {
+    run_with_hook(pu, program, mic, &mut NoHook)
+}
+
+// Run the program like `run`, calling the hook around every executed instruction
+pub fn run_with_hook<'a>(
+    pu: &'a mut ProcessingUnit,
+    program: &[Instruction],
+    mic: usize,
+    hook: &mut impl ExecutionHook,
+) -> Result<StateView<'a>, MdpuError> {
+    execute_program(pu, program, mic, hook)?;
+    Ok(pu.state())
+}
+
+// Run the program like `run`, stopping early once `stop` is set from another thread.
+// A cancelled run still returns the state reached so far and can be resumed by running again.
+pub fn run_cancellable<'a>(
+    pu: &'a mut ProcessingUnit,
+    program: &[Instruction],
+    mic: usize,
+    stop: &AtomicBool,
+) -> Result<(StateView<'a>, HaltReason), MdpuError> {
+    let mut hook = CancelHook::new(stop, CANCEL_CHECK_INTERVAL);
+    execute_program(pu, program, mic, &mut hook)?;
+    let reason = if hook.cancelled {
+        HaltReason::Cancelled
+    } else {
+        HaltReason::Halted
+    };
+    Ok((pu.state(), reason))
+}
+
+// Where execution continues after an instruction
+enum Flow {
+    Next,
+    Jump(usize),
+    Halt(i32), // With this exit code
+    Break,     // Continue with the next instruction, but stop stepping first
+}
+
+// ++++++++++++++++++++++++++++++ Program execution ++++++++++++++++++++++++++++++ //
+// Step through the program from the current instruction pointer until it halts
+fn execute_program(
+    pu: &mut ProcessingUnit,
+    program: &[Instruction],
+    mic: usize,
+    hook: &mut impl ExecutionHook,
+) -> Result<(), MdpuError> {
+    let mic = mic.min(pu.instruction_count.saturating_add(pu.max_instructions));
+    let result = execute_steps(pu, program, mic, hook);
+    // Whatever the program printed reaches the sink before the run returns, even on a fault
+    pu.output.flush();
+    result
+}
+
+fn execute_steps(
+    pu: &mut ProcessingUnit,
+    program: &[Instruction],
+    mic: usize,
+    hook: &mut impl ExecutionHook,
+) -> Result<(), MdpuError> {
+    while pu.instruction_pointer < program.len() {
+        if pu.instruction_count >= mic {
+            return Err(MdpuError::InstructionLimitExceeded { limit: mic });
+        }
+
+        let ip = pu.instruction_pointer;
+        let instr = &program[ip];
+        if hook.before(ip, instr, pu) == HookControl::Stop {
+            break;
+        }
+
+        let outcome = pu.step_buffered(program);
+        if let StepOutcome::Fault(err) = outcome {
+            return Err(err);
+        }
+        hook.after(ip, instr, pu);
+        match outcome {
+            StepOutcome::Halted => break, // Stop execution
+            StepOutcome::Breakpoint => {
+                let dump = format!("Breakpoint at instruction {}\n{}", ip, pu.state());
+                pu.output.write_out(&dump);
+                if pu.break_mode == BreakMode::Stop {
+                    break;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+// Rotate the bits of `value` by `count` modulo 32, a negative count being a large one
+fn rotate(value: i32, count: i32, op: fn(u32, u32) -> u32) -> i32 {
+    op(value as u32, count as u32 % 32) as i32
+}
+
+// Mask of the low `width` bits. Bit fields are worked on as 64 bits, so one that runs
+// past bit 31 in a program that was not validated reads zeros there instead of panicking.
+fn field_mask(width: usize) -> u64 {
+    (1u64 << width.min(32)) - 1
+}
+
+// Mask of bit `index`, which wraps to 0..=31 like a shift count
+fn bit(index: i32) -> i32 {
+    1i32.wrapping_shl(index as u32)
+}
+
+// Execute a single instruction and report where to continue
+// in a program of `len` instructions
+fn execute_instruction(
+    pu: &mut ProcessingUnit,
+    instr: &Instruction,
+    len: usize,
+) -> Result<Flow, MdpuError> {
+    match instr.opcode {
+        Opcode::Add => pu.arithmetic_registers(instr, Flags::add)?,
+        Opcode::Sub => pu.arithmetic_registers(instr, Flags::sub)?,
+        Opcode::Mul => pu.arithmetic_registers(instr, Flags::mul)?,
+        Opcode::Div => pu.divide(instr, Flags::div)?,
+        Opcode::Store => pu.store(instr.reg1, pu.absolute(instr.addr)?)?,
+        Opcode::Load => pu.load(pu.absolute(instr.addr)?, instr.reg1)?,
+        Opcode::LoadImmediate => {
+            pu.check_register_bounds(instr.reg1)?;
+            pu.registers[instr.reg1] = instr.immediate;
+        }
+        Opcode::Push => pu.push(instr.reg1)?,
+        Opcode::Pop => pu.pop(instr.reg1)?,
+        Opcode::PushImmediate => pu.push_block(instr.opcode, [instr.immediate].into_iter())?,
+        Opcode::PushAll => {
+            // Taken out of the unit while the block borrows them, then put back
+            let registers = core::mem::take(&mut pu.registers);
+            let pushed = pu.push_block(instr.opcode, registers.iter().copied());
+            pu.registers = registers;
+            pushed?
+        }
+        Opcode::PopAll => pu.pop_all()?,
+        Opcode::Peek => pu.peek(instr.reg1, instr.immediate)?,
+        Opcode::Drop => pu.drop_values(instr.immediate)?,
+        Opcode::Jmp => return pu.jump_target(instr.addr, len).map(Flow::Jump),
+        Opcode::Jz => {
+            pu.check_register_bounds(instr.reg1)?;
+            if pu.registers[instr.reg1] == 0 {
+                return pu.jump_target(instr.addr, len).map(Flow::Jump);
+            }
+        }
+        Opcode::Jnz => {
+            pu.check_register_bounds(instr.reg1)?;
+            if pu.registers[instr.reg1] != 0 {
+                return pu.jump_target(instr.addr, len).map(Flow::Jump);
+            }
+        }
+        Opcode::Mov => pu.mov(instr.reg1, instr.reg2)?,
+        Opcode::Swap => {
+            pu.check_register_bounds(instr.reg1)?;
+            pu.check_register_bounds(instr.reg2)?;
+            pu.registers.swap(instr.reg1, instr.reg2);
+        }
+        Opcode::Mulh | Opcode::Mulhu => {
+            pu.check_register_bounds(instr.reg1)?;
+            pu.check_register_bounds(instr.reg2)?;
+            pu.check_register_bounds(instr.reg3)?;
+            let (a, b) = (pu.registers[instr.reg1], pu.registers[instr.reg2]);
+            let product = if instr.opcode == Opcode::Mulh {
+                i64::from(a) * i64::from(b)
+            } else {
+                (u64::from(a as u32) * u64::from(b as u32)) as i64
+            };
+            pu.registers[instr.reg3] = (product >> 32) as i32;
+        }
+        Opcode::Mulw => {
+            let (a, b) = pu.sources(instr)?;
+            let product = i64::from(a) * i64::from(b);
+            // The low half goes second, so a built MULW R R, which validation refuses,
+            // keeps it
+            pu.registers[instr.reg1] = (product >> 32) as i32;
+            pu.registers[instr.reg2] = product as i32;
+        }
+        Opcode::Cmovz | Opcode::Cmovnz => {
+            pu.check_register_bounds(instr.reg1)?;
+            pu.check_register_bounds(instr.reg2)?;
+            pu.check_register_bounds(instr.reg3)?;
+            let zero = pu.registers[instr.reg3] == 0;
+            if zero == (instr.opcode == Opcode::Cmovz) {
+                pu.registers[instr.reg1] = pu.registers[instr.reg2];
+            }
+        }
+        Opcode::Je => {
+            pu.check_register_bounds(instr.reg1)?;
+            pu.check_register_bounds(instr.reg2)?;
+            if pu.registers[instr.reg1] == pu.registers[instr.reg2] {
+                return pu.jump_target(instr.addr, len).map(Flow::Jump);
+            }
+        }
+        Opcode::Jne => {
+            pu.check_register_bounds(instr.reg1)?;
+            pu.check_register_bounds(instr.reg2)?;
+            if pu.registers[instr.reg1] != pu.registers[instr.reg2] {
+                return pu.jump_target(instr.addr, len).map(Flow::Jump);
+            }
+        }
+        Opcode::And => {
+            pu.check_register_bounds(instr.reg1)?;
+            pu.check_register_bounds(instr.reg2)?;
+            pu.check_register_bounds(instr.reg3)?;
+            pu.registers[instr.reg3] = pu.registers[instr.reg1] & pu.registers[instr.reg2];
+        }
+        Opcode::Or => {
+            pu.check_register_bounds(instr.reg1)?;
+            pu.check_register_bounds(instr.reg2)?;
+            pu.check_register_bounds(instr.reg3)?;
+            pu.registers[instr.reg3] = pu.registers[instr.reg1] | pu.registers[instr.reg2];
+        }
+        Opcode::Xor => {
+            pu.check_register_bounds(instr.reg1)?;
+            pu.check_register_bounds(instr.reg2)?;
+            pu.check_register_bounds(instr.reg3)?;
+            pu.registers[instr.reg3] = pu.registers[instr.reg1] ^ pu.registers[instr.reg2];
+        }
+        Opcode::Not => {
+            pu.check_register_bounds(instr.reg1)?;
+            pu.check_register_bounds(instr.reg2)?;
+            pu.registers[instr.reg2] = !pu.registers[instr.reg1];
+        }
+        Opcode::Sextb | Opcode::Sexth | Opcode::Zextb | Opcode::Zexth => {
+            pu.check_register_bounds(instr.reg1)?;
+            pu.check_register_bounds(instr.reg2)?;
+            let value = pu.registers[instr.reg1];
+            pu.registers[instr.reg2] = match instr.opcode {
+                Opcode::Sextb => i32::from(value as i8),
+                Opcode::Sexth => i32::from(value as i16),
+                Opcode::Zextb => i32::from(value as u8),
+                _ => i32::from(value as u16),
+            };
+        }
+        Opcode::Popcnt | Opcode::Clz | Opcode::Ctz => {
+            pu.check_register_bounds(instr.reg1)?;
+            pu.check_register_bounds(instr.reg2)?;
+            // Counted on the bit pattern, so -1 has 32 set bits and no zeros
+            let bits = pu.registers[instr.reg1] as u32;
+            pu.registers[instr.reg2] = match instr.opcode {
+                Opcode::Popcnt => bits.count_ones(),
+                Opcode::Clz => bits.leading_zeros(),
+                _ => bits.trailing_zeros(),
+            } as i32;
+        }
+        Opcode::Bset => pu.apply_registers(instr, |a, b| a | bit(b))?,
+        Opcode::Bclr => pu.apply_registers(instr, |a, b| a & !bit(b))?,
+        Opcode::Btgl => pu.apply_registers(instr, |a, b| a ^ bit(b))?,
+        Opcode::Btst => {
+            pu.apply_registers(instr, |a, b| i32::from(a & bit(b) != 0))?;
+            pu.flags = Flags::of(pu.registers[instr.reg3], false, false);
+        }
+        Opcode::BsetImmediate => pu.apply_immediate(instr, |a, b| a | bit(b))?,
+        Opcode::BclrImmediate => pu.apply_immediate(instr, |a, b| a & !bit(b))?,
+        Opcode::BtglImmediate => pu.apply_immediate(instr, |a, b| a ^ bit(b))?,
+        Opcode::Extr | Opcode::Extrs | Opcode::Insr => {
+            pu.check_register_bounds(instr.reg1)?;
+            pu.check_register_bounds(instr.reg2)?;
+            let pos = (instr.immediate as u32).min(32);
+            let width = pu.absolute(instr.addr)?.min(32);
+            let source = u64::from(pu.registers[instr.reg2] as u32);
+            pu.registers[instr.reg1] = match instr.opcode {
+                Opcode::Extr => ((source >> pos) & field_mask(width)) as i32,
+                // Moving the field to the top of 64 bits and back copies its highest bit
+                Opcode::Extrs if width > 0 => {
+                    ((((source >> pos) << (64 - width)) as i64) >> (64 - width)) as i32
+                }
+                Opcode::Extrs => 0,
+                _ => {
+                    let mask = field_mask(width) << pos;
+                    let dest = u64::from(pu.registers[instr.reg1] as u32);
+                    ((dest & !mask) | ((source << pos) & mask)) as i32
+                }
+            };
+        }
+        Opcode::Shl => {
+            pu.check_register_bounds(instr.reg1)?;
+            pu.check_register_bounds(instr.reg2)?;
+            pu.check_register_bounds(instr.reg3)?;
+            // Shift counts wrap to 0..=31
+            pu.registers[instr.reg3] =
+                pu.registers[instr.reg1].wrapping_shl(pu.registers[instr.reg2] as u32);
+        }
+        Opcode::Shr => {
+            pu.check_register_bounds(instr.reg1)?;
+            pu.check_register_bounds(instr.reg2)?;
+            pu.check_register_bounds(instr.reg3)?;
+            pu.registers[instr.reg3] =
+                pu.registers[instr.reg1].wrapping_shr(pu.registers[instr.reg2] as u32);
+        }
+        Opcode::ShrLogical => {
+            pu.check_register_bounds(instr.reg1)?;
+            pu.check_register_bounds(instr.reg2)?;
+            pu.check_register_bounds(instr.reg3)?;
+            // Shifting the bits as unsigned brings in zeros from the left
+            let bits = pu.registers[instr.reg1] as u32;
+            pu.registers[instr.reg3] = bits.wrapping_shr(pu.registers[instr.reg2] as u32) as i32;
+        }
+        Opcode::Rol => pu.rotate(instr, u32::rotate_left)?,
+        Opcode::Ror => pu.rotate(instr, u32::rotate_right)?,
+        Opcode::Cmp => {
+            pu.check_register_bounds(instr.reg1)?;
+            pu.check_register_bounds(instr.reg2)?;
+            pu.flags = Flags::sub(pu.registers[instr.reg1], pu.registers[instr.reg2]).1;
+        }
+        Opcode::Test => {
+            pu.check_register_bounds(instr.reg1)?;
+            pu.check_register_bounds(instr.reg2)?;
+            let result = pu.registers[instr.reg1] & pu.registers[instr.reg2];
+            pu.flags = Flags::of(result, false, false);
+        }
+        Opcode::CmpUnsigned => {
+            pu.check_register_bounds(instr.reg1)?;
+            pu.check_register_bounds(instr.reg2)?;
+            pu.flags = Flags::compare_unsigned(pu.registers[instr.reg1], pu.registers[instr.reg2]);
+        }
+        Opcode::Ja | Opcode::Jae | Opcode::Jb | Opcode::Jbe => {
+            let taken = match instr.opcode {
+                Opcode::Ja => pu.flags.above(),
+                Opcode::Jae => !pu.flags.below(),
+                Opcode::Jb => pu.flags.below(),
+                _ => !pu.flags.above(),
+            };
+            if taken {
+                return pu.jump_target(instr.addr, len).map(Flow::Jump);
+            }
+        }
+        Opcode::CmpWrite => pu.arithmetic_registers(instr, Flags::sub)?,
+        Opcode::TestWrite => {
+            pu.check_register_bounds(instr.reg1)?;
+            pu.check_register_bounds(instr.reg2)?;
+            pu.check_register_bounds(instr.reg3)?;
+            let result = pu.registers[instr.reg1] & pu.registers[instr.reg2];
+            pu.registers[instr.reg3] = result;
+            pu.flags = Flags::of(result, false, false);
+        }
+        Opcode::B => return pu.jump_target(instr.addr, len).map(Flow::Jump),
+        Opcode::Bz => {
+            pu.check_register_bounds(instr.reg1)?;
+            if pu.registers[instr.reg1] == 0 {
+                return pu.jump_target(instr.addr, len).map(Flow::Jump);
+            }
+        }
+        Opcode::Bnz => {
+            pu.check_register_bounds(instr.reg1)?;
+            if pu.registers[instr.reg1] != 0 {
+                return pu.jump_target(instr.addr, len).map(Flow::Jump);
+            }
+        }
+        Opcode::Loop => {
+            pu.check_register_bounds(instr.reg1)?;
+            pu.registers[instr.reg1] = pu.registers[instr.reg1].wrapping_sub(1);
+            if pu.registers[instr.reg1] != 0 {
+                return pu.jump_target(instr.addr, len).map(Flow::Jump);
+            }
+        }
+        Opcode::Br => return pu.jump_target(instr.addr, len).map(Flow::Jump),
+        Opcode::Brz => {
+            pu.check_register_bounds(instr.reg1)?;
+            if pu.registers[instr.reg1] == 0 {
+                return pu.jump_target(instr.addr, len).map(Flow::Jump);
+            }
+        }
+        Opcode::Brnz => {
+            pu.check_register_bounds(instr.reg1)?;
+            if pu.registers[instr.reg1] != 0 {
+                return pu.jump_target(instr.addr, len).map(Flow::Jump);
+            }
+        }
+        Opcode::Neg => {
+            pu.arithmetic_unary(instr, (instr.reg1, instr.reg2), |a| Flags::sub(0, a))?
+        }
+        Opcode::Abs => pu.arithmetic_unary(instr, (instr.reg1, instr.reg2), |a| {
+            let (result, overflow) = a.overflowing_abs();
+            (result, Flags::of(result, false, overflow))
+        })?,
+        Opcode::Mod => pu.divide(instr, Flags::rem)?,
+        Opcode::Divmod => pu.divmod(instr)?,
+        Opcode::IncMemory => {
+            pu.arithmetic_memory(instr.opcode, pu.absolute(instr.addr)?, |a| Flags::add(a, 1))?
+        }
+        Opcode::DecMemory => {
+            pu.arithmetic_memory(instr.opcode, pu.absolute(instr.addr)?, |a| Flags::sub(a, 1))?
+        }
+        Opcode::IncIndirect => {
+            let addr = pu.address_in(instr.reg1)?;
+            pu.arithmetic_memory(instr.opcode, addr, |a| Flags::add(a, 1))?
+        }
+        Opcode::DecIndirect => {
+            let addr = pu.address_in(instr.reg1)?;
+            pu.arithmetic_memory(instr.opcode, addr, |a| Flags::sub(a, 1))?
+        }
+        Opcode::Inc => {
+            pu.arithmetic_unary(instr, (instr.reg1, instr.reg1), |a| Flags::add(a, 1))?
+        }
+        Opcode::Dec => {
+            pu.arithmetic_unary(instr, (instr.reg1, instr.reg1), |a| Flags::sub(a, 1))?
+        }
+        Opcode::Call => {
+            let target = pu.jump_target(instr.addr, len)?;
+            pu.call()?;
+            return Ok(Flow::Jump(target));
+        }
+        Opcode::Ret => return pu.ret().map(Flow::Jump),
+        Opcode::JumpRegister => return pu.jump_address(instr.reg1, len).map(Flow::Jump),
+        Opcode::Adds => pu.arithmetic_registers(instr, Flags::add_saturating)?,
+        Opcode::Subs => pu.arithmetic_registers(instr, Flags::sub_saturating)?,
+        Opcode::AddsImmediate => pu.arithmetic_immediate(instr, Flags::add_saturating)?,
+        Opcode::SubsImmediate => pu.arithmetic_immediate(instr, Flags::sub_saturating)?,
+        Opcode::AddImmediate => pu.arithmetic_immediate(instr, Flags::add)?,
+        Opcode::SubImmediate => pu.arithmetic_immediate(instr, Flags::sub)?,
+        Opcode::MulImmediate => pu.arithmetic_immediate(instr, Flags::mul)?,
+        Opcode::DivImmediate => {
+            if instr.immediate == 0 {
+                return Err(MdpuError::DivisionByZeroImmediate {
+                    ip: pu.instruction_pointer,
+                });
+            }
+            pu.arithmetic_immediate(instr, Flags::div)?
+        }
+        Opcode::AndImmediate => pu.apply_immediate(instr, |a, b| a & b)?,
+        Opcode::OrImmediate => pu.apply_immediate(instr, |a, b| a | b)?,
+        Opcode::XorImmediate => pu.apply_immediate(instr, |a, b| a ^ b)?,
+        // Shift counts wrap to 0..=31 like SHL and SHR, though the assembler only accepts
+        // counts in that range
+        Opcode::ShlImmediate => pu.apply_immediate(instr, |a, b| a.wrapping_shl(b as u32))?,
+        Opcode::ShrImmediate => pu.apply_immediate(instr, |a, b| a.wrapping_shr(b as u32))?,
+        Opcode::RolImmediate => pu.apply_immediate(instr, |a, b| rotate(a, b, u32::rotate_left))?,
+        Opcode::RorImmediate => {
+            pu.apply_immediate(instr, |a, b| rotate(a, b, u32::rotate_right))?
+        }
+        Opcode::LoadIndirect => {
+            pu.check_register_bounds(instr.reg1)?;
+            let addr = pu.address_in(instr.reg2)?;
+            pu.registers[instr.reg1] = pu.memory[addr];
+        }
+        Opcode::StoreIndirect => {
+            pu.check_register_bounds(instr.reg1)?;
+            let addr = pu.address_in(instr.reg2)?;
+            pu.memory[addr] = pu.registers[instr.reg1];
+        }
+        Opcode::LoadOffset => {
+            pu.check_register_bounds(instr.reg1)?;
+            let addr = pu.offset_address(instr.reg2, instr.immediate)?;
+            pu.registers[instr.reg1] = pu.memory[addr];
+        }
+        Opcode::StoreOffset => {
+            pu.check_register_bounds(instr.reg1)?;
+            let addr = pu.offset_address(instr.reg2, instr.immediate)?;
+            pu.memory[addr] = pu.registers[instr.reg1];
+        }
+        Opcode::Enter => pu.enter(instr.immediate)?,
+        Opcode::Leave => pu.leave()?,
+        Opcode::LoadFrame => {
+            pu.check_register_bounds(instr.reg1)?;
+            let addr = pu.frame_address(instr.immediate)?;
+            pu.registers[instr.reg1] = pu.memory[addr];
+        }
+        Opcode::StoreFrame => {
+            pu.check_register_bounds(instr.reg1)?;
+            let addr = pu.frame_address(instr.immediate)?;
+            pu.memory[addr] = pu.registers[instr.reg1];
+        }
+        Opcode::Nop => {}
+        Opcode::Halt => return Ok(Flow::Halt(0)),
+        Opcode::HaltImmediate => return Ok(Flow::Halt(instr.immediate)),
+        Opcode::HaltRegister => {
+            pu.check_register_bounds(instr.reg1)?;
+            return Ok(Flow::Halt(pu.registers[instr.reg1]));
+        }
+        Opcode::Brk => return Ok(Flow::Break),
+        Opcode::Print => {
+            pu.check_register_bounds(instr.reg1)?;
+            let line = format!("{}\n", pu.registers[instr.reg1]);
+            pu.output.write_out(&line);
+        }
+        Opcode::PrintString => pu.print_string(pu.absolute(instr.addr)?)?,
+        Opcode::ReadInt => {
+            pu.check_register_bounds(instr.reg1)?;
+            pu.registers[instr.reg1] = pu.read_int(instr.reg1)?;
+        }
+        Opcode::Rdsp => {
+            pu.check_register_bounds(instr.reg1)?;
+            pu.registers[instr.reg1] = i32::try_from(pu.stack_pointer).unwrap_or(i32::MAX);
+        }
+        Opcode::Wrsp => pu.set_stack_pointer(instr.reg1)?,
+        Opcode::Rdpc => {
+            pu.check_register_bounds(instr.reg1)?;
+            pu.registers[instr.reg1] =
+                i32::try_from(pu.instruction_pointer + 1).unwrap_or(i32::MAX);
+        }
+        Opcode::Rdcycle => {
+            pu.check_register_bounds(instr.reg1)?;
+            pu.registers[instr.reg1] = i32::try_from(pu.instruction_count).unwrap_or(i32::MAX);
+        }
+        Opcode::Rand => {
+            pu.check_register_bounds(instr.reg1)?;
+            pu.registers[instr.reg1] = pu.rng.next_i32();
+        }
+        Opcode::RandRange => {
+            pu.check_register_bounds(instr.reg1)?;
+            pu.check_register_bounds(instr.reg2)?;
+            let max = pu.registers[instr.reg2];
+            if max <= 0 {
+                return Err(MdpuError::EmptyRandomRange {
+                    reg: instr.reg2,
+                    max,
+                    ip: pu.instruction_pointer,
+                });
+            }
+            pu.registers[instr.reg1] = pu.rng.below(max);
+        }
+        Opcode::ReadChar => {
+            pu.check_register_bounds(instr.reg1)?;
+            pu.registers[instr.reg1] = pu.input().read_byte().map_or(-1, i32::from);
+        }
+        Opcode::PrintStringIndirect => {
+            let addr = pu.address_in(instr.reg1)?;
+            pu.print_string(addr)?;
+        }
+        Opcode::PrintChar => {
+            pu.check_register_bounds(instr.reg1)?;
+            let c = character(pu.registers[instr.reg1]);
+            pu.output.write_out(c.encode_utf8(&mut [0; 4]));
+        }
+        Opcode::Sys => {
+            if let SyscallEffect::Exit(code) = pu.syscall(instr.immediate)? {
+                return Ok(Flow::Halt(code));
+            }
+        }
+        Opcode::AssertEq => {
+            pu.check_register_bounds(instr.reg1)?;
+            pu.assert_equal(instr.reg1, None, instr.immediate)?;
+        }
+        Opcode::AssertEqRegister => {
+            pu.check_register_bounds(instr.reg1)?;
+            pu.check_register_bounds(instr.reg2)?;
+            pu.assert_equal(instr.reg1, Some(instr.reg2), pu.registers[instr.reg2])?;
+        }
+    }
+
+    Ok(Flow::Next)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::ToString;
+
+    use super::*;
+    use crate::asm::assemble;
+    use crate::binary::opcode_from_number;
+    #[cfg(feature = "std")]
+    use crate::output::CaptureSink;
+    use crate::output::NullSink;
+    #[cfg(feature = "std")]
+    use std::sync::{Arc, Mutex};
+
+    // Instructions of an assembly listing
+    fn program(source: &str) -> Vec<Instruction> {
+        assemble(source).unwrap().into_instructions()
+    }
+
+    // Machine that prints nothing and reads `input`
+    fn machine(registers: usize, memory: usize, input: &'static [u8]) -> ProcessingUnit {
+        let mut pu = ProcessingUnit::initialize(registers, memory);
+        pu.set_output(NullSink);
+        pu.input = Input::from_bytes(input.iter().copied());
+        pu
+    }
+
+    // Error of running `source` to its end on `pu`
+    fn fault(pu: &mut ProcessingUnit, source: &str) -> MdpuError {
+        run(pu, &program(source), 100).unwrap_err()
+    }
+
+    // Machine printing into a capture and reading `input`, with the output the capture held
+    // each time the machine went to the input for a byte
+    #[cfg(feature = "std")]
+    fn capturing(input: &'static [u8]) -> (ProcessingUnit, CaptureSink, Arc<Mutex<Vec<String>>>) {
+        let capture = CaptureSink::new();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let mut pu = ProcessingUnit::initialize(4, 8);
+        pu.set_output(capture.clone());
+        let (watched, record) = (capture.clone(), seen.clone());
+        let mut bytes = input.iter().copied();
+        pu.input = Input::from_bytes(core::iter::from_fn(move || {
+            let mut seen = record.lock().unwrap();
+            let out = watched.out();
+            if seen.last() != Some(&out) {
+                seen.push(out);
+            }
+            bytes.next()
+        }));
+        (pu, capture, seen)
+    }
+
+    // Machine of `registers` registers and `memory` cells after loading the data of
+    // `source` and running it to its end
+    fn ran(registers: usize, memory: usize, source: &str) -> ProcessingUnit {
+        let mut pu = machine(registers, memory, b"");
+        let program = assemble(source).unwrap();
+        pu.load_data(&program).unwrap();
+        run(&mut pu, &program, 1000).unwrap();
+        pu
+    }
+
+    // What `source` prints running on a machine with `memory` cells that reads `input`
+    #[cfg(feature = "std")]
+    fn printed(memory: usize, input: &'static [u8], source: &str) -> String {
+        let capture = CaptureSink::new();
+        let mut pu = machine(4, memory, input);
+        pu.set_output(capture.clone());
+        let program = assemble(source).unwrap();
+        pu.load_data(&program).unwrap();
+        run(&mut pu, &program, 1000).unwrap();
+        capture.out()
+    }
+
+    #[test]
+    fn run_returns_a_view_that_copies_into_an_owned_state() {
+        let mut pu = machine(2, 4, b"");
+        let view = run(&mut pu, &program("LI R0 7\nPUSH R0\nHALT 2"), 100).unwrap();
+        assert_eq!(view.registers.as_ptr(), pu.registers().as_ptr());
+        let state = ProcessingUnitState::from(pu.state());
+        assert_eq!(state.registers, [7, 0]);
+        assert_eq!(state.stack, [7]);
+        assert_eq!(state.stack_pointer, 2);
+        assert_eq!(state.exit_code, Some(2));
+        assert_eq!(state.view(), pu.state());
+        assert_eq!(state.to_string(), pu.state().to_string());
+    }
+
+    #[test]
+    fn pop_all_on_zero_size_memory_faults() {
+        let mut pu = ProcessingUnit::initialize(2, 0);
+        let outcome = pu.step(&program("POPA"));
+        assert!(matches!(
+            outcome,
+            StepOutcome::Fault(MdpuError::StackShort { held: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn pop_all_on_empty_stack_faults() {
+        let mut pu = ProcessingUnit::initialize(2, 8);
+        let outcome = pu.step(&program("POPA"));
+        assert_eq!(
+            outcome,
+            StepOutcome::Fault(MdpuError::StackShort {
+                opcode: Opcode::PopAll,
+                needed: 2,
+                held: 0,
+                ip: 0,
+            })
+        );
+        assert_eq!(pu.stack_pointer, 7);
+    }
+
+    // Every opcode with registers, addresses and immediates at and past their limits, on
+    // machines with almost no memory, faults or runs on but never panics
+    #[test]
+    fn every_opcode_with_extreme_operands_returns() {
+        for opcode in (0..=u8::MAX).filter_map(opcode_from_number) {
+            for memory in [0, 1, 2] {
+                for reg in [0, 1, 7, usize::MAX] {
+                    for addr in [
+                        AddrOperand::Absolute(0),
+                        AddrOperand::Absolute(1),
+                        AddrOperand::Absolute(33),
+                        AddrOperand::Absolute(usize::MAX),
+                        AddrOperand::Relative(i32::MIN),
+                        AddrOperand::Relative(-1),
+                        AddrOperand::Relative(0),
+                        AddrOperand::Relative(i32::MAX),
+                    ] {
+                        for immediate in [i32::MIN, -1, 0, 1, 31, 32, i32::MAX] {
+                            let instr = Instruction {
+                                opcode,
+                                reg1: reg,
+                                reg2: reg.wrapping_add(1),
+                                reg3: reg,
+                                addr,
+                                immediate,
+                            };
+                            for trap in [false, true] {
+                                let mut pu = machine(2, memory, b"");
+                                pu.set_trap_overflow(trap);
+                                pu.registers.copy_from_slice(&[i32::MIN, -1]);
+                                // The second step runs wherever the first one went
+                                pu.step(&[instr, instr]);
+                                pu.step(&[instr, instr]);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn shifts_by_huge_counts_wrap_the_count() {
+        let mut pu = machine(4, 4, b"");
+        let source = "LI R0 1\nLI R1 2147483647\nSHL R0 R1 R2\nSHR R0 R1 R3";
+        run(&mut pu, &program(source), 100).unwrap();
+        assert_eq!(pu.registers(), &[1, i32::MAX, i32::MIN, 0]);
+        let source = "LI R0 -1\nLI R1 -1\nSHRL R0 R1 R2\nROL R0 R1 R3";
+        pu.reset_execution();
+        run(&mut pu, &program(source), 100).unwrap();
+        assert_eq!(pu.registers(), &[-1, -1, 1, -1]);
+    }
+
+    #[test]
+    fn min_divided_by_minus_one_wraps() {
+        let mut pu = machine(4, 4, b"");
+        let source = "LI R0 -2147483648\nLI R1 -1\nDIV R0 R1 R2\nMOD R0 R1 R3";
+        run(&mut pu, &program(source), 100).unwrap();
+        assert_eq!(pu.registers(), &[i32::MIN, -1, i32::MIN, 0]);
+        let source = "DIVI R2 R0 -1\nDIVMOD R0 R1 R0 R1";
+        pu.reset_execution();
+        run(&mut pu, &program(source), 100).unwrap();
+        assert_eq!(pu.registers(), &[i32::MIN, 0, i32::MIN, 0]);
+    }
+
+    #[test]
+    fn stack_opcodes_on_zero_size_memory_fault() {
+        for (source, expected) in [
+            ("PUSH R0", MdpuError::StackOverflow { reg: 0, ip: 0 }),
+            (
+                "POP R0",
+                MdpuError::StackUnderflow {
+                    opcode: Opcode::Pop,
+                    needed: 1,
+                    depth: 0,
+                    base: 0,
+                    ip: 0,
+                },
+            ),
+            ("CALL 0", MdpuError::CallStackOverflow { ip: 0 }),
+            (
+                "RET",
+                MdpuError::StackUnderflow {
+                    opcode: Opcode::Ret,
+                    needed: 1,
+                    depth: 0,
+                    base: 0,
+                    ip: 0,
+                },
+            ),
+            ("ENTER 0", MdpuError::FrameOverflow { slots: 0, ip: 0 }),
+            ("LEAVE", MdpuError::NoFrame { ip: 0 }),
+            (
+                "LOADF R0 0",
+                MdpuError::FrameOutOfBounds { offset: 0, ip: 0 },
+            ),
+            ("LOAD R0 0", MdpuError::MemoryOutOfBounds { addr: 0, ip: 0 }),
+            (
+                "PUSHI 1",
+                MdpuError::StackFull {
+                    opcode: Opcode::PushImmediate,
+                    needed: 1,
+                    free: 0,
+                    ip: 0,
+                },
+            ),
+            (
+                "LI R0 1\nWRSP R0",
+                MdpuError::StackPointerOutOfBounds {
+                    reg: 0,
+                    value: 1,
+                    ip: 1,
+                },
+            ),
+        ] {
+            assert_eq!(
+                fault(&mut machine(1, 0, b""), source),
+                expected,
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn out_of_range_operands_fault() {
+        for (source, expected) in [
+            (
+                "LI R0 -1\nLOADR R1 R0",
+                MdpuError::AddressRegisterOutOfBounds {
+                    reg: 0,
+                    value: -1,
+                    ip: 1,
+                },
+            ),
+            (
+                "LI R0 2147483647\nLOADO R1 R0 1",
+                MdpuError::OffsetAddressOutOfBounds {
+                    reg: 0,
+                    addr: 1 << 31,
+                    ip: 1,
+                },
+            ),
+            (
+                "LI R0 2\nJMPR R0",
+                MdpuError::JumpOutOfBounds {
+                    reg: 0,
+                    value: 2,
+                    len: 2,
+                    ip: 1,
+                },
+            ),
+            (
+                "BR 2147483647",
+                MdpuError::BranchOutOfBounds {
+                    offset: i32::MAX - 1,
+                    ip: 0,
+                },
+            ),
+            ("INC R9", MdpuError::RegisterOutOfBounds { reg: 9, ip: 0 }),
+            ("INCM 4", MdpuError::MemoryOutOfBounds { addr: 4, ip: 0 }),
+        ] {
+            assert_eq!(
+                fault(&mut machine(2, 4, b""), source),
+                expected,
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn arithmetic_faults() {
+        let mut pu = machine(2, 4, b"");
+        pu.set_trap_overflow(true);
+        assert_eq!(
+            fault(&mut pu, "LI R0 2147483647\nINC R0"),
+            MdpuError::ArithmeticOverflow {
+                opcode: Opcode::Inc,
+                lhs: i32::MAX,
+                rhs: None,
+                ip: 1,
+            }
+        );
+        // The assembler rejects DIVI by 0, so it can only come from a built instruction
+        let divi = Instruction::new(Opcode::DivImmediate);
+        assert_eq!(
+            run(&mut machine(2, 4, b""), &[divi], 100).unwrap_err(),
+            MdpuError::DivisionByZeroImmediate { ip: 0 }
+        );
+        assert_eq!(
+            fault(&mut machine(2, 4, b""), "RANDR R0 R1"),
+            MdpuError::EmptyRandomRange {
+                reg: 1,
+                max: 0,
+                ip: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn stack_block_faults() {
+        assert_eq!(
+            fault(&mut machine(4, 3, b""), "PUSHA"),
+            MdpuError::StackFull {
+                opcode: Opcode::PushAll,
+                needed: 4,
+                free: 2,
+                ip: 0,
+            }
+        );
+        assert_eq!(
+            fault(&mut machine(2, 4, b""), "PUSHI 1\nPOPA"),
+            MdpuError::StackShort {
+                opcode: Opcode::PopAll,
+                needed: 2,
+                held: 1,
+                ip: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn frame_faults() {
+        assert_eq!(
+            fault(&mut machine(1, 4, b""), "ENTER 3"),
+            MdpuError::FrameOverflow { slots: 3, ip: 0 }
+        );
+        assert_eq!(
+            fault(&mut machine(1, 4, b""), "ENTER -1"),
+            MdpuError::FrameOverflow { slots: -1, ip: 0 }
+        );
+        assert_eq!(
+            fault(&mut machine(1, 4, b""), "ENTER 0\nSTOREF R0 1"),
+            MdpuError::FrameOutOfBounds { offset: 1, ip: 1 }
+        );
+    }
+
+    #[test]
+    fn host_faults() {
+        assert_eq!(
+            fault(&mut machine(2, 4, b""), "ASSERT_EQ R0 1"),
+            MdpuError::AssertionFailed {
+                reg: 0,
+                other: None,
+                expected: 1,
+                actual: 0,
+                ip: 0,
+            }
+        );
+        assert_eq!(
+            fault(&mut machine(2, 4, b""), "SYS 99"),
+            MdpuError::UnknownSyscall { number: 99, ip: 0 }
+        );
+        let mut pu = machine(2, 4, b"");
+        pu.memory.fill(65);
+        assert_eq!(
+            fault(&mut pu, "PRINTS 1"),
+            MdpuError::UnterminatedString { addr: 1, ip: 0 }
+        );
+        assert_eq!(
+            fault(&mut machine(2, 4, b" \n"), "READI R1"),
+            MdpuError::EndOfInput { reg: 1, ip: 0 }
+        );
+        assert_eq!(
+            fault(&mut machine(2, 4, b"99999999999"), "READI R1"),
+            MdpuError::InvalidInput {
+                reg: 1,
+                word: String::from("99999999999"),
+                ip: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn runaway_program_hits_the_limit() {
+        assert_eq!(
+            fault(&mut machine(1, 1, b""), "top:\nJMP top"),
+            MdpuError::InstructionLimitExceeded { limit: 100 }
+        );
+    }
+
+    #[test]
+    fn accessors_return_errors_out_of_bounds() {
+        let mut pu = machine(2, 4, b"");
+        let register = MdpuError::RegisterOutOfBounds { reg: 2, ip: 0 };
+        assert_eq!(pu.get_register(2), Err(register.clone()));
+        assert_eq!(pu.set_register(2, 1), Err(register));
+        let memory = MdpuError::MemoryOutOfBounds { addr: 4, ip: 0 };
+        assert_eq!(pu.read_memory(4), Err(memory.clone()));
+        assert_eq!(pu.write_memory(4, 1), Err(memory));
+    }
+
+    #[test]
+    fn execution_faults_are_returned() {
+        for (source, expected) in [
+            (
+                "MOV R0 R5",
+                MdpuError::RegisterOutOfBounds { reg: 5, ip: 0 },
+            ),
+            (
+                "STORE R0 9",
+                MdpuError::MemoryOutOfBounds { addr: 9, ip: 0 },
+            ),
+            (
+                "LI R1 0\nDIV R0 R1 R0",
+                MdpuError::DivisionByZero { reg: 1, ip: 1 },
+            ),
+            (
+                "LI R1 0\nMOD R0 R1 R0",
+                MdpuError::DivisionByZero { reg: 1, ip: 1 },
+            ),
+            (
+                "PUSH R0\nPUSH R0\nPUSH R0\nPUSH R0",
+                MdpuError::StackOverflow { reg: 0, ip: 3 },
+            ),
+            (
+                "PUSH R1\nPOP R0\nPOP R1",
+                MdpuError::StackUnderflow {
+                    opcode: Opcode::Pop,
+                    needed: 1,
+                    depth: 0,
+                    base: 3,
+                    ip: 2,
+                },
+            ),
+            (
+                "top:\nINC R0\nJMP top",
+                MdpuError::InstructionLimitExceeded { limit: 100 },
+            ),
+        ] {
+            assert_eq!(
+                fault(&mut machine(2, 4, b""), source),
+                expected,
+                "{}",
+                source
+            );
+        }
+    }
+
+    // JE and JNE land on their target, not on the instruction after it
+    #[test]
+    fn compare_jumps_land_on_their_target() {
+        for (jump, taken) in [("JE", true), ("JNE", false)] {
+            let source = format!("{} R0 R1 target\nINC R2\ntarget: INC R3\nINC R3", jump);
+            let mut pu = machine(4, 4, b"");
+            run(&mut pu, &program(&source), 100).unwrap();
+            let skipped = i32::from(!taken);
+            assert_eq!(pu.registers(), &[0, 0, skipped, 2], "{}", jump);
+        }
+    }
+
+    #[test]
+    fn relative_targets_count_from_the_jump() {
+        let source = "CALL .+3\nHALT R0\nRET\nINC R0\nJMP .-2";
+        let mut pu = machine(4, 4, b"");
+        run(&mut pu, &program(source), 100).unwrap();
+        assert_eq!(pu.exit_code(), Some(1));
+
+        let mut pu = machine(4, 4, b"");
+        assert_eq!(
+            fault(&mut pu, "JMP .-1"),
+            MdpuError::BranchOutOfBounds { offset: -2, ip: 0 }
+        );
+        // The return address is not pushed for a call that cannot land
+        let mut pu = machine(4, 4, b"");
+        assert_eq!(
+            fault(&mut pu, "CALL .+2"),
+            MdpuError::BranchOutOfBounds { offset: 1, ip: 0 }
+        );
+        assert_eq!(pu.stack_pointer, 3);
+
+        // `.` is the jump itself, and a relative branch also takes a signed offset from
+        // the next instruction
+        assert_eq!(program("JMP ."), program("JMP .-0"));
+        assert_eq!(program("BR .\nBR .+2"), program("BR -1\nBR +1"));
+        // A sign alone does not make any other target relative
+        for source in ["JMP -1", "JMP +3", "CALL +1", "JZ R0 -2", "JE R0 R1 +0"] {
+            let err = assemble(source).unwrap_err().to_string();
+            assert!(err.contains("has a sign"), "{}: {}", source, err);
+        }
+    }
+
+    #[test]
+    fn relative_memory_address_faults() {
+        for opcode in [Opcode::Load, Opcode::Store, Opcode::IncMemory, Opcode::Extr] {
+            let instr = Instruction {
+                addr: AddrOperand::Relative(-1),
+                ..Instruction::new(opcode)
+            };
+            let mut pu = machine(4, 4, b"");
+            assert_eq!(
+                run(&mut pu, &[instr], 100),
+                Err(MdpuError::RelativeAddress { offset: -1, ip: 0 }),
+                "{:?}",
+                opcode
+            );
+        }
+    }
+
+    // R1 = R0! by recursion, each level keeping its n in a frame local
+    const FACTORIAL: &str = "CALL factorial
+HALT
+factorial: ENTER 1
+STOREF R0 -1
+JNZ R0 recurse
+LI R1 1
+LEAVE
+RET
+recurse: DEC R0
+CALL factorial
+LOADF R0 -1
+MUL R1 R0 R1
+LEAVE
+RET";
+
+    #[test]
+    fn recursive_factorial() {
+        for (n, factorial) in [(0, 1), (1, 1), (5, 120), (10, 3628800)] {
+            let mut pu = machine(2, 64, b"");
+            pu.set_register(0, n).unwrap();
+            run(&mut pu, &program(FACTORIAL), 1000).unwrap();
+            assert_eq!(pu.registers(), &[n, factorial]);
+            assert_eq!(pu.stack(), &[]);
+            assert_eq!(pu.frame_pointer(), 64);
+        }
+    }
+
+    #[test]
+    fn recursion_past_the_stack_faults() {
+        let mut pu = machine(2, 16, b"");
+        pu.set_register(0, 100).unwrap();
+        assert!(matches!(
+            run(&mut pu, &program(FACTORIAL), 1000),
+            Err(MdpuError::CallStackOverflow { .. } | MdpuError::FrameOverflow { .. })
+        ));
+    }
+
+    #[test]
+    fn return_with_an_empty_stack_faults() {
+        assert_eq!(
+            fault(&mut machine(1, 4, b""), "RET"),
+            underflow(Opcode::Ret, 1, 0, 0)
+        );
+    }
+
+    fn underflow(opcode: Opcode, needed: usize, depth: usize, ip: usize) -> MdpuError {
+        MdpuError::StackUnderflow {
+            opcode,
+            needed,
+            depth,
+            base: 3,
+            ip,
+        }
+    }
+
+    #[test]
+    fn pop_at_the_base_follows_the_underflow_policy() {
+        let source = "LI R0 7\nPUSH R0\nLI R0 9\nPOP R1\nPOP R0";
+        let mut pu = machine(2, 4, b"");
+        assert_eq!(fault(&mut pu, source), underflow(Opcode::Pop, 1, 0, 4));
+        assert_eq!(pu.registers(), &[9, 7]);
+
+        let mut pu = machine(2, 4, b"");
+        pu.set_underflow_policy(UnderflowPolicy::ReturnZero);
+        run(&mut pu, &program(source), 100).unwrap();
+        assert_eq!(pu.registers(), &[0, 7]);
+        assert_eq!(pu.stack_pointer, 3);
+    }
+
+    #[test]
+    fn peek_and_drop_reach_exactly_the_base() {
+        let pushed = "PUSHI 1\nPUSHI 2\n";
+        for (source, registers, depth) in [
+            ("PEEK R0 0\nPEEK R1 1", [2, 1], 2),
+            ("DROP 1\nPEEK R0", [1, 0], 1),
+            ("DROP 2\nDROP 0", [0, 0], 0),
+        ] {
+            let mut pu = ran(2, 4, &[pushed, source].concat());
+            assert_eq!(pu.registers(), &registers, "{}", source);
+            assert_eq!(pu.stack().len(), depth, "{}", source);
+            // One more value is past the base under either policy
+            let past = [pushed, source, "\nPEEK R0 ", &depth.to_string()].concat();
+            assert_eq!(
+                fault(&mut machine(2, 4, b""), &past),
+                underflow(Opcode::Peek, depth + 1, depth, 4),
+                "{}",
+                past
+            );
+            pu = machine(2, 4, b"");
+            pu.set_underflow_policy(UnderflowPolicy::ReturnZero);
+            run(&mut pu, &program(&[&past, "\nDROP 3"].concat()), 100).unwrap();
+            assert_eq!(pu.registers()[0], 0, "{}", past);
+            assert!(pu.stack().is_empty(), "{}", past);
+        }
+        assert_eq!(
+            fault(&mut machine(2, 4, b""), &[pushed, "DROP 3"].concat()),
+            underflow(Opcode::Drop, 3, 2, 2)
+        );
+    }
+
+    #[test]
+    fn return_with_an_empty_stack_faults_under_either_policy() {
+        for policy in [UnderflowPolicy::Trap, UnderflowPolicy::ReturnZero] {
+            let mut pu = machine(1, 4, b"");
+            pu.set_underflow_policy(policy);
+            assert_eq!(fault(&mut pu, "RET"), underflow(Opcode::Ret, 1, 0, 0));
+            // The return address popped, a second RET finds only the base
+            let mut pu = machine(1, 4, b"");
+            pu.set_underflow_policy(policy);
+            assert_eq!(
+                fault(&mut pu, "CALL f\nf: RET"),
+                underflow(Opcode::Ret, 1, 0, 1)
+            );
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn printed_loop_reaches_the_sink() {
+        let (mut pu, capture, _) = capturing(b"");
+        let source = "LI R0 1\nLI R1 5\nnext: PRINT R0\nINC R0\nLOOP R1 next";
+        run(&mut pu, &program(source), 100).unwrap();
+        assert_eq!(capture.out(), "1\n2\n3\n4\n5\n");
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn output_is_written_before_input_is_read() {
+        for read in ["READI R1", "READC R1", "SYS 3"] {
+            let (mut pu, capture, seen) = capturing(b"9");
+            let source = format!("LI R0 7\nPRINT R0\n{read}\nPRINT R0");
+            run(&mut pu, &program(&source), 100).unwrap();
+            assert_eq!(*seen.lock().unwrap(), ["7\n"], "{read}");
+            assert!(capture.out().starts_with("7\n"), "{read}");
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn read_numbers_until_the_input_fails() {
+        // Running total of the numbers read, printed after each one
+        let source = "LI R1 3\nnext: READI R0\nADD R2 R0 R2\nPRINT R2\nLOOP R1 next";
+        let (mut pu, capture, seen) = capturing(b"42 -7 5");
+        run(&mut pu, &program(source), 100).unwrap();
+        assert_eq!(capture.out(), "42\n35\n40\n");
+        assert_eq!(*seen.lock().unwrap(), ["", "42\n", "42\n35\n"]);
+
+        let (mut pu, capture, seen) = capturing(b"42 -7 junk");
+        assert_eq!(
+            fault(&mut pu, source),
+            MdpuError::InvalidInput {
+                reg: 0,
+                word: String::from("junk"),
+                ip: 1,
+            }
+        );
+        assert_eq!(capture.out(), "42\n35\n");
+        assert_eq!(*seen.lock().unwrap(), ["", "42\n", "42\n35\n"]);
+
+        let (mut pu, capture, seen) = capturing(b"42 -7\n");
+        assert_eq!(
+            fault(&mut pu, source),
+            MdpuError::EndOfInput { reg: 0, ip: 1 }
+        );
+        assert_eq!(capture.out(), "42\n35\n");
+        assert_eq!(*seen.lock().unwrap(), ["", "42\n", "42\n35\n"]);
+    }
+
+    #[test]
+    fn call_returns_past_the_call() {
+        // R1 = R0! with each level saving its n on the stack next to its return address
+        let source = "LI R0 5\nCALL factorial\nHALT
+factorial: JNZ R0 recurse\nLI R1 1\nRET
+recurse: PUSH R0\nDEC R0\nCALL factorial\nPOP R0\nMUL R1 R0 R1\nRET";
+        let pu = ran(2, 32, source);
+        assert_eq!(pu.registers(), &[5, 120]);
+        assert_eq!(pu.stack(), &[]);
+    }
+
+    #[test]
+    fn frames_keep_the_callers_locals() {
+        let source = "CALL outer\nHALT
+outer: ENTER 3\nLI R0 1\nSTOREF R0 -1\nLI R0 2\nSTOREF R0 -2\nLI R0 3\nSTOREF R0 -3
+CALL inner\nLOADF R0 -1\nLOADF R2 -2\nADD R0 R2 R0\nLOADF R2 -3\nADD R0 R2 R0\nLEAVE\nRET
+inner: ENTER 2\nLI R1 40\nSTOREF R1 -1\nLI R1 2\nSTOREF R1 -2
+LOADF R1 -1\nLOADF R2 -2\nADD R1 R2 R1\nLEAVE\nRET";
+        let pu = ran(4, 32, source);
+        assert_eq!(pu.registers()[..2], [6, 42]);
+        assert_eq!(pu.frame_pointer(), 32);
+        assert_eq!(pu.stack(), &[]);
+    }
+
+    #[test]
+    fn immediate_arithmetic() {
+        // 100 degrees Fahrenheit in Celsius, rounded toward zero
+        let source = "LI R0 100\nADDI R1 R0 -32\nMULI R1 R1 5\nDIVI R1 R1 9";
+        assert_eq!(ran(2, 4, source).registers(), &[100, 37]);
+        let err = assemble("DIVI R1 R0 0").unwrap_err().to_string();
+        assert!(err.contains("DIVI by an immediate 0"), "{}", err);
+    }
+
+    #[test]
+    fn immediate_masks_take_all_32_bits() {
+        let source =
+            "LI R0 0x1234\nANDI R1 R0 0xFFFF_FFF0\nORI R2 R1 0b101\nXORI R3 R2 0x8000_0000";
+        assert_eq!(
+            ran(4, 4, source).registers(),
+            &[0x1234, 0x1230, 0x1235, 0x8000_1235_u32 as i32]
+        );
+    }
+
+    #[test]
+    fn shr_keeps_the_sign_and_shrl_brings_in_zeros() {
+        let source =
+            "LI R0 -8\nLI R1 1\nLI R2 31\nSHR R0 R1 R3\nSHRL R0 R1 R4\nSHR R0 R2 R5\nSHRL R0 R2 R1";
+        assert_eq!(
+            ran(6, 4, source).registers(),
+            &[-8, 1, 31, -4, 0x7FFF_FFFC, -1]
+        );
+    }
+
+    #[test]
+    fn rotations_wrap_bits_around_and_their_count() {
+        let source = "LI R0 -2147483647\nLI R1 1\nLI R2 32\nROL R0 R1 R3\nROR R0 R1 R4
+RORI R5 R0 0\nROL R0 R2 R6\nROLI R7 R0 4";
+        let value = 0x8000_0001_u32 as i32;
+        assert_eq!(
+            ran(8, 4, source).registers(),
+            &[value, 1, 32, 3, 0xC000_0000_u32 as i32, value, value, 0x18]
+        );
+    }
+
+    #[test]
+    fn register_indirect_loads_walk_an_array() {
+        let source = ".data 0\n.word 1, 2, 3, 4, 5, 6, 7, 8, 9, 10
+LI R1 0\nLI R2 10\nloop: LOADR R3 R1\nADD R0 R3 R0\nINC R1\nJNE R1 R2 loop";
+        let pu = ran(4, 16, source);
+        assert_eq!(pu.registers(), &[55, 10, 10, 10]);
+    }
+
+    #[test]
+    fn base_offset_addresses_reach_around_a_pointer() {
+        let source = ".data 4\n.word 7, 20, 300
+LI R0 6\nLOAD R1 [R0 - 2]\nLOAD R2 [R0-1]\nLOAD R3 [R0]
+ADD R3 R2 R3\nADD R3 R1 R3\nSTORE R3 [R0 + 0]";
+        let pu = ran(4, 16, source);
+        assert_eq!(pu.registers(), &[6, 7, 20, 327]);
+        assert_eq!(pu.read_memory(6), Ok(327));
+    }
+
+    #[test]
+    fn jmpr_calls_through_a_function_pointer() {
+        // R0 points at double when R3 is 0 and at triple otherwise
+        for (choice, result) in [(0, 28), (1, 42)] {
+            let source = format!(
+                "LI R3 {choice}\nLI R0 double\nJZ R3 call\nLI R0 triple
+call: LI R1 14\nLI R2 done\nJMPR R0
+double: ADD R1 R1 R1\nJMPR R2\ntriple: MULI R1 R1 3\nJMPR R2\ndone: HALT"
+            );
+            assert_eq!(ran(4, 4, &source).registers()[1], result, "{choice}");
+        }
+    }
+
+    #[test]
+    fn relative_branches_loop_back() {
+        let source = "LI R0 5\nLI R2 1\nADD R1 R0 R1\nSUB R0 R2 R0\nBRNZ R0 -3
+BRNZ R1 done\nLI R1 -1\ndone: HALT";
+        assert_eq!(ran(4, 4, source).registers(), &[0, 15, 1, 0]);
+    }
+
+    #[test]
+    fn loop_runs_its_body_count_times() {
+        let source = "LI R2 100\nagain: ADD R0 R2 R0\nLOOP R2 again
+LI R2 100\nback: ADD R1 R2 R1\nDEC R2\nJNZ R2 back";
+        assert_eq!(ran(3, 4, source).registers(), &[5050, 5050, 0]);
+
+        // A count of 0 wraps to -1 and jumps back like any other
+        let mut pu = machine(1, 4, b"");
+        let program = program("again: LOOP R0 again");
+        assert_eq!(pu.step(&program), StepOutcome::Continue);
+        assert_eq!(pu.registers(), &[-1]);
+        assert_eq!(pu.instruction_pointer(), 0);
+    }
+
+    #[test]
+    fn cmp_and_test_set_only_the_flags() {
+        let mut pu = ran(2, 4, "LI R0 3\nLI R1 7\nTEST R0 R1\nCMP R0 R1");
+        assert_eq!(pu.registers(), &[3, 7]);
+        assert_eq!(pu.flags().bits(), Flags::NEGATIVE | Flags::CARRY);
+
+        // A third register still gets the result
+        pu = ran(3, 4, "LI R0 3\nLI R1 7\nCMP R0 R1 R2");
+        assert_eq!(pu.registers(), &[3, 7, -4]);
+        pu = ran(3, 4, "LI R0 6\nLI R1 3\nTEST R0 R1 R2");
+        assert_eq!(pu.registers(), &[6, 3, 2]);
+        assert!(!pu.flags().zero());
+    }
+
+    #[test]
+    fn unsigned_branches_read_minus_one_as_the_largest_value() {
+        let source = "LI R0 -1\nLI R1 1\nCMPU R0 R1\nJBE small\nLI R2 1
+small: CMPU R1 R0\nJAE done\nLI R3 1\ndone: HALT";
+        let pu = ran(4, 4, source);
+        assert_eq!(pu.registers(), &[-1, 1, 1, 1]);
+        assert!(pu.flags().carry());
+        assert!(!pu.flags().negative() && !pu.flags().overflow());
+    }
+
+    #[test]
+    fn conditional_moves_pick_the_larger_value() {
+        let source =
+            ".macro MAX dst a b\nSUB a b R7\nSHRI R7 R7 31\nMOV dst a\nCMOVNZ dst b R7\n.endmacro
+LI R0 -3\nLI R1 12\nMAX R2 R0 R1\nLI R3 40\nLI R4 12\nMAX R5 R3 R4";
+        let pu = ran(8, 4, source);
+        assert_eq!(pu.registers()[2], 12);
+        assert_eq!(pu.registers()[5], 40);
+        assert_eq!(
+            ran(2, 4, "LI R0 1\nLI R1 5\nCMOVZ R0 R1 R0").registers(),
+            &[1, 5]
+        );
+    }
+
+    #[test]
+    fn swap_exchanges_registers() {
+        let source = "LI R0 3\nLI R1 1\nLI R2 2\nSWAP R0 R1\nSWAP R1 R2";
+        assert_eq!(ran(3, 4, source).registers(), &[1, 2, 3]);
+        // A register swapped with itself keeps its value
+        assert_eq!(ran(1, 4, "LI R0 9\nSWAP R0 R0").registers(), &[9]);
+    }
+
+    #[test]
+    fn saturating_arithmetic_clamps_at_the_ends() {
+        let source = "LI R0 2147483647\nLI R1 -2147483648\nLI R2 1
+ADDS R0 R2 R2\nSUBSI R3 R1 1\nSUBS R1 R1 R0";
+        assert_eq!(
+            ran(4, 4, source).registers(),
+            &[0, i32::MIN, i32::MAX, i32::MIN]
+        );
+        assert_eq!(
+            ran(1, 4, "LI R0 -2147483648\nADDSI R0 R0 -1").registers(),
+            &[i32::MIN]
+        );
+    }
+
+    // Overflowing arithmetic wraps, or faults naming its operands with overflow trapping on
+    const OVERFLOWS: &str = "LI R0 2147483647\nLI R1 -2147483648\nLI R5 1
+ADD R0 R5 R2\nSUB R1 R5 R3\nLI R4 1_000_000\nMOV R5 R4\nMUL R4 R5 R6
+MOV R7 R0\nINC R7\nDEC R1";
+
+    #[test]
+    fn overflow_wraps_unless_trapped() {
+        let pu = ran(8, 4, OVERFLOWS);
+        assert_eq!(
+            pu.registers(),
+            &[
+                i32::MAX,
+                i32::MAX,
+                i32::MIN,
+                i32::MAX,
+                1_000_000,
+                1_000_000,
+                -727379968,
+                i32::MIN
+            ]
+        );
+
+        let mut pu = machine(8, 4, b"");
+        pu.set_trap_overflow(true);
+        assert_eq!(
+            fault(&mut pu, OVERFLOWS),
+            MdpuError::ArithmeticOverflow {
+                opcode: Opcode::Add,
+                lhs: i32::MAX,
+                rhs: Some(1),
+                ip: 3,
+            }
+        );
+    }
+
+    #[test]
+    fn high_words_of_products() {
+        // MIN * MIN is 2^62, and -3 * 5 only has the sign in its high word
+        let source = "LI R0 -2147483648\nMULW R1 R2 R0 R0\nMULH R0 R0 R3
+LI R4 -3\nLI R5 5\nMULW R6 R7 R4 R5";
+        assert_eq!(
+            ran(8, 4, source).registers(),
+            &[i32::MIN, 1 << 30, 0, 1 << 30, -3, 5, -1, -15]
+        );
+        // As unsigned, -1 * -1 is 0xFFFF_FFFE_0000_0001, where as signed it is 1
+        let source = "LI R0 6\nLI R1 7\nMULH R0 R1 R2\nLI R3 -1\nMULHU R3 R3 R1\nMULH R3 R3 R0";
+        assert_eq!(ran(4, 4, source).registers(), &[0, -2, 0, -1]);
+        // Both sources are read before either half is written
+        let source = "LI R0 -3\nLI R1 5\nMULW R0 R1 R0 R1";
+        assert_eq!(ran(2, 4, source).registers(), &[-1, -15]);
+    }
+
+    #[test]
+    fn divmod_rounds_toward_zero() {
+        // The decimal digits of 1234, lowest first, into memory
+        let source = "LI R0 1234\nLI R1 10\nLI R3 0\ndivide: DIVMOD R0 R2 R0 R1\nSTORER R2 R3
+INC R3\nJNZ R0 divide";
+        let pu = ran(4, 8, source);
+        assert_eq!(pu.memory()[..4], [4, 3, 2, 1]);
+        assert_eq!(pu.registers()[3], 4);
+
+        for (a, b, quotient, remainder) in
+            [(-7, 2, -3, -1), (7, -2, -3, 1), (i32::MIN, -1, i32::MIN, 0)]
+        {
+            let source = format!("LI R2 {a}\nLI R3 {b}\nDIVMOD R0 R1 R2 R3");
+            assert_eq!(
+                ran(4, 4, &source).registers(),
+                &[quotient, remainder, a, b],
+                "{a} {b}"
+            );
+        }
+        // Both sources are read before either result is written
+        let source = "LI R0 -7\nLI R1 2\nDIVMOD R1 R0 R0 R1";
+        assert_eq!(ran(2, 4, source).registers(), &[-1, -3]);
+        assert_eq!(
+            fault(&mut machine(4, 4, b""), "LI R2 1\nDIVMOD R0 R1 R2 R3"),
+            MdpuError::DivisionByZero { reg: 3, ip: 1 }
+        );
+    }
+
+    #[test]
+    fn bit_counts() {
+        // Set bits, leading zeros and trailing zeros
+        for (value, counts) in [
+            (0, [0, 32, 32]),
+            (-1, [32, 0, 0]),
+            (1, [1, 31, 0]),
+            (i32::MIN, [1, 0, 31]),
+        ] {
+            let source = format!("LI R0 {value}\nPOPCNT R0 R1\nCLZ R0 R2\nCTZ R0 R3");
+            assert_eq!(ran(4, 4, &source).registers()[1..], counts, "{value}");
+        }
+    }
+
+    #[test]
+    fn single_bits_are_set_cleared_toggled_and_tested() {
+        let source = "LI R0 1\nLI R1 31\nBSET R0 R1 R0\nBCLRI R0 R0 0\nBTGLI R2 R0 4\nBTGLI R2 R2 4
+BTST R0 R1 R3\nLI R1 5\nBTST R0 R1 R4\nLI R6 33\nBSET R7 R6 R7";
+        let pu = ran(8, 4, source);
+        assert_eq!(pu.registers()[..5], [i32::MIN, 5, i32::MIN, 1, 0]);
+        assert!(pu.flags().zero());
+        // A bit index from a register wraps like a shift count
+        assert_eq!(pu.registers()[7], 2);
+    }
+
+    #[test]
+    fn bit_fields_are_packed_and_unpacked() {
+        let source = "LI R7 0x5A\nINSR R0 R7 8 8\nLI R7 1\nINSR R0 R7 31 1
+EXTR R1 R0 8 8\nEXTR R2 R0 31 1\nEXTRS R3 R0 28 4\nEXTR R4 R0 28 4\nEXTR R5 R0 0 32\nEXTRS R6 R0 0 32";
+        let packed = 0x8000_5A00_u32 as i32;
+        assert_eq!(
+            ran(8, 4, source).registers()[..7],
+            [packed, 0x5A, 1, -8, 8, packed, packed]
+        );
+    }
+
+    #[test]
+    fn bytes_and_halfwords_widen_by_sign_or_zero() {
+        let source = "LI R0 0x80\nSEXTB R0 R1\nZEXTB R0 R2\nLI R3 0xFFFF\nSEXTH R3 R4\nZEXTH R3 R5
+LI R0 100\nSEXTB R0 R6\nZEXTH R0 R7";
+        assert_eq!(
+            ran(8, 4, source).registers()[1..],
+            [-128, 128, 0xFFFF, -1, 65535, 100, 100]
+        );
+    }
+
+    #[test]
+    fn pusha_and_popa_save_every_register() {
+        let source = "LI R0 10\nLI R1 20\nLI R2 30\nLI R3 40\nPUSHI 7\nCALL clobber\nPOP R0\nHALT
+clobber: PUSHA\nLI R0 -1\nLI R1 -1\nLI R2 -1\nLI R3 -1\nPOPA\nRET";
+        let pu = ran(4, 16, source);
+        assert_eq!(pu.registers(), &[7, 20, 30, 40]);
+        assert_eq!(pu.stack(), &[]);
+    }
+
+    #[test]
+    fn counters_in_memory() {
+        let source = "LI R3 10\ncount: INCM 0\nLOOP R3 count\nDECM 0\nLOAD R0 0
+LI R1 1\nINCMR R1\nLOAD R2 1\nDECMR R1\nDECMR R1\nLOAD R3 1";
+        assert_eq!(ran(4, 16, source).registers(), &[9, 1, 1, -1]);
+        assert_eq!(
+            fault(&mut machine(2, 4, b""), "LI R1 4\nINCMR R1"),
+            MdpuError::AddressRegisterOutOfBounds {
+                reg: 1,
+                value: 4,
+                ip: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn lea_loads_data_and_code_addresses() {
+        let source =
+            ".data 8\nbuffer: .word 0, 0, 0\nLEA R0 buffer+1\nLI R1 5\nSTORER R1 R0\nLOAD R2 9
+LEA R0 finish\nJMPR R0\nLI R3 -1\nfinish: LI R3 1";
+        let pu = ran(4, 16, source);
+        assert_eq!(pu.registers(), &[7, 5, 5, 1]);
+        assert_eq!(pu.read_memory(9), Ok(5));
+    }
+
+    #[test]
+    fn zero_register_reads_0_and_drops_writes() {
+        let source = "LI R0 5\nMOV R1 R0\nLI R2 7\nMOV R2 R0\nLI R3 12\nADD R3 R2 R0";
+        let mut pu = machine(4, 4, b"");
+        pu.set_zero_register(true);
+        run(&mut pu, &program(source), 100).unwrap();
+        assert_eq!(pu.registers(), &[0, 0, 0, 12]);
+        assert_eq!(ran(4, 4, source).registers(), &[17, 5, 5, 12]);
+    }
+
+    #[test]
+    fn halt_sets_the_exit_code() {
+        // The number of odd numbers among 1 to 9
+        let source = "LI R1 9\nnext: ANDI R2 R1 1\nADD R0 R2 R0\nLOOP R1 next\nHALT R0";
+        assert_eq!(ran(4, 4, source).exit_code(), Some(5));
+        assert_eq!(ran(1, 4, "HALT 3\nHALT 4").exit_code(), Some(3));
+        assert_eq!(ran(1, 4, "HALT").exit_code(), Some(0));
+        assert_eq!(ran(1, 4, "NOP").exit_code(), None);
+    }
+
+    #[test]
+    fn assertions_hold_or_fault_with_both_values() {
+        let source = "LI R1 5\nLI R2 1\nnext: MUL R2 R1 R2\nDEC R1\nJNZ R1 next
+ASSERT_EQ R2 120\nASSERT_EQ R1 0\nLI R3 120\nASSERT_EQ_R R2 R3\nHALT";
+        assert_eq!(ran(4, 4, source).exit_code(), Some(0));
+
+        let failing = source.replace("ASSERT_EQ R2 120", "ASSERT_EQ R2 121");
+        assert_eq!(
+            fault(&mut machine(4, 4, b""), &failing),
+            MdpuError::AssertionFailed {
+                reg: 2,
+                other: None,
+                expected: 121,
+                actual: 120,
+                ip: 5,
+            }
+        );
+        assert_eq!(
+            fault(&mut machine(2, 4, b""), "LI R1 1\nASSERT_EQ_R R0 R1"),
+            MdpuError::AssertionFailed {
+                reg: 0,
+                other: Some(1),
+                expected: 1,
+                actual: 0,
+                ip: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn breakpoint_continues_or_stops_the_run() {
+        let source = "LI R1 3\nfirst: ADD R0 R1 R0\nDEC R1\nJNZ R1 first\nPUSH R0\nBRK\nPOP R2\nADD R0 R2 R0";
+        let pu = ran(4, 16, source);
+        assert_eq!(pu.registers()[0], 12);
+        assert_eq!(pu.stack(), &[]);
+
+        let mut pu = machine(4, 16, b"");
+        pu.set_break_mode(BreakMode::Stop);
+        run(&mut pu, &program(source), 100).unwrap();
+        assert_eq!(pu.registers()[0], 6);
+        assert_eq!(pu.stack(), &[6]);
+        assert_eq!(pu.instruction_pointer(), 6);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn syscalls_read_and_print() {
+        let source = "SYS 3\nJZ R1 missing\nMOV R2 R0\nSYS 3\nJZ R1 missing\nADD R0 R2 R0\nSYS 1
+LI R0 10\nSYS 2\nLI R0 0\nSYS 0\nmissing: LI R0 1\nSYS 0";
+        assert_eq!(printed(16, b"20 22", source), "42\n");
+
+        let mut pu = machine(4, 16, b"20");
+        run(&mut pu, &program(source), 100).unwrap();
+        assert_eq!(pu.exit_code(), Some(1));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn print_writes_a_line_per_number() {
+        let source = "LI R0 0\nLI R1 1\nLI R3 10\nnext: PRINT R0\nADD R0 R1 R2\nMOV R0 R1\nMOV R1 R2\nLOOP R3 next";
+        assert_eq!(
+            printed(16, b"", source),
+            "0\n1\n1\n2\n3\n5\n8\n13\n21\n34\n"
+        );
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn printc_writes_characters() {
+        let source = ".data 0\ngreeting: .asciiz \"Hello, world\"\nLI R1 greeting
+next: LOADR R0 R1\nJZ R0 done\nPRINTC R0\nINC R1\nJMP next\ndone: LI R0 '\\n'\nPRINTC R0";
+        assert_eq!(printed(32, b"", source), "Hello, world\n");
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn prints_writes_up_to_the_terminating_0() {
+        let source = ".data 0\ngreeting: .asciiz \"Hello, world\\n\"\nfarewell: .asciiz \"Bye\\n\"
+PRINTS greeting\nLI R1 farewell\nPRINTSR R1";
+        assert_eq!(printed(32, b"", source), "Hello, world\nBye\n");
+
+        let mut pu = machine(1, 2, b"");
+        pu.write_memory(0, 'A' as i32).unwrap();
+        pu.write_memory(1, 'B' as i32).unwrap();
+        assert_eq!(
+            fault(&mut pu, "PRINTS 0"),
+            MdpuError::UnterminatedString { addr: 0, ip: 0 }
+        );
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn readc_reads_bytes_until_the_end() {
+        // Letters in the first line of the input
+        let source = "next: READC R0\nLI R1 -1\nJE R0 R1 done\nLI R1 '\\n'\nJE R0 R1 done
+ANDI R0 R0 0xDF\nLI R1 'A'\nCMP R0 R1\nJB next\nLI R1 'Z'\nCMP R1 R0\nJB next
+INC R2\nJMP next\ndone: PRINT R2";
+        assert_eq!(printed(16, b"Hello, world 42", source), "10\n");
+        assert_eq!(printed(16, b"ab\ncd", source), "2\n");
+        assert_eq!(printed(16, b"", source), "0\n");
+    }
+
+    #[test]
+    fn seeded_rolls_repeat() {
+        let source = "LI R1 6\nLI R2 10\nnext: RANDR R0 R1\nINC R0\nPUSH R0\nLOOP R2 next";
+        let rolls = |seed| {
+            let mut pu = machine(3, 16, b"");
+            pu.set_seed(seed);
+            run(&mut pu, &program(source), 1000).unwrap();
+            pu.stack().to_vec()
+        };
+        let first = rolls(7);
+        assert_eq!(first.len(), 10);
+        assert!(first.iter().all(|roll| (1..=6).contains(roll)), "{first:?}");
+        assert_eq!(rolls(7), first);
+    }
+
+    #[test]
+    fn rdcycle_counts_the_instructions_between() {
+        let source = "RDCYCLE R2\nLI R1 5\nnext: INC R3\nLOOP R1 next\nRDCYCLE R0\nSUB R0 R2 R0\nASSERT_EQ R0 12";
+        assert_eq!(ran(4, 4, source).registers()[0], 12);
+    }
+
+    #[test]
+    fn rdpc_gives_a_return_address() {
+        let source = "LI R0 5\nRDPC R3\nADDI R3 R3 2\nJMP double\nLI R1 1\nHALT
+double: ADD R0 R0 R0\nJMPR R3";
+        let pu = ran(4, 4, source);
+        assert_eq!(pu.registers()[..2], [10, 1]);
+        assert_eq!(pu.registers()[3], 4);
+    }
+
+    #[test]
+    fn wrsp_drops_what_was_pushed_since_rdsp() {
+        let source = "LI R0 1\nPUSH R0\nRDSP R1\nPUSHI 2\nPUSHI 3\nPUSHI 4\nWRSP R1";
+        let pu = ran(4, 16, source);
+        assert_eq!(pu.registers()[1], 14);
+        assert_eq!(pu.stack(), &[1]);
+        assert_eq!(pu.stack_pointer, 14);
+    }
+}
@@ -0,0 +1,80 @@
+// Source lines of a program's instructions, so runtime output can point back at them
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+// Where an instruction was written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourceLocation<'a> {
+    pub file: Option<&'a str>, // None for source assembled from a string
+    pub line: usize,
+}
+
+// `file:line`, or `line N` for source without a file name, as in assembler messages
+impl fmt::Display for SourceLocation<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.file {
+            Some(file) => write!(f, "{}:{}", file, self.line),
+            None => write!(f, "line {}", self.line),
+        }
+    }
+}
+
+// Source line of every instruction, empty for programs that were not assembled from
+// source or had it stripped
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DebugInfo {
+    files: Vec<String>,                 // Every file named by `lines`, each once
+    lines: Vec<(Option<usize>, usize)>, // Index into `files` and line number, by instruction
+}
+
+impl DebugInfo {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Record where the next instruction was written
+    pub fn push(&mut self, file: Option<&str>, line: usize) {
+        let file = file.map(|name| self.file_index(name));
+        self.lines.push((file, line));
+    }
+
+    fn file_index(&mut self, name: &str) -> usize {
+        if let Some(index) = self.files.iter().position(|known| known == name) {
+            return index;
+        }
+        self.files.push(String::from(name));
+        self.files.len() - 1
+    }
+
+    // Where the instruction at `ip` was written, if known
+    pub fn location(&self, ip: usize) -> Option<SourceLocation<'_>> {
+        let &(file, line) = self.lines.get(ip)?;
+        Some(SourceLocation {
+            file: file.map(|index| self.files[index].as_str()),
+            line,
+        })
+    }
+
+    // Number of instructions with a known location
+    pub fn len(&self) -> usize {
+        self.lines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+
+    pub fn files(&self) -> &[String] {
+        &self.files
+    }
+
+    pub(crate) fn lines(&self) -> &[(Option<usize>, usize)] {
+        &self.lines
+    }
+
+    // Table from its parts, which must only name files in `files`
+    pub(crate) fn from_parts(files: Vec<String>, lines: Vec<(Option<usize>, usize)>) -> Self {
+        DebugInfo { files, lines }
+    }
+}
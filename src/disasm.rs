@@ -0,0 +1,131 @@
+// Assembly text from assembled instructions, for reading binary programs
+//
+// Every line assembles back to the instruction it came from. The instruction's address
+// is written in a comment at the end of its line, as a line of its own would assemble
+// to a NOP and shift every address after it.
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+use crate::isa::{Instruction, Operand};
+use crate::program::Program;
+use crate::symbols::SymbolTable;
+
+// Width of the instruction column, before the address comment
+const INSTRUCTION_WIDTH: usize = 24;
+
+// One line per instruction, with jump targets as addresses
+pub fn disassemble(instructions: &[Instruction]) -> String {
+    render(instructions, &SymbolTable::default())
+}
+
+// Like `disassemble`, but with the program's entry and data image as directives and its
+// labels at their addresses, jump targets written as `label (addr)` in the comment
+pub fn disassemble_program(program: &Program) -> String {
+    let labels = program.symbols();
+    let mut out = String::new();
+    if program.entry() != 0 {
+        let entry = target(labels, program.len(), program.entry());
+        let _ = writeln!(out, ".entry {}", entry);
+    }
+    for (addr, values) in program
+        .data()
+        .iter()
+        .filter(|(_, values)| !values.is_empty())
+    {
+        // One `.word` line per block, as each line assembles to a block of its own
+        let words: Vec<String> = values.iter().map(i32::to_string).collect();
+        let _ = writeln!(out, ".data {}\n.word {}", addr, words.join(", "));
+    }
+    out.push_str(&render(program, labels));
+    out
+}
+
+fn render(instructions: &[Instruction], labels: &SymbolTable) -> String {
+    let len = instructions.len();
+    let width = (0..len)
+        .filter_map(|ip| label(labels, len, ip))
+        .map(|name| name.len() + 2)
+        .max()
+        .unwrap_or(0);
+
+    let mut out = String::new();
+    for (ip, instr) in instructions.iter().enumerate() {
+        let defined = label(labels, len, ip).map_or(String::new(), |name| name.to_string() + ":");
+        let code = code(instr, ip, labels, len);
+        let mut line = format!("{:<width$}{:<INSTRUCTION_WIDTH$} ; {}", defined, code, ip);
+        if let Some((name, addr)) = instr
+            .jump_target(ip)
+            .and_then(|addr| Some((label(labels, len, addr)?, addr)))
+        {
+            let _ = write!(line, " -> {} ({})", name, addr);
+        }
+        out.push_str(line.trim_end());
+        out.push('\n');
+    }
+    out
+}
+
+// Assembly for one instruction. Fields its opcode does not use are only kept by the
+// legacy five-field form, so that form is written when any of them is set.
+fn code(instr: &Instruction, ip: usize, labels: &SymbolTable, len: usize) -> String {
+    if !fits_layout(instr) {
+        return format!(
+            "{} R{} R{} R{} {} {}",
+            instr.opcode.mnemonic(),
+            instr.reg1,
+            instr.reg2,
+            instr.reg3,
+            instr.addr,
+            instr.immediate
+        );
+    }
+    let mut code = instr.to_string();
+    // A label assembles to an absolute target, except for the relative branches
+    let keeps_form =
+        !instr.addr.is_relative() || instr.opcode.operands().contains(&Operand::Offset);
+    if let Some(name) = instr
+        .jump_target(ip)
+        .filter(|_| keeps_form)
+        .and_then(|addr| label(labels, len, addr))
+    {
+        // The target is always the last operand
+        code.truncate(code.rfind(' ').expect("a jump has a target operand") + 1);
+        code.push_str(name);
+    }
+    code
+}
+
+// Whether the instruction has nothing set outside the operands its opcode uses, and its
+// addr in the form its operand is written in
+fn fits_layout(instr: &Instruction) -> bool {
+    let mut canonical = Instruction::new(instr.opcode);
+    for operand in instr.opcode.operands() {
+        match operand {
+            Operand::Reg1 => canonical.reg1 = instr.reg1,
+            Operand::Reg2 => canonical.reg2 = instr.reg2,
+            Operand::Reg3 => canonical.reg3 = instr.reg3,
+            Operand::Addr | Operand::Target => canonical.addr = instr.addr,
+            // Only written relative for an offset and absolute for a width or register
+            Operand::Offset | Operand::Width | Operand::Reg4
+                if instr.addr.is_relative() == (*operand == Operand::Offset) =>
+            {
+                canonical.addr = instr.addr
+            }
+            Operand::Offset | Operand::Width | Operand::Reg4 => return false,
+            Operand::Imm => canonical.immediate = instr.immediate,
+        }
+    }
+    canonical == *instr
+}
+
+// Label written at `addr`. Only addresses that hold an instruction get one, since a
+// label after the last instruction would need a line of its own.
+fn label(labels: &SymbolTable, len: usize, addr: usize) -> Option<&str> {
+    (addr < len).then(|| labels.label_at(addr)).flatten()
+}
+
+fn target(labels: &SymbolTable, len: usize, addr: usize) -> String {
+    label(labels, len, addr).map_or_else(|| addr.to_string(), ToString::to_string)
+}
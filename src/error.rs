@@ -0,0 +1,897 @@
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::{String, ToString};
+use core::error::Error;
+use core::fmt;
+
+use crate::isa::{AddrOperand, Opcode, Operand};
+
+// Errors that can occur while executing a program
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MdpuError {
+    RegisterOutOfBounds {
+        reg: usize,
+        ip: usize,
+    },
+    MemoryOutOfBounds {
+        addr: usize,
+        ip: usize,
+    },
+    // LOADR or STORER
+    AddressRegisterOutOfBounds {
+        reg: usize,
+        value: i32,
+        ip: usize,
+    },
+    // LOADO or STOREO, R + imm
+    OffsetAddressOutOfBounds {
+        reg: usize,
+        addr: i64,
+        ip: usize,
+    },
+    // JMPR outside the program
+    JumpOutOfBounds {
+        reg: usize,
+        value: i32,
+        len: usize,
+        ip: usize,
+    },
+    DivisionByZero {
+        reg: usize,
+        ip: usize,
+    },
+    // Arithmetic that overflowed with overflow trapping on. Unary opcodes like NEG
+    // have no right-hand operand.
+    ArithmeticOverflow {
+        opcode: Opcode,
+        lhs: i32,
+        rhs: Option<i32>,
+        ip: usize,
+    },
+    // DIVI with an immediate of 0
+    DivisionByZeroImmediate {
+        ip: usize,
+    },
+    StackOverflow {
+        reg: usize,
+        ip: usize,
+    },
+    // POP, PEEK, DROP or RET needing more values than the `depth` the stack holds above
+    // its base at address `base`
+    StackUnderflow {
+        opcode: Opcode,
+        needed: usize,
+        depth: usize,
+        base: usize,
+        ip: usize,
+    },
+    // PUSHI or PUSHA without room for everything it pushes, so nothing was pushed
+    StackFull {
+        opcode: Opcode,
+        needed: usize,
+        free: usize,
+        ip: usize,
+    },
+    // POPA with fewer values on the stack than there are registers, so nothing was popped
+    StackShort {
+        opcode: Opcode,
+        needed: usize,
+        held: usize,
+        ip: usize,
+    },
+    // ASSERT_EQ or ASSERT_EQ_R that did not hold. `other` is the register ASSERT_EQ_R
+    // compared against, None for an immediate.
+    AssertionFailed {
+        reg: usize,
+        other: Option<usize>,
+        expected: i32,
+        actual: i32,
+        ip: usize,
+    },
+    // SYS with a number its handler does not know
+    UnknownSyscall {
+        number: i32,
+        ip: usize,
+    },
+    // PRINTS or PRINTSR of a string with no 0 after it in memory
+    UnterminatedString {
+        addr: usize,
+        ip: usize,
+    },
+    // READI with nothing but whitespace left in the input
+    EndOfInput {
+        reg: usize,
+        ip: usize,
+    },
+    // READI of a word that is not a number that fits in a register
+    InvalidInput {
+        reg: usize,
+        word: String,
+        ip: usize,
+    },
+    // RANDR with a bound in reg that is not positive, so there is nothing to draw from
+    EmptyRandomRange {
+        reg: usize,
+        max: i32,
+        ip: usize,
+    },
+    // WRSP of an address outside the stack region
+    StackPointerOutOfBounds {
+        reg: usize,
+        value: i32,
+        ip: usize,
+    },
+    // No room for the return address of a CALL
+    CallStackOverflow {
+        ip: usize,
+    },
+    // ENTER without room for the frame
+    FrameOverflow {
+        slots: i32,
+        ip: usize,
+    },
+    // LEAVE with no frame from ENTER to leave
+    NoFrame {
+        ip: usize,
+    },
+    // LOADF or STOREF outside memory
+    FrameOutOfBounds {
+        offset: i32,
+        ip: usize,
+    },
+    // Relative jump target outside the program
+    BranchOutOfBounds {
+        offset: i32,
+        ip: usize,
+    },
+    // Relative addr where only an absolute one means anything, as for the memory address
+    // of LOAD or the width of EXTR
+    RelativeAddress {
+        offset: i32,
+        ip: usize,
+    },
+    InstructionLimitExceeded {
+        limit: usize,
+    },
+}
+
+impl fmt::Display for MdpuError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MdpuError::AddressRegisterOutOfBounds { reg, value, ip } => write!(
+                f,
+                "Memory address out of bounds: R{} holds {} at instruction {}",
+                reg, value, ip
+            ),
+            MdpuError::JumpOutOfBounds {
+                reg,
+                value,
+                len,
+                ip,
+            } => write!(
+                f,
+                "Jump target out of bounds: R{} holds {} but the program has {} instruction(s), at instruction {}",
+                reg, value, len, ip
+            ),
+            MdpuError::BranchOutOfBounds { offset, ip } => write!(
+                f,
+                "Branch target out of bounds: {} at instruction {} lands at {}",
+                AddrOperand::Relative(*offset),
+                ip,
+                *ip as i64 + 1 + i64::from(*offset)
+            ),
+            MdpuError::RelativeAddress { offset, ip } => write!(
+                f,
+                "Relative address {} at instruction {}, which needs an absolute one",
+                AddrOperand::Relative(*offset),
+                ip
+            ),
+            MdpuError::OffsetAddressOutOfBounds { reg, addr, ip } => write!(
+                f,
+                "Memory address out of bounds: R{} plus its offset is {} at instruction {}",
+                reg, addr, ip
+            ),
+            MdpuError::RegisterOutOfBounds { reg, ip } => {
+                write!(
+                    f,
+                    "Register index out of bounds: R{} at instruction {}",
+                    reg, ip
+                )
+            }
+            MdpuError::MemoryOutOfBounds { addr, ip } => {
+                write!(
+                    f,
+                    "Memory address out of bounds: {} at instruction {}",
+                    addr, ip
+                )
+            }
+            MdpuError::DivisionByZero { reg, ip } => {
+                write!(f, "Division by zero on R{} at instruction {}", reg, ip)
+            }
+            MdpuError::DivisionByZeroImmediate { ip } => {
+                write!(f, "Division by zero on DIVI 0 at instruction {}", ip)
+            }
+            MdpuError::ArithmeticOverflow {
+                opcode,
+                lhs,
+                rhs,
+                ip,
+            } => {
+                write!(f, "Arithmetic overflow: {} of {}", opcode.mnemonic(), lhs)?;
+                if let Some(rhs) = rhs {
+                    write!(f, " and {}", rhs)?;
+                }
+                write!(f, " at instruction {}", ip)
+            }
+            MdpuError::StackOverflow { reg, ip } => {
+                write!(f, "Stack overflow on R{} at instruction {}", reg, ip)
+            }
+            MdpuError::StackUnderflow {
+                opcode,
+                needed,
+                depth,
+                base,
+                ip,
+            } => write!(
+                f,
+                "Stack underflow on {} at instruction {}: needs {} value(s) but the stack holds {} above its base at {}",
+                opcode.mnemonic(),
+                ip,
+                needed,
+                depth,
+                base
+            ),
+            MdpuError::StackFull {
+                opcode,
+                needed,
+                free,
+                ip,
+            } => write!(
+                f,
+                "Stack overflow on {} at instruction {}: needs {} cell(s) but {} are free",
+                opcode.mnemonic(),
+                ip,
+                needed,
+                free
+            ),
+            MdpuError::StackShort {
+                opcode,
+                needed,
+                held,
+                ip,
+            } => write!(
+                f,
+                "Stack underflow on {} at instruction {}: needs {} value(s) but the stack holds {}",
+                opcode.mnemonic(),
+                ip,
+                needed,
+                held
+            ),
+            MdpuError::AssertionFailed {
+                reg,
+                other,
+                expected,
+                actual,
+                ip,
+            } => {
+                write!(
+                    f,
+                    "Assertion failed at instruction {}: R{} holds {}, expected {}",
+                    ip, reg, actual, expected
+                )?;
+                if let Some(other) = other {
+                    write!(f, " from R{}", other)?;
+                }
+                Ok(())
+            }
+            MdpuError::UnknownSyscall { number, ip } => {
+                write!(f, "Unknown syscall {} at instruction {}", number, ip)
+            }
+            MdpuError::UnterminatedString { addr, ip } => write!(
+                f,
+                "Unterminated string: no 0 between address {} and the end of memory at instruction {}",
+                addr, ip
+            ),
+            MdpuError::EndOfInput { reg, ip } => write!(
+                f,
+                "End of input reading into R{} at instruction {}",
+                reg, ip
+            ),
+            MdpuError::InvalidInput { reg, word, ip } => write!(
+                f,
+                "Invalid input reading into R{}: {:?} is not a number at instruction {}",
+                reg, word, ip
+            ),
+            MdpuError::EmptyRandomRange { reg, max, ip } => write!(
+                f,
+                "Empty random range: R{} holds {}, which is not positive, at instruction {}",
+                reg, max, ip
+            ),
+            MdpuError::StackPointerOutOfBounds { reg, value, ip } => write!(
+                f,
+                "Stack pointer out of bounds: R{} holds {}, outside the stack, at instruction {}",
+                reg, value, ip
+            ),
+            MdpuError::CallStackOverflow { ip } => {
+                write!(f, "Stack overflow on CALL at instruction {}", ip)
+            }
+            MdpuError::FrameOverflow { slots, ip } => write!(
+                f,
+                "Stack overflow on ENTER {} at instruction {}: no room for the frame",
+                slots, ip
+            ),
+            MdpuError::NoFrame { ip } => {
+                write!(f, "LEAVE at instruction {} has no frame to leave", ip)
+            }
+            MdpuError::FrameOutOfBounds { offset, ip } => write!(
+                f,
+                "Frame offset {} is outside memory at instruction {}",
+                offset, ip
+            ),
+            MdpuError::InstructionLimitExceeded { limit } => write!(
+                f,
+                "Maximum instruction count of {} exceeded, possible infinite loop",
+                limit
+            ),
+        }
+    }
+}
+
+impl MdpuError {
+    // Address of the faulting instruction, for every fault but the instruction limit
+    pub fn ip(&self) -> Option<usize> {
+        match self {
+            MdpuError::RegisterOutOfBounds { ip, .. }
+            | MdpuError::MemoryOutOfBounds { ip, .. }
+            | MdpuError::AddressRegisterOutOfBounds { ip, .. }
+            | MdpuError::OffsetAddressOutOfBounds { ip, .. }
+            | MdpuError::JumpOutOfBounds { ip, .. }
+            | MdpuError::BranchOutOfBounds { ip, .. }
+            | MdpuError::RelativeAddress { ip, .. }
+            | MdpuError::DivisionByZero { ip, .. }
+            | MdpuError::DivisionByZeroImmediate { ip }
+            | MdpuError::ArithmeticOverflow { ip, .. }
+            | MdpuError::StackOverflow { ip, .. }
+            | MdpuError::StackUnderflow { ip, .. }
+            | MdpuError::StackFull { ip, .. }
+            | MdpuError::StackShort { ip, .. }
+            | MdpuError::AssertionFailed { ip, .. }
+            | MdpuError::UnknownSyscall { ip, .. }
+            | MdpuError::UnterminatedString { ip, .. }
+            | MdpuError::EndOfInput { ip, .. }
+            | MdpuError::InvalidInput { ip, .. }
+            | MdpuError::EmptyRandomRange { ip, .. }
+            | MdpuError::StackPointerOutOfBounds { ip, .. }
+            | MdpuError::CallStackOverflow { ip }
+            | MdpuError::FrameOverflow { ip, .. }
+            | MdpuError::NoFrame { ip }
+            | MdpuError::FrameOutOfBounds { ip, .. } => Some(*ip),
+            MdpuError::InstructionLimitExceeded { .. } => None,
+        }
+    }
+
+    // Register the faulting instruction was using, if the fault concerns one
+    pub fn register(&self) -> Option<usize> {
+        match self {
+            MdpuError::RegisterOutOfBounds { reg, .. }
+            | MdpuError::AddressRegisterOutOfBounds { reg, .. }
+            | MdpuError::OffsetAddressOutOfBounds { reg, .. }
+            | MdpuError::JumpOutOfBounds { reg, .. }
+            | MdpuError::DivisionByZero { reg, .. }
+            | MdpuError::StackOverflow { reg, .. }
+            | MdpuError::AssertionFailed { reg, .. }
+            | MdpuError::EndOfInput { reg, .. }
+            | MdpuError::InvalidInput { reg, .. }
+            | MdpuError::EmptyRandomRange { reg, .. }
+            | MdpuError::StackPointerOutOfBounds { reg, .. } => Some(*reg),
+            MdpuError::MemoryOutOfBounds { .. }
+            | MdpuError::DivisionByZeroImmediate { .. }
+            | MdpuError::ArithmeticOverflow { .. }
+            | MdpuError::StackUnderflow { .. }
+            | MdpuError::StackFull { .. }
+            | MdpuError::StackShort { .. }
+            | MdpuError::UnknownSyscall { .. }
+            | MdpuError::UnterminatedString { .. }
+            | MdpuError::CallStackOverflow { .. }
+            | MdpuError::FrameOverflow { .. }
+            | MdpuError::BranchOutOfBounds { .. }
+            | MdpuError::RelativeAddress { .. }
+            | MdpuError::NoFrame { .. }
+            | MdpuError::FrameOutOfBounds { .. }
+            | MdpuError::InstructionLimitExceeded { .. } => None,
+        }
+    }
+}
+
+impl Error for MdpuError {}
+
+// Errors reported when a processing unit configuration is invalid
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BuildError {
+    ZeroMemory,
+    StackTooLarge {
+        stack_size: usize,
+        memory_size: usize,
+    },
+    RegisterOutOfBounds {
+        reg: usize,
+        registers: usize,
+    },
+    MemoryOutOfBounds {
+        addr: usize,
+        len: usize,
+        memory_size: usize,
+    },
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::ZeroMemory => write!(f, "Memory size must be at least one cell"),
+            BuildError::StackTooLarge {
+                stack_size,
+                memory_size,
+            } => write!(
+                f,
+                "Stack size {} must be smaller than the memory size {}",
+                stack_size, memory_size
+            ),
+            BuildError::RegisterOutOfBounds { reg, registers } => write!(
+                f,
+                "Initial value for R{} is outside the {} available registers",
+                reg, registers
+            ),
+            BuildError::MemoryOutOfBounds {
+                addr,
+                len,
+                memory_size,
+            } => write!(
+                f,
+                "Initial memory block of {} values at address {} does not fit in {} cells",
+                len, addr, memory_size
+            ),
+        }
+    }
+}
+
+impl Error for BuildError {}
+
+// Operand that does not fit the machine a program is validated against, or a bit field
+// that does not fit in a register
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValidationError {
+    RegisterOutOfBounds {
+        ip: usize,
+        operand: Operand, // Which register operand holds `reg`
+        reg: usize,
+        registers: usize,
+    },
+    MemoryOutOfBounds {
+        ip: usize,
+        addr: usize,
+        memory_size: usize,
+    },
+    JumpOutOfBounds {
+        ip: usize,
+        target: usize,
+        len: usize, // Number of instructions in the program
+    },
+    BranchOutOfBounds {
+        ip: usize,
+        operand: Operand, // Target or Offset
+        offset: i32,      // From the instruction after `ip`
+        len: usize,
+    },
+    // A memory address, bit field width or fourth register that is relative
+    RelativeAddress {
+        ip: usize,
+        operand: Operand, // Addr, Width or Reg4
+        offset: i32,
+    },
+    BitFieldOutOfRange {
+        ip: usize,
+        pos: i32,
+        width: usize,
+    },
+    // An opcode that writes two results given one register for both, see
+    // `Opcode::distinct_registers`
+    AliasedRegisters {
+        ip: usize,
+        opcode: Opcode,
+        reg: usize,
+    },
+}
+
+impl ValidationError {
+    // Address of the offending instruction
+    pub fn ip(&self) -> usize {
+        match self {
+            ValidationError::RegisterOutOfBounds { ip, .. }
+            | ValidationError::MemoryOutOfBounds { ip, .. }
+            | ValidationError::JumpOutOfBounds { ip, .. }
+            | ValidationError::BranchOutOfBounds { ip, .. }
+            | ValidationError::BitFieldOutOfRange { ip, .. }
+            | ValidationError::RelativeAddress { ip, .. }
+            | ValidationError::AliasedRegisters { ip, .. } => *ip,
+        }
+    }
+
+    // Operand of the instruction that is out of bounds
+    pub fn operand(&self) -> Operand {
+        match self {
+            ValidationError::RegisterOutOfBounds { operand, .. }
+            | ValidationError::BranchOutOfBounds { operand, .. }
+            | ValidationError::RelativeAddress { operand, .. } => *operand,
+            ValidationError::MemoryOutOfBounds { .. } => Operand::Addr,
+            ValidationError::JumpOutOfBounds { .. } => Operand::Target,
+            ValidationError::BitFieldOutOfRange { .. } => Operand::Width,
+            ValidationError::AliasedRegisters { opcode, .. } => opcode
+                .distinct_registers()
+                .map_or(Operand::Reg2, |(_, second)| second),
+        }
+    }
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationError::RegisterOutOfBounds {
+                ip, reg, registers, ..
+            } => write!(
+                f,
+                "Register R{} at instruction {} is outside the {} available registers",
+                reg, ip, registers
+            ),
+            ValidationError::MemoryOutOfBounds {
+                ip,
+                addr,
+                memory_size,
+            } => write!(
+                f,
+                "Memory address {} at instruction {} is outside the {} memory cells",
+                addr, ip, memory_size
+            ),
+            ValidationError::JumpOutOfBounds { ip, target, len } => write!(
+                f,
+                "Jump target {} at instruction {} is past the end of the {} instruction program",
+                target, ip, len
+            ),
+            ValidationError::BranchOutOfBounds {
+                ip, offset, len, ..
+            } => write!(
+                f,
+                "Branch target {} at instruction {} lands at {}, outside the {} instruction program",
+                AddrOperand::Relative(*offset),
+                ip,
+                *ip as i64 + 1 + i64::from(*offset),
+                len
+            ),
+            ValidationError::BitFieldOutOfRange { ip, pos, width } => write!(
+                f,
+                "Bit field of {} bits from bit {} at instruction {} does not fit in 32 bits",
+                width, pos, ip
+            ),
+            ValidationError::RelativeAddress { ip, offset, .. } => write!(
+                f,
+                "Address {} at instruction {} is relative, but only a jump target may be",
+                AddrOperand::Relative(*offset),
+                ip
+            ),
+            ValidationError::AliasedRegisters { ip, opcode, reg } => write!(
+                f,
+                "{} at instruction {} writes both of its results to R{}, which needs two registers",
+                opcode.mnemonic(),
+                ip,
+                reg
+            ),
+        }
+    }
+}
+
+impl Error for ValidationError {}
+
+// Error reported when a binary program image cannot be decoded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BinaryError {
+    BadMagic,
+    UnsupportedVersion {
+        version: u16,
+    },
+    ChecksumMismatch {
+        expected: u32, // From the header
+        actual: u32,   // Of the bytes that follow it
+    },
+    Truncated {
+        section: &'static str, // The image ends inside this section
+    },
+    ValueTooLarge {
+        section: &'static str, // A number does not fit this platform's usize
+    },
+    UnknownOpcode {
+        code: u8,
+        ip: usize,
+    },
+    UnknownAddrForm {
+        form: usize, // Neither 0 for absolute nor 1 for relative
+        ip: usize,
+    },
+    TrailingBytes {
+        len: usize,
+    },
+    CountMismatch {
+        section: &'static str,
+        header: usize, // What the header says the section holds
+        found: usize,
+    },
+    EntryOutOfBounds {
+        entry: usize,
+        len: usize,
+    },
+    DebugLinesMismatch {
+        lines: usize, // Source lines in the debug section
+        instructions: usize,
+    },
+    InvalidFileName, // A file name in the debug section is not UTF-8
+    FileIndexOutOfBounds {
+        index: usize, // As stored, one more than the position in the file list
+        files: usize,
+    },
+}
+
+impl fmt::Display for BinaryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BinaryError::BadMagic => write!(f, "Not an mdpu binary program"),
+            BinaryError::UnsupportedVersion { version } => write!(
+                f,
+                "Binary program format version {} is not supported, expected {}",
+                version,
+                crate::binary::FORMAT_VERSION
+            ),
+            BinaryError::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "Binary program is corrupted: its checksum is {:#010x}, but its header says {:#010x}",
+                actual, expected
+            ),
+            BinaryError::Truncated { section } => {
+                write!(f, "Binary program is truncated in the {} section", section)
+            }
+            BinaryError::ValueTooLarge { section } => write!(
+                f,
+                "Value in the {} section is too large for this platform",
+                section
+            ),
+            BinaryError::UnknownOpcode { code, ip } => {
+                write!(f, "Unknown opcode number {} at instruction {}", code, ip)
+            }
+            BinaryError::UnknownAddrForm { form, ip } => {
+                write!(f, "Unknown addr form {} at instruction {}", form, ip)
+            }
+            BinaryError::TrailingBytes { len } => {
+                write!(f, "Binary program has {} unexpected bytes at its end", len)
+            }
+            BinaryError::CountMismatch {
+                section,
+                header,
+                found,
+            } => write!(
+                f,
+                "Binary program header promises {} values in the {} section, found {}",
+                header, section, found
+            ),
+            BinaryError::EntryOutOfBounds { entry, len } => write!(
+                f,
+                "Entry address {} is outside the {} instruction program",
+                entry, len
+            ),
+            BinaryError::DebugLinesMismatch {
+                lines,
+                instructions,
+            } => write!(
+                f,
+                "Debug section has {} source lines for {} instructions",
+                lines, instructions
+            ),
+            BinaryError::InvalidFileName => {
+                write!(f, "File name in the debug section is not valid UTF-8")
+            }
+            BinaryError::FileIndexOutOfBounds { index, files } => write!(
+                f,
+                "Debug section refers to file {} of {}",
+                index, files
+            ),
+        }
+    }
+}
+
+impl Error for BinaryError {}
+
+// Error returned when an instruction does not fit the 64-bit word of `Instruction::encode`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EncodeError {
+    FieldTooWide {
+        field: &'static str, // Instruction field, such as `reg1` or `immediate`
+        value: i128,
+        bits: u32,
+    },
+    // An addr relative when the opcode's field in the word is absolute, or the other way
+    // around, see `Instruction::encode`
+    AddrForm {
+        opcode: Opcode,
+        addr: AddrOperand,
+    },
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncodeError::FieldTooWide { field, value, bits } => write!(
+                f,
+                "Value {} of {} does not fit its {}-bit field",
+                value, field, bits
+            ),
+            EncodeError::AddrForm { opcode, addr } => write!(
+                f,
+                "{} cannot hold the {} address {} in an encoded word",
+                opcode.mnemonic(),
+                if addr.is_relative() {
+                    "relative"
+                } else {
+                    "absolute"
+                },
+                addr
+            ),
+        }
+    }
+}
+
+impl Error for EncodeError {}
+
+// Error returned when a 64-bit word is not an instruction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    UnknownOpcode { code: u8 },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::UnknownOpcode { code } => write!(f, "Unknown opcode number {}", code),
+        }
+    }
+}
+
+impl Error for DecodeError {}
+
+// Error reported when program text cannot be assembled, pointing at the offending token
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub file: Option<String>,
+    pub line: usize,   // 1-based
+    pub column: usize, // 1-based, counted in characters
+    pub width: usize,  // Characters covered by the offending token
+    pub source_line: String,
+    pub message: String,
+    pub note: Option<Box<ParseError>>, // Where the offending line came from, e.g. a macro invocation
+}
+
+impl ParseError {
+    // Render the error like a compiler diagnostic, with a caret under the offending token
+    pub fn render(&self) -> String {
+        self.render_as("error")
+    }
+
+    fn render_as(&self, kind: &str) -> String {
+        let gutter = " ".repeat(self.line.to_string().len());
+        // Keep tabs so the caret lines up with the source line however tabs are displayed
+        let indent: String = self
+            .source_line
+            .chars()
+            .take(self.column.saturating_sub(1))
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let mut text = format!(
+            "{}: {}\n{}--> {}\n{} |\n{} | {}\n{} | {}{}\n",
+            kind,
+            self.message,
+            gutter,
+            self.location(),
+            gutter,
+            self.line,
+            self.source_line,
+            gutter,
+            indent,
+            "^".repeat(self.width.max(1))
+        );
+        if let Some(note) = &self.note {
+            text.push_str(&note.render_as("note"));
+        }
+        text
+    }
+
+    // Attach a note after any the error already has
+    pub fn add_note(&mut self, note: ParseError) {
+        let mut last = &mut self.note;
+        while let Some(next) = last {
+            last = &mut next.note;
+        }
+        *last = Some(Box::new(note));
+    }
+
+    // The error without its notes, as `file:line:column: message`
+    fn fmt_message(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "{}:", file)?,
+            None => write!(f, "line ")?,
+        }
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+
+    // `file:line:column`, or `line:column` when the source has no file name
+    fn location(&self) -> String {
+        match &self.file {
+            Some(file) => format!("{}:{}:{}", file, self.line, self.column),
+            None => format!("{}:{}", self.line, self.column),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_message(f)?;
+        let mut note = &self.note;
+        while let Some(next) = note {
+            write!(f, " (")?;
+            next.fmt_message(f)?;
+            write!(f, ")")?;
+            note = &next.note;
+        }
+        Ok(())
+    }
+}
+
+impl Error for ParseError {}
+
+// Error returned when a program file cannot be loaded
+#[cfg(feature = "std")]
+#[derive(Debug)]
+pub enum LoadError {
+    Io(std::io::Error),
+    Parse(Vec<ParseError>), // Every problem found in the file, in line order
+    Binary(BinaryError),
+    Invalid(Vec<ValidationError>), // Operands of a binary program that do not fit the machine
+}
+
+#[cfg(feature = "std")]
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Io(err) => write!(f, "{}", err),
+            LoadError::Parse(errors) => write_lines(f, errors),
+            LoadError::Binary(err) => write!(f, "{}", err),
+            LoadError::Invalid(errors) => write_lines(f, errors),
+        }
+    }
+}
+
+// One error per line
+#[cfg(feature = "std")]
+fn write_lines(f: &mut fmt::Formatter<'_>, errors: &[impl fmt::Display]) -> fmt::Result {
+    for (index, err) in errors.iter().enumerate() {
+        if index > 0 {
+            writeln!(f)?;
+        }
+        write!(f, "{}", err)?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "std")]
+impl Error for LoadError {}
+
+#[cfg(feature = "std")]
+impl From<std::io::Error> for LoadError {
+    fn from(err: std::io::Error) -> Self {
+        LoadError::Io(err)
+    }
+}
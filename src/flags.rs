@@ -0,0 +1,133 @@
+// Condition flags set by CMP, TEST and the arithmetic opcodes
+use core::fmt;
+
+// Flags word of a processing unit, one bit per condition
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Flags(u8);
+
+impl Flags {
+    pub const ZERO: u8 = 1; // The result was 0
+    pub const NEGATIVE: u8 = 2; // The result's sign bit was set
+    pub const CARRY: u8 = 4; // Unsigned overflow: a carry out of an add or a borrow in a subtract
+    pub const OVERFLOW: u8 = 8; // Signed overflow: the result wrapped around
+
+    pub fn from_bits(bits: u8) -> Self {
+        Flags(bits & (Self::ZERO | Self::NEGATIVE | Self::CARRY | Self::OVERFLOW))
+    }
+
+    pub fn bits(self) -> u8 {
+        self.0
+    }
+
+    pub fn zero(self) -> bool {
+        self.0 & Self::ZERO != 0
+    }
+
+    pub fn negative(self) -> bool {
+        self.0 & Self::NEGATIVE != 0
+    }
+
+    pub fn carry(self) -> bool {
+        self.0 & Self::CARRY != 0
+    }
+
+    pub fn overflow(self) -> bool {
+        self.0 & Self::OVERFLOW != 0
+    }
+
+    // Flags of `result`, with carry and overflow as the operation that produced it found
+    pub(crate) fn of(result: i32, carry: bool, overflow: bool) -> Self {
+        let bit = |set: bool, bit: u8| if set { bit } else { 0 };
+        Flags(
+            bit(result == 0, Self::ZERO)
+                | bit(result < 0, Self::NEGATIVE)
+                | bit(carry, Self::CARRY)
+                | bit(overflow, Self::OVERFLOW),
+        )
+    }
+
+    // a + b, wrapping
+    pub(crate) fn add(a: i32, b: i32) -> (i32, Self) {
+        let (result, overflow) = a.overflowing_add(b);
+        let carry = (a as u32).overflowing_add(b as u32).1;
+        (result, Self::of(result, carry, overflow))
+    }
+
+    // a - b, wrapping, with carry set when b is larger than a as unsigned numbers
+    pub(crate) fn sub(a: i32, b: i32) -> (i32, Self) {
+        let (result, overflow) = a.overflowing_sub(b);
+        let carry = (a as u32) < (b as u32);
+        (result, Self::of(result, carry, overflow))
+    }
+
+    // a + b, clamped to the i32 range, with overflow set when it had to be clamped
+    pub(crate) fn add_saturating(a: i32, b: i32) -> (i32, Self) {
+        let (_, flags) = Self::add(a, b);
+        let result = a.saturating_add(b);
+        (result, Self::of(result, flags.carry(), flags.overflow()))
+    }
+
+    // a - b, clamped like `add_saturating`
+    pub(crate) fn sub_saturating(a: i32, b: i32) -> (i32, Self) {
+        let (_, flags) = Self::sub(a, b);
+        let result = a.saturating_sub(b);
+        (result, Self::of(result, flags.carry(), flags.overflow()))
+    }
+
+    // Flags of comparing a and b as unsigned numbers: zero when they are equal and carry
+    // when a is below b. Negative and overflow only mean something for signed numbers, so
+    // they are clear.
+    pub(crate) fn compare_unsigned(a: i32, b: i32) -> Self {
+        Self::of(i32::from(a != b), (a as u32) < (b as u32), false)
+    }
+
+    // Unsigned a > b after CMP or CMPU, which set carry and zero the same way
+    pub(crate) fn above(self) -> bool {
+        !self.carry() && !self.zero()
+    }
+
+    // Unsigned a < b after CMP or CMPU
+    pub(crate) fn below(self) -> bool {
+        self.carry()
+    }
+
+    // a * b, wrapping, with carry and overflow both set when the product did not fit
+    pub(crate) fn mul(a: i32, b: i32) -> (i32, Self) {
+        let (result, overflow) = a.overflowing_mul(b);
+        (result, Self::of(result, overflow, overflow))
+    }
+
+    // a / b for a nonzero b, wrapping, which only overflows for i32::MIN / -1
+    pub(crate) fn div(a: i32, b: i32) -> (i32, Self) {
+        let (result, overflow) = a.overflowing_div(b);
+        (result, Self::of(result, false, overflow))
+    }
+
+    // a % b for a nonzero b, with overflow set like `div`
+    pub(crate) fn rem(a: i32, b: i32) -> (i32, Self) {
+        let (result, overflow) = a.overflowing_rem(b);
+        (result, Self::of(result, false, overflow))
+    }
+}
+
+// The letters of the set flags, such as `Z C`, or `-` when none is set
+impl fmt::Display for Flags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names = [
+            (Self::ZERO, "Z"),
+            (Self::NEGATIVE, "N"),
+            (Self::CARRY, "C"),
+            (Self::OVERFLOW, "V"),
+        ];
+        let mut set = names.iter().filter(|&&(bit, _)| self.0 & bit != 0);
+        match set.next() {
+            Some((_, name)) => f.write_str(name)?,
+            None => return f.write_str("-"),
+        }
+        for (_, name) in set {
+            write!(f, " {}", name)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,177 @@
+#[cfg(feature = "std")]
+use alloc::format;
+#[cfg(feature = "std")]
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "std")]
+use std::io::Write;
+
+use crate::cpu::ProcessingUnit;
+#[cfg(feature = "std")]
+use crate::debug::DebugInfo;
+use crate::isa::{Instruction, Opcode};
+#[cfg(feature = "std")]
+use crate::symbols::SymbolTable;
+
+// Decision returned by a hook before an instruction executes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookControl {
+    Continue,
+    Stop,
+}
+
+// Instrumentation called around every executed instruction
+pub trait ExecutionHook {
+    fn before(&mut self, _ip: usize, _instr: &Instruction, _pu: &ProcessingUnit) -> HookControl {
+        HookControl::Continue
+    }
+
+    fn after(&mut self, _ip: usize, _instr: &Instruction, _pu: &ProcessingUnit) {}
+}
+
+// Hook that observes nothing, used by plain `run`
+pub(crate) struct NoHook;
+
+impl ExecutionHook for NoHook {}
+
+// Stops execution once another thread raises the stop flag, polled every `interval` instructions
+pub(crate) struct CancelHook<'a> {
+    stop: &'a AtomicBool,
+    interval: usize,
+    seen: usize,
+    pub(crate) cancelled: bool,
+}
+
+impl<'a> CancelHook<'a> {
+    pub(crate) fn new(stop: &'a AtomicBool, interval: usize) -> Self {
+        CancelHook {
+            stop,
+            interval: interval.max(1),
+            seen: 0,
+            cancelled: false,
+        }
+    }
+}
+
+impl ExecutionHook for CancelHook<'_> {
+    fn before(&mut self, _ip: usize, _instr: &Instruction, _pu: &ProcessingUnit) -> HookControl {
+        let due = self.seen.is_multiple_of(self.interval);
+        self.seen = self.seen.wrapping_add(1);
+        if due && self.stop.load(Ordering::Relaxed) {
+            self.cancelled = true;
+            return HookControl::Stop;
+        }
+        HookControl::Continue
+    }
+}
+
+// Counts executed instructions, in total and per opcode
+#[derive(Debug, Default, Clone)]
+pub struct InstructionCounter {
+    pub total: usize,
+    pub per_opcode: Vec<(Opcode, usize)>,
+}
+
+impl InstructionCounter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Number of times the given opcode was executed
+    pub fn count(&self, opcode: Opcode) -> usize {
+        self.per_opcode
+            .iter()
+            .find(|(op, _)| *op == opcode)
+            .map_or(0, |(_, count)| *count)
+    }
+}
+
+impl ExecutionHook for InstructionCounter {
+    fn after(&mut self, _ip: usize, instr: &Instruction, _pu: &ProcessingUnit) {
+        self.total += 1;
+        match self
+            .per_opcode
+            .iter_mut()
+            .find(|(op, _)| *op == instr.opcode)
+        {
+            Some((_, count)) => *count += 1,
+            None => self.per_opcode.push((instr.opcode, 1)),
+        }
+    }
+}
+
+// Writes one line per executed instruction with the resulting registers
+#[cfg(feature = "std")]
+pub struct Tracer<W: Write> {
+    out: W,
+    symbols: SymbolTable, // Names shown next to the addresses and registers they name
+    debug: DebugInfo,     // Source lines shown next to the addresses
+}
+
+#[cfg(feature = "std")]
+impl<W: Write> Tracer<W> {
+    pub fn new(out: W) -> Self {
+        Tracer {
+            out,
+            symbols: SymbolTable::default(),
+            debug: DebugInfo::default(),
+        }
+    }
+
+    // Show label names from `symbols` next to the addresses they point at, and the values
+    // of registers with an alias when an instruction uses them
+    pub fn with_symbols(mut self, symbols: SymbolTable) -> Self {
+        self.symbols = symbols;
+        self
+    }
+
+    // Show the source line of each instruction from `debug`, as in `program.s:23`
+    pub fn with_debug_info(mut self, debug: DebugInfo) -> Self {
+        self.debug = debug;
+        self
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+#[cfg(feature = "std")]
+impl<W: Write> ExecutionHook for Tracer<W> {
+    fn after(&mut self, ip: usize, instr: &Instruction, pu: &ProcessingUnit) {
+        let mut aliases = Vec::new();
+        for reg in instr.registers() {
+            let value = pu.registers().get(reg);
+            if let (Some(name), Some(value)) = (self.symbols.register_alias(reg), value) {
+                let shown = format!("{} = {}", name, value);
+                if !aliases.contains(&shown) {
+                    aliases.push(shown);
+                }
+            }
+        }
+        let aliases = if aliases.is_empty() {
+            String::new()
+        } else {
+            format!(" ({})", aliases.join(", "))
+        };
+
+        let mut at = format!("{:>5}", ip);
+        if let Some(location) = self.debug.location(ip) {
+            at.push_str(&format!(" {}", location));
+        }
+        if let Some(label) = self.symbols.label_at(ip) {
+            at.push_str(&format!(" {}", label));
+        }
+
+        // Tracing is best effort and must not abort execution
+        let _ = writeln!(
+            self.out,
+            "{}: {:?} -> {:?}{}",
+            at,
+            instr.opcode,
+            pu.registers(),
+            aliases
+        );
+    }
+}
@@ -0,0 +1,53 @@
+// Input a program reads with READI, READC and the reading syscalls
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::iter::Peekable;
+#[cfg(feature = "std")]
+use std::io::{self, BufRead, BufReader};
+
+// Bytes of input, read as the program asks for them
+pub struct Input {
+    bytes: Peekable<Box<dyn Iterator<Item = u8> + Send>>,
+}
+
+// Standard input with `std`, and nothing to read without it
+impl Default for Input {
+    fn default() -> Self {
+        #[cfg(feature = "std")]
+        return Self::from_reader(BufReader::new(io::stdin()));
+        #[cfg(not(feature = "std"))]
+        return Self::from_bytes(core::iter::empty());
+    }
+}
+
+impl Input {
+    // Input read from `reader`, ending at its end or at the first read error
+    #[cfg(feature = "std")]
+    pub fn from_reader(reader: impl BufRead + Send + 'static) -> Self {
+        Self::from_bytes(reader.bytes().map_while(Result::ok))
+    }
+
+    pub fn from_bytes(bytes: impl Iterator<Item = u8> + Send + 'static) -> Self {
+        let bytes: Box<dyn Iterator<Item = u8> + Send> = Box::new(bytes);
+        Input {
+            bytes: bytes.peekable(),
+        }
+    }
+
+    // Next byte, None at the end of the input
+    pub fn read_byte(&mut self) -> Option<u8> {
+        self.bytes.next()
+    }
+
+    // Next run of bytes up to whitespace or the end of the input, skipping the whitespace
+    // before it. None when only whitespace is left.
+    pub fn read_word(&mut self) -> Option<String> {
+        while self.bytes.next_if(u8::is_ascii_whitespace).is_some() {}
+        let mut word = Vec::new();
+        while let Some(byte) = self.bytes.next_if(|byte| !byte.is_ascii_whitespace()) {
+            word.push(byte);
+        }
+        (!word.is_empty()).then(|| String::from_utf8_lossy(&word).into_owned())
+    }
+}
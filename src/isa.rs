@@ -0,0 +1,1083 @@
+use core::fmt;
+
+use crate::binary::{opcode_from_number, opcode_number};
+use crate::error::{DecodeError, EncodeError};
+
+// Define opcodes. Arithmetic wraps around on overflow, the same in every build, and sets
+// the overflow flag when it does; only ADDS and its relatives clamp instead.
+#[allow(dead_code)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Opcode {
+    Nop,
+    Add, // reg3 = reg1 + reg2, so i32::MAX + 1 wraps to i32::MIN
+    Sub, // reg3 = reg1 - reg2, so i32::MIN - 1 wraps to i32::MAX
+    Mul, // reg3 = reg1 * reg2, keeping the low 32 bits of the product
+    Div, // reg3 = reg1 / reg2 rounded toward zero, with i32::MIN / -1 wrapping to i32::MIN
+    Store,
+    Load,
+    LoadImmediate,
+    Push,
+    Pop,
+    Jmp,
+    Jz,
+    Jnz,
+    Mov,
+    Je,
+    Jne,
+    And,
+    Or,
+    Xor,
+    Not,
+    Shl,  // Shift left, filling with zeros
+    Shr,  // Arithmetic shift right, copying the sign bit into the vacated bits
+    Cmp,  // Set the flags from reg1 - reg2
+    Test, // Set the zero and negative flags from reg1 & reg2
+    B,
+    Bz,
+    Bnz,
+    Neg, // Wraps for i32::MIN, which stays i32::MIN
+    Abs, // Wraps like NEG
+    Mod,
+    Inc, // Wraps from i32::MAX to i32::MIN
+    Dec, // Wraps from i32::MIN to i32::MAX
+    Halt,
+    Call,
+    Ret,
+    Enter,
+    Leave,
+    LoadFrame,
+    StoreFrame,
+    AddImmediate,
+    SubImmediate,
+    MulImmediate,
+    DivImmediate,
+    AndImmediate,
+    OrImmediate,
+    XorImmediate,
+    ShlImmediate,
+    ShrImmediate, // Arithmetic, like SHR
+    ShrLogical,   // Logical shift right, filling with zeros whatever the sign
+    Rol,          // Rotate left, by the count modulo 32
+    Ror,          // Rotate right, by the count modulo 32
+    RolImmediate,
+    RorImmediate,
+    LoadIndirect,        // reg1 = memory[reg2]
+    StoreIndirect,       // memory[reg2] = reg1
+    LoadOffset,          // reg1 = memory[reg2 + immediate]
+    StoreOffset,         // memory[reg2 + immediate] = reg1
+    JumpRegister,        // Jump to the address in reg1
+    Br,                  // Branch `immediate` instructions past the next one
+    Brz,                 // Same, if reg1 is 0
+    Brnz,                // Same, if reg1 is not 0
+    Loop,                // Decrement reg1, wrapping like DEC, then jump if it is not 0
+    CmpWrite,            // Legacy CMP, also writing reg1 - reg2 to reg3
+    TestWrite,           // Legacy TEST, also writing reg1 & reg2 to reg3
+    CmpUnsigned,         // Set zero and carry from reg1 - reg2 as unsigned, clearing the others
+    Ja,                  // Jump if above: carry and zero clear
+    Jae,                 // Jump if above or equal: carry clear
+    Jb,                  // Jump if below: carry set
+    Jbe,                 // Jump if below or equal: carry or zero set
+    Cmovz,               // reg1 = reg2 if reg3 is 0, leaving reg1 alone otherwise
+    Cmovnz,              // reg1 = reg2 if reg3 is not 0
+    Swap,                // Exchange reg1 and reg2
+    Adds,                // reg3 = reg1 + reg2, clamped to the i32 range instead of wrapping
+    Subs,                // reg3 = reg1 - reg2, clamped like ADDS
+    AddsImmediate,       // reg1 = reg2 + immediate, clamped like ADDS
+    SubsImmediate,       // reg1 = reg2 - immediate, clamped like ADDS
+    Mulh,                // reg3 = high 32 bits of the 64-bit product reg1 * reg2
+    Mulhu,               // Same, with reg1 and reg2 as unsigned numbers
+    Mulw,                // reg1 and reg2 = high and low 32 bits of reg3 * reg4
+    Divmod,              // reg1 and reg2 = reg3 / reg4 and reg3 % reg4, wrapping like DIV
+    Popcnt,              // reg2 = number of set bits in reg1
+    Clz,                 // reg2 = number of zero bits above the highest set bit of reg1, 32 for 0
+    Ctz,                 // reg2 = number of zero bits below the lowest set bit of reg1, 32 for 0
+    Bset,                // reg3 = reg1 with bit reg2 set, the bit index wrapping like a shift count
+    Bclr,                // reg3 = reg1 with bit reg2 cleared
+    Btgl,                // reg3 = reg1 with bit reg2 flipped
+    Btst,                // reg3 = bit reg2 of reg1, 0 or 1, also setting the zero flag when it is 0
+    BsetImmediate,       // reg1 = reg2 with bit `immediate` set
+    BclrImmediate,       // reg1 = reg2 with bit `immediate` cleared
+    BtglImmediate,       // reg1 = reg2 with bit `immediate` flipped
+    Extr,                // reg1 = the `addr` bits of reg2 from bit `immediate` up, zero-extended
+    Extrs,               // Same, sign-extended from the field's highest bit
+    Insr,                // Replace those bits of reg1 with the low `addr` bits of reg2
+    Sextb,               // reg2 = low 8 bits of reg1, sign-extended
+    Sexth,               // reg2 = low 16 bits of reg1, sign-extended
+    Zextb,               // reg2 = low 8 bits of reg1, zero-extended
+    Zexth,               // reg2 = low 16 bits of reg1, zero-extended
+    PushImmediate,       // Push `immediate`
+    PushAll,             // Push every register, R0 first, if there is room for all of them
+    PopAll,              // Pop every register, the last one first, undoing PUSHA
+    Peek,        // reg1 = the value `immediate` cells below the top of the stack, 0 for the top
+    Drop,        // Pop `immediate` values, keeping none of them
+    IncMemory,   // Increment memory[addr], wrapping like INC
+    DecMemory,   // Decrement memory[addr], wrapping like DEC
+    IncIndirect, // Increment memory[reg1]
+    DecIndirect, // Decrement memory[reg1]
+    HaltImmediate, // Halt with `immediate` as the exit code, written `HALT 3`
+    HaltRegister, // Halt with reg1 as the exit code, written `HALT R2`
+    AssertEq,    // Fault with `AssertionFailed` unless reg1 equals `immediate`
+    AssertEqRegister, // Fault with `AssertionFailed` unless reg1 equals reg2
+    Brk,         // Breakpoint: stop stepping with `StepOutcome::Breakpoint`
+    Sys,         // Run syscall number `immediate` through the machine's handler
+    Print,       // Write reg1 in decimal and a newline to the output sink
+    PrintChar,   // Write the character with the Unicode code point in reg1, alone
+    PrintString, // Write the characters from memory[addr] up to a 0 cell, like PRINTC
+    PrintStringIndirect, // Same from memory[reg1]
+    ReadInt,     // reg1 = next whitespace-separated number of the input
+    ReadChar,    // reg1 = next byte of the input, -1 at its end
+    Rand,        // reg1 = random number, any i32 as likely as the others
+    RandRange,   // reg1 = random number from 0 up to but not including reg2
+    Rdcycle,     // reg1 = instructions executed before this one, at most i32::MAX
+    Rdpc,        // reg1 = address of the next instruction, for JMPR to return to
+    Rdsp,        // reg1 = stack pointer, the address the next PUSH writes
+    Wrsp,        // Stack pointer = reg1, which must be inside the stack region
+}
+
+// Instruction fields an opcode reads, in the order they are written in assembly
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    Reg1,
+    Reg2,
+    Reg3,
+    Addr,   // Memory address held in `addr`
+    Target, // Instruction address held in `addr`, absolute or relative
+    Offset, // Jump target held in `addr`, relative even when written as a label
+    Imm,
+    Width, // Number of bits in a bit field, from 1 to 32, held in `addr`
+    Reg4,  // Second source of MULW and DIVMOD, held in `addr`
+}
+
+// What the addr field of an instruction holds. A memory address or bit field width is
+// always absolute; a jump target may also be a signed distance from the instruction
+// after the jump, which is what BR, BRZ and BRNZ always hold. Assembly writes a relative
+// target from the jump itself, as `.+N` or `.-N`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum AddrOperand {
+    Absolute(usize),
+    Relative(i32),
+}
+
+impl AddrOperand {
+    // The address named by the operand of an instruction at `ip`. None for a relative
+    // one that lands before the first instruction.
+    pub fn resolve(self, ip: usize) -> Option<usize> {
+        match self {
+            AddrOperand::Absolute(addr) => Some(addr),
+            AddrOperand::Relative(offset) => relative_target(ip, offset),
+        }
+    }
+
+    pub fn is_relative(self) -> bool {
+        matches!(self, AddrOperand::Relative(_))
+    }
+}
+
+// An absolute address as a plain number and a relative one from the instruction holding
+// it, such as `.+3` for `Relative(2)`
+impl fmt::Display for AddrOperand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AddrOperand::Absolute(addr) => write!(f, "{}", addr),
+            AddrOperand::Relative(offset) => write!(f, ".{:+}", i64::from(*offset) + 1),
+        }
+    }
+}
+
+// Where execution can continue after an opcode runs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Control {
+    Next,     // Always the following instruction
+    Jump,     // Always the jump target
+    Branch,   // The jump target or the following instruction
+    Call,     // The jump target, then the following instruction once it returns
+    Return,   // Wherever the matching call left off, which is only known at runtime
+    Indirect, // Wherever a register points, which is also only known at runtime
+    Halt,
+}
+
+// Assembly mnemonic of every opcode
+const MNEMONICS: [(Opcode, &str); 124] = [
+    (Opcode::Nop, "NOP"),
+    (Opcode::Add, "ADD"),
+    (Opcode::Sub, "SUB"),
+    (Opcode::Mul, "MUL"),
+    (Opcode::Div, "DIV"),
+    (Opcode::Store, "STORE"),
+    (Opcode::Load, "LOAD"),
+    (Opcode::LoadImmediate, "LI"),
+    (Opcode::Push, "PUSH"),
+    (Opcode::Pop, "POP"),
+    (Opcode::Jmp, "JMP"),
+    (Opcode::Jz, "JZ"),
+    (Opcode::Jnz, "JNZ"),
+    (Opcode::Mov, "MOV"),
+    (Opcode::Je, "JE"),
+    (Opcode::Jne, "JNE"),
+    (Opcode::And, "AND"),
+    (Opcode::Or, "OR"),
+    (Opcode::Xor, "XOR"),
+    (Opcode::Not, "NOT"),
+    (Opcode::Shl, "SHL"),
+    (Opcode::Shr, "SHR"),
+    (Opcode::Cmp, "CMP"),
+    (Opcode::Test, "TEST"),
+    (Opcode::B, "B"),
+    (Opcode::Bz, "BZ"),
+    (Opcode::Bnz, "BNZ"),
+    (Opcode::Neg, "NEG"),
+    (Opcode::Abs, "ABS"),
+    (Opcode::Mod, "MOD"),
+    (Opcode::Inc, "INC"),
+    (Opcode::Dec, "DEC"),
+    (Opcode::Halt, "HALT"),
+    (Opcode::Call, "CALL"),
+    (Opcode::Ret, "RET"),
+    (Opcode::Enter, "ENTER"),
+    (Opcode::Leave, "LEAVE"),
+    (Opcode::LoadFrame, "LOADF"),
+    (Opcode::StoreFrame, "STOREF"),
+    (Opcode::AddImmediate, "ADDI"),
+    (Opcode::SubImmediate, "SUBI"),
+    (Opcode::MulImmediate, "MULI"),
+    (Opcode::DivImmediate, "DIVI"),
+    (Opcode::AndImmediate, "ANDI"),
+    (Opcode::OrImmediate, "ORI"),
+    (Opcode::XorImmediate, "XORI"),
+    (Opcode::ShlImmediate, "SHLI"),
+    (Opcode::ShrImmediate, "SHRI"),
+    (Opcode::ShrLogical, "SHRL"),
+    (Opcode::Rol, "ROL"),
+    (Opcode::Ror, "ROR"),
+    (Opcode::RolImmediate, "ROLI"),
+    (Opcode::RorImmediate, "RORI"),
+    (Opcode::LoadIndirect, "LOADR"),
+    (Opcode::StoreIndirect, "STORER"),
+    (Opcode::LoadOffset, "LOADO"),
+    (Opcode::StoreOffset, "STOREO"),
+    (Opcode::JumpRegister, "JMPR"),
+    (Opcode::Br, "BR"),
+    (Opcode::Brz, "BRZ"),
+    (Opcode::Brnz, "BRNZ"),
+    (Opcode::Loop, "LOOP"),
+    (Opcode::CmpWrite, "CMPW"),
+    (Opcode::TestWrite, "TESTW"),
+    (Opcode::CmpUnsigned, "CMPU"),
+    (Opcode::Ja, "JA"),
+    (Opcode::Jae, "JAE"),
+    (Opcode::Jb, "JB"),
+    (Opcode::Jbe, "JBE"),
+    (Opcode::Cmovz, "CMOVZ"),
+    (Opcode::Cmovnz, "CMOVNZ"),
+    (Opcode::Swap, "SWAP"),
+    (Opcode::Adds, "ADDS"),
+    (Opcode::Subs, "SUBS"),
+    (Opcode::AddsImmediate, "ADDSI"),
+    (Opcode::SubsImmediate, "SUBSI"),
+    (Opcode::Mulh, "MULH"),
+    (Opcode::Mulhu, "MULHU"),
+    (Opcode::Mulw, "MULW"),
+    (Opcode::Divmod, "DIVMOD"),
+    (Opcode::Popcnt, "POPCNT"),
+    (Opcode::Clz, "CLZ"),
+    (Opcode::Ctz, "CTZ"),
+    (Opcode::Bset, "BSET"),
+    (Opcode::Bclr, "BCLR"),
+    (Opcode::Btgl, "BTGL"),
+    (Opcode::Btst, "BTST"),
+    (Opcode::BsetImmediate, "BSETI"),
+    (Opcode::BclrImmediate, "BCLRI"),
+    (Opcode::BtglImmediate, "BTGLI"),
+    (Opcode::Extr, "EXTR"),
+    (Opcode::Extrs, "EXTRS"),
+    (Opcode::Insr, "INSR"),
+    (Opcode::Sextb, "SEXTB"),
+    (Opcode::Sexth, "SEXTH"),
+    (Opcode::Zextb, "ZEXTB"),
+    (Opcode::Zexth, "ZEXTH"),
+    (Opcode::PushImmediate, "PUSHI"),
+    (Opcode::PushAll, "PUSHA"),
+    (Opcode::PopAll, "POPA"),
+    (Opcode::Peek, "PEEK"),
+    (Opcode::Drop, "DROP"),
+    (Opcode::IncMemory, "INCM"),
+    (Opcode::DecMemory, "DECM"),
+    (Opcode::IncIndirect, "INCMR"),
+    (Opcode::DecIndirect, "DECMR"),
+    (Opcode::HaltImmediate, "HALTI"),
+    (Opcode::HaltRegister, "HALTR"),
+    (Opcode::AssertEq, "ASSERT_EQ"),
+    (Opcode::AssertEqRegister, "ASSERT_EQ_R"),
+    (Opcode::Brk, "BRK"),
+    (Opcode::Sys, "SYS"),
+    (Opcode::Print, "PRINT"),
+    (Opcode::PrintChar, "PRINTC"),
+    (Opcode::PrintString, "PRINTS"),
+    (Opcode::PrintStringIndirect, "PRINTSR"),
+    (Opcode::ReadInt, "READI"),
+    (Opcode::ReadChar, "READC"),
+    (Opcode::Rand, "RAND"),
+    (Opcode::RandRange, "RANDR"),
+    (Opcode::Rdcycle, "RDCYCLE"),
+    (Opcode::Rdpc, "RDPC"),
+    (Opcode::Rdsp, "RDSP"),
+    (Opcode::Wrsp, "WRSP"),
+];
+
+impl Opcode {
+    // Name used for this opcode in assembly
+    pub fn mnemonic(self) -> &'static str {
+        MNEMONICS
+            .iter()
+            .find(|(opcode, _)| *opcode == self)
+            .map_or("", |(_, name)| name)
+    }
+
+    // Opcode written as `name` in assembly, if any
+    pub fn from_mnemonic(name: &str) -> Option<Opcode> {
+        MNEMONICS
+            .iter()
+            .find(|(_, mnemonic)| *mnemonic == name)
+            .map(|(opcode, _)| *opcode)
+    }
+
+    // Operands used by this opcode
+    pub fn operands(self) -> &'static [Operand] {
+        use Operand::*;
+        match self {
+            Opcode::Nop
+            | Opcode::Halt
+            | Opcode::Ret
+            | Opcode::Leave
+            | Opcode::PushAll
+            | Opcode::PopAll
+            | Opcode::Brk => &[],
+            Opcode::Add
+            | Opcode::Sub
+            | Opcode::Mul
+            | Opcode::Div
+            | Opcode::Mod
+            | Opcode::And
+            | Opcode::Or
+            | Opcode::Xor
+            | Opcode::Shl
+            | Opcode::Shr
+            | Opcode::ShrLogical
+            | Opcode::Rol
+            | Opcode::Ror
+            | Opcode::CmpWrite
+            | Opcode::TestWrite
+            | Opcode::Cmovz
+            | Opcode::Cmovnz
+            | Opcode::Adds
+            | Opcode::Subs
+            | Opcode::Mulh
+            | Opcode::Mulhu
+            | Opcode::Bset
+            | Opcode::Bclr
+            | Opcode::Btgl
+            | Opcode::Btst => &[Reg1, Reg2, Reg3],
+            Opcode::Cmp
+            | Opcode::Test
+            | Opcode::CmpUnsigned
+            | Opcode::AssertEqRegister
+            | Opcode::RandRange => &[Reg1, Reg2],
+            Opcode::Store | Opcode::Load => &[Reg1, Addr],
+            Opcode::LoadImmediate
+            | Opcode::LoadFrame
+            | Opcode::StoreFrame
+            | Opcode::AssertEq
+            | Opcode::Peek => &[Reg1, Imm],
+            Opcode::Enter
+            | Opcode::PushImmediate
+            | Opcode::Drop
+            | Opcode::HaltImmediate
+            | Opcode::Sys => &[Imm],
+            Opcode::Push
+            | Opcode::Pop
+            | Opcode::Inc
+            | Opcode::Dec
+            | Opcode::JumpRegister
+            | Opcode::IncIndirect
+            | Opcode::DecIndirect
+            | Opcode::HaltRegister
+            | Opcode::Print
+            | Opcode::PrintChar
+            | Opcode::PrintStringIndirect
+            | Opcode::ReadInt
+            | Opcode::ReadChar
+            | Opcode::Rand
+            | Opcode::Rdcycle
+            | Opcode::Rdpc
+            | Opcode::Rdsp
+            | Opcode::Wrsp => &[Reg1],
+            Opcode::IncMemory | Opcode::DecMemory | Opcode::PrintString => &[Addr],
+            Opcode::Jmp
+            | Opcode::B
+            | Opcode::Call
+            | Opcode::Ja
+            | Opcode::Jae
+            | Opcode::Jb
+            | Opcode::Jbe => &[Target],
+            Opcode::Jz | Opcode::Jnz | Opcode::Bz | Opcode::Bnz | Opcode::Loop => &[Reg1, Target],
+            Opcode::Br => &[Offset],
+            Opcode::Brz | Opcode::Brnz => &[Reg1, Offset],
+            Opcode::Mov
+            | Opcode::Not
+            | Opcode::Neg
+            | Opcode::Abs
+            | Opcode::LoadIndirect
+            | Opcode::StoreIndirect
+            | Opcode::Swap
+            | Opcode::Popcnt
+            | Opcode::Clz
+            | Opcode::Ctz
+            | Opcode::Sextb
+            | Opcode::Sexth
+            | Opcode::Zextb
+            | Opcode::Zexth => &[Reg1, Reg2],
+            Opcode::Je | Opcode::Jne => &[Reg1, Reg2, Target],
+            Opcode::AddImmediate
+            | Opcode::SubImmediate
+            | Opcode::MulImmediate
+            | Opcode::DivImmediate
+            | Opcode::AndImmediate
+            | Opcode::OrImmediate
+            | Opcode::XorImmediate
+            | Opcode::ShlImmediate
+            | Opcode::ShrImmediate
+            | Opcode::RolImmediate
+            | Opcode::RorImmediate
+            | Opcode::LoadOffset
+            | Opcode::StoreOffset
+            | Opcode::AddsImmediate
+            | Opcode::SubsImmediate
+            | Opcode::BsetImmediate
+            | Opcode::BclrImmediate
+            | Opcode::BtglImmediate => &[Reg1, Reg2, Imm],
+            Opcode::Extr | Opcode::Extrs | Opcode::Insr => &[Reg1, Reg2, Imm, Width],
+            Opcode::Mulw | Opcode::Divmod => &[Reg1, Reg2, Reg3, Reg4],
+        }
+    }
+
+    // Register operand this opcode writes, if any
+    pub fn written(self) -> Option<Operand> {
+        match self {
+            Opcode::Add
+            | Opcode::Sub
+            | Opcode::Mul
+            | Opcode::Div
+            | Opcode::Mod
+            | Opcode::And
+            | Opcode::Or
+            | Opcode::Xor
+            | Opcode::Shl
+            | Opcode::Shr
+            | Opcode::ShrLogical
+            | Opcode::Rol
+            | Opcode::Ror
+            | Opcode::CmpWrite
+            | Opcode::TestWrite
+            | Opcode::Adds
+            | Opcode::Subs
+            | Opcode::Mulh
+            | Opcode::Mulhu
+            | Opcode::Bset
+            | Opcode::Bclr
+            | Opcode::Btgl
+            | Opcode::Btst => Some(Operand::Reg3),
+            Opcode::Load
+            | Opcode::LoadImmediate
+            | Opcode::LoadFrame
+            | Opcode::LoadIndirect
+            | Opcode::LoadOffset
+            | Opcode::AddImmediate
+            | Opcode::SubImmediate
+            | Opcode::MulImmediate
+            | Opcode::DivImmediate
+            | Opcode::AndImmediate
+            | Opcode::OrImmediate
+            | Opcode::XorImmediate
+            | Opcode::ShlImmediate
+            | Opcode::ShrImmediate
+            | Opcode::RolImmediate
+            | Opcode::RorImmediate
+            | Opcode::AddsImmediate
+            | Opcode::SubsImmediate
+            | Opcode::BsetImmediate
+            | Opcode::BclrImmediate
+            | Opcode::BtglImmediate
+            | Opcode::Extr
+            | Opcode::Extrs
+            | Opcode::Insr
+            | Opcode::Pop
+            | Opcode::Peek
+            | Opcode::Mov
+            | Opcode::Inc
+            | Opcode::Dec
+            | Opcode::Loop
+            | Opcode::Cmovz
+            | Opcode::Cmovnz
+            | Opcode::ReadInt
+            | Opcode::ReadChar
+            | Opcode::Rand
+            | Opcode::RandRange
+            | Opcode::Rdcycle
+            | Opcode::Rdpc
+            | Opcode::Rdsp => Some(Operand::Reg1),
+            // These three write reg2 too, see `distinct_registers`
+            Opcode::Swap | Opcode::Mulw | Opcode::Divmod => Some(Operand::Reg1),
+            Opcode::Not
+            | Opcode::Neg
+            | Opcode::Abs
+            | Opcode::Popcnt
+            | Opcode::Clz
+            | Opcode::Ctz
+            | Opcode::Sextb
+            | Opcode::Sexth
+            | Opcode::Zextb
+            | Opcode::Zexth => Some(Operand::Reg2),
+            _ => None,
+        }
+    }
+
+    // Register operands that must name different registers. Most opcodes read all their
+    // operands before writing their one result, so any of them may name the same register,
+    // and SWAP R R leaves R as it is. MULW and DIVMOD read their operands before writing
+    // two results, one of which would be lost in a shared register, so the assembler and
+    // `Program::validate` refuse the same register for both results.
+    pub fn distinct_registers(self) -> Option<(Operand, Operand)> {
+        match self {
+            Opcode::Mulw | Opcode::Divmod => Some((Operand::Reg1, Operand::Reg2)),
+            _ => None,
+        }
+    }
+
+    // Whether the opcode replaces every flag, as CMP, TEST and the arithmetic opcodes do
+    pub fn sets_flags(self) -> bool {
+        matches!(
+            self,
+            Opcode::Add
+                | Opcode::Sub
+                | Opcode::Mul
+                | Opcode::Div
+                | Opcode::Mod
+                | Opcode::Neg
+                | Opcode::Abs
+                | Opcode::Inc
+                | Opcode::Dec
+                | Opcode::AddImmediate
+                | Opcode::SubImmediate
+                | Opcode::MulImmediate
+                | Opcode::DivImmediate
+                | Opcode::Cmp
+                | Opcode::Test
+                | Opcode::CmpWrite
+                | Opcode::TestWrite
+                | Opcode::CmpUnsigned
+                | Opcode::Adds
+                | Opcode::Subs
+                | Opcode::AddsImmediate
+                | Opcode::SubsImmediate
+                | Opcode::Divmod
+                | Opcode::Btst
+                | Opcode::IncMemory
+                | Opcode::DecMemory
+                | Opcode::IncIndirect
+                | Opcode::DecIndirect
+        )
+    }
+
+    pub fn control(self) -> Control {
+        match self {
+            Opcode::Jmp | Opcode::B | Opcode::Br => Control::Jump,
+            Opcode::Jz
+            | Opcode::Jnz
+            | Opcode::Je
+            | Opcode::Jne
+            | Opcode::Bz
+            | Opcode::Bnz
+            | Opcode::Brz
+            | Opcode::Brnz
+            | Opcode::Loop
+            | Opcode::Ja
+            | Opcode::Jae
+            | Opcode::Jb
+            | Opcode::Jbe => Control::Branch,
+            Opcode::Call => Control::Call,
+            Opcode::Ret => Control::Return,
+            Opcode::JumpRegister => Control::Indirect,
+            Opcode::Halt | Opcode::HaltImmediate | Opcode::HaltRegister => Control::Halt,
+            _ => Control::Next,
+        }
+    }
+}
+
+// Define the structure of an instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Instruction {
+    pub opcode: Opcode,
+    pub reg1: usize,
+    pub reg2: usize,
+    pub reg3: usize,
+    pub addr: AddrOperand,
+    pub immediate: i32,
+}
+
+impl Instruction {
+    // Instruction with every operand field set to zero, its addr relative for the
+    // relative branches and absolute for every other opcode
+    pub fn new(opcode: Opcode) -> Self {
+        let addr = if opcode.operands().contains(&Operand::Offset) {
+            AddrOperand::Relative(0)
+        } else {
+            AddrOperand::Absolute(0)
+        };
+        Instruction {
+            opcode,
+            reg1: 0,
+            reg2: 0,
+            reg3: 0,
+            addr,
+            immediate: 0,
+        }
+    }
+
+    // Register index held by `operand`, if it is a register operand
+    pub(crate) fn register(&self, operand: Operand) -> Option<usize> {
+        match operand {
+            Operand::Reg1 => Some(self.reg1),
+            Operand::Reg2 => Some(self.reg2),
+            Operand::Reg3 => Some(self.reg3),
+            Operand::Reg4 => match self.addr {
+                AddrOperand::Absolute(reg) => Some(reg),
+                AddrOperand::Relative(_) => None,
+            },
+            _ => None,
+        }
+    }
+
+    // Register indices this instruction reads or writes
+    pub fn registers(&self) -> impl Iterator<Item = usize> + '_ {
+        self.opcode
+            .operands()
+            .iter()
+            .filter_map(|&operand| self.register(operand))
+    }
+
+    // Register indices whose values this instruction uses
+    pub fn reads(&self) -> impl Iterator<Item = usize> + '_ {
+        let written = self.opcode.written();
+        let second = self.opcode.distinct_registers().map(|(_, second)| second);
+        // These keep what was in their destination in some way, so they read it too
+        let updates = matches!(
+            self.opcode,
+            Opcode::Inc
+                | Opcode::Dec
+                | Opcode::Loop
+                | Opcode::Cmovz
+                | Opcode::Cmovnz
+                | Opcode::Swap
+                | Opcode::Insr
+        );
+        self.opcode
+            .operands()
+            .iter()
+            .filter(move |&&operand| {
+                updates || (Some(operand) != written && Some(operand) != second)
+            })
+            .filter_map(|&operand| self.register(operand))
+    }
+
+    // Register index this instruction writes, if any
+    pub fn writes(&self) -> Option<usize> {
+        self.opcode
+            .written()
+            .and_then(|operand| self.register(operand))
+    }
+
+    // Addresses that may run after this instruction when it sits at `ip`
+    pub fn successors(&self, ip: usize) -> impl Iterator<Item = usize> {
+        let (next, target) = match self.opcode.control() {
+            Control::Next => (true, false),
+            Control::Jump => (false, true),
+            Control::Branch | Control::Call => (true, true),
+            Control::Return | Control::Indirect | Control::Halt => (false, false),
+        };
+        let target = if target { self.jump_target(ip) } else { None };
+        next.then(|| ip + 1).into_iter().chain(target)
+    }
+
+    // Memory address this instruction accesses directly, if any
+    pub fn memory_address(&self) -> Option<usize> {
+        match self.addr {
+            AddrOperand::Absolute(addr) if self.opcode.operands().contains(&Operand::Addr) => {
+                Some(addr)
+            }
+            _ => None,
+        }
+    }
+
+    // Pack the instruction into one 64-bit word, from the most significant bits down:
+    //
+    //   63..56  opcode number, as in binary programs
+    //   55..48  reg1
+    //   47..40  reg2
+    //   39..32  reg3
+    //   31..16  addr, two's complement for BR, BRZ and BRNZ and unsigned for the others
+    //   15..0   immediate, two's complement
+    //
+    // Every field is encoded whether or not the opcode uses it, so a field that does not
+    // fit its width is an error, and so is an addr that is not relative exactly when the
+    // opcode is one of those three, as the word has no room to say which it is.
+    pub fn encode(&self) -> Result<u64, EncodeError> {
+        let field = |field: &'static str, value: usize, bits: u32| {
+            if value >> bits == 0 {
+                Ok(value as u64)
+            } else {
+                Err(EncodeError::FieldTooWide {
+                    field,
+                    value: value as i128,
+                    bits,
+                })
+            }
+        };
+        let signed = |field: &'static str, value: i32| {
+            i16::try_from(value).map_err(|_| EncodeError::FieldTooWide {
+                field,
+                value: i128::from(value),
+                bits: 16,
+            })
+        };
+        let addr = match self.addr {
+            AddrOperand::Absolute(addr) if !relative_addr(self.opcode) => field("addr", addr, 16)?,
+            AddrOperand::Relative(offset) if relative_addr(self.opcode) => {
+                u64::from(signed("addr", offset)? as u16)
+            }
+            addr => {
+                return Err(EncodeError::AddrForm {
+                    opcode: self.opcode,
+                    addr,
+                })
+            }
+        };
+        let immediate = signed("immediate", self.immediate)?;
+        Ok(u64::from(opcode_number(self.opcode)) << 56
+            | field("reg1", self.reg1, 8)? << 48
+            | field("reg2", self.reg2, 8)? << 40
+            | field("reg3", self.reg3, 8)? << 32
+            | addr << 16
+            | u64::from(immediate as u16))
+    }
+
+    // Unpack a word written by `encode`
+    pub fn decode(word: u64) -> Result<Instruction, DecodeError> {
+        let code = (word >> 56) as u8;
+        let opcode = opcode_from_number(code).ok_or(DecodeError::UnknownOpcode { code })?;
+        let addr = (word >> 16) as u16;
+        Ok(Instruction {
+            opcode,
+            reg1: usize::from((word >> 48) as u8),
+            reg2: usize::from((word >> 40) as u8),
+            reg3: usize::from((word >> 32) as u8),
+            addr: if relative_addr(opcode) {
+                AddrOperand::Relative(i32::from(addr as i16))
+            } else {
+                AddrOperand::Absolute(usize::from(addr))
+            },
+            immediate: i32::from(word as u16 as i16),
+        })
+    }
+
+    // Instruction address this instruction may jump to when it sits at `ip`, if any. A
+    // relative target that lands before the first instruction has none.
+    pub fn jump_target(&self, ip: usize) -> Option<usize> {
+        let operands = self.opcode.operands();
+        if operands.contains(&Operand::Target) || operands.contains(&Operand::Offset) {
+            self.addr.resolve(ip)
+        } else {
+            None
+        }
+    }
+}
+
+// Assembly text for the instruction, such as `ADD R0 R1 R2` or `JNZ R1 4`
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.opcode.mnemonic())?;
+        for operand in self.opcode.operands() {
+            match operand {
+                Operand::Reg1 => write!(f, " R{}", self.reg1)?,
+                Operand::Reg2 => write!(f, " R{}", self.reg2)?,
+                Operand::Reg3 => write!(f, " R{}", self.reg3)?,
+                Operand::Reg4 => match self.addr {
+                    AddrOperand::Absolute(reg) => write!(f, " R{}", reg)?,
+                    addr => write!(f, " {}", addr)?,
+                },
+                // A branch offset is written as the distance from the next instruction
+                Operand::Offset => match self.addr {
+                    AddrOperand::Relative(offset) => write!(f, " {:+}", offset)?,
+                    addr => write!(f, " {}", addr)?,
+                },
+                Operand::Addr | Operand::Target | Operand::Width => write!(f, " {}", self.addr)?,
+                Operand::Imm => write!(f, " {}", self.immediate)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+// Whether the opcode's addr is relative in an encoded word: only the relative branches'
+fn relative_addr(opcode: Opcode) -> bool {
+    opcode.operands().contains(&Operand::Offset)
+}
+
+// Address `offset` instructions past the one after `ip`, if it is not negative
+pub(crate) fn relative_target(ip: usize, offset: i32) -> Option<usize> {
+    let target = i64::try_from(ip).ok()? + 1 + i64::from(offset);
+    usize::try_from(target).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::String;
+    use alloc::vec::Vec;
+
+    use super::*;
+    use crate::asm::assemble;
+    use crate::binary::{decode_program, encode_program};
+    use crate::disasm::disassemble;
+    use crate::error::{DecodeError, EncodeError};
+    use crate::program::Program;
+
+    // One line of every opcode, with its mnemonic and an operand in each of its fields
+    fn every_opcode() -> String {
+        let mut source = String::new();
+        for (opcode, mnemonic) in MNEMONICS {
+            source.push_str(mnemonic);
+            for operand in opcode.operands() {
+                source.push_str(match operand {
+                    Operand::Reg1 => " R1",
+                    Operand::Reg2 => " R2",
+                    Operand::Reg3 => " R3",
+                    Operand::Addr => " 5",
+                    Operand::Target => " 2",
+                    Operand::Offset => " +1",
+                    Operand::Imm => " 3",
+                    Operand::Width => " 4",
+                    Operand::Reg4 => " R4",
+                });
+            }
+            source.push('\n');
+        }
+        source
+    }
+
+    #[test]
+    fn every_opcode_round_trips_through_disassembly() {
+        let instructions = assemble(&every_opcode()).unwrap().into_instructions();
+        let opcodes: Vec<Opcode> = instructions.iter().map(|instr| instr.opcode).collect();
+        assert_eq!(opcodes, MNEMONICS.map(|(opcode, _)| opcode));
+
+        let text = disassemble(&instructions);
+        let reassembled = assemble(&text).unwrap().into_instructions();
+        assert_eq!(reassembled, instructions);
+    }
+
+    #[test]
+    fn every_opcode_round_trips_through_bytecode() {
+        let program = assemble(&every_opcode())
+            .unwrap()
+            .with_data(3, Vec::from([i32::MIN, -1, 0, i32::MAX]))
+            .with_entry(1);
+        let decoded = decode_program(&encode_program(&program)).unwrap();
+        assert_eq!(decoded.instructions(), program.instructions());
+        assert_eq!(decoded.data(), program.data());
+        assert_eq!(decoded.entry(), 1);
+        assert_eq!(decoded.debug_info(), program.debug_info());
+    }
+
+    #[test]
+    fn every_opcode_round_trips_through_a_word_at_its_field_limits() {
+        for (opcode, _) in MNEMONICS {
+            for immediate in [i32::from(i16::MIN), -1, 0, 1, i32::from(i16::MAX)] {
+                for (register, absolute, relative) in [
+                    (0, 0, i32::from(i16::MIN)),
+                    (1, 1, -1),
+                    (255, 65535, i32::from(i16::MAX)),
+                ] {
+                    let mut instr = Instruction {
+                        reg1: register,
+                        reg2: register,
+                        reg3: register,
+                        immediate,
+                        ..Instruction::new(opcode)
+                    };
+                    instr.addr = if instr.addr.is_relative() {
+                        AddrOperand::Relative(relative)
+                    } else {
+                        AddrOperand::Absolute(absolute)
+                    };
+                    assert_eq!(Instruction::decode(instr.encode().unwrap()), Ok(instr));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn encode_rejects_fields_too_wide() {
+        let too_wide = |field, value, bits| Err(EncodeError::FieldTooWide { field, value, bits });
+        let add = Instruction::new(Opcode::Add);
+        let br = Instruction::new(Opcode::Br);
+        for (instr, expected) in [
+            (Instruction { reg1: 256, ..add }, too_wide("reg1", 256, 8)),
+            (Instruction { reg2: 256, ..add }, too_wide("reg2", 256, 8)),
+            (Instruction { reg3: 256, ..add }, too_wide("reg3", 256, 8)),
+            (
+                Instruction {
+                    addr: AddrOperand::Absolute(65536),
+                    ..add
+                },
+                too_wide("addr", 65536, 16),
+            ),
+            (
+                Instruction {
+                    addr: AddrOperand::Relative(32768),
+                    ..br
+                },
+                too_wide("addr", 32768, 16),
+            ),
+            (
+                Instruction {
+                    addr: AddrOperand::Relative(-32769),
+                    ..br
+                },
+                too_wide("addr", -32769, 16),
+            ),
+            (
+                Instruction {
+                    immediate: 32768,
+                    ..add
+                },
+                too_wide("immediate", 32768, 16),
+            ),
+            (
+                Instruction {
+                    immediate: -32769,
+                    ..add
+                },
+                too_wide("immediate", -32769, 16),
+            ),
+        ] {
+            assert_eq!(instr.encode(), expected);
+        }
+    }
+
+    // The word has no bit to say which form an addr is in, so only the form the opcode
+    // reads it in can be encoded
+    #[test]
+    fn encode_rejects_an_addr_in_the_other_form() {
+        for (opcode, addr) in [
+            (Opcode::Load, AddrOperand::Relative(0)),
+            (Opcode::Jmp, AddrOperand::Relative(-1)),
+            (Opcode::Br, AddrOperand::Absolute(0)),
+        ] {
+            let instr = Instruction {
+                addr,
+                ..Instruction::new(opcode)
+            };
+            assert_eq!(instr.encode(), Err(EncodeError::AddrForm { opcode, addr }));
+        }
+    }
+
+    // Offsets and addresses at the edges of 16 and 32 bits, where a sign or a narrower
+    // field could lose them
+    const OFFSETS: [i32; 10] = [
+        i32::MIN,
+        i16::MIN as i32 - 1,
+        i16::MIN as i32,
+        -2,
+        -1,
+        0,
+        1,
+        i16::MAX as i32,
+        i16::MAX as i32 + 1,
+        i32::MAX,
+    ];
+    const ADDRESSES: [usize; 5] = [0, 1, 65535, 65536, u32::MAX as usize];
+
+    // Every jump with a relative target at each offset and an absolute one at each address,
+    // with the relative branches only relative
+    fn every_jump() -> Vec<Instruction> {
+        let mut jumps = Vec::new();
+        for (opcode, _) in MNEMONICS {
+            let operands = opcode.operands();
+            let jump = Instruction {
+                reg1: usize::from(operands.contains(&Operand::Reg1)),
+                reg2: 2 * usize::from(operands.contains(&Operand::Reg2)),
+                ..Instruction::new(opcode)
+            };
+            if operands.contains(&Operand::Target) {
+                let absolute = ADDRESSES.map(AddrOperand::Absolute);
+                jumps.extend(absolute.map(|addr| Instruction { addr, ..jump }));
+            }
+            if operands.contains(&Operand::Target) || operands.contains(&Operand::Offset) {
+                let relative = OFFSETS.map(AddrOperand::Relative);
+                jumps.extend(relative.map(|addr| Instruction { addr, ..jump }));
+            }
+        }
+        jumps
+    }
+
+    #[test]
+    fn relative_and_absolute_targets_round_trip() {
+        let jumps = every_jump();
+        let text = disassemble(&jumps);
+        assert!(text.contains("BR -2147483648 "), "{}", text);
+        assert!(text.contains("JMP .+2147483648 "), "{}", text);
+        assert!(text.contains("JMP .-2147483647 "), "{}", text);
+        assert_eq!(assemble(&text).unwrap().into_instructions(), jumps);
+
+        let program = Program::from_instructions(jumps.clone());
+        let decoded = decode_program(&encode_program(&program)).unwrap();
+        assert_eq!(decoded.instructions(), &jumps[..]);
+    }
+
+    // Forms no operand is written in, which `Program::validate` refuses, still round trip
+    // through the legacy five-field form
+    #[test]
+    fn addr_in_an_unusual_form_round_trips() {
+        let with_addr = |opcode, addr| Instruction {
+            addr,
+            ..Instruction::new(opcode)
+        };
+        let unusual = Vec::from([
+            with_addr(Opcode::Br, AddrOperand::Absolute(3)),
+            with_addr(Opcode::Brz, AddrOperand::Absolute(65536)),
+            with_addr(Opcode::Load, AddrOperand::Relative(-3)),
+            with_addr(Opcode::Extr, AddrOperand::Relative(i32::MIN)),
+            with_addr(Opcode::Add, AddrOperand::Relative(i32::MAX)),
+        ]);
+        let text = disassemble(&unusual);
+        assert_eq!(assemble(&text).unwrap().into_instructions(), unusual);
+
+        let program = Program::from_instructions(unusual.clone());
+        let decoded = decode_program(&encode_program(&program)).unwrap();
+        assert_eq!(decoded.instructions(), &unusual[..]);
+    }
+
+    #[test]
+    fn decode_rejects_unknown_opcode_numbers() {
+        let known = MNEMONICS.len() as u8;
+        for code in known..=u8::MAX {
+            let word = u64::from(code) << 56;
+            assert_eq!(
+                Instruction::decode(word),
+                Err(DecodeError::UnknownOpcode { code })
+            );
+        }
+    }
+}
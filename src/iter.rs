@@ -0,0 +1,81 @@
+use alloc::vec::Vec;
+
+use crate::cpu::{ProcessingUnit, StepOutcome};
+use crate::error::MdpuError;
+use crate::isa::{Instruction, Opcode};
+
+// View of the machine after an instruction has executed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StepSnapshot {
+    pub opcode: Opcode,
+    pub ip: usize,
+    pub instruction_count: usize,
+    pub registers: Vec<i32>,
+}
+
+// Iterator that steps a processing unit through a program, one instruction per item
+pub struct ExecutionIter<'a> {
+    pu: &'a mut ProcessingUnit,
+    program: &'a [Instruction],
+    error: Option<MdpuError>,
+    done: bool,
+}
+
+impl<'a> ExecutionIter<'a> {
+    // Error that stopped execution, if any
+    pub fn error(&self) -> Option<&MdpuError> {
+        self.error.as_ref()
+    }
+}
+
+impl Iterator for ExecutionIter<'_> {
+    type Item = StepSnapshot;
+
+    fn next(&mut self) -> Option<StepSnapshot> {
+        if self.done {
+            return None;
+        }
+
+        let ip = self.pu.instruction_pointer();
+        let Some(instr) = self.program.get(ip) else {
+            self.done = true;
+            return None;
+        };
+
+        let limit = self.pu.max_instructions();
+        if self.pu.instruction_count() >= limit {
+            self.error = Some(MdpuError::InstructionLimitExceeded { limit });
+            self.done = true;
+            return None;
+        }
+
+        match self.pu.step(self.program) {
+            StepOutcome::Continue | StepOutcome::Breakpoint => {}
+            StepOutcome::Halted => self.done = true,
+            StepOutcome::Fault(err) => {
+                self.error = Some(err);
+                self.done = true;
+                return None;
+            }
+        }
+
+        Some(StepSnapshot {
+            opcode: instr.opcode,
+            ip,
+            instruction_count: self.pu.instruction_count(),
+            registers: self.pu.registers().to_vec(),
+        })
+    }
+}
+
+impl ProcessingUnit {
+    // Iterate over the execution of a program, yielding a snapshot after every instruction
+    pub fn iter_execution<'a>(&'a mut self, program: &'a [Instruction]) -> ExecutionIter<'a> {
+        ExecutionIter {
+            pu: self,
+            program,
+            error: None,
+            done: false,
+        }
+    }
+}
@@ -0,0 +1,65 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+mod analysis;
+pub mod asm;
+pub mod binary;
+pub mod builder;
+pub mod cpu;
+pub mod debug;
+pub mod disasm;
+pub mod error;
+pub mod flags;
+pub mod hook;
+pub mod input;
+pub mod isa;
+pub mod iter;
+#[cfg(feature = "std")]
+pub mod loader;
+#[cfg(feature = "std")]
+mod optimize;
+pub mod output;
+pub mod program;
+mod random;
+#[cfg(feature = "std")]
+pub mod snapshot;
+pub mod symbols;
+pub mod syscall;
+
+pub use asm::assemble;
+pub use binary::{
+    decode_header, decode_program, decode_program_unverified, encode_program, BinaryHeader,
+};
+#[cfg(feature = "std")]
+pub use binary::{read_bytecode, write_bytecode};
+pub use builder::ProcessingUnitBuilder;
+pub use cpu::{
+    run, run_cancellable, run_with_hook, BreakMode, HaltReason, ProcessingUnit,
+    ProcessingUnitState, StateView, StepOutcome, UnderflowPolicy, DEFAULT_MAX_INSTRUCTIONS,
+};
+pub use debug::{DebugInfo, SourceLocation};
+pub use disasm::{disassemble, disassemble_program};
+#[cfg(feature = "std")]
+pub use error::LoadError;
+pub use error::{
+    BinaryError, BuildError, DecodeError, EncodeError, MdpuError, ParseError, ValidationError,
+};
+pub use flags::Flags;
+#[cfg(feature = "std")]
+pub use hook::Tracer;
+pub use hook::{ExecutionHook, HookControl, InstructionCounter};
+pub use input::Input;
+pub use isa::{AddrOperand, Control, Instruction, Opcode, Operand};
+pub use iter::{ExecutionIter, StepSnapshot};
+#[cfg(feature = "std")]
+pub use loader::{
+    load_binary_program, load_program, load_program_from, load_program_permissive,
+    load_program_with, load_programs_with, write_binary_program, LoadOptions, STDIN_FILENAME,
+};
+#[cfg(feature = "std")]
+pub use output::{CaptureSink, StdioSink};
+pub use output::{NullSink, OutputSink};
+pub use program::Program;
+pub use symbols::{SymbolEntry, SymbolKind, SymbolTable};
+pub use syscall::{StandardSyscalls, SyscallContext, SyscallEffect, SyscallHandler};
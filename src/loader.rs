@@ -0,0 +1,216 @@
+// Program loading from the file system, only available with the `std` feature
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+
+use crate::asm::{link, Assembly, Severity, SourceFile};
+use crate::binary::{decode_program, decode_program_unverified, encode_program, is_binary};
+use crate::debug::DebugInfo;
+use crate::error::LoadError;
+use crate::program::Program;
+
+// How `load_program_with` assembles a program
+#[derive(Debug, Clone, Default)]
+pub struct LoadOptions {
+    // Assemble lines with an unknown opcode as NOP and print a warning for each of them
+    pub permissive: bool,
+    // Symbols defined before the first line, as if by `.define NAME VALUE`
+    pub defines: Vec<(String, String)>,
+    // Fail to load on any warning, such as unreachable code or an unused label
+    pub deny_warnings: bool,
+    // Check operands against a machine with this many registers and memory cells
+    pub machine: Option<(usize, usize)>,
+    // Write an annotated listing of the assembled program to this file
+    pub listing: Option<String>,
+    // Load a binary program even if it does not match its checksum
+    pub skip_checksum: bool,
+    // Run the peephole optimizer over the assembled program and say how much it removed
+    pub optimize: bool,
+    // Drop the source line of each instruction, which faults and traces otherwise show
+    pub strip_debug_info: bool,
+}
+
+// Function to load a program from a file. Every problem in the file is reported at
+// once, unknown opcodes included.
+pub fn load_program(filename: &str) -> Result<Program, LoadError> {
+    load_program_with(filename, &LoadOptions::default())
+}
+
+// Load a program like `load_program`, but assemble lines with an unknown opcode as NOP
+// and print a warning for each of them
+pub fn load_program_permissive(filename: &str) -> Result<Program, LoadError> {
+    let options = LoadOptions {
+        permissive: true,
+        ..LoadOptions::default()
+    };
+    load_program_with(filename, &options)
+}
+
+// File name that reads the program from standard input instead
+pub const STDIN_FILENAME: &str = "-";
+
+// Name diagnostics give a program read from standard input
+const STDIN_NAME: &str = "<stdin>";
+
+// Load a program from a file with the given options, or from standard input when the
+// file name is `-`. Binary programs written by `write_binary_program` are recognized by
+// their magic bytes and loaded as they are.
+pub fn load_program_with(filename: &str, options: &LoadOptions) -> Result<Program, LoadError> {
+    load_programs_with(&[filename], options)
+}
+
+// Link several assembly files into one program, their code placed in the order given.
+// Labels and constants are private to their file unless it exports them with `.global`.
+pub fn load_programs_with(filenames: &[&str], options: &LoadOptions) -> Result<Program, LoadError> {
+    let mut units = Vec::new();
+    for &filename in filenames {
+        let (name, bytes) = if filename == STDIN_FILENAME {
+            (STDIN_NAME, read_all(&mut io::stdin().lock())?)
+        } else {
+            (filename, read_all(&mut fs::File::open(filename)?)?)
+        };
+        if is_binary(&bytes) {
+            if filenames.len() > 1 {
+                let message = format!("{} is a binary program, which cannot be linked", name);
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, message).into());
+            }
+            return load_binary(&bytes, options);
+        }
+        units.push(source_file(Path::new(name), utf8(bytes)?));
+    }
+    assemble_units(&units, options)
+}
+
+// Load a program read to its end from `reader` like `load_program_with`. Diagnostics
+// call the source `name`, and relative includes are relative to its directory.
+pub fn load_program_from(
+    reader: &mut impl Read,
+    name: &str,
+    options: &LoadOptions,
+) -> Result<Program, LoadError> {
+    let bytes = read_all(reader)?;
+    if is_binary(&bytes) {
+        return load_binary(&bytes, options);
+    }
+    let root = source_file(Path::new(name), utf8(bytes)?);
+    assemble_units(&[root], options)
+}
+
+fn read_all(reader: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn utf8(bytes: Vec<u8>) -> io::Result<String> {
+    String::from_utf8(bytes).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+fn assemble_units(units: &[SourceFile], options: &LoadOptions) -> Result<Program, LoadError> {
+    let mut assembly = link(units, &options.defines, &mut |path, from| {
+        // Relative paths are relative to the directory of the including file
+        let dir = from.and_then(|name| Path::new(name).parent());
+        let path = dir.map_or_else(|| Path::new(path).to_path_buf(), |dir| dir.join(path));
+        read_source(&path).map_err(|err| format!("Cannot include {}: {}", path.display(), err))
+    });
+
+    let errors: Vec<_> = assembly
+        .errors(options.permissive, options.deny_warnings)
+        .cloned()
+        .collect();
+    if !errors.is_empty() {
+        return Err(LoadError::Parse(errors));
+    }
+    let optimized = options.optimize.then(|| assembly.optimize());
+    if let Some((registers, memory)) = options.machine {
+        if let Err(violations) = assembly.program.validate(registers, memory) {
+            let errors = violations
+                .iter()
+                .map(|err| assembly.diagnostic_at(err.ip(), err.operand(), err.to_string()))
+                .collect();
+            return Err(LoadError::Parse(errors));
+        }
+    }
+    if let Some(path) = &options.listing {
+        fs::write(path, assembly.listing()).map_err(|err| {
+            std::io::Error::new(
+                err.kind(),
+                format!("Cannot write listing {}: {}", path, err),
+            )
+        })?;
+    }
+    let Assembly {
+        program,
+        diagnostics,
+        ..
+    } = assembly;
+    for (severity, err) in &diagnostics {
+        match severity {
+            Severity::Error => {}
+            Severity::UnknownOpcode => eprintln!("Warning: {}, treated as NOP", err),
+            Severity::Warning => eprintln!("Warning: {}", err),
+        }
+    }
+    match optimized {
+        Some(Ok(removed)) => eprintln!("Optimized: removed {} instruction(s)", removed),
+        Some(Err(reason)) => eprintln!("Not optimizing: {}", reason),
+        None => {}
+    }
+    Ok(strip(program, options))
+}
+
+// Load a program written by `write_binary_program` or `mdpu asm`
+pub fn load_binary_program(filename: &str) -> Result<Program, LoadError> {
+    let bytes = fs::read(filename)?;
+    decode_program(&bytes).map_err(LoadError::Binary)
+}
+
+// Write the instructions and data of `program` to a file that loads without assembling
+pub fn write_binary_program(program: &Program, filename: &str) -> io::Result<()> {
+    fs::write(filename, encode_program(program))
+}
+
+// A binary program has no source, so only the options about the machine and its debug
+// section apply
+fn load_binary(bytes: &[u8], options: &LoadOptions) -> Result<Program, LoadError> {
+    if options.listing.is_some() {
+        let message = "A listing needs the program's assembly source";
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, message).into());
+    }
+    let decode = if options.skip_checksum {
+        decode_program_unverified
+    } else {
+        decode_program
+    };
+    let program = decode(bytes).map_err(LoadError::Binary)?;
+    if let Some((registers, memory)) = options.machine {
+        program
+            .validate(registers, memory)
+            .map_err(LoadError::Invalid)?;
+    }
+    Ok(strip(program, options))
+}
+
+fn strip(program: Program, options: &LoadOptions) -> Program {
+    if options.strip_debug_info {
+        program.with_debug_info(DebugInfo::default())
+    } else {
+        program
+    }
+}
+
+// Read a source file, identified by its canonical path so include cycles are found no
+// matter how the path is spelled
+fn read_source(path: &Path) -> io::Result<SourceFile> {
+    Ok(source_file(path, fs::read_to_string(path)?))
+}
+
+fn source_file(path: &Path, text: String) -> SourceFile {
+    let name = path.display().to_string();
+    let key = fs::canonicalize(path).map_or_else(|_| name.clone(), |key| key.display().to_string());
+    SourceFile {
+        name: Some(name),
+        key,
+        text,
+    }
+}
@@ -0,0 +1,497 @@
+use std::env;
+use std::fs;
+use std::io::BufReader;
+use std::path::Path;
+use std::process;
+
+use mdpu::{
+    disassemble_program, load_programs_with, run, write_binary_program, BreakMode, LoadError,
+    LoadOptions, MdpuError, Opcode, OutputSink, ProcessingUnit, ProcessingUnitBuilder, Program,
+    StdioSink, UnderflowPolicy, DEFAULT_MAX_INSTRUCTIONS, STDIN_FILENAME,
+};
+
+const USAGE: &str =
+    "Usage: mdpu [options] <register_size_dimensions> <memory_size_dimensions> <program_file>...
+       mdpu [options] --resume <snapshot_file> <program_file>...
+       mdpu [options] asm <program_file>... [-o <output_file>]
+       mdpu [options] disasm <program_file>...
+       mdpu --help
+
+<program_file> is assembly source or a binary program written by `mdpu asm`, or - to
+read the program from standard input. Several assembly files are linked into one
+program in the order given, sharing the symbols each exports with .global.
+
+Options:
+  --permissive           Run lines with an unknown opcode as NOP instead of refusing to load
+  --define <name>[=<value>]
+                         Define a symbol for .if and .ifdef, as if by .define (value 1 if omitted)
+  --deny-warnings        Refuse to load a program the assembler warns about
+  --strip-debug          Leave out the source line of each instruction, which faults name
+  -O, --optimize         Remove redundant instructions from the assembled program, including a
+                         PUSH and POP pair that would overflow the stack
+  --no-verify            Load a binary program even if its checksum shows it is corrupted
+  --trap-overflow        Fault on arithmetic that overflows instead of letting it wrap
+  --zero-register        Hardwire R0 to 0, dropping every write to it
+  --seed <n>             Seed RAND and RANDR to repeat a run (default: a new seed, printed)
+  --break=<mode>         At a BRK, print the machine's state and `continue` (default) or `stop`
+  --underflow=<policy>   POP, PEEK and DROP past the stack's base `trap` (default) or give `zero`
+  --map <file>           Write every label and constant with its value to <file>
+  --listing <file>       Write each source line with its address and assembled code to <file>
+  --stdin-file <file>    Give the program <file> to read with READI and READC instead of stdin
+  --snapshot-out <file>  Save the machine to <file> if the instruction limit is exceeded
+  --resume <file>        Continue from a snapshot written by --snapshot-out
+  -o, --output <file>    Where `asm` writes the binary program (default: <program_file>
+                         with the extension .mdpub)";
+
+// Failure categories, each with a stable process exit code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Failure {
+    Usage,
+    Load,
+    Runtime,
+    Limit,
+    Assertion,
+}
+
+impl Failure {
+    const ALL: [Failure; 5] = [
+        Failure::Usage,
+        Failure::Load,
+        Failure::Runtime,
+        Failure::Limit,
+        Failure::Assertion,
+    ];
+
+    fn exit_code(self) -> i32 {
+        match self {
+            Failure::Usage => 2,
+            Failure::Load => 3,
+            Failure::Runtime => 4,
+            Failure::Limit => 5,
+            Failure::Assertion => 6,
+        }
+    }
+
+    fn category(self) -> &'static str {
+        match self {
+            Failure::Usage => "usage error",
+            Failure::Load => "load error",
+            Failure::Runtime => "runtime fault",
+            Failure::Limit => "instruction limit",
+            Failure::Assertion => "assertion failure",
+        }
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            Failure::Usage => "invalid arguments or machine dimensions",
+            Failure::Load => "the program or snapshot could not be read or parsed",
+            Failure::Runtime => "division by zero, out-of-bounds access or stack fault",
+            Failure::Limit => "the maximum instruction count was exceeded",
+            Failure::Assertion => "an ASSERT_EQ or ASSERT_EQ_R did not hold",
+        }
+    }
+
+    // Category of a fault raised while running the program
+    fn of(err: &MdpuError) -> Self {
+        match err {
+            MdpuError::InstructionLimitExceeded { .. } => Failure::Limit,
+            MdpuError::AssertionFailed { .. } => Failure::Assertion,
+            _ => Failure::Runtime,
+        }
+    }
+}
+
+// Full help text, including the exit status table
+fn help() -> String {
+    let mut text = format!(
+        "{}\n\nExit status:\n  0  the program halted cleanly\n  N  the program halted with HALT N, or HALT R with N in R\n",
+        USAGE
+    );
+    for failure in Failure::ALL {
+        text.push_str(&format!(
+            "  {}  {}: {}\n",
+            failure.exit_code(),
+            failure.category(),
+            failure.description()
+        ));
+    }
+    text
+}
+
+// What the command line asks for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Command {
+    Run,
+    Assemble,    // `asm`: write the program as a binary file instead of running it
+    Disassemble, // `disasm`: print the program as assembly instead of running it
+}
+
+// Command line options
+struct Options {
+    command: Command,
+    positional: Vec<String>,
+    output: Option<String>,
+    snapshot_out: Option<String>,
+    stdin_file: Option<String>,
+    map: Option<String>,
+    resume: Option<String>,
+    trap_overflow: bool,
+    zero_register: bool,
+    break_mode: Option<BreakMode>,
+    underflow_policy: Option<UnderflowPolicy>,
+    seed: Option<u64>,
+    load: LoadOptions,
+    help: bool,
+}
+
+// Split the arguments into flags and positional arguments
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut options = Options {
+        command: Command::Run,
+        positional: Vec::new(),
+        output: None,
+        snapshot_out: None,
+        stdin_file: None,
+        map: None,
+        resume: None,
+        trap_overflow: false,
+        zero_register: false,
+        break_mode: None,
+        underflow_policy: None,
+        seed: None,
+        load: LoadOptions::default(),
+        help: false,
+    };
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        let mut value = |flag: &str| {
+            iter.next()
+                .cloned()
+                .ok_or_else(|| format!("Missing value for {}", flag))
+        };
+        match arg.as_str() {
+            "--help" | "-h" => options.help = true,
+            "--permissive" => options.load.permissive = true,
+            "--deny-warnings" => options.load.deny_warnings = true,
+            "--no-verify" => options.load.skip_checksum = true,
+            "-O" | "--optimize" => options.load.optimize = true,
+            "--strip-debug" => options.load.strip_debug_info = true,
+            "--trap-overflow" => options.trap_overflow = true,
+            "--zero-register" => options.zero_register = true,
+            flag if flag.starts_with("--break=") => {
+                options.break_mode = match &flag["--break=".len()..] {
+                    "continue" => Some(BreakMode::Continue),
+                    "stop" => Some(BreakMode::Stop),
+                    mode => {
+                        return Err(format!(
+                            "Invalid break mode {:?}, must be continue or stop",
+                            mode
+                        ))
+                    }
+                }
+            }
+            flag if flag.starts_with("--underflow=") => {
+                options.underflow_policy = match &flag["--underflow=".len()..] {
+                    "trap" => Some(UnderflowPolicy::Trap),
+                    "zero" => Some(UnderflowPolicy::ReturnZero),
+                    policy => {
+                        return Err(format!(
+                            "Invalid underflow policy {:?}, must be trap or zero",
+                            policy
+                        ))
+                    }
+                }
+            }
+            "--define" => {
+                let define = value(arg)?;
+                let (name, value) = define.split_once('=').unwrap_or((&define, "1"));
+                options
+                    .load
+                    .defines
+                    .push((name.to_string(), value.to_string()));
+            }
+            "--snapshot-out" => options.snapshot_out = Some(value(arg)?),
+            "--stdin-file" => options.stdin_file = Some(value(arg)?),
+            "--seed" => {
+                let seed = value(arg)?;
+                let parsed = seed.parse().map_err(|_| {
+                    format!("Invalid seed {:?}, must be a non-negative integer", seed)
+                })?;
+                options.seed = Some(parsed);
+            }
+            "--map" => options.map = Some(value(arg)?),
+            "--listing" => options.load.listing = Some(value(arg)?),
+            "--resume" => options.resume = Some(value(arg)?),
+            "-o" | "--output" => options.output = Some(value(arg)?),
+            "asm" if options.positional.is_empty() && options.command == Command::Run => {
+                options.command = Command::Assemble
+            }
+            "disasm" if options.positional.is_empty() && options.command == Command::Run => {
+                options.command = Command::Disassemble
+            }
+            flag if flag.starts_with("--") => return Err(format!("Unknown option {}", flag)),
+            _ => options.positional.push(arg.clone()),
+        }
+    }
+
+    let expected = if options.command != Command::Run || options.resume.is_some() {
+        1
+    } else {
+        3
+    };
+    if !options.help && options.positional.len() < expected {
+        return Err(format!(
+            "Expected at least {} arguments, got {}\n{}",
+            expected,
+            options.positional.len(),
+            USAGE
+        ));
+    }
+    Ok(options)
+}
+
+// Function to parse the dimensions
+fn parse_dimensions(dimensions: &str) -> Result<Vec<usize>, String> {
+    dimensions
+        .split('x')
+        .map(|dim| {
+            dim.parse::<usize>()
+                .map_err(|_| format!("Invalid dimension {:?}, must be a positive integer", dim))
+        })
+        .collect()
+}
+
+// Load and link the program files named on the command line and write the symbol map
+// if asked to, exiting on failure
+fn load(console: &mut dyn OutputSink, options: &Options) -> Program {
+    // Dimensions come first when starting a new machine
+    let skip = if options.command == Command::Run && options.resume.is_none() {
+        2
+    } else {
+        0
+    };
+    let files: Vec<&str> = options.positional[skip..]
+        .iter()
+        .map(String::as_str)
+        .collect();
+    let program = match load_programs_with(&files, &options.load) {
+        Ok(program) => program,
+        Err(LoadError::Parse(errors)) => {
+            for err in &errors {
+                console.write_err(&format!("{}\n", err.render()));
+            }
+            fail(
+                console,
+                Failure::Load,
+                &format!("Failed to load program: {} problem(s) found", errors.len()),
+            )
+        }
+        Err(LoadError::Invalid(errors)) => {
+            for err in &errors {
+                console.write_err(&format!("error: {}\n", err));
+            }
+            fail(
+                console,
+                Failure::Load,
+                &format!("Failed to load program: {} problem(s) found", errors.len()),
+            )
+        }
+        Err(err) => fail(
+            console,
+            Failure::Load,
+            &format!("Failed to load program: {}", err),
+        ),
+    };
+
+    if let Some(path) = &options.map {
+        if let Err(err) = fs::write(path, program.symbols().to_string()) {
+            fail(
+                console,
+                Failure::Usage,
+                &format!("Failed to write symbol map {}: {}", path, err),
+            );
+        }
+    }
+    program
+}
+
+// Report an error through the sink and exit with the category's status
+fn fail(sink: &mut dyn OutputSink, failure: Failure, message: &str) -> ! {
+    sink.write_err(&format!("Error ({}): {}\n", failure.category(), message));
+    process::exit(failure.exit_code());
+}
+
+fn main() {
+    let mut console = StdioSink;
+
+    let args: Vec<String> = env::args().skip(1).collect();
+    let mut options = match parse_args(&args) {
+        Ok(options) => options,
+        Err(message) => fail(&mut console, Failure::Usage, &message),
+    };
+    if options.help {
+        console.write_out(&help());
+        return;
+    }
+
+    if options.command == Command::Assemble {
+        let input = &options.positional[0];
+        if input == STDIN_FILENAME && options.output.is_none() {
+            let message = "asm needs -o when the program comes from standard input";
+            fail(&mut console, Failure::Usage, message);
+        }
+        let program = load(&mut console, &options);
+        let output = options.output.clone().unwrap_or_else(|| {
+            let input = Path::new(input);
+            input.with_extension("mdpub").display().to_string()
+        });
+        if let Err(err) = write_binary_program(&program, &output) {
+            fail(
+                &mut console,
+                Failure::Usage,
+                &format!("Failed to write binary program {}: {}", output, err),
+            );
+        }
+        return;
+    }
+    if options.command == Command::Disassemble {
+        let program = load(&mut console, &options);
+        console.write_out(&disassemble_program(&program));
+        return;
+    }
+
+    let mut pu = if let Some(snapshot) = &options.resume {
+        match ProcessingUnit::load_snapshot(snapshot) {
+            Ok(pu) => pu,
+            Err(err) => fail(
+                &mut console,
+                Failure::Load,
+                &format!("Failed to resume snapshot: {}", err),
+            ),
+        }
+    } else {
+        // Parse the dimensions for registers and memory
+        let (register_shape, memory_shape) = match (
+            parse_dimensions(&options.positional[0]),
+            parse_dimensions(&options.positional[1]),
+        ) {
+            (Ok(registers), Ok(memory)) => (registers, memory),
+            (Err(message), _) | (_, Err(message)) => fail(&mut console, Failure::Usage, &message),
+        };
+
+        match ProcessingUnitBuilder::new()
+            .registers(register_shape.iter().product())
+            .memory(&memory_shape)
+            .max_instructions(DEFAULT_MAX_INSTRUCTIONS)
+            .trap_overflow(options.trap_overflow)
+            .zero_register(options.zero_register)
+            .break_mode(options.break_mode.unwrap_or_default())
+            .underflow_policy(options.underflow_policy.unwrap_or_default())
+            .build()
+        {
+            Ok(pu) => pu,
+            Err(err) => fail(&mut console, Failure::Usage, &err.to_string()),
+        }
+    };
+
+    // A resumed machine keeps the modes its snapshot recorded unless the flags give others
+    if options.trap_overflow {
+        pu.set_trap_overflow(true);
+    }
+    if options.zero_register {
+        pu.set_zero_register(true);
+    }
+    if let Some(mode) = options.break_mode {
+        pu.set_break_mode(mode);
+    }
+    if let Some(policy) = options.underflow_policy {
+        pu.set_underflow_policy(policy);
+    }
+    // A snapshot carries the random number generator on unless a seed is given
+    if let Some(seed) = options.seed {
+        pu.set_seed(seed);
+    }
+    if let Some(path) = &options.stdin_file {
+        match fs::File::open(path) {
+            Ok(file) => pu.set_input(BufReader::new(file)),
+            Err(err) => fail(
+                &mut console,
+                Failure::Load,
+                &format!("Failed to open input file {}: {}", path, err),
+            ),
+        }
+    }
+
+    // Operands that do not fit this machine are reported before anything runs
+    options.load.machine = Some((pu.registers().len(), pu.memory().len()));
+    let program = load(&mut console, &options);
+
+    // A run drawing random numbers from a seed of its own can only be repeated with it
+    let random = program
+        .iter()
+        .any(|instr| matches!(instr.opcode, Opcode::Rand | Opcode::RandRange));
+    if random && options.seed.is_none() && options.resume.is_none() {
+        console.write_err(&format!(
+            "Random seed {} (repeat this run with --seed {})\n",
+            pu.seed(),
+            pu.seed()
+        ));
+    }
+
+    // A resumed machine already holds the data image and whatever the program did to it
+    if options.resume.is_none() {
+        if let Err(err) = pu.load_data(&program) {
+            fail(
+                &mut console,
+                Failure::Load,
+                &format!("Failed to load program data: {}", err),
+            );
+        }
+    }
+
+    // A resumed machine gets a fresh budget on top of what it already executed
+    let mic = pu.instruction_count().saturating_add(pu.max_instructions());
+    let (report, exit_code) = match run(&mut pu, &program, mic) {
+        Ok(state) => (state.to_string(), state.exit_code.unwrap_or(0)),
+        Err(err @ MdpuError::InstructionLimitExceeded { .. }) if options.snapshot_out.is_some() => {
+            let path = options.snapshot_out.as_deref().unwrap_or_default();
+            if let Err(save_err) = pu.save_snapshot(path) {
+                fail(
+                    pu.output(),
+                    Failure::Limit,
+                    &format!("{}; failed to write snapshot: {}", err, save_err),
+                );
+            }
+            fail(
+                pu.output(),
+                Failure::Limit,
+                &format!(
+                    "{}\nSnapshot written to {}, continue with --resume {}",
+                    err, path, path
+                ),
+            );
+        }
+        Err(err) => {
+            // Point at the source line and name the register by its alias when the source
+            // gave it one
+            let mut message = err.to_string();
+            if let Some(location) = err.ip().and_then(|ip| program.debug_info().location(ip)) {
+                message.push_str(&format!(" ({})", location));
+            }
+            let alias = err
+                .register()
+                .and_then(|reg| Some((reg, program.symbols().register_alias(reg)?)));
+            if let Some((reg, name)) = alias {
+                message.push_str(&format!(" (R{} is {})", reg, name));
+            }
+            fail(pu.output(), Failure::of(&err), &message)
+        }
+    };
+
+    pu.output().write_out(&report);
+    if exit_code != 0 {
+        process::exit(exit_code);
+    }
+}
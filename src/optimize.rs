@@ -0,0 +1,304 @@
+// Peephole optimizer run over an assembled program by `-O`
+//
+// Each rewrite looks at one or two neighbouring instructions and removes or replaces
+// them without changing what the program computes when it runs to its end. A removed
+// PUSH and POP pair can no longer fault, though, so a program that overflowed its stack
+// there, or named a register the machine does not have, runs on after -O. An instruction that something jumps
+// to, a label points at or the program starts at is never removed, and neither is the
+// first instruction of a pair when the second is such a target, so control can never
+// enter the middle of a rewritten sequence.
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::isa::{AddrOperand, Control, Instruction, Opcode};
+
+// Instructions after optimizing and where each original instruction went
+pub(crate) struct Peephole {
+    pub(crate) instructions: Vec<Instruction>,
+    pub(crate) moved: Vec<Option<usize>>, // New address of each old one, None if removed
+}
+
+impl Peephole {
+    // New address for an old one, which may be the address just past the end. A removed
+    // instruction is replaced by the next one that was kept.
+    pub(crate) fn relocate(&self, addr: usize) -> usize {
+        match self.moved.get(addr..) {
+            Some(rest) => rest
+                .iter()
+                .flatten()
+                .next()
+                .copied()
+                .unwrap_or(self.instructions.len()),
+            None => self.instructions.len() + (addr - self.moved.len()),
+        }
+    }
+
+    pub(crate) fn removed(&self) -> usize {
+        self.moved.iter().filter(|new| new.is_none()).count()
+    }
+}
+
+// Optimize `program`. `targets` holds every address control may arrive at other than by
+// falling through. The first `fixed` instructions are left as they are, since the
+// `.org` and `.align` padding among them only lands where it should if nothing before
+// it moves.
+pub(crate) fn peephole(program: &[Instruction], targets: &[usize], fixed: usize) -> Peephole {
+    let len = program.len();
+    // Treating the fixed instructions as targets keeps every rewrite away from them
+    let mut target = vec![false; len + 1];
+    target[..fixed.min(len)].fill(true);
+    for &addr in targets.iter().filter(|&&addr| addr <= len) {
+        target[addr] = true;
+    }
+    for (ip, instr) in program.iter().enumerate() {
+        if let Some(addr) = instr.jump_target(ip).filter(|&addr| addr <= len) {
+            target[addr] = true;
+        }
+    }
+
+    let mut code: Vec<Option<Instruction>> = program.iter().copied().map(Some).collect();
+    // Removing an instruction can bring two others together, so repeat until nothing changes
+    while rewrite(&mut code, &target) {}
+
+    let mut moved = Vec::with_capacity(len);
+    let mut instructions = Vec::with_capacity(len);
+    for instr in &code {
+        moved.push(instr.map(|_| instructions.len()));
+        instructions.extend(*instr);
+    }
+    let mut result = Peephole {
+        instructions,
+        moved,
+    };
+    for (ip, instr) in program.iter().enumerate() {
+        let Some(new) = result.moved[ip] else {
+            continue;
+        };
+        let Some(target) = instr.jump_target(ip) else {
+            continue;
+        };
+        result.instructions[new].addr = match instr.addr {
+            AddrOperand::Absolute(_) => AddrOperand::Absolute(result.relocate(target)),
+            // A relative target keeps pointing at the same instruction from its new place
+            AddrOperand::Relative(_) => {
+                let offset = result.relocate(target) as i64 - (new as i64 + 1);
+                AddrOperand::Relative(offset as i32)
+            }
+        };
+    }
+    result
+}
+
+// One pass of every rewrite over `code`, returning whether anything changed
+fn rewrite(code: &mut [Option<Instruction>], target: &[bool]) -> bool {
+    let len = code.len();
+    let mut changed = false;
+    let mut ip = 0;
+    while ip < len {
+        let Some(instr) = code[ip] else {
+            ip += 1;
+            continue;
+        };
+        // The next instruction still in the program, if only fall-through reaches it
+        let next = (ip + 1..len)
+            .find(|&at| code[at].is_some())
+            .filter(|&at| !target[at]);
+
+        let rewritten = match (instr.opcode, next.map(|at| (at, code[at].expect("kept")))) {
+            // A jump to where execution goes anyway
+            _ if !target[ip] && jumps_to_next(&instr, ip, code) => {
+                code[ip] = None;
+                true
+            }
+            // Pushing a register and popping it straight back changes nothing, unless the
+            // PUSH overflows the stack, whose size is only known once the program runs
+            (Opcode::Push, Some((at, pop)))
+                if !target[ip] && pop.opcode == Opcode::Pop && pop.reg1 == instr.reg1 =>
+            {
+                code[ip] = None;
+                code[at] = None;
+                true
+            }
+            // Adding a register just set to zero, when the flags the ADD sets and a MOV
+            // would not are replaced before anything can see them
+            (Opcode::LoadImmediate, Some((at, add)))
+                if instr.immediate == 0
+                    && add.opcode == Opcode::Add
+                    && flags_replaced_after(at, code, target) =>
+            {
+                let zero = instr.reg1;
+                let other = match (add.reg1 == zero, add.reg2 == zero) {
+                    (true, false) => add.reg2,
+                    (false, true) => add.reg1,
+                    _ => {
+                        ip += 1;
+                        continue;
+                    }
+                };
+                if add.reg3 == other {
+                    code[at] = None; // `other + 0` back into `other`
+                } else {
+                    // The zero is overwritten by the move, so nothing reads it
+                    if add.reg3 == zero && !target[ip] {
+                        code[ip] = None;
+                    }
+                    let mut mov = Instruction::new(Opcode::Mov);
+                    mov.reg1 = add.reg3;
+                    mov.reg2 = other;
+                    code[at] = Some(mov);
+                }
+                true
+            }
+            // Blank lines in a row only need one NOP between them
+            (Opcode::Nop, Some((at, nop))) if nop.opcode == Opcode::Nop => {
+                code[at] = None;
+                changed = true;
+                continue; // Look at the NOP after it too
+            }
+            _ => false,
+        };
+        changed |= rewritten;
+        ip += 1;
+    }
+    changed
+}
+
+// Whether the instruction that falls through from the one at `ip` sets every flag, and
+// is only reached that way
+fn flags_replaced_after(ip: usize, code: &[Option<Instruction>], target: &[bool]) -> bool {
+    (ip + 1..code.len())
+        .find(|&at| code[at].is_some())
+        .filter(|&at| !target[at])
+        .and_then(|at| code[at])
+        .is_some_and(|next| next.opcode.sets_flags())
+}
+
+// Whether `instr` at `ip` jumps, possibly conditionally, to the next instruction still
+// in the program. Such a jump can go unless it does something else too, like a call or
+// the decrement of a LOOP.
+fn jumps_to_next(instr: &Instruction, ip: usize, code: &[Option<Instruction>]) -> bool {
+    if !matches!(instr.opcode.control(), Control::Jump | Control::Branch)
+        || instr.opcode.written().is_some()
+    {
+        return false;
+    }
+    let Some(target) = instr.jump_target(ip) else {
+        return false;
+    };
+    target > ip
+        && code[ip + 1..target.min(code.len())]
+            .iter()
+            .all(Option::is_none)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::String;
+
+    use super::*;
+    use crate::asm::{assemble, assemble_lenient, SourceFile};
+    use crate::cpu::{run, ProcessingUnit};
+    use crate::error::MdpuError;
+
+    // Instructions of `source` after -O, and how many it removed
+    fn optimized(source: &str) -> (Vec<Instruction>, usize) {
+        let root = SourceFile {
+            name: None,
+            key: String::new(),
+            text: String::from(source),
+        };
+        let mut assembly = assemble_lenient(root, &[], &mut |_, _| Err(String::new()));
+        let removed = assembly.optimize().unwrap();
+        (assembly.program.into_instructions(), removed)
+    }
+
+    fn program(source: &str) -> Vec<Instruction> {
+        assemble(source).unwrap().into_instructions()
+    }
+
+    #[test]
+    fn each_rewrite_applies() {
+        for (source, expected, removed) in [
+            ("LI R0 1\nPUSH R2\nPOP R2\nHALT", "LI R0 1\nHALT", 2),
+            (
+                "LI R1 0\nADD R0 R1 R2\nCMP R2 R0\nHALT",
+                "LI R1 0\nMOV R2 R0\nCMP R2 R0\nHALT",
+                0,
+            ),
+            (
+                "LI R1 0\nADD R1 R0 R0\nCMP R0 R0\nHALT",
+                "LI R1 0\nCMP R0 R0\nHALT",
+                1,
+            ),
+            ("LI R0 1\nJMP next\nnext: HALT", "LI R0 1\nHALT", 1),
+            ("LI R0 1\nNOP\nNOP\nNOP\nHALT", "LI R0 1\nNOP\nHALT", 2),
+        ] {
+            assert_eq!(
+                optimized(source),
+                (program(expected), removed),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn label_inside_a_pattern_suppresses_it() {
+        for source in [
+            "PUSH R2\nback: POP R2\nJNZ R0 back",
+            "LI R1 0\nsum: ADD R0 R1 R2\nCMP R2 R0\nJNZ R2 sum",
+            "NOP\nmore: NOP\nJNZ R0 more",
+        ] {
+            assert_eq!(optimized(source), (program(source), 0), "{}", source);
+        }
+    }
+
+    #[test]
+    fn flags_the_add_sets_keep_it() {
+        // JZ reads the zero flag the ADD set, which a MOV would leave as it was
+        let source = "LI R1 0\nADD R0 R1 R2\nJZ R2 0\nHALT";
+        assert_eq!(optimized(source), (program(source), 0));
+    }
+
+    #[test]
+    fn jumps_follow_their_target_when_it_moves() {
+        let source = "LI R0 3\nNOP\nNOP\nNOP\nloop: DEC R0\nJNZ R0 loop\nBRNZ R1 loop\nHALT";
+        let (instructions, removed) = optimized(source);
+        assert_eq!(removed, 2);
+        assert_eq!(instructions[2].opcode, Opcode::Dec);
+        assert_eq!(instructions[3].jump_target(3), Some(2));
+        assert_eq!(instructions[4].jump_target(4), Some(2));
+
+        let mut before = ProcessingUnit::initialize(2, 4);
+        let mut after = ProcessingUnit::initialize(2, 4);
+        run(&mut before, &program(source), 100).unwrap();
+        run(&mut after, &instructions, 100).unwrap();
+        assert_eq!(before.registers(), after.registers());
+    }
+
+    // The first instruction is only reached by starting there, so it can go like any
+    // other unless something jumps back to it
+    #[test]
+    fn first_instruction_can_be_removed() {
+        assert_eq!(optimized("PUSH R2\nPOP R2\nHALT"), (program("HALT"), 2));
+        let source = "top: PUSH R2\nPOP R2\nJNZ R0 top";
+        assert_eq!(optimized(source), (program(source), 0));
+        let source = ".entry start\nHALT\nstart: PUSH R2\nPOP R2\nHALT";
+        assert_eq!(optimized(source), (program(source), 0));
+    }
+
+    #[test]
+    fn removed_push_no_longer_overflows() {
+        let source = "PUSH R0\nPUSH R1\nPOP R1\nHALT";
+        let (instructions, removed) = optimized(source);
+        assert_eq!(removed, 2);
+        // One free cell: the second PUSH overflowed before -O removed it
+        let mut before = ProcessingUnit::initialize(2, 2);
+        assert_eq!(
+            run(&mut before, &program(source), 100).unwrap_err(),
+            MdpuError::StackOverflow { reg: 1, ip: 1 }
+        );
+        let mut after = ProcessingUnit::initialize(2, 2);
+        assert!(run(&mut after, &instructions, 100).is_ok());
+    }
+}
@@ -0,0 +1,158 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+#[cfg(feature = "std")]
+use std::io::{self, Write};
+#[cfg(feature = "std")]
+use std::sync::{Arc, Mutex};
+
+// Destination for everything the machine and CLI print
+pub trait OutputSink: Send {
+    fn write_out(&mut self, text: &str);
+    fn write_err(&mut self, text: &str);
+}
+
+// Character written for the Unicode code point `code` by PRINTC and SYS 2: U+FFFD, the
+// replacement character, for a value that is not one, like a negative number or a
+// surrogate
+pub(crate) fn character(code: i32) -> char {
+    u32::try_from(code)
+        .ok()
+        .and_then(char::from_u32)
+        .unwrap_or(char::REPLACEMENT_CHARACTER)
+}
+
+// Sink used by new machines: the console with `std`, nothing without
+pub(crate) fn default_sink() -> Box<dyn OutputSink> {
+    #[cfg(feature = "std")]
+    return Box::new(StdioSink);
+    #[cfg(not(feature = "std"))]
+    return Box::new(NullSink);
+}
+
+// Sink discarding everything written to it
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NullSink;
+
+impl OutputSink for NullSink {
+    fn write_out(&mut self, _text: &str) {}
+
+    fn write_err(&mut self, _text: &str) {}
+}
+
+// Sink writing to the process stdout and stderr
+#[cfg(feature = "std")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct StdioSink;
+
+#[cfg(feature = "std")]
+impl OutputSink for StdioSink {
+    fn write_out(&mut self, text: &str) {
+        // Console output is best effort, a closed pipe must not abort execution
+        let _ = io::stdout().write_all(text.as_bytes());
+    }
+
+    fn write_err(&mut self, text: &str) {
+        let _ = io::stderr().write_all(text.as_bytes());
+    }
+}
+
+// Sink collecting output in memory; clones share the same buffers
+#[cfg(feature = "std")]
+#[derive(Debug, Default, Clone)]
+pub struct CaptureSink {
+    out: Arc<Mutex<String>>,
+    err: Arc<Mutex<String>>,
+}
+
+#[cfg(feature = "std")]
+impl CaptureSink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Everything written to the normal output so far
+    pub fn out(&self) -> String {
+        self.out.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    // Everything written to the error output so far
+    pub fn err(&self) -> String {
+        self.err.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+}
+
+#[cfg(feature = "std")]
+impl OutputSink for CaptureSink {
+    fn write_out(&mut self, text: &str) {
+        self.out
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push_str(text);
+    }
+
+    fn write_err(&mut self, text: &str) {
+        self.err
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push_str(text);
+    }
+}
+
+// Number of bytes of normal output held back before they are written to the sink
+const OUTPUT_BUFFER_SIZE: usize = 8192;
+
+// Sink of a processing unit, collecting normal output so that a loop of PRINTs writes to
+// the sink in large pieces instead of once per line. Error output is written at once,
+// after the normal output before it.
+pub(crate) struct BufferedSink {
+    sink: Box<dyn OutputSink>,
+    pending: String,
+}
+
+impl BufferedSink {
+    pub(crate) fn new(sink: Box<dyn OutputSink>) -> Self {
+        BufferedSink {
+            sink,
+            pending: String::new(),
+        }
+    }
+
+    pub(crate) fn flush(&mut self) {
+        if !self.pending.is_empty() {
+            self.sink.write_out(&self.pending);
+            self.pending.clear();
+        }
+    }
+
+    // The sink itself, with everything held back written to it
+    pub(crate) fn sink(&mut self) -> &mut dyn OutputSink {
+        self.flush();
+        self.sink.as_mut()
+    }
+}
+
+impl Default for BufferedSink {
+    fn default() -> Self {
+        Self::new(default_sink())
+    }
+}
+
+impl OutputSink for BufferedSink {
+    fn write_out(&mut self, text: &str) {
+        self.pending.push_str(text);
+        if self.pending.len() >= OUTPUT_BUFFER_SIZE {
+            self.flush();
+        }
+    }
+
+    fn write_err(&mut self, text: &str) {
+        self.flush();
+        self.sink.write_err(text);
+    }
+}
+
+impl Drop for BufferedSink {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
@@ -0,0 +1,435 @@
+use alloc::vec::Vec;
+use core::ops::Deref;
+
+use crate::builder::ProcessingUnitBuilder;
+use crate::cpu::ProcessingUnit;
+use crate::debug::DebugInfo;
+use crate::error::{BuildError, ValidationError};
+use crate::isa::{AddrOperand, Instruction, Opcode, Operand};
+use crate::symbols::SymbolTable;
+
+// Stack cells reserved by `ProcessingUnit::sized_for` for programs that use the stack
+pub const SIZED_STACK_CELLS: usize = 64;
+
+// An assembled program with questions that can be asked about it
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Program {
+    instructions: Vec<Instruction>,
+    data: Vec<(usize, Vec<i32>)>, // Values written to memory at the given address before running
+    symbols: SymbolTable,         // Labels and constants, when assembled from source
+    entry: usize,                 // Address of the first instruction to run
+    debug: DebugInfo,             // Source line of each instruction, when assembled from source
+}
+
+impl Program {
+    pub fn from_instructions(instructions: Vec<Instruction>) -> Self {
+        Program {
+            instructions,
+            data: Vec::new(),
+            symbols: SymbolTable::default(),
+            entry: 0,
+            debug: DebugInfo::default(),
+        }
+    }
+
+    // Add values to place in memory starting at `addr` before the program runs
+    pub fn with_data(mut self, addr: usize, values: Vec<i32>) -> Self {
+        self.data.push((addr, values));
+        self
+    }
+
+    // Initial memory image as (address, values) blocks
+    pub fn data(&self) -> &[(usize, Vec<i32>)] {
+        &self.data
+    }
+
+    // Start execution at `entry` instead of the first instruction
+    pub fn with_entry(mut self, entry: usize) -> Self {
+        self.entry = entry;
+        self
+    }
+
+    pub fn entry(&self) -> usize {
+        self.entry
+    }
+
+    pub fn with_symbols(mut self, symbols: SymbolTable) -> Self {
+        self.symbols = symbols;
+        self
+    }
+
+    // Labels and constants the program was assembled with
+    pub fn symbols(&self) -> &SymbolTable {
+        &self.symbols
+    }
+
+    // Attach source lines, or drop them with an empty table
+    pub fn with_debug_info(mut self, debug: DebugInfo) -> Self {
+        self.debug = debug;
+        self
+    }
+
+    // Where each instruction was written, for naming source lines at runtime
+    pub fn debug_info(&self) -> &DebugInfo {
+        &self.debug
+    }
+
+    pub fn instructions(&self) -> &[Instruction] {
+        &self.instructions
+    }
+
+    pub fn into_instructions(self) -> Vec<Instruction> {
+        self.instructions
+    }
+
+    // Highest register index any instruction refers to
+    pub fn max_register_used(&self) -> Option<usize> {
+        self.instructions
+            .iter()
+            .flat_map(Instruction::registers)
+            .max()
+    }
+
+    // Highest memory address accessed directly by LOAD/STORE or initialized by the data image
+    pub fn max_memory_address_referenced(&self) -> Option<usize> {
+        let data_end = self
+            .data
+            .iter()
+            .filter(|(_, values)| !values.is_empty())
+            .map(|(addr, values)| addr + values.len() - 1);
+        self.instructions
+            .iter()
+            .filter_map(Instruction::memory_address)
+            .chain(data_end)
+            .max()
+    }
+
+    // Distinct instruction addresses targeted by jumps and branches, in ascending order
+    pub fn jump_targets(&self) -> Vec<usize> {
+        let mut targets: Vec<usize> = self
+            .instructions
+            .iter()
+            .enumerate()
+            .filter_map(|(ip, instr)| instr.jump_target(ip))
+            .collect();
+        targets.sort_unstable();
+        targets.dedup();
+        targets
+    }
+
+    // Check every register operand, memory address and jump target against a machine with
+    // `registers` registers and `memory` cells, every bit field against 32 bits and every
+    // pair of registers that must differ, so a bad operand on a path that rarely runs is
+    // found before the program starts. A jump to the address just past the last
+    // instruction halts the program like running off its end, so it is allowed.
+    pub fn validate(&self, registers: usize, memory: usize) -> Result<(), Vec<ValidationError>> {
+        let len = self.instructions.len();
+        let mut errors = Vec::new();
+        for (ip, instr) in self.instructions.iter().enumerate() {
+            for &operand in instr.opcode.operands() {
+                let error = match operand {
+                    Operand::Reg4 => match instr.addr {
+                        AddrOperand::Relative(offset) => Some(ValidationError::RelativeAddress {
+                            ip,
+                            operand,
+                            offset,
+                        }),
+                        AddrOperand::Absolute(reg) => {
+                            (reg >= registers).then_some(ValidationError::RegisterOutOfBounds {
+                                ip,
+                                operand,
+                                reg,
+                                registers,
+                            })
+                        }
+                    },
+                    Operand::Reg1 | Operand::Reg2 | Operand::Reg3 => instr
+                        .register(operand)
+                        .filter(|&reg| reg >= registers)
+                        .map(|reg| ValidationError::RegisterOutOfBounds {
+                            ip,
+                            operand,
+                            reg,
+                            registers,
+                        }),
+                    Operand::Addr | Operand::Width => match instr.addr {
+                        AddrOperand::Relative(offset) => Some(ValidationError::RelativeAddress {
+                            ip,
+                            operand,
+                            offset,
+                        }),
+                        AddrOperand::Absolute(addr) if operand == Operand::Addr => (addr >= memory)
+                            .then_some(ValidationError::MemoryOutOfBounds {
+                                ip,
+                                addr,
+                                memory_size: memory,
+                            }),
+                        AddrOperand::Absolute(width) => {
+                            let end = i64::from(instr.immediate) + width as i64;
+                            let fits = (0..=31).contains(&instr.immediate)
+                                && (1..=32).contains(&width)
+                                && end <= 32;
+                            (!fits).then_some(ValidationError::BitFieldOutOfRange {
+                                ip,
+                                pos: instr.immediate,
+                                width,
+                            })
+                        }
+                    },
+                    Operand::Target | Operand::Offset => match instr.addr {
+                        AddrOperand::Absolute(target) => (target > len)
+                            .then_some(ValidationError::JumpOutOfBounds { ip, target, len }),
+                        AddrOperand::Relative(offset) => instr
+                            .jump_target(ip)
+                            .is_none_or(|target| target > len)
+                            .then_some(ValidationError::BranchOutOfBounds {
+                                ip,
+                                operand,
+                                offset,
+                                len,
+                            }),
+                    },
+                    Operand::Imm => None,
+                };
+                errors.extend(error);
+            }
+            if let Some((first, second)) = instr.opcode.distinct_registers() {
+                let aliased = instr
+                    .register(first)
+                    .filter(|&reg| instr.register(second) == Some(reg));
+                errors.extend(aliased.map(|reg| ValidationError::AliasedRegisters {
+                    ip,
+                    opcode: instr.opcode,
+                    reg,
+                }));
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    pub fn uses_stack(&self) -> bool {
+        self.instructions.iter().any(|instr| {
+            matches!(
+                instr.opcode,
+                Opcode::Push
+                    | Opcode::Pop
+                    | Opcode::PushImmediate
+                    | Opcode::PushAll
+                    | Opcode::PopAll
+                    | Opcode::Peek
+                    | Opcode::Drop
+                    | Opcode::Call
+                    | Opcode::Ret
+                    | Opcode::Enter
+                    | Opcode::Leave
+                    | Opcode::Rdsp
+                    | Opcode::Wrsp
+            )
+        })
+    }
+
+    // Smallest register count and memory size that fit the program, as used by
+    // `ProcessingUnit::sized_for`
+    pub fn suggested_dimensions(&self) -> (usize, usize) {
+        let registers = self.max_register_used().map_or(0, |reg| reg + 1);
+        let data = self
+            .max_memory_address_referenced()
+            .map_or(0, |addr| addr + 1);
+        // The stack sits above the data, separated by one cell it never writes
+        (registers, data + self.stack_cells(registers) + 1)
+    }
+
+    // Stack cells `ProcessingUnit::sized_for` reserves for a machine with `registers`
+    // registers, with room for one PUSHA on top of the usual reserve
+    fn stack_cells(&self, registers: usize) -> usize {
+        if self
+            .instructions
+            .iter()
+            .any(|instr| instr.opcode == Opcode::PushAll)
+        {
+            SIZED_STACK_CELLS + registers
+        } else if self.uses_stack() {
+            SIZED_STACK_CELLS
+        } else {
+            0
+        }
+    }
+}
+
+impl Deref for Program {
+    type Target = [Instruction];
+
+    fn deref(&self) -> &[Instruction] {
+        &self.instructions
+    }
+}
+
+impl From<Vec<Instruction>> for Program {
+    fn from(instructions: Vec<Instruction>) -> Self {
+        Program::from_instructions(instructions)
+    }
+}
+
+impl ProcessingUnit {
+    // Write the program's data image into memory and point the instruction pointer at its
+    // entry, leaving the machine untouched if the data does not fit
+    pub fn load_data(&mut self, program: &Program) -> Result<(), BuildError> {
+        let memory_size = self.memory.len();
+        for (addr, values) in &program.data {
+            if addr.saturating_add(values.len()) > memory_size {
+                return Err(BuildError::MemoryOutOfBounds {
+                    addr: *addr,
+                    len: values.len(),
+                    memory_size,
+                });
+            }
+        }
+        for (addr, values) in &program.data {
+            self.memory[*addr..*addr + values.len()].copy_from_slice(values);
+        }
+        self.instruction_pointer = program.entry;
+        Ok(())
+    }
+
+    // Create a processing unit just large enough for the registers, memory and stack the
+    // program uses, with its data image already in memory and ready to start at its entry
+    pub fn sized_for(program: &Program) -> ProcessingUnit {
+        let (registers, memory) = program.suggested_dimensions();
+        let mut builder = ProcessingUnitBuilder::new()
+            .registers(registers)
+            .memory(&[memory])
+            .stack_size(program.stack_cells(registers));
+        for (addr, values) in &program.data {
+            builder = builder.initial_memory(*addr, values);
+        }
+        let mut pu = builder
+            .build()
+            .expect("memory is never empty, larger than the stack and holds the data image");
+        pu.instruction_pointer = program.entry;
+        pu
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+
+    fn instruction(opcode: Opcode, reg1: usize, reg2: usize) -> Instruction {
+        Instruction {
+            reg1,
+            reg2,
+            ..Instruction::new(opcode)
+        }
+    }
+
+    #[test]
+    fn validate_refuses_one_register_for_two_results() {
+        let program = Program::from_instructions(Vec::from([
+            instruction(Opcode::Divmod, 1, 1),
+            instruction(Opcode::Mulw, 2, 2),
+            instruction(Opcode::Divmod, 1, 2),
+            instruction(Opcode::Swap, 3, 3),
+            instruction(Opcode::Add, 3, 3),
+        ]));
+        assert_eq!(
+            program.validate(4, 4),
+            Err(Vec::from([
+                ValidationError::AliasedRegisters {
+                    ip: 0,
+                    opcode: Opcode::Divmod,
+                    reg: 1,
+                },
+                ValidationError::AliasedRegisters {
+                    ip: 1,
+                    opcode: Opcode::Mulw,
+                    reg: 2,
+                },
+            ]))
+        );
+    }
+
+    #[test]
+    fn validate_checks_relative_addresses() {
+        let with_addr = |opcode, addr| Instruction {
+            addr,
+            ..Instruction::new(opcode)
+        };
+        let program = Program::from_instructions(Vec::from([
+            with_addr(Opcode::Jmp, AddrOperand::Relative(2)),
+            with_addr(Opcode::Jmp, AddrOperand::Relative(4)),
+            with_addr(Opcode::Br, AddrOperand::Relative(-4)),
+            with_addr(Opcode::Br, AddrOperand::Absolute(1)),
+            with_addr(Opcode::Load, AddrOperand::Relative(0)),
+        ]));
+        assert_eq!(
+            program.validate(4, 4),
+            Err(Vec::from([
+                ValidationError::BranchOutOfBounds {
+                    ip: 1,
+                    operand: Operand::Target,
+                    offset: 4,
+                    len: 5,
+                },
+                ValidationError::BranchOutOfBounds {
+                    ip: 2,
+                    operand: Operand::Offset,
+                    offset: -4,
+                    len: 5,
+                },
+                ValidationError::RelativeAddress {
+                    ip: 4,
+                    operand: Operand::Addr,
+                    offset: 0,
+                },
+            ]))
+        );
+    }
+
+    #[test]
+    fn assembler_refuses_one_register_for_two_results() {
+        for source in [
+            "DIVMOD R1 R1 R2 R3",
+            "MULW R0 0 R1 R2",
+            "DIVMOD R1 R1 R0 0 0",
+        ] {
+            let err = assemble(source).unwrap_err();
+            assert!(err.message.contains("needs two registers"), "{}", source);
+        }
+        assert!(assemble("SWAP R1 R1\nDIVMOD R1 R2 R1 R2\nADD R1 R1 R1").is_ok());
+    }
+
+    #[test]
+    fn validate_checks_the_fourth_register() {
+        let mulw = |addr| Instruction {
+            reg1: 1,
+            addr,
+            ..Instruction::new(Opcode::Mulw)
+        };
+        let program = Program::from_instructions(Vec::from([
+            mulw(AddrOperand::Absolute(3)),
+            mulw(AddrOperand::Absolute(4)),
+            mulw(AddrOperand::Relative(0)),
+        ]));
+        assert_eq!(
+            program.validate(4, 4),
+            Err(Vec::from([
+                ValidationError::RegisterOutOfBounds {
+                    ip: 1,
+                    operand: Operand::Reg4,
+                    reg: 4,
+                    registers: 4,
+                },
+                ValidationError::RelativeAddress {
+                    ip: 2,
+                    operand: Operand::Reg4,
+                    offset: 0,
+                },
+            ]))
+        );
+    }
+}
@@ -0,0 +1,102 @@
+// Pseudo-random numbers for RAND and RANDR
+//
+// SplitMix64: small, fast and good enough for simulations, but not for anything that
+// must be unpredictable. The whole state is one number, so a snapshot can carry it.
+
+// Generator of a processing unit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) struct Rng {
+    pub(crate) state: u64,
+}
+
+impl Default for Rng {
+    fn default() -> Self {
+        Self::new(default_seed())
+    }
+}
+
+impl Rng {
+    // Generator whose numbers only depend on `seed`
+    pub(crate) fn new(seed: u64) -> Self {
+        Rng { state: seed }
+    }
+
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    // Any i32, each as likely as the others
+    pub(crate) fn next_i32(&mut self) -> i32 {
+        (self.next_u64() >> 32) as i32
+    }
+
+    // A number from 0 up to but not including `max`, which must be positive. Draws that
+    // would make the low numbers more likely are thrown away.
+    pub(crate) fn below(&mut self, max: i32) -> i32 {
+        let max = max as u64;
+        let zone = (1u64 << 32) - (1u64 << 32) % max;
+        loop {
+            let draw = self.next_u64() >> 32;
+            if draw < zone {
+                return (draw % max) as i32;
+            }
+        }
+    }
+}
+
+// Seed for a machine that was not given one: different for every machine with `std`, 0
+// without it
+pub(crate) fn default_seed() -> u64 {
+    #[cfg(feature = "std")]
+    {
+        use std::collections::hash_map::RandomState;
+        use std::hash::BuildHasher;
+        RandomState::new().hash_one(0u8)
+    }
+    #[cfg(not(feature = "std"))]
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seed_0_gives_the_reference_sequence() {
+        let mut rng = Rng::new(0);
+        assert_eq!(rng.next_u64(), 0xE220_A839_7B1D_CDAF);
+        assert_eq!(rng.next_u64(), 0x6E78_9E6A_A1B9_65F4);
+    }
+
+    #[test]
+    fn seed_fixes_the_numbers() {
+        let mut rng = Rng::new(42);
+        let numbers: [i32; 5] = core::array::from_fn(|_| rng.next_i32());
+        assert_eq!(
+            numbers,
+            [-1109970394, 686809907, 1196582743, 1478287871, 163338330]
+        );
+    }
+
+    #[test]
+    fn below_stays_under_its_bound() {
+        let mut rng = Rng::new(7);
+        for max in [1, 2, 3, 10, 1 << 30, i32::MAX] {
+            for _ in 0..1000 {
+                let value = rng.below(max);
+                assert!((0..max).contains(&value), "{} from below({})", value, max);
+            }
+        }
+        // Every number in a small range comes up
+        let mut seen = [false; 6];
+        for _ in 0..1000 {
+            seen[rng.below(6) as usize] = true;
+        }
+        assert_eq!(seen, [true; 6]);
+    }
+}
@@ -0,0 +1,349 @@
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::cpu::{BreakMode, ProcessingUnit, UnderflowPolicy};
+use crate::flags::Flags;
+use crate::random::Rng;
+
+// First line of every snapshot file, bumped when the layout changes
+const SNAPSHOT_HEADER: &str = "mdpu-snapshot 1";
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn join(values: &[i32]) -> String {
+    values
+        .iter()
+        .map(|v| v.to_string())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+impl ProcessingUnit {
+    // Persist the complete machine state, including where execution stopped
+    pub fn save_snapshot(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut text = String::new();
+        let _ = writeln!(text, "{}", SNAPSHOT_HEADER);
+        let _ = writeln!(text, "registers {}", join(&self.registers));
+        let _ = writeln!(text, "memory {}", join(&self.memory));
+        let _ = writeln!(text, "stack_pointer {}", self.stack_pointer);
+        let _ = writeln!(text, "stack_limit {}", self.stack_limit);
+        let _ = writeln!(text, "frame_pointer {}", self.frame_pointer);
+        let _ = writeln!(text, "flags {}", self.flags.bits());
+        let _ = writeln!(text, "max_instructions {}", self.max_instructions);
+        let _ = writeln!(text, "trap_overflow {}", u8::from(self.trap_overflow));
+        let _ = writeln!(text, "zero_register {}", u8::from(self.zero_register));
+        let break_mode = match self.break_mode {
+            BreakMode::Continue => "continue",
+            BreakMode::Stop => "stop",
+        };
+        let _ = writeln!(text, "break_mode {}", break_mode);
+        let underflow_policy = match self.underflow_policy {
+            UnderflowPolicy::Trap => "trap",
+            UnderflowPolicy::ReturnZero => "zero",
+        };
+        let _ = writeln!(text, "underflow_policy {}", underflow_policy);
+        let _ = writeln!(text, "instruction_pointer {}", self.instruction_pointer);
+        let _ = writeln!(text, "instruction_count {}", self.instruction_count);
+        let exit_code = self
+            .exit_code
+            .map_or("none".to_string(), |code| code.to_string());
+        let _ = writeln!(text, "exit_code {}", exit_code);
+        let _ = writeln!(text, "seed {}", self.seed);
+        let _ = writeln!(text, "random_state {}", self.rng.state);
+        fs::write(path, text)
+    }
+
+    // Restore a machine written by `save_snapshot`; output goes to the console
+    pub fn load_snapshot(path: impl AsRef<Path>) -> io::Result<ProcessingUnit> {
+        let text = fs::read_to_string(path)?;
+        let mut lines = text.lines();
+        if lines.next() != Some(SNAPSHOT_HEADER) {
+            return Err(invalid("Not an mdpu snapshot file".to_string()));
+        }
+
+        let mut field = |name: &str| -> io::Result<Vec<&str>> {
+            let line = lines
+                .next()
+                .ok_or_else(|| invalid(format!("Snapshot is missing `{}`", name)))?;
+            let mut parts = line.split_whitespace();
+            if parts.next() != Some(name) {
+                return Err(invalid(format!("Expected `{}` in snapshot", name)));
+            }
+            Ok(parts.collect())
+        };
+
+        fn numbers<T: std::str::FromStr>(name: &str, values: Vec<&str>) -> io::Result<Vec<T>> {
+            values
+                .into_iter()
+                .map(|v| {
+                    v.parse()
+                        .map_err(|_| invalid(format!("Invalid value `{}` for `{}`", v, name)))
+                })
+                .collect()
+        }
+
+        fn single<T: std::str::FromStr + Copy>(name: &str, values: Vec<&str>) -> io::Result<T> {
+            match numbers(name, values)?.as_slice() {
+                [value] => Ok(*value),
+                _ => Err(invalid(format!("Expected one value for `{}`", name))),
+            }
+        }
+
+        // The one word of a field that holds one of `choices`
+        fn choice<T: Copy>(name: &str, values: Vec<&str>, choices: &[(&str, T)]) -> io::Result<T> {
+            let [value] = values.as_slice() else {
+                return Err(invalid(format!("Expected one value for `{}`", name)));
+            };
+            choices
+                .iter()
+                .find(|(word, _)| word == value)
+                .map(|&(_, choice)| choice)
+                .ok_or_else(|| invalid(format!("Invalid value `{}` for `{}`", value, name)))
+        }
+
+        let registers = numbers("registers", field("registers")?)?;
+        let memory: Vec<i32> = numbers("memory", field("memory")?)?;
+        let stack_pointer = single("stack_pointer", field("stack_pointer")?)?;
+        let stack_limit = single("stack_limit", field("stack_limit")?)?;
+        let frame_pointer = single("frame_pointer", field("frame_pointer")?)?;
+        let bits: u8 = single("flags", field("flags")?)?;
+        if Flags::from_bits(bits).bits() != bits {
+            return Err(invalid(format!("Invalid value `{}` for `flags`", bits)));
+        }
+        let flags = Flags::from_bits(bits);
+        let max_instructions = single("max_instructions", field("max_instructions")?)?;
+        let switch = [("0", false), ("1", true)];
+        let trap_overflow = choice("trap_overflow", field("trap_overflow")?, &switch)?;
+        let zero_register = choice("zero_register", field("zero_register")?, &switch)?;
+        let break_mode = choice(
+            "break_mode",
+            field("break_mode")?,
+            &[("continue", BreakMode::Continue), ("stop", BreakMode::Stop)],
+        )?;
+        let underflow_policy = choice(
+            "underflow_policy",
+            field("underflow_policy")?,
+            &[
+                ("trap", UnderflowPolicy::Trap),
+                ("zero", UnderflowPolicy::ReturnZero),
+            ],
+        )?;
+        let instruction_pointer = single("instruction_pointer", field("instruction_pointer")?)?;
+        let instruction_count = single("instruction_count", field("instruction_count")?)?;
+        let exit_code = match field("exit_code")?.as_slice() {
+            ["none"] => None,
+            values => Some(single("exit_code", values.to_vec())?),
+        };
+        let seed = single("seed", field("seed")?)?;
+        let random_state = single("random_state", field("random_state")?)?;
+        if let Some(line) = lines.next() {
+            return Err(invalid(format!(
+                "Unexpected `{}` at the end of the snapshot",
+                line
+            )));
+        }
+
+        if stack_pointer >= memory.len() || stack_limit > stack_pointer {
+            return Err(invalid(format!(
+                "Stack pointer {} is inconsistent with {} memory cells",
+                stack_pointer,
+                memory.len()
+            )));
+        }
+
+        let mut pu = ProcessingUnit::with_stack(registers.len(), memory.len(), 0);
+        pu.registers = registers;
+        pu.memory = memory;
+        pu.stack_pointer = stack_pointer;
+        pu.stack_limit = stack_limit;
+        pu.frame_pointer = frame_pointer;
+        pu.flags = flags;
+        pu.max_instructions = max_instructions;
+        pu.trap_overflow = trap_overflow;
+        pu.zero_register = zero_register;
+        pu.break_mode = break_mode;
+        pu.underflow_policy = underflow_policy;
+        pu.instruction_pointer = instruction_pointer;
+        pu.instruction_count = instruction_count;
+        pu.exit_code = exit_code;
+        pu.seed = seed;
+        pu.rng = Rng {
+            state: random_state,
+        };
+        Ok(pu)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::asm::assemble;
+    use crate::cpu::run;
+    use crate::error::MdpuError;
+    use crate::isa::Instruction;
+    use crate::output::NullSink;
+
+    const LOOP: &str = "LI R1 50
+loop: RAND R2
+ADD R0 R2 R0
+PUSH R0
+POP R3
+CMP R0 R3
+DEC R1
+JNZ R1 loop
+HALT";
+
+    // File in the temporary directory that no other test uses
+    fn temp_file(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("mdpu-{}-{}", std::process::id(), name))
+    }
+
+    fn machine() -> ProcessingUnit {
+        let mut pu = ProcessingUnit::initialize(4, 8);
+        pu.set_output(NullSink);
+        pu.set_seed(7);
+        pu
+    }
+
+    #[test]
+    fn resumed_run_ends_like_an_uninterrupted_one() {
+        let program: Vec<Instruction> = assemble(LOOP).unwrap().into_instructions();
+        let mut whole = machine();
+        run(&mut whole, &program, 1000).unwrap();
+        let total = whole.instruction_count();
+
+        let mut first = machine();
+        let half = total / 2;
+        let err = run(&mut first, &program, half).unwrap_err();
+        assert_eq!(err, MdpuError::InstructionLimitExceeded { limit: half });
+        let path = temp_file("resume.snapshot");
+        first.save_snapshot(&path).unwrap();
+        let mut resumed = ProcessingUnit::load_snapshot(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        resumed.set_output(NullSink);
+        assert_eq!(resumed.instruction_pointer(), first.instruction_pointer());
+        assert_eq!(resumed.instruction_count(), half);
+
+        run(&mut resumed, &program, 1000).unwrap();
+        assert_eq!(resumed.state(), whole.state());
+        assert_eq!(resumed.memory(), whole.memory());
+        assert_eq!(resumed.instruction_pointer(), whole.instruction_pointer());
+        assert_eq!(resumed.instruction_count(), total);
+    }
+
+    #[test]
+    fn inconsistent_stack_pointer_is_refused() {
+        let path = temp_file("inconsistent.snapshot");
+        machine().save_snapshot(&path).unwrap();
+        let text = fs::read_to_string(&path).unwrap();
+        fs::write(&path, text.replace("stack_pointer 7", "stack_pointer 8")).unwrap();
+        let Err(err) = ProcessingUnit::load_snapshot(&path) else {
+            panic!("loaded a snapshot with its stack pointer past the end of memory");
+        };
+        fs::remove_file(&path).unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            err.to_string(),
+            "Stack pointer 8 is inconsistent with 8 memory cells"
+        );
+    }
+
+    #[test]
+    fn every_field_round_trips() {
+        let mut pu = ProcessingUnit::with_stack(3, 16, 6);
+        pu.registers = Vec::from([1, -2, i32::MIN]);
+        pu.memory[0] = 42;
+        pu.memory[15] = -1;
+        pu.stack_pointer = 12;
+        pu.frame_pointer = 13;
+        pu.flags = Flags::from_bits(Flags::NEGATIVE | Flags::CARRY);
+        pu.max_instructions = 77;
+        pu.trap_overflow = true;
+        pu.zero_register = true;
+        pu.break_mode = BreakMode::Stop;
+        pu.underflow_policy = UnderflowPolicy::ReturnZero;
+        pu.instruction_pointer = 5;
+        pu.instruction_count = 40;
+        pu.exit_code = Some(-3);
+        pu.set_seed(99);
+        pu.rng.next_i32();
+
+        let path = temp_file("fields.snapshot");
+        pu.save_snapshot(&path).unwrap();
+        let loaded = ProcessingUnit::load_snapshot(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.registers, pu.registers);
+        assert_eq!(loaded.memory, pu.memory);
+        assert_eq!(loaded.stack_pointer, 12);
+        assert_eq!(loaded.stack_limit, pu.stack_limit);
+        assert_eq!(loaded.frame_pointer, 13);
+        assert_eq!(loaded.flags, pu.flags);
+        assert_eq!(loaded.max_instructions, 77);
+        assert!(loaded.trap_overflow);
+        assert!(loaded.zero_register);
+        assert_eq!(loaded.break_mode, BreakMode::Stop);
+        assert_eq!(loaded.underflow_policy, UnderflowPolicy::ReturnZero);
+        assert_eq!(loaded.instruction_pointer, 5);
+        assert_eq!(loaded.instruction_count, 40);
+        assert_eq!(loaded.exit_code, Some(-3));
+        assert_eq!(loaded.seed, 99);
+        assert_eq!(loaded.rng.state, pu.rng.state);
+
+        // And the defaults, with no exit code
+        let pu = machine();
+        pu.save_snapshot(&path).unwrap();
+        let loaded = ProcessingUnit::load_snapshot(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.exit_code, None);
+        assert!(!loaded.trap_overflow && !loaded.zero_register);
+        assert_eq!(loaded.break_mode, BreakMode::Continue);
+        assert_eq!(loaded.underflow_policy, UnderflowPolicy::Trap);
+        assert_eq!(loaded.max_instructions, usize::MAX);
+    }
+
+    #[test]
+    fn malformed_snapshots_are_refused() {
+        let path = temp_file("malformed.snapshot");
+        machine().save_snapshot(&path).unwrap();
+        let text = fs::read_to_string(&path).unwrap();
+        for (from, to, message) in [
+            (
+                "mdpu-snapshot 1",
+                "mdpu-snapshot 2",
+                "Not an mdpu snapshot file",
+            ),
+            ("flags 0", "flags 16", "Invalid value `16` for `flags`"),
+            (
+                "break_mode continue",
+                "break_mode pause",
+                "Invalid value `pause` for `break_mode`",
+            ),
+            (
+                "trap_overflow 0",
+                "trap_overflow",
+                "Expected one value for `trap_overflow`",
+            ),
+            (
+                "exit_code none",
+                "exit_code x",
+                "Invalid value `x` for `exit_code`",
+            ),
+            ("\nseed", "\nrng", "Expected `seed` in snapshot"),
+        ] {
+            fs::write(&path, text.replace(from, to)).unwrap();
+            let Err(err) = ProcessingUnit::load_snapshot(&path) else {
+                panic!("loaded a snapshot with {:?}", to);
+            };
+            assert_eq!(err.to_string(), message);
+        }
+        fs::write(&path, format!("{}extra 1\n", text)).unwrap();
+        assert!(ProcessingUnit::load_snapshot(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
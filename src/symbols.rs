@@ -0,0 +1,94 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+// What a symbol names
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolKind {
+    Label,    // An instruction address
+    Data,     // A memory address, for labels on data directives
+    Constant, // A value from `.const`, `.define` or `EQU`
+    Register, // A register index, from `.alias`
+}
+
+// A label or constant and where it was defined
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SymbolEntry {
+    pub name: String, // Local labels are qualified with their scope, as in `main.loop`
+    pub value: i128,
+    pub kind: SymbolKind,
+    pub file: Option<String>,
+    pub line: usize,
+}
+
+// Every label and constant of an assembled program, sorted by value
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SymbolTable {
+    entries: Vec<SymbolEntry>,
+}
+
+impl SymbolTable {
+    pub fn new(mut entries: Vec<SymbolEntry>) -> Self {
+        entries.sort_by(|a, b| a.value.cmp(&b.value).then_with(|| a.name.cmp(&b.name)));
+        SymbolTable { entries }
+    }
+
+    pub fn entries(&self) -> &[SymbolEntry] {
+        &self.entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&SymbolEntry> {
+        self.entries.iter().find(|entry| entry.name == name)
+    }
+
+    // Name of the first label pointing at `addr`, for output that shows addresses
+    pub fn label_at(&self, addr: usize) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|entry| entry.kind == SymbolKind::Label && entry.value == addr as i128)
+            .map(|entry| entry.name.as_str())
+    }
+
+    // Name of the first alias for register `reg`, for output that shows registers
+    pub fn register_alias(&self, reg: usize) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|entry| entry.kind == SymbolKind::Register && entry.value == reg as i128)
+            .map(|entry| entry.name.as_str())
+    }
+}
+
+// One `name = value` line per symbol. Data labels, constants and register aliases are
+// marked `(data)`, `(const)` and `(register)`, and the defining file is shown when the symbols come from more than one file.
+impl fmt::Display for SymbolTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let first_file = self.entries.first().map(|entry| &entry.file);
+        let show_files = self
+            .entries
+            .iter()
+            .any(|entry| Some(&entry.file) != first_file);
+
+        for entry in &self.entries {
+            write!(f, "{} = {}", entry.name, entry.value)?;
+            let mut notes = Vec::new();
+            match entry.kind {
+                SymbolKind::Label => {}
+                SymbolKind::Data => notes.push("data"),
+                SymbolKind::Constant => notes.push("const"),
+                SymbolKind::Register => notes.push("register"),
+            }
+            if let (true, Some(file)) = (show_files, &entry.file) {
+                notes.push(file);
+            }
+            if !notes.is_empty() {
+                write!(f, " ({})", notes.join(", "))?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,139 @@
+// Host services a program asks for with `SYS n`
+//
+// The number picks the service and its arguments and results are passed in registers:
+// the value a syscall takes or returns is in R0, and a syscall that can fail reports
+// whether it succeeded in R1. A machine runs every SYS through its `SyscallHandler`,
+// `StandardSyscalls` unless the embedder set another one.
+use alloc::boxed::Box;
+use alloc::format;
+
+use crate::error::MdpuError;
+use crate::input::Input;
+use crate::output::{character, BufferedSink, OutputSink};
+
+pub const SYS_EXIT: i32 = 0; // Halt with R0 as the exit code, like HALT R0
+pub const SYS_PRINT_INT: i32 = 1; // Write R0 in decimal
+pub const SYS_PRINT_CHAR: i32 = 2; // Write the character whose code is in R0
+pub const SYS_READ_INT: i32 = 3; // R0 = next word of the input as a number, R1 = 1 if it was one
+pub const SYS_READ_CHAR: i32 = 4; // R0 = next byte of the input, -1 at its end
+pub const SYS_INSTRUCTION_COUNT: i32 = 5; // R0 = instructions executed before this SYS
+
+// How execution goes on after a syscall
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyscallEffect {
+    Continue,
+    Exit(i32), // Halt with this exit code
+}
+
+// The parts of the machine a syscall may use
+pub struct SyscallContext<'a> {
+    pub(crate) registers: &'a mut [i32],
+    pub(crate) output: &'a mut BufferedSink,
+    pub(crate) input: &'a mut Input,
+    pub(crate) ip: usize,
+    pub(crate) instruction_count: usize,
+}
+
+impl SyscallContext<'_> {
+    pub fn register(&self, reg: usize) -> Result<i32, MdpuError> {
+        self.registers
+            .get(reg)
+            .copied()
+            .ok_or(MdpuError::RegisterOutOfBounds { reg, ip: self.ip })
+    }
+
+    pub fn set_register(&mut self, reg: usize, value: i32) -> Result<(), MdpuError> {
+        let ip = self.ip;
+        *self
+            .registers
+            .get_mut(reg)
+            .ok_or(MdpuError::RegisterOutOfBounds { reg, ip })? = value;
+        Ok(())
+    }
+
+    // Sink of the machine, where printed values go
+    pub fn output(&mut self) -> &mut dyn OutputSink {
+        &mut *self.output
+    }
+
+    // Input of the machine, shared with READI and READC. Like them, it writes out what
+    // was printed before anything is read.
+    pub fn input(&mut self) -> &mut Input {
+        self.output.flush();
+        &mut *self.input
+    }
+
+    // Address of the SYS being run
+    pub fn ip(&self) -> usize {
+        self.ip
+    }
+
+    // Number of instructions executed before the SYS
+    pub fn instruction_count(&self) -> usize {
+        self.instruction_count
+    }
+
+    // The fault for a syscall number the handler does not know
+    pub fn unknown(&self, number: i32) -> MdpuError {
+        MdpuError::UnknownSyscall {
+            number,
+            ip: self.ip,
+        }
+    }
+}
+
+// Runs the syscalls of a machine. A handler that adds its own numbers can pass the
+// others on to `StandardSyscalls`.
+pub trait SyscallHandler: Send {
+    fn syscall(
+        &mut self,
+        number: i32,
+        context: &mut SyscallContext<'_>,
+    ) -> Result<SyscallEffect, MdpuError>;
+}
+
+// The syscalls numbered by the `SYS_` constants
+#[derive(Debug, Default, Clone, Copy)]
+pub struct StandardSyscalls;
+
+impl SyscallHandler for StandardSyscalls {
+    fn syscall(
+        &mut self,
+        number: i32,
+        context: &mut SyscallContext<'_>,
+    ) -> Result<SyscallEffect, MdpuError> {
+        match number {
+            SYS_EXIT => return Ok(SyscallEffect::Exit(context.register(0)?)),
+            SYS_PRINT_INT => {
+                let value = context.register(0)?;
+                context.output().write_out(&format!("{}", value));
+            }
+            SYS_PRINT_CHAR => {
+                let c = character(context.register(0)?);
+                context.output().write_out(c.encode_utf8(&mut [0; 4]));
+            }
+            SYS_READ_INT => {
+                let value = context
+                    .input()
+                    .read_word()
+                    .and_then(|word| word.parse().ok());
+                context.set_register(0, value.unwrap_or(0))?;
+                context.set_register(1, i32::from(value.is_some()))?;
+            }
+            SYS_READ_CHAR => {
+                let byte = context.input().read_byte().map_or(-1, i32::from);
+                context.set_register(0, byte)?;
+            }
+            SYS_INSTRUCTION_COUNT => {
+                let count = i32::try_from(context.instruction_count()).unwrap_or(i32::MAX);
+                context.set_register(0, count)?;
+            }
+            _ => return Err(context.unknown(number)),
+        }
+        Ok(SyscallEffect::Continue)
+    }
+}
+
+pub(crate) fn default_syscalls() -> Box<dyn SyscallHandler> {
+    Box::new(StandardSyscalls)
+}
@@ -0,0 +1,154 @@
+// Exit statuses and results of the mdpu binary, run on programs piped to its standard
+// input or on the examples in programs/
+#![cfg(feature = "std")]
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+// Run mdpu with `args`, writing `program` to its standard input
+fn mdpu(args: &[&str], program: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_mdpu"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // mdpu may exit before reading its input, closing the pipe under the write
+    let _ = child.stdin.take().unwrap().write_all(program.as_bytes());
+    child.wait_with_output().unwrap()
+}
+
+fn status(args: &[&str], program: &str) -> Option<i32> {
+    mdpu(args, program).status.code()
+}
+
+#[test]
+fn finished_run_exits_with_0() {
+    let output = mdpu(&["2", "4", "-"], "LI R0 7\nHALT");
+    assert_eq!(output.status.code(), Some(0));
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.starts_with("Registers: [7, 0]"), "{}", stdout);
+}
+
+#[test]
+fn usage_error_exits_with_2() {
+    assert_eq!(status(&[], ""), Some(2));
+    assert_eq!(status(&["2", "0", "-"], "HALT"), Some(2));
+    assert_eq!(status(&["--no-such-flag", "2", "4", "-"], "HALT"), Some(2));
+}
+
+#[test]
+fn load_error_exits_with_3() {
+    assert_eq!(status(&["2", "4", "-"], "FROB R0"), Some(3));
+    assert_eq!(status(&["2", "4", "missing.instr"], ""), Some(3));
+}
+
+#[test]
+fn runtime_fault_exits_with_4() {
+    assert_eq!(status(&["2", "4", "-"], "POP R0"), Some(4));
+    assert_eq!(status(&["2", "4", "-"], "LI R1 0\nDIV R0 R1 R0"), Some(4));
+}
+
+#[test]
+fn instruction_limit_exits_with_5() {
+    assert_eq!(status(&["2", "4", "-"], "top: JMP top"), Some(5));
+}
+
+#[test]
+fn failed_assertion_exits_with_6() {
+    assert_eq!(status(&["2", "4", "-"], "ASSERT_EQ R0 1"), Some(6));
+    assert_eq!(
+        status(&["2", "4", "-"], "LI R1 1\nASSERT_EQ_R R0 R1"),
+        Some(6)
+    );
+}
+
+#[test]
+fn halt_exits_with_its_code() {
+    assert_eq!(status(&["2", "4", "-"], "HALT 9"), Some(9));
+    assert_eq!(status(&["2", "4", "-"], "LI R1 42\nHALT R1"), Some(42));
+    assert_eq!(status(&["2", "4", "-"], "HALT 0"), Some(0));
+}
+
+#[test]
+fn seed_fixes_the_random_numbers() {
+    let program = "RAND R0\nLI R1 10\nRANDR R1 R1\nHALT";
+    let first = mdpu(&["--seed", "42", "2", "4", "-"], program);
+    let stdout = String::from_utf8(first.stdout).unwrap();
+    assert!(
+        stdout.starts_with("Registers: [-1109970394, "),
+        "{}",
+        stdout
+    );
+    let second = mdpu(&["--seed", "42", "2", "4", "-"], program);
+    assert_eq!(String::from_utf8(second.stdout).unwrap(), stdout);
+    // Only a run without a seed says which one it used
+    assert!(!String::from_utf8_lossy(&first.stderr).contains("Random seed"));
+    let unseeded = mdpu(&["2", "4", "-"], program);
+    assert!(String::from_utf8_lossy(&unseeded.stderr).contains("Random seed"));
+}
+
+#[test]
+fn stdin_file_feeds_readi() {
+    let path = std::env::temp_dir().join(format!("mdpu-input-{}", std::process::id()));
+    std::fs::write(&path, "42 -7 5").unwrap();
+    let path = path.to_str().unwrap();
+    let program = "LI R1 3\nnext: READI R0\nADD R2 R0 R2\nPRINT R2\nLOOP R1 next";
+    let output = mdpu(&["--stdin-file", path, "4", "16", "-"], program);
+    assert_eq!(output.status.code(), Some(0));
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.starts_with("42\n35\n40\n"), "{}", stdout);
+
+    std::fs::write(path, "42 junk").unwrap();
+    assert_eq!(
+        status(&["--stdin-file", path, "4", "16", "-"], program),
+        Some(4)
+    );
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn define_sets_a_symbol_for_ifdef() {
+    let program = ".ifdef DEBUG\nHALT 1\n.else\nHALT 2\n.endif";
+    assert_eq!(status(&["2", "4", "-"], program), Some(2));
+    assert_eq!(
+        status(&["--define", "DEBUG", "2", "4", "-"], program),
+        Some(1)
+    );
+    let program = "HALT LEVEL";
+    assert_eq!(
+        status(&["--define", "LEVEL=7", "2", "4", "-"], program),
+        Some(7)
+    );
+}
+
+#[test]
+fn example_programs_leave_their_results() {
+    for (args, registers) in [
+        (
+            &["18", "100", "programs/0.instr"][..],
+            "[5, 5, 15, 10, 150, 30, 30, 30, 30, 0, 15, 15, -11, 163840, 0, 0, -5, 5]",
+        ),
+        (&["4", "16", "programs/1.instr"], "[55, 0, 1, 0]"),
+        (
+            &["8", "16", "programs/2.instr"],
+            "[-3, 12, 12, 40, 40, 0, 28, 28]",
+        ),
+        (&["4", "16", "programs/3.instr"], "[4, -1, -1, -1]"),
+        (
+            &["--define", "LARGE", "8", "16", "programs/3.instr"],
+            "[8, -1, -1, -1, -1, -1, -1, -1]",
+        ),
+        (&["4", "16", "programs/4.instr"], "[42, 0, 6, 1]"),
+        (
+            &["4", "16", "programs/5.instr", "programs/5-math.instr"],
+            "[42, 6, 0, 1]",
+        ),
+    ] {
+        let output = mdpu(args, "");
+        assert_eq!(output.status.code(), Some(0), "{:?}", args);
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        let expected = format!("Registers: {}\n", registers);
+        assert!(stdout.starts_with(&expected), "{:?}: {}", args, stdout);
+    }
+}
@@ -0,0 +1,87 @@
+// The execution core through the APIs that are there without `std`, so this also runs
+// under `cargo test --no-default-features`
+use std::sync::{Arc, Mutex};
+
+use mdpu::{
+    assemble, decode_program, encode_program, run, MdpuError, OutputSink, ProcessingUnit,
+    ProcessingUnitBuilder,
+};
+
+// Sink keeping what a machine prints where the test can still read it
+#[derive(Clone, Default)]
+struct Shared(Arc<Mutex<String>>);
+
+impl OutputSink for Shared {
+    fn write_out(&mut self, text: &str) {
+        self.0.lock().unwrap().push_str(text);
+    }
+
+    fn write_err(&mut self, text: &str) {
+        self.0.lock().unwrap().push_str(text);
+    }
+}
+
+fn machine(registers: usize, memory: usize) -> ProcessingUnit {
+    ProcessingUnitBuilder::new()
+        .registers(registers)
+        .memory(&[memory])
+        .build()
+        .unwrap()
+}
+
+#[test]
+fn assembled_program_runs() {
+    let program = assemble("LI R0 5\nloop: ADD R1 R0 R1\nDEC R0\nJNZ R0 loop\nHALT 3").unwrap();
+    let mut pu = machine(2, 4);
+    let state = run(&mut pu, &program, 100).unwrap();
+    assert_eq!(state.registers, &[0, 15]);
+    assert_eq!(state.exit_code, Some(3));
+}
+
+#[test]
+fn data_and_stack_live_in_memory() {
+    let program = assemble(".data 0\n.word 20, 22\nLOAD R0 0\nLOAD R1 1\nPUSH R0\nPUSH R1\nPOP R2\nPOP R3\nADD R2 R3 R0\nSTORE R0 2").unwrap();
+    let mut pu = machine(4, 8);
+    pu.load_data(&program).unwrap();
+    run(&mut pu, &program, 100).unwrap();
+    assert_eq!(pu.registers(), &[42, 22, 22, 20]);
+    assert_eq!(pu.read_memory(2), Ok(42));
+    assert!(pu.stack().is_empty());
+}
+
+#[test]
+fn printed_output_reaches_the_sink() {
+    let program =
+        assemble("LI R0 1\nloop: PRINT R0\nINC R0\nLI R1 6\nCMP R0 R1\nJNE R0 R1 loop\nPRINTC R1")
+            .unwrap();
+    let sink = Shared::default();
+    let mut pu = machine(2, 4);
+    pu.set_output(sink.clone());
+    run(&mut pu, &program, 100).unwrap();
+    assert!(sink.0.lock().unwrap().starts_with("1\n2\n3\n4\n5\n"));
+}
+
+#[test]
+fn faults_and_the_limit_are_returned() {
+    let mut pu = machine(2, 4);
+    let program = assemble("LI R1 0\nDIV R0 R1 R0").unwrap();
+    assert_eq!(
+        run(&mut pu, &program, 100).unwrap_err(),
+        MdpuError::DivisionByZero { reg: 1, ip: 1 }
+    );
+
+    let mut pu = machine(2, 4);
+    let program = assemble("loop: JMP loop").unwrap();
+    assert_eq!(
+        run(&mut pu, &program, 50).unwrap_err(),
+        MdpuError::InstructionLimitExceeded { limit: 50 }
+    );
+}
+
+#[test]
+fn binary_image_runs_like_its_source() {
+    let program = assemble("LI R0 6\nMULI R0 R0 7\nHALT R0").unwrap();
+    let decoded = decode_program(&encode_program(&program)).unwrap();
+    let mut pu = machine(1, 2);
+    assert_eq!(run(&mut pu, &decoded, 100).unwrap().exit_code, Some(42));
+}
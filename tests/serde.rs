@@ -0,0 +1,78 @@
+// JSON round trips of a machine, its instructions and its state, which only build with
+// `--features serde`
+#![cfg(feature = "serde")]
+use mdpu::{
+    assemble, run, Instruction, ProcessingUnit, ProcessingUnitBuilder, ProcessingUnitState,
+    StepOutcome,
+};
+
+// Sums 1..10 on the stack and draws a random number, touching the registers, memory,
+// stack pointer, flags and generator
+const SOURCE: &str = "LI R1 10
+next: PUSH R1
+LOOP R1 next
+LI R1 10
+add: POP R2
+ADD R0 R2 R0
+LOOP R1 add
+RAND R3
+CMP R0 R3
+STORE R0 0
+HALT 1";
+
+fn machine() -> ProcessingUnit {
+    let mut pu = ProcessingUnitBuilder::new()
+        .registers(4)
+        .memory(&[32])
+        .trap_overflow(true)
+        .build()
+        .unwrap();
+    pu.set_seed(9);
+    pu
+}
+
+#[test]
+fn machine_resumes_from_json_mid_program() {
+    let program = assemble(SOURCE).unwrap();
+    let mut whole = machine();
+    run(&mut whole, &program, 1000).unwrap();
+
+    for steps in [0, 1, 12, 25] {
+        let mut first = machine();
+        for _ in 0..steps {
+            assert_eq!(first.step(&program), StepOutcome::Continue);
+        }
+        let json = serde_json::to_string(&first).unwrap();
+        let mut resumed: ProcessingUnit = serde_json::from_str(&json).unwrap();
+        assert_eq!(resumed.instruction_pointer(), first.instruction_pointer());
+        assert_eq!(resumed.state(), first.state());
+        run(&mut resumed, &program, 1000).unwrap();
+        assert_eq!(resumed.state(), whole.state(), "after {} steps", steps);
+        assert_eq!(resumed.memory(), whole.memory());
+        assert_eq!(resumed.instruction_count(), whole.instruction_count());
+        assert!(resumed.trap_overflow());
+    }
+}
+
+#[test]
+fn instructions_round_trip_through_json() {
+    let source = "ADD R0 R1 R2\nBR -3\nJMP .+2\nLOAD R1 [R2-1]\nDIVMOD R0 R1 R2 R3\nLI R0 -5";
+    let instructions = assemble(source).unwrap().into_instructions();
+    let json = serde_json::to_string(&instructions).unwrap();
+    let decoded: Vec<Instruction> = serde_json::from_str(&json).unwrap();
+    assert_eq!(decoded, instructions);
+}
+
+#[test]
+fn state_round_trips_through_json() {
+    let mut pu = machine();
+    run(&mut pu, &assemble(SOURCE).unwrap(), 1000).unwrap();
+    let state = ProcessingUnitState::from(pu.state());
+    let json = serde_json::to_string(&state).unwrap();
+    assert_eq!(
+        serde_json::from_str::<ProcessingUnitState>(&json).unwrap(),
+        state
+    );
+    // The borrowed view writes the same JSON
+    assert_eq!(serde_json::to_string(&pu.state()).unwrap(), json);
+}
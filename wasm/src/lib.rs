@@ -0,0 +1,60 @@
+// WebAssembly bindings for running programs in the browser
+use wasm_bindgen::prelude::*;
+
+use mdpu::{assemble, run, ProcessingUnit, ProcessingUnitBuilder, Program};
+
+#[wasm_bindgen]
+pub struct WasmMachine {
+    pu: ProcessingUnit,
+    program: Program,
+}
+
+#[wasm_bindgen]
+impl WasmMachine {
+    #[wasm_bindgen(constructor)]
+    pub fn new(registers: usize, memory: usize) -> Result<WasmMachine, JsError> {
+        let pu = ProcessingUnitBuilder::new()
+            .registers(registers)
+            .memory(&[memory])
+            .build()?;
+        Ok(WasmMachine {
+            pu,
+            program: Program::default(),
+        })
+    }
+
+    // Assemble program text, make it the program to run and write its data into memory.
+    // Registers, flags, the stack and the instruction count start over.
+    pub fn load(&mut self, text: &str) -> Result<(), JsError> {
+        let program = assemble(text)?;
+        self.pu.reset_registers_only();
+        self.pu.load_data(&program)?;
+        self.program = program;
+        Ok(())
+    }
+
+    // Run for at most `max_instructions` more instructions
+    pub fn run(&mut self, max_instructions: usize) -> Result<(), JsError> {
+        let limit = self.pu.instruction_count().saturating_add(max_instructions);
+        run(&mut self.pu, &self.program, limit)?;
+        Ok(())
+    }
+
+    pub fn registers(&self) -> Vec<i32> {
+        self.pu.registers().to_vec()
+    }
+
+    pub fn stack(&self) -> Vec<i32> {
+        self.pu.stack().to_vec()
+    }
+
+    // Flags word, with the bits of `Flags::ZERO` and the other flag constants
+    pub fn flags(&self) -> u8 {
+        self.pu.flags().bits()
+    }
+
+    // Code of the HALT that stopped the program, undefined if it ran off its end
+    pub fn exit_code(&self) -> Option<i32> {
+        self.pu.exit_code()
+    }
+}
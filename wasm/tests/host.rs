@@ -0,0 +1,25 @@
+// The bindings run natively, on paths that never build a `JsError` (that needs a JS host)
+use mdpu_wasm::WasmMachine;
+
+#[test]
+fn each_load_and_run_starts_a_fresh_budget() {
+    let mut machine = WasmMachine::new(2, 8).unwrap();
+    machine.load("LI R0 1\nPUSH R0\nCMP R0 R0").unwrap();
+    machine.run(4).unwrap();
+    assert_eq!(machine.stack(), [1]);
+    assert_ne!(machine.flags(), 0);
+
+    // The count, stack and flags left by the first program do not carry over
+    machine.load("LI R0 1\nLI R1 2\nADD R0 R1 R1\nNOP").unwrap();
+    assert!(machine.stack().is_empty());
+    assert_eq!(machine.flags(), 0);
+    machine.run(4).unwrap();
+    assert_eq!(machine.registers(), [1, 3]);
+
+    // The eight instructions counted so far are not charged to this run of eight
+    machine
+        .load("LI R0 3\nloop: DEC R0\nJNZ R0 loop\nHALT 7")
+        .unwrap();
+    machine.run(8).unwrap();
+    assert_eq!(machine.exit_code(), Some(7));
+}